@@ -1,6 +1,17 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
 fn main() {
-    let target = std::env::var("TARGET").unwrap();
+    let target = env::var("TARGET").unwrap();
     if target.starts_with("thumb") {
+        // memory.x is kept in the crate root instead of using the embassy-stm32 `memory-x`
+        // feature, so the flash sectors used for persistent storage stay out of the image
+        let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+        fs::copy("memory.x", out.join("memory.x")).unwrap();
+        println!("cargo:rustc-link-search={}", out.display());
+        println!("cargo:rerun-if-changed=memory.x");
+
         println!("cargo:rustc-link-arg-bins=--nmagic");
         println!("cargo:rustc-link-arg-bins=-Tlink.x");
         println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
@@ -0,0 +1,61 @@
+use std::io::{self, ErrorKind, Read, Write};
+
+use icbm_protocol::frame::{self, FrameDecoder, MAX_FRAME_SIZE, MAX_MESSAGE_SIZE};
+use icbm_protocol::message::{Request, Response};
+
+// Request/response client over any byte stream (serial port, pty, ...).
+// Timeouts are those configured on the underlying port.
+pub struct Client<P: Read + Write> {
+    port: P,
+    next_id: u16,
+    decoder: FrameDecoder,
+}
+
+impl<P: Read + Write> Client<P> {
+    pub fn new(port: P) -> Self {
+        Client {
+            port,
+            next_id: 1,
+            decoder: FrameDecoder::new(),
+        }
+    }
+
+    pub fn port_mut(&mut self) -> &mut P {
+        &mut self.port
+    }
+
+    pub fn request(&mut self, request: &Request) -> io::Result<Response> {
+        let request_id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1).max(1);
+
+        let mut message = [0u8; MAX_MESSAGE_SIZE];
+        let len = request
+            .encode(request_id, &mut message)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("{e:?}")))?;
+        let mut frame = [0u8; MAX_FRAME_SIZE];
+        let frame_len = frame::encode(&message[..len], &mut frame)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("{e:?}")))?;
+        self.port.write_all(&frame[..frame_len])?;
+        self.port.flush()?;
+
+        // Frames for other request ids (late replies to timed out requests) are skipped
+        let mut buf = [0u8; 64];
+        loop {
+            let n = self.port.read(&mut buf)?;
+            if n == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            for &byte in &buf[..n] {
+                match self.decoder.feed(byte, &mut message) {
+                    Some(Ok(len)) => match Response::decode(&message[..len]) {
+                        Ok((id, response)) if id == request_id => return Ok(response),
+                        Ok((id, _)) => eprintln!("ignoring response to request {id}"),
+                        Err(e) => eprintln!("ignoring undecodable response: {e:?}"),
+                    },
+                    Some(Err(e)) => eprintln!("ignoring corrupt frame: {e:?}"),
+                    None => {}
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::io::{self, ErrorKind, Read, Write};
+
+use chrono::DateTime;
+use icbm_protocol::datalog::{self, state, BlockHeader, Encoder, Sample, BLOCK_SIZE};
+use icbm_protocol::message::{ErrorCode, Request, Response};
+
+use crate::client::Client;
+
+// One downloaded data log block with its decoded samples
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub samples: Vec<Sample>,
+}
+
+// Decodes the raw bytes of a block; `None` if the header is invalid
+pub fn decode_block(raw: &[u8]) -> Option<Block> {
+    let header = BlockHeader::decode(raw)?;
+    Some(Block {
+        header,
+        samples: datalog::entries(raw).collect(),
+    })
+}
+
+// Downloads every block held by the device, oldest first. Blocks the logger drops from
+// the ring during the download are skipped.
+pub fn download<P: Read + Write>(client: &mut Client<P>) -> io::Result<Vec<Block>> {
+    let Response::LogInfo {
+        blocks,
+        first_seq,
+        last_seq,
+    } = client.request(&Request::GetLogInfo)?
+    else {
+        return Err(io::Error::other("unexpected response to log info request"));
+    };
+    if blocks == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut out = Vec::new();
+    for seq in first_seq..=last_seq {
+        if let Some(raw) = read_block(client, seq)? {
+            match decode_block(&raw) {
+                Some(block) if block.header.seq == seq => out.push(block),
+                _ => eprintln!("skipping undecodable log block #{seq}"),
+            }
+        }
+    }
+    Ok(out)
+}
+
+// Raw bytes of block `seq`, or `None` if the device no longer holds it
+fn read_block<P: Read + Write>(client: &mut Client<P>, seq: u32) -> io::Result<Option<Vec<u8>>> {
+    let mut raw = Vec::new();
+    loop {
+        let offset = raw.len() as u16;
+        match client.request(&Request::ReadLog { seq, offset })? {
+            Response::LogChunk(chunk) if chunk.seq == seq && chunk.offset == offset => {
+                raw.extend_from_slice(chunk.data());
+                if raw.len() >= chunk.block_len as usize {
+                    return Ok(Some(raw));
+                }
+                if chunk.len == 0 || raw.len() > BLOCK_SIZE {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "truncated log block",
+                    ));
+                }
+            }
+            Response::Error(ErrorCode::InvalidValue) if offset == 0 => return Ok(None),
+            other => {
+                return Err(io::Error::other(format!(
+                    "unexpected response to log read: {other:?}"
+                )))
+            }
+        }
+    }
+}
+
+pub const CSV_HEADER: &str =
+    "boot,seq,time_s,datetime,temp_c,humidity_rh,co2_ppm,flow_ml_min,heater_on,co2_dosed,humidifier_on,alarms";
+
+// One line per sample; missing readings are left empty
+pub fn write_csv<W: Write>(blocks: &[Block], mut out: W) -> io::Result<()> {
+    writeln!(out, "{CSV_HEADER}")?;
+    let field = |value: Option<f32>, decimals: usize| {
+        value.map_or(String::new(), |v| format!("{v:.decimals$}"))
+    };
+    for block in blocks {
+        for s in &block.samples {
+            writeln!(
+                out,
+                "{},{},{},{},{},{},{},{},{},{},{},{:#x}",
+                block.header.boot,
+                block.header.seq,
+                s.time_s,
+                s.unix_s.map_or(String::new(), iso8601),
+                field(s.temp_c, 2),
+                field(s.humidity_rh, 1),
+                field(s.co2_ppm, 0),
+                field(s.flow_ml_min, 2),
+                (s.state & state::HEATER_ON != 0) as u8,
+                (s.state & state::CO2_DOSED != 0) as u8,
+                (s.state & state::HUMIDIFIER_ON != 0) as u8,
+                s.alarms,
+            )?;
+        }
+    }
+    out.flush()
+}
+
+// UTC date and time of a Unix timestamp, e.g. 2024-05-01T12:00:00Z
+pub fn iso8601(unix_s: u32) -> String {
+    DateTime::from_timestamp(unix_s as i64, 0).map_or(String::new(), |t| {
+        t.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+    })
+}
+
+// Splits `samples` into blocks the way the firmware's logger does, starting at `seq`
+pub fn encode_blocks(boot: u16, seq: u32, samples: &[Sample]) -> Vec<Vec<u8>> {
+    let mut blocks = Vec::new();
+    let mut block: Vec<u8> = Vec::new();
+    let mut encoder = Encoder::new();
+    let mut entry = [0u8; datalog::MAX_ENTRY_SIZE];
+
+    for sample in samples {
+        let mut next = encoder.clone();
+        let mut len = next.encode(sample, &mut entry);
+        if block.is_empty() || block.len() + len > BLOCK_SIZE {
+            if !block.is_empty() {
+                blocks.push(std::mem::take(&mut block));
+            }
+            let header = BlockHeader {
+                boot,
+                seq: seq + blocks.len() as u32,
+            };
+            block.extend_from_slice(&header.encode());
+            next = Encoder::new();
+            len = next.encode(sample, &mut entry);
+        }
+        block.extend_from_slice(&entry[..len]);
+        encoder = next;
+    }
+    if !block.is_empty() {
+        blocks.push(block);
+    }
+    blocks
+}
@@ -0,0 +1,9 @@
+// Host-side tools for the ICBM framed binary protocol (see icbm_protocol), the MQTT
+// telemetry publisher, the Modbus slave, the data log download and the SNTP clock sync
+
+pub mod client;
+pub mod datalog;
+pub mod modbus;
+pub mod mqtt;
+pub mod simulator;
+pub mod sntp;
@@ -0,0 +1,1640 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::net::{Ipv4Addr, TcpListener};
+use std::process::ExitCode;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::NaiveDateTime;
+use icbm_host::client::Client;
+use icbm_host::datalog;
+use icbm_host::modbus::{self, Link, Master, RtuLink, Slave, TcpLink};
+use icbm_host::mqtt::{Broker, Message, MqttClient};
+use icbm_host::simulator::{simulated_log, simulated_samples, Simulator};
+use icbm_host::sntp::{self, Behaviour, QueryError};
+use icbm_protocol::message::{
+    alarm, Device, ErrorCode, ProfileStatus, Request, Response, Setpoints,
+};
+use icbm_protocol::modbus::{function, map, rtu, Exception, MAX_READ_REGISTERS};
+use icbm_protocol::profile::{self, Command, RunState, Segment, Variable};
+use icbm_protocol::PROTOCOL_VERSION;
+use serialport::{Parity, SerialPort, TTYPort};
+
+// The console is the first CDC-ACM interface, the binary protocol the second
+const DEFAULT_PORT: &str = "/dev/ttyACM1";
+const BAUD_RATE: u32 = 115_200;
+const TIMEOUT: Duration = Duration::from_secs(2);
+// Self-test takes ~10 s and calibration ~5 min on the device
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(30);
+const CALIBRATION_TIMEOUT: Duration = Duration::from_secs(600);
+// Every chunk read makes the device scan its log in flash
+const LOG_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_BROKER: &str = "localhost:1883";
+const DEFAULT_LISTEN: &str = "0.0.0.0:1883";
+// The stand-in uses an unprivileged port; the device listens on 502
+const DEFAULT_MODBUS_LISTEN: &str = "0.0.0.0:5020";
+const MODBUS_TIMEOUT: Duration = Duration::from_secs(1);
+// Read timeout of the RTU stand-in, standing in for the 3.5 character frame gap
+const RTU_FRAME_GAP: Duration = Duration::from_millis(10);
+// The device always asks port 123, which needs root or CAP_NET_BIND_SERVICE to serve
+const DEFAULT_NTP_LISTEN: &str = "0.0.0.0:123";
+const NTP_TIMEOUT: Duration = Duration::from_secs(2);
+
+const USAGE: &str = "\
+usage: icbm-host [--port <path>] <command> [args]
+
+commands:
+  hello                     check the link and protocol version
+  readings                  current readings
+  setpoints                 current setpoints
+  set [--co2 <ppm>] [--temp <C>] [--co2-tol <ppm>] [--temp-tol <C>] [--burst <ms>]
+                            change and persist setpoints
+  alarms                    active alarms
+  calibrate                 run the sensor calibration (~5 min)
+  callog                    list stored calibration records
+  selftest                  run the sensor self-tests
+  loginfo                   data log status
+  time                      device wall-clock time
+  settime [<date>T<time>]   set the device clock (UTC), by default to this computer's
+  ntp                       SNTP clock sync status
+  diag                      read counters, self-test results and serial numbers of
+                            every sensor and actuator
+  log [--output <file>]     download the data log as CSV (default stdout)
+  profile                   setpoint profile status and segments
+  profile load <file>       replace the profile with the segments in a file, one per
+                            line as for 'profile add'; '#' starts a comment
+  profile add <temp|co2> (hold <min> | ramp <target> <min> | step <target> <min>)
+                            append a segment
+  profile clear             remove all segments
+  profile start [<date>T<time>]
+                            start the profile now or at a time (UTC)
+  profile pause|resume|stop
+  simulate                  run a device simulator on a new pty and print its path
+  loopback                  round-trip every message through the simulator on a pty
+
+mqtt:
+  broker [--listen <addr>]  run an MQTT broker stand-in printing every publish
+                            (default 0.0.0.0:1883)
+  mqtt-pub [--broker <addr>] [--retain] <topic> <payload>
+                            publish one message (default broker localhost:1883)
+  mqtt-sub [--broker <addr>] <filter>
+                            print messages matching a topic filter
+  mqtt-loopback             check publish/subscribe against a local broker stand-in
+
+modbus:
+  modbus-slave [--listen <addr>] [--rtu] [--unit <id>]
+                            run a slave stand-in on TCP (default 0.0.0.0:5020) or,
+                            with --rtu, on a new pty
+  modbus-read (--tcp <host[:port]> | --rtu <path>) [--unit <id>]
+                            print the decoded register map
+  modbus-check (--tcp <host[:port]> | --rtu <path>) [--unit <id>]
+                            run the conformance checks against a slave; setpoints are
+                            restored (to register resolution) afterwards
+  modbus-loopback           run the conformance checks against the stand-in over TCP
+                            and RTU
+
+ntp:
+  ntp-server [--listen <addr>] [--offset <s>]
+                            run an SNTP server stand-in serving this computer's clock,
+                            shifted by an offset (default 0.0.0.0:123)
+  ntp-query <host[:port]>   offset of this computer's clock from an NTP server
+  ntp-loopback              check the SNTP exchange against a local server stand-in
+";
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let mut port = DEFAULT_PORT.to_string();
+    if args.first().map(String::as_str) == Some("--port") {
+        if args.len() < 2 {
+            eprint!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+        port = args.remove(1);
+        args.remove(0);
+    }
+
+    let Some(command) = args.first().cloned() else {
+        eprint!("{USAGE}");
+        return ExitCode::FAILURE;
+    };
+
+    let result = match command.as_str() {
+        "simulate" => simulate(),
+        "loopback" => loopback(),
+        "broker" => broker(&args[1..]),
+        "mqtt-pub" => mqtt_pub(&args[1..]),
+        "mqtt-sub" => mqtt_sub(&args[1..]),
+        "mqtt-loopback" => mqtt_loopback(),
+        "modbus-slave" => modbus_slave(&args[1..]),
+        "modbus-read" => modbus_read(&args[1..]),
+        "modbus-check" => modbus_check(&args[1..]),
+        "modbus-loopback" => modbus_loopback(),
+        "ntp-server" => ntp_server(&args[1..]),
+        "ntp-query" => ntp_query(&args[1..]),
+        "ntp-loopback" => ntp_loopback(),
+        _ => run(&port, &command, &args[1..]),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, String>;
+
+fn open(port: &str) -> Result<TTYPort> {
+    serialport::new(port, BAUD_RATE)
+        .timeout(TIMEOUT)
+        .open_native()
+        .map_err(|e| format!("cannot open {port}: {e}"))
+}
+
+fn run(port: &str, command: &str, args: &[String]) -> Result<()> {
+    let mut client = Client::new(open(port)?);
+
+    match command {
+        "hello" => print(&request(&mut client, Request::Hello)?),
+        "readings" => print(&request(&mut client, Request::GetReadings)?),
+        "setpoints" => print(&request(&mut client, Request::GetSetpoints)?),
+        "alarms" => print(&request(&mut client, Request::GetAlarms)?),
+        "set" => {
+            let Response::Setpoints(mut setpoints) = request(&mut client, Request::GetSetpoints)?
+            else {
+                return Err("unexpected response to setpoint query".into());
+            };
+            apply_args(&mut setpoints, args)?;
+            print(&request(&mut client, Request::SetSetpoints(setpoints))?);
+        }
+        "calibrate" => {
+            println!("calibrating, this takes about 5 minutes...");
+            set_timeout(&mut client, CALIBRATION_TIMEOUT)?;
+            print(&request(&mut client, Request::StartCalibration)?);
+        }
+        "selftest" => {
+            set_timeout(&mut client, SELF_TEST_TIMEOUT)?;
+            print(&request(&mut client, Request::SelfTest)?);
+        }
+        "callog" => {
+            let mut index = 0;
+            loop {
+                match request(&mut client, Request::GetCalibration { index })? {
+                    Response::CalibrationEntry(entry) => {
+                        print(&Response::CalibrationEntry(entry));
+                        index += 1;
+                        if index >= entry.total {
+                            break;
+                        }
+                    }
+                    other => {
+                        print(&other);
+                        break;
+                    }
+                }
+            }
+        }
+        "loginfo" => print(&request(&mut client, Request::GetLogInfo)?),
+        "time" => print(&request(&mut client, Request::GetTime)?),
+        "settime" => {
+            let unix_s = match args.first() {
+                Some(text) => NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S")
+                    .map_err(|e| format!("invalid time '{text}': {e}"))?
+                    .and_utc()
+                    .timestamp(),
+                None => SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_err(|e| e.to_string())?
+                    .as_secs() as i64,
+            };
+            let unix_s = u32::try_from(unix_s).map_err(|_| "time out of range")?;
+            print(&request(&mut client, Request::SetTime { unix_s })?);
+        }
+        "ntp" => print(&request(&mut client, Request::GetTimeSync)?),
+        "diag" => {
+            for device in Device::ALL {
+                print(&request(&mut client, Request::GetDiagnostics { device })?);
+            }
+        }
+        "profile" => profile(&mut client, args)?,
+        "log" => {
+            let mut args = args.to_vec();
+            let output = take_option(&mut args, "--output")?;
+            set_timeout(&mut client, LOG_TIMEOUT)?;
+            let blocks = datalog::download(&mut client).map_err(|e| e.to_string())?;
+            let samples: usize = blocks.iter().map(|b| b.samples.len()).sum();
+            match output {
+                Some(path) => {
+                    let file = File::create(&path).map_err(|e| format!("{path}: {e}"))?;
+                    datalog::write_csv(&blocks, BufWriter::new(file))
+                        .map_err(|e| format!("{path}: {e}"))?;
+                    println!(
+                        "{} blocks, {samples} samples written to {path}",
+                        blocks.len()
+                    );
+                }
+                None => {
+                    datalog::write_csv(&blocks, io::stdout().lock()).map_err(|e| e.to_string())?
+                }
+            }
+        }
+        _ => return Err(format!("unknown command '{command}'\n{USAGE}")),
+    }
+    Ok(())
+}
+
+fn profile(client: &mut Client<TTYPort>, args: &[String]) -> Result<()> {
+    let Some((action, rest)) = args.split_first() else {
+        let response = request(client, Request::GetProfile)?;
+        print(&response);
+        if let Response::Profile(status) = response {
+            print_segments(client, &status)?;
+        }
+        return Ok(());
+    };
+    let response = match (action.as_str(), rest) {
+        ("load", [path]) => {
+            let text = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+            let mut segments = Vec::new();
+            for (number, line) in text.lines().enumerate() {
+                let line = line.split('#').next().unwrap_or("").trim();
+                if !line.is_empty() {
+                    segments.push(
+                        profile::parse_segment(line)
+                            .map_err(|e| format!("{path}:{}: {e}", number + 1))?,
+                    );
+                }
+            }
+            expect_ack(request(client, Request::ClearProfile)?)?;
+            for (variable, segment) in segments.iter().copied() {
+                expect_ack(request(
+                    client,
+                    Request::AddProfileSegment { variable, segment },
+                )?)?;
+            }
+            println!("{} segments loaded", segments.len());
+            return Ok(());
+        }
+        ("add", words) if !words.is_empty() => {
+            let (variable, segment) = profile::parse_segment(&words.join(" "))?;
+            request(client, Request::AddProfileSegment { variable, segment })?
+        }
+        ("clear", []) => request(client, Request::ClearProfile)?,
+        ("start", []) => request(client, Request::ProfileControl(Command::Start { at: None }))?,
+        ("start", [time]) => {
+            let at = NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S")
+                .map_err(|e| format!("invalid time '{time}': {e}"))?
+                .and_utc()
+                .timestamp();
+            let at = u32::try_from(at).map_err(|_| "time out of range")?;
+            request(
+                client,
+                Request::ProfileControl(Command::Start { at: Some(at) }),
+            )?
+        }
+        ("pause", []) => request(client, Request::ProfileControl(Command::Pause))?,
+        ("resume", []) => request(client, Request::ProfileControl(Command::Resume))?,
+        ("stop", []) => request(client, Request::ProfileControl(Command::Stop))?,
+        _ => return Err(format!("unknown profile command\n{USAGE}")),
+    };
+    print(&response);
+    Ok(())
+}
+
+fn expect_ack(response: Response) -> Result<()> {
+    match response {
+        Response::Ack => Ok(()),
+        Response::Error(code) => Err(format!("device error: {}", describe(code))),
+        other => Err(format!("unexpected response {other:?}")),
+    }
+}
+
+fn print_segments<P: std::io::Read + std::io::Write>(
+    client: &mut Client<P>,
+    status: &ProfileStatus,
+) -> Result<()> {
+    for variable in Variable::ALL {
+        for index in 0..status.segments[variable as usize] {
+            let response = request(client, Request::GetProfileSegment { variable, index })?;
+            let Response::ProfileSegment { segment, .. } = response else {
+                return Err(format!("unexpected response {response:?}"));
+            };
+            let duration = hms(segment.duration_s());
+            let unit = variable.unit();
+            match segment {
+                Segment::Hold { .. } => {
+                    println!("  {} {}: hold for {duration}", variable.name(), index + 1)
+                }
+                Segment::Ramp { target, .. } => println!(
+                    "  {} {}: ramp to {target:.2} {unit} over {duration}",
+                    variable.name(),
+                    index + 1
+                ),
+                Segment::Step { target, .. } => println!(
+                    "  {} {}: step to {target:.2} {unit} for {duration}",
+                    variable.name(),
+                    index + 1
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn hms(seconds: u32) -> String {
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn request<P: std::io::Read + std::io::Write>(
+    client: &mut Client<P>,
+    request: Request,
+) -> Result<Response> {
+    client
+        .request(&request)
+        .map_err(|e| format!("{request:?} failed: {e}"))
+}
+
+fn set_timeout(client: &mut Client<TTYPort>, timeout: Duration) -> Result<()> {
+    client
+        .port_mut()
+        .set_timeout(timeout)
+        .map_err(|e| e.to_string())
+}
+
+fn apply_args(setpoints: &mut Setpoints, args: &[String]) -> Result<()> {
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        return Err("expected --<name> <value> pairs".into());
+    }
+    for pair in args.chunks(2) {
+        let value = &pair[1];
+        let float = || {
+            value
+                .parse::<f32>()
+                .map_err(|_| format!("invalid number '{value}'"))
+        };
+        match pair[0].as_str() {
+            "--co2" => setpoints.co2_ppm = float()?,
+            "--temp" => setpoints.temp_c = float()?,
+            "--co2-tol" => setpoints.co2_tolerance_ppm = float()?,
+            "--temp-tol" => setpoints.temp_tolerance_c = float()?,
+            "--burst" => {
+                setpoints.co2_burst_ms = value
+                    .parse()
+                    .map_err(|_| format!("invalid integer '{value}'"))?
+            }
+            other => return Err(format!("unknown setpoint '{other}'")),
+        }
+    }
+    Ok(())
+}
+
+fn print(response: &Response) {
+    match response {
+        Response::Hello { protocol_version } => {
+            println!("device protocol version {protocol_version} (host {PROTOCOL_VERSION})")
+        }
+        Response::Readings(r) => {
+            let opt = |v: Option<f32>, precision: usize| {
+                v.map_or("n/a".to_string(), |v| format!("{v:.precision$}"))
+            };
+            println!("temp:    {} C", opt(r.temp_c, 2));
+            println!("co2:     {} ppm", opt(r.co2_ppm, 0));
+            println!("rh:      {} %", opt(r.humidity_rh, 1));
+            println!("heater:  {}", if r.heater_on { "on" } else { "off" });
+            println!("humid:   {}", if r.humidifier_on { "on" } else { "off" });
+            println!("bursts:  {}", r.co2_bursts);
+            println!("updated: {} s", r.updated_at_s);
+        }
+        Response::Setpoints(s) => {
+            println!(
+                "co2:      {:.0} ppm (±{:.0})",
+                s.co2_ppm, s.co2_tolerance_ppm
+            );
+            println!("temp:     {:.2} C (±{:.2})", s.temp_c, s.temp_tolerance_c);
+            println!("burst:    {} ms", s.co2_burst_ms);
+        }
+        Response::Alarms(a) => {
+            if a.active == 0 {
+                println!("no active alarms");
+            }
+            for (flag, name) in [
+                (alarm::TEMP_SENSOR_FAULT, "temperature sensor fault"),
+                (alarm::CO2_SENSOR_FAULT, "CO2 sensor fault"),
+                (alarm::TEMP_OUT_OF_RANGE, "temperature out of range"),
+                (alarm::CO2_OUT_OF_RANGE, "CO2 out of range"),
+                (alarm::HUMIDITY_LOW, "humidity low, check the water pan"),
+                (
+                    alarm::ACTUATOR_FAULT,
+                    "Peltier bridge fault, bridge disabled",
+                ),
+                (
+                    alarm::CO2_SENSOR_MISMATCH,
+                    "CO2 sensors disagree, check calibration",
+                ),
+                (alarm::DOOR_OPEN, "door open, CO2 dosing suspended"),
+                (
+                    alarm::CO2_SUPPLY_EMPTY,
+                    "CO2 supply exhausted, check the cylinder",
+                ),
+                (alarm::HUMIDITY_SENSOR_FAULT, "humidity sensor fault"),
+            ] {
+                if a.active & flag != 0 {
+                    println!("ALARM: {name}");
+                }
+            }
+        }
+        Response::Calibrated { seq } => println!("calibration stored as record #{seq}"),
+        Response::CalibrationEntry(e) => println!(
+            "#{} t={}s scd41={:012x} explorir={} ref={:.2}C/{}ppm/{}Pa/{}m \
+             temp={:?}->{:?} offset={:?} co2={:?}->{:?}",
+            e.seq,
+            e.timestamp_s,
+            e.scd41_serial,
+            e.explorir_serial(),
+            e.reference_temp_c,
+            e.reference_co2_ppm,
+            e.pressure_pa,
+            e.altitude_m,
+            e.temp_before_c,
+            e.temp_after_c,
+            e.temp_offset_c,
+            e.co2_before_ppm,
+            e.co2_after_ppm,
+        ),
+        Response::SelfTest { scd41, explorir } => {
+            let result = |ok: bool| if ok { "pass" } else { "FAIL" };
+            println!("SCD41:    {}", result(*scd41));
+            println!("ExplorIR: {}", result(*explorir));
+        }
+        Response::LogInfo {
+            blocks,
+            first_seq,
+            last_seq,
+        } => {
+            if *blocks == 0 {
+                println!("data log empty");
+            } else {
+                println!("data log: {blocks} blocks (#{first_seq} - #{last_seq})");
+            }
+        }
+        Response::Time { unix_s, uptime_s } => {
+            match unix_s {
+                Some(unix_s) => println!("time:   {}", datalog::iso8601(*unix_s)),
+                None => println!("time:   not set"),
+            }
+            println!("uptime: {uptime_s} s");
+        }
+        Response::TimeSync(s) if s.server == [0; 4] => println!("ntp:     off"),
+        Response::TimeSync(s) => {
+            println!("server:  {}", Ipv4Addr::from(s.server));
+            match s.since_sync_s {
+                Some(since) => println!(
+                    "synced:  {since} s ago, stratum {}, delay {} ms",
+                    s.stratum, s.delay_ms
+                ),
+                None => println!("synced:  never"),
+            }
+            if let Some(offset) = s.offset_ms {
+                println!("offset:  {offset} ms");
+            }
+            if let Some(drift) = s.drift_ppm {
+                println!("drift:   {drift:.1} ppm");
+            }
+            println!("syncs:   {}, {} failed", s.syncs, s.failures);
+        }
+        Response::Diagnostics(d) => {
+            println!(
+                "{:<12} {} reads, {} timeouts, {} CRC, {} parse, {} other errors",
+                d.device.name(),
+                d.reads,
+                d.timeouts,
+                d.crc_errors,
+                d.parse_errors,
+                d.other_errors
+            );
+            match d.since_good_s {
+                Some(since) => print!("             last good {since} s ago"),
+                None => print!("             never read"),
+            }
+            if let Some(passed) = d.self_test {
+                print!(", self-test {}", if passed { "pass" } else { "FAIL" });
+            }
+            if !d.serial().is_empty() {
+                print!(", serial {}", d.serial());
+            }
+            println!();
+            if !d.last_error().is_empty() {
+                println!("             last error: {}", d.last_error());
+            }
+        }
+        Response::Profile(p) => {
+            match p.state {
+                RunState::Waiting => match p.starts_at {
+                    Some(at) => println!("profile: waiting, starts {}", datalog::iso8601(at)),
+                    None => println!("profile: waiting"),
+                },
+                RunState::Idle => println!("profile: idle"),
+                state => println!(
+                    "profile: {}, {} of {}",
+                    state.name(),
+                    hms(p.elapsed_s),
+                    hms(p.duration_s)
+                ),
+            }
+            for (variable, value) in Variable::ALL.into_iter().zip([p.temp_c, p.co2_ppm]) {
+                let i = variable as usize;
+                print!("{:<8} {value:.2} {}", variable.name(), variable.unit());
+                if p.state.is_active() {
+                    print!(
+                        ", segment {}/{}",
+                        (p.segment[i] + 1).min(p.segments[i]),
+                        p.segments[i]
+                    );
+                }
+                println!();
+            }
+        }
+        Response::ProfileSegment {
+            variable,
+            index,
+            count,
+            segment,
+        } => println!(
+            "{} segment {}/{count}: {segment:?}",
+            variable.name(),
+            index + 1
+        ),
+        Response::LogChunk(c) => println!(
+            "log block #{} bytes {}..{} of {}",
+            c.seq,
+            c.offset,
+            c.offset as usize + c.data().len(),
+            c.block_len
+        ),
+        Response::Ack => println!("ok"),
+        Response::Error(code) => println!("device error: {}", describe(*code)),
+    }
+}
+
+fn describe(code: ErrorCode) -> &'static str {
+    match code {
+        ErrorCode::Malformed => "malformed message",
+        ErrorCode::UnsupportedVersion => "unsupported protocol version",
+        ErrorCode::UnknownMessage => "unknown message",
+        ErrorCode::InvalidValue => "invalid value",
+        ErrorCode::Failed => "operation failed (see device log)",
+    }
+}
+
+fn simulate() -> Result<()> {
+    let (master, slave) = TTYPort::pair().map_err(|e| e.to_string())?;
+    let path = slave.name().ok_or("pty has no name")?;
+    println!("simulator listening on {path}");
+    println!("e.g. icbm-host --port {path} readings");
+    // The slave end is kept open so the pty stays up between client connections
+    let _slave = slave;
+    Simulator::new().serve(master).map_err(|e| e.to_string())
+}
+
+// Runs the simulator on one end of a pty and exercises every request from the other,
+// checking the responses decode to what the simulator sent
+fn loopback() -> Result<()> {
+    let (master, mut slave) = TTYPort::pair().map_err(|e| e.to_string())?;
+    slave.set_timeout(TIMEOUT).map_err(|e| e.to_string())?;
+    thread::spawn(move || Simulator::new().serve(master));
+
+    let mut client = Client::new(slave);
+    let setpoints = Setpoints {
+        co2_ppm: 80_000.0,
+        temp_c: 36.5,
+        co2_tolerance_ppm: 1500.0,
+        temp_tolerance_c: 0.5,
+        co2_burst_ms: 750,
+    };
+    let invalid = Setpoints {
+        temp_c: 90.0,
+        ..setpoints
+    };
+
+    let mut failures = 0;
+    let mut check = |name: &str, ok: bool| {
+        println!("{:<28} {}", name, if ok { "ok" } else { "FAILED" });
+        if !ok {
+            failures += 1;
+        }
+    };
+
+    check(
+        "hello",
+        matches!(request(&mut client, Request::Hello)?,
+            Response::Hello { protocol_version } if protocol_version == PROTOCOL_VERSION),
+    );
+    check(
+        "readings",
+        matches!(request(&mut client, Request::GetReadings)?,
+            Response::Readings(r)
+                if r.temp_c.is_some() && r.co2_ppm.is_some() && r.humidity_rh.is_some()),
+    );
+    check(
+        "set setpoints",
+        request(&mut client, Request::SetSetpoints(setpoints))? == Response::Ack,
+    );
+    check(
+        "get setpoints",
+        request(&mut client, Request::GetSetpoints)? == Response::Setpoints(setpoints),
+    );
+    check(
+        "reject invalid setpoints",
+        request(&mut client, Request::SetSetpoints(invalid))?
+            == Response::Error(ErrorCode::InvalidValue),
+    );
+    // The simulator starts out dry
+    check(
+        "alarms",
+        matches!(
+            request(&mut client, Request::GetAlarms)?,
+            Response::Alarms(a) if a.active & alarm::HUMIDITY_LOW != 0
+        ),
+    );
+    check(
+        "calibrate",
+        request(&mut client, Request::StartCalibration)? == Response::Calibrated { seq: 1 },
+    );
+    check(
+        "calibration entry",
+        matches!(request(&mut client, Request::GetCalibration { index: 0 })?,
+            Response::CalibrationEntry(e)
+                if e.seq == 1 && e.total == 1 && e.explorir_serial() == "SIM-EXPLORIR"),
+    );
+    check(
+        "missing calibration entry",
+        request(&mut client, Request::GetCalibration { index: 5 })?
+            == Response::Error(ErrorCode::InvalidValue),
+    );
+    check(
+        "self-test",
+        request(&mut client, Request::SelfTest)?
+            == Response::SelfTest {
+                scd41: true,
+                explorir: true,
+            },
+    );
+
+    check(
+        "clock not set",
+        matches!(
+            request(&mut client, Request::GetTime)?,
+            Response::Time { unix_s: None, .. }
+        ),
+    );
+    check(
+        "set time",
+        request(
+            &mut client,
+            Request::SetTime {
+                unix_s: 1_760_000_000,
+            },
+        )? == Response::Ack,
+    );
+    check(
+        "get time",
+        matches!(request(&mut client, Request::GetTime)?,
+            Response::Time { unix_s: Some(t), .. } if (1_760_000_000..1_760_000_005).contains(&t)),
+    );
+    check(
+        "reject invalid time",
+        request(
+            &mut client,
+            Request::SetTime {
+                unix_s: 946_684_800,
+            },
+        )? == Response::Error(ErrorCode::InvalidValue),
+    );
+
+    check(
+        "time sync status",
+        matches!(request(&mut client, Request::GetTimeSync)?,
+            Response::TimeSync(s) if s.server == [0; 4] && s.since_sync_s.is_none()),
+    );
+    check(
+        "diagnostics",
+        matches!(request(&mut client, Request::GetDiagnostics { device: Device::ExplorIr })?,
+            Response::Diagnostics(d) if d.device == Device::ExplorIr && d.reads > 0
+                && d.self_test == Some(true) && d.serial() == "SIM-EXPLORIR"),
+    );
+
+    let expected: Vec<_> = simulated_log()
+        .iter()
+        .filter_map(|raw| datalog::decode_block(raw))
+        .collect();
+    check(
+        "log info",
+        request(&mut client, Request::GetLogInfo)?
+            == Response::LogInfo {
+                blocks: expected.len() as u32,
+                first_seq: expected[0].header.seq,
+                last_seq: expected[expected.len() - 1].header.seq,
+            },
+    );
+    check(
+        "missing log block",
+        request(&mut client, Request::ReadLog { seq: 1, offset: 0 })?
+            == Response::Error(ErrorCode::InvalidValue),
+    );
+    let downloaded = datalog::download(&mut client).map_err(|e| e.to_string())?;
+    check(
+        "log download",
+        downloaded == expected && expected.iter().all(|b| !b.samples.is_empty()),
+    );
+    check("log values", log_matches(&downloaded));
+    let mut csv = Vec::new();
+    datalog::write_csv(&downloaded, &mut csv).map_err(|e| e.to_string())?;
+    let samples: usize = expected.iter().map(|b| b.samples.len()).sum();
+    check(
+        "log csv",
+        csv.split(|&b| b == b'\n').filter(|l| !l.is_empty()).count() == samples + 1,
+    );
+    failures += profile_checks(&mut client, &setpoints)?;
+
+    if failures == 0 {
+        println!("all round trips passed");
+        Ok(())
+    } else {
+        Err(format!("{failures} round trip(s) failed"))
+    }
+}
+
+// Edits and runs a profile on the simulator, whose clock is set by then. Returns the
+// number of failed checks.
+fn profile_checks<P: std::io::Read + std::io::Write>(
+    client: &mut Client<P>,
+    setpoints: &Setpoints,
+) -> Result<usize> {
+    let mut failures = 0;
+    let mut check = |name: &str, ok: bool| {
+        println!("{:<28} {}", name, if ok { "ok" } else { "FAILED" });
+        if !ok {
+            failures += 1;
+        }
+    };
+    let status = |client: &mut Client<P>| -> Result<ProfileStatus> {
+        match request(client, Request::GetProfile)? {
+            Response::Profile(status) => Ok(status),
+            other => Err(format!("unexpected response {other:?}")),
+        }
+    };
+    let invalid = Response::Error(ErrorCode::InvalidValue);
+    let control = |command| Request::ProfileControl(command);
+
+    check(
+        "profile idle",
+        matches!(status(client)?, ProfileStatus { state: RunState::Idle, segments: [0, 0], temp_c, .. }
+            if temp_c == setpoints.temp_c),
+    );
+    // 30 min at 38.5 C, then down to 36 C over an hour; CO2 down to 6% over 100 min
+    let mut added = true;
+    for line in ["temp step 38.5 30", "temp ramp 36 60", "co2 ramp 60000 100"] {
+        let (variable, segment) = profile::parse_segment(line)?;
+        added &=
+            request(client, Request::AddProfileSegment { variable, segment })? == Response::Ack;
+    }
+    check("add profile segments", added);
+    let (variable, segment) = profile::parse_segment("temp step 90 10")?;
+    check(
+        "reject profile target",
+        request(client, Request::AddProfileSegment { variable, segment })? == invalid,
+    );
+    check(
+        "profile segment",
+        request(
+            client,
+            Request::GetProfileSegment {
+                variable: Variable::Temp,
+                index: 1,
+            },
+        )? == Response::ProfileSegment {
+            variable: Variable::Temp,
+            index: 1,
+            count: 2,
+            segment: Segment::Ramp {
+                target: 36.0,
+                duration_s: 3600,
+            },
+        },
+    );
+    check(
+        "missing profile segment",
+        request(
+            client,
+            Request::GetProfileSegment {
+                variable: Variable::Co2,
+                index: 1,
+            },
+        )? == invalid,
+    );
+    check(
+        "reject pause while idle",
+        request(client, control(Command::Pause))? == invalid,
+    );
+
+    // Started 45 min ago: a quarter into the temperature ramp
+    let Response::Time {
+        unix_s: Some(now), ..
+    } = request(client, Request::GetTime)?
+    else {
+        return Err("simulator clock not set".into());
+    };
+    check(
+        "start profile in the past",
+        request(
+            client,
+            control(Command::Start {
+                at: Some(now - 2700),
+            }),
+        )? == Response::Ack,
+    );
+    let running = status(client)?;
+    let co2_expected = setpoints.co2_ppm + (60_000.0 - setpoints.co2_ppm) * 0.45;
+    check(
+        "profile setpoints",
+        running.state == RunState::Running
+            && (2700..2705).contains(&running.elapsed_s)
+            && running.duration_s == 6000
+            && running.segment == [1, 0]
+            && (running.temp_c - 37.875).abs() < 0.01
+            && (running.co2_ppm - co2_expected).abs() < 20.0,
+    );
+    check(
+        "reject edit while running",
+        request(client, Request::ClearProfile)? == invalid,
+    );
+    check(
+        "reject second start",
+        request(client, control(Command::Start { at: None }))? == invalid,
+    );
+    check(
+        "pause profile",
+        request(client, control(Command::Pause))? == Response::Ack
+            && status(client)?.state == RunState::Paused,
+    );
+    check(
+        "resume profile",
+        request(client, control(Command::Resume))? == Response::Ack
+            && status(client)?.state == RunState::Running,
+    );
+    check(
+        "stop profile",
+        request(client, control(Command::Stop))? == Response::Ack
+            && matches!(status(client)?, ProfileStatus { state: RunState::Idle, co2_ppm, .. }
+                if co2_ppm == setpoints.co2_ppm),
+    );
+    check(
+        "schedule profile",
+        request(
+            client,
+            control(Command::Start {
+                at: Some(now + 3600),
+            }),
+        )? == Response::Ack
+            && matches!(status(client)?, ProfileStatus { state: RunState::Waiting, starts_at, .. }
+                if starts_at == Some(now + 3600)),
+    );
+    check(
+        "clear profile",
+        request(client, control(Command::Stop))? == Response::Ack
+            && request(client, Request::ClearProfile)? == Response::Ack
+            && status(client)?.segments == [0, 0],
+    );
+    Ok(failures)
+}
+
+// Compares downloaded samples with what the simulator logged, to the stored resolution
+fn log_matches(blocks: &[datalog::Block]) -> bool {
+    let close = |a: Option<f32>, b: Option<f32>, resolution: f32| match (a, b) {
+        (Some(a), Some(b)) => (a - b).abs() <= resolution * 0.51,
+        (a, b) => a.is_none() && b.is_none(),
+    };
+    let logged = simulated_samples();
+    let mut pending = logged
+        .iter()
+        .flat_map(|(boot, samples)| samples.iter().map(move |s| (*boot, s)));
+    blocks.iter().all(|block| {
+        block.samples.iter().all(|s| {
+            pending.next().is_some_and(|(boot, expected)| {
+                boot == block.header.boot
+                    && s.time_s == expected.time_s
+                    && s.unix_s == expected.unix_s
+                    && s.state == expected.state
+                    && s.alarms == expected.alarms
+                    && close(s.temp_c, expected.temp_c, 0.01)
+                    && close(s.humidity_rh, expected.humidity_rh, 0.1)
+                    && close(s.co2_ppm, expected.co2_ppm, 1.0)
+                    && close(s.flow_ml_min, expected.flow_ml_min, 0.01)
+            })
+        })
+    }) && pending.next().is_none()
+}
+
+// Removes `--<name> <value>` from `args`, returning the value
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>> {
+    let Some(i) = args.iter().position(|a| a == name) else {
+        return Ok(None);
+    };
+    if i + 1 >= args.len() {
+        return Err(format!("{name} needs a value"));
+    }
+    let value = args.remove(i + 1);
+    args.remove(i);
+    Ok(Some(value))
+}
+
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let before = args.len();
+    args.retain(|a| a != name);
+    args.len() != before
+}
+
+fn broker(args: &[String]) -> Result<()> {
+    let mut args = args.to_vec();
+    let listen = take_option(&mut args, "--listen")?.unwrap_or(DEFAULT_LISTEN.into());
+    if !args.is_empty() {
+        return Err(format!("unexpected arguments {args:?}"));
+    }
+    let broker = Broker::bind(&listen).map_err(|e| format!("cannot listen on {listen}: {e}"))?;
+    println!("broker listening on {listen}");
+    broker.serve().map_err(|e| e.to_string())
+}
+
+fn mqtt_connect(address: &str) -> Result<MqttClient> {
+    let client_id = format!("icbm-host-{}", std::process::id());
+    MqttClient::connect(address, &client_id)
+        .map_err(|e| format!("cannot connect to {address}: {e}"))
+}
+
+fn mqtt_pub(args: &[String]) -> Result<()> {
+    let mut args = args.to_vec();
+    let address = take_option(&mut args, "--broker")?.unwrap_or(DEFAULT_BROKER.into());
+    let retain = take_flag(&mut args, "--retain");
+    let [topic, payload] = args.as_slice() else {
+        return Err(format!("expected <topic> <payload>\n{USAGE}"));
+    };
+    let mut client = mqtt_connect(&address)?;
+    client
+        .publish(topic, payload.as_bytes(), retain)
+        .and_then(|()| client.disconnect())
+        .map_err(|e| e.to_string())
+}
+
+fn mqtt_sub(args: &[String]) -> Result<()> {
+    let mut args = args.to_vec();
+    let address = take_option(&mut args, "--broker")?.unwrap_or(DEFAULT_BROKER.into());
+    let [filter] = args.as_slice() else {
+        return Err(format!("expected <filter>\n{USAGE}"));
+    };
+    let mut client = mqtt_connect(&address)?;
+    client.subscribe(filter).map_err(|e| e.to_string())?;
+    loop {
+        let message = client.next_message().map_err(|e| e.to_string())?;
+        println!(
+            "{}{} {}",
+            message.topic,
+            if message.retain { " (retained)" } else { "" },
+            String::from_utf8_lossy(&message.payload)
+        );
+    }
+}
+
+// Runs the broker stand-in on a loopback port and checks the delivery rules the device
+// publisher relies on, using the same MQTT codec as the firmware
+fn mqtt_loopback() -> Result<()> {
+    let broker = Broker::bind("127.0.0.1:0")
+        .map_err(|e| e.to_string())?
+        .quiet();
+    let address = broker.local_addr().map_err(|e| e.to_string())?;
+    thread::spawn(move || broker.serve());
+
+    let connect = |client_id: &str| -> Result<MqttClient> {
+        let mut client =
+            MqttClient::connect(address, client_id).map_err(|e| format!("{client_id}: {e}"))?;
+        client
+            .stream_mut()
+            .set_read_timeout(Some(TIMEOUT))
+            .map_err(|e| e.to_string())?;
+        Ok(client)
+    };
+    let message = |topic: &str, payload: &[u8], retain: bool| Message {
+        topic: topic.into(),
+        payload: payload.to_vec(),
+        retain,
+    };
+    let io = |e: std::io::Error| e.to_string();
+
+    let mut failures = 0;
+    let mut check = |name: &str, ok: bool| {
+        println!("{:<28} {}", name, if ok { "ok" } else { "FAILED" });
+        if !ok {
+            failures += 1;
+        }
+    };
+
+    let mut device = connect("device")?;
+    let mut monitor = connect("monitor")?;
+    let mut operator = connect("operator")?;
+
+    device.publish("icbm/alarms", b"none", true).map_err(io)?;
+    device.publish("icbm/setpoints", b"{}", true).map_err(io)?;
+    device.subscribe("icbm/setpoints/set").map_err(io)?;
+    monitor.subscribe("icbm/alarms").map_err(io)?;
+    check(
+        "retained on subscribe",
+        monitor.next_message().map_err(io)? == message("icbm/alarms", b"none", true),
+    );
+
+    monitor.subscribe("icbm/+").map_err(io)?;
+    let retained = monitor.next_message().map_err(io)?;
+    check(
+        "single-level wildcard",
+        retained.topic == "icbm/alarms" || retained.topic == "icbm/setpoints",
+    );
+    let _ = monitor.next_message().map_err(io)?;
+
+    device.publish("other/co2", b"1", false).map_err(io)?;
+    device.publish("icbm/co2", b"50000", false).map_err(io)?;
+    check(
+        "live publish, filtered",
+        monitor.next_message().map_err(io)? == message("icbm/co2", b"50000", false),
+    );
+
+    monitor.subscribe("icbm/#").map_err(io)?;
+    // Retained messages are replayed for the new filter
+    let _ = monitor.next_message().map_err(io)?;
+    let _ = monitor.next_message().map_err(io)?;
+    device
+        .publish("icbm/heater/duty", b"10.0", false)
+        .map_err(io)?;
+    check(
+        "multi-level wildcard",
+        monitor.next_message().map_err(io)? == message("icbm/heater/duty", b"10.0", false),
+    );
+
+    let large = vec![b'x'; 300];
+    device.publish("icbm/large", &large, false).map_err(io)?;
+    check(
+        "multi-byte remaining length",
+        monitor.next_message().map_err(io)? == message("icbm/large", &large, false),
+    );
+
+    operator
+        .publish("icbm/setpoints/set", b"temp 36.5", false)
+        .map_err(io)?;
+    check(
+        "setpoint command delivered",
+        device.next_message().map_err(io)? == message("icbm/setpoints/set", b"temp 36.5", false),
+    );
+
+    device.disconnect().map_err(io)?;
+    monitor.disconnect().map_err(io)?;
+    operator.disconnect().map_err(io)?;
+
+    if failures == 0 {
+        println!("all MQTT checks passed");
+        Ok(())
+    } else {
+        Err(format!("{failures} MQTT check(s) failed"))
+    }
+}
+
+fn ntp_server(args: &[String]) -> Result<()> {
+    let mut args = args.to_vec();
+    let listen = take_option(&mut args, "--listen")?.unwrap_or(DEFAULT_NTP_LISTEN.into());
+    let offset_s = take_option(&mut args, "--offset")?.map_or(Ok(0.0), |offset| {
+        offset
+            .parse::<f64>()
+            .map_err(|_| format!("invalid offset '{offset}'"))
+    })?;
+    if !args.is_empty() {
+        return Err(format!("unexpected arguments {args:?}"));
+    }
+    let server = sntp::Server::bind(&listen)
+        .map_err(|e| format!("cannot listen on {listen}: {e}"))?
+        .offset((offset_s * 1e6) as i64);
+    println!("SNTP server listening on {listen}, offset {offset_s} s");
+    server.serve().map_err(|e| e.to_string())
+}
+
+fn ntp_query(args: &[String]) -> Result<()> {
+    let [server] = args else {
+        return Err(format!("expected <host[:port]>\n{USAGE}"));
+    };
+    let address = if server.contains(':') {
+        server.clone()
+    } else {
+        format!("{server}:{}", icbm_protocol::sntp::PORT)
+    };
+    let sync = sntp::query(&address, NTP_TIMEOUT).map_err(|e| format!("{address}: {e}"))?;
+    println!("offset:    {:.3} ms", sync.offset_us as f64 / 1000.0);
+    println!("delay:     {:.3} ms", sync.delay_us as f64 / 1000.0);
+    println!("stratum:   {}", sync.stratum);
+    if sync.stratum == 1 {
+        println!("reference: {}", String::from_utf8_lossy(&sync.reference_id));
+    } else {
+        println!("reference: {}", Ipv4Addr::from(sync.reference_id));
+    }
+    Ok(())
+}
+
+// Runs server stand-ins on loopback ports and checks the exchange and the reply checks
+// the device relies on, using the same SNTP codec as the firmware
+fn ntp_loopback() -> Result<()> {
+    let spawn = |offset_us: i64, behaviour: Behaviour| -> Result<std::net::SocketAddr> {
+        let server = sntp::Server::bind("127.0.0.1:0")
+            .map_err(|e| e.to_string())?
+            .offset(offset_us)
+            .behaviour(behaviour)
+            .quiet();
+        let address = server.local_addr().map_err(|e| e.to_string())?;
+        thread::spawn(move || server.serve());
+        Ok(address)
+    };
+
+    let mut failures = 0;
+    let mut check = |name: &str, ok: bool| {
+        println!("{:<28} {}", name, if ok { "ok" } else { "FAILED" });
+        if !ok {
+            failures += 1;
+        }
+    };
+
+    let query = |address| sntp::query(address, NTP_TIMEOUT);
+    let exact = query(spawn(0, Behaviour::Normal)?);
+    check(
+        "offset",
+        matches!(&exact, Ok(sync) if sync.offset_us.abs() < 10_000 && sync.stratum == 1),
+    );
+    check(
+        "round-trip delay",
+        matches!(&exact, Ok(sync) if (0..100_000).contains(&sync.delay_us)),
+    );
+    check(
+        "offset of skewed server",
+        matches!(query(spawn(-2_500_000, Behaviour::Normal)?),
+            Ok(sync) if (sync.offset_us + 2_500_000).abs() < 10_000),
+    );
+    check(
+        "kiss-o'-death",
+        matches!(query(spawn(0, Behaviour::Kiss)?),
+            Err(QueryError::Reply(icbm_protocol::sntp::Error::Kiss(code))) if &code == b"RATE"),
+    );
+    check(
+        "unsynchronized server",
+        matches!(
+            query(spawn(0, Behaviour::Unsynchronized)?),
+            Err(QueryError::Reply(
+                icbm_protocol::sntp::Error::Unsynchronized
+            ))
+        ),
+    );
+    check(
+        "reply to other request",
+        matches!(query(spawn(0, Behaviour::WrongOriginate)?),
+            Err(QueryError::Io(e))
+                if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut)),
+    );
+
+    if failures == 0 {
+        println!("all SNTP checks passed");
+        Ok(())
+    } else {
+        Err(format!("{failures} SNTP check(s) failed"))
+    }
+}
+
+enum ModbusTarget {
+    Tcp(String),
+    Rtu(String),
+}
+
+// Parses `--tcp <addr>` / `--rtu <path>` and `--unit <id>`
+fn modbus_target(args: &[String]) -> Result<(ModbusTarget, u8)> {
+    let mut args = args.to_vec();
+    let tcp = take_option(&mut args, "--tcp")?;
+    let rtu = take_option(&mut args, "--rtu")?;
+    let unit = modbus_unit(&mut args)?;
+    if !args.is_empty() {
+        return Err(format!("unexpected arguments {args:?}"));
+    }
+    let target = match (tcp, rtu) {
+        (Some(addr), None) if addr.contains(':') => ModbusTarget::Tcp(addr),
+        (Some(host), None) => {
+            ModbusTarget::Tcp(format!("{host}:{}", icbm_protocol::modbus::TCP_PORT))
+        }
+        (None, Some(path)) => ModbusTarget::Rtu(path),
+        _ => return Err(format!("expected either --tcp or --rtu\n{USAGE}")),
+    };
+    Ok((target, unit))
+}
+
+fn modbus_unit(args: &mut Vec<String>) -> Result<u8> {
+    take_option(args, "--unit")?.map_or(Ok(icbm_protocol::modbus::DEFAULT_UNIT_ID), |unit| {
+        unit.parse().map_err(|_| format!("invalid unit '{unit}'"))
+    })
+}
+
+fn tcp_master(addr: &str, unit: u8) -> Result<Master<TcpLink>> {
+    let link = TcpLink::connect(addr, MODBUS_TIMEOUT)
+        .map_err(|e| format!("cannot connect to {addr}: {e}"))?;
+    Ok(Master::new(link, unit))
+}
+
+fn rtu_master(path: &str, unit: u8) -> Result<Master<RtuLink<TTYPort>>> {
+    let port = serialport::new(path, rtu::BAUD_RATE)
+        .parity(Parity::Even)
+        .timeout(MODBUS_TIMEOUT)
+        .open_native()
+        .map_err(|e| format!("cannot open {path}: {e}"))?;
+    Ok(Master::new(RtuLink::new(port), unit))
+}
+
+fn modbus_slave(args: &[String]) -> Result<()> {
+    let mut args = args.to_vec();
+    let listen = take_option(&mut args, "--listen")?.unwrap_or(DEFAULT_MODBUS_LISTEN.into());
+    let rtu = take_flag(&mut args, "--rtu");
+    let unit = modbus_unit(&mut args)?;
+    if !args.is_empty() {
+        return Err(format!("unexpected arguments {args:?}"));
+    }
+    if rtu {
+        let (mut master, slave) = TTYPort::pair().map_err(|e| e.to_string())?;
+        master
+            .set_timeout(RTU_FRAME_GAP)
+            .map_err(|e| e.to_string())?;
+        let path = slave.name().ok_or("pty has no name")?;
+        println!("Modbus RTU slave {unit} on {path}");
+        println!("e.g. icbm-host modbus-read --rtu {path} --unit {unit}");
+        let _slave = slave;
+        return Slave::new(unit)
+            .serve_rtu(master)
+            .map_err(|e| e.to_string());
+    }
+    let listener =
+        TcpListener::bind(&listen).map_err(|e| format!("cannot listen on {listen}: {e}"))?;
+    println!("Modbus TCP slave listening on {listen}");
+    Slave::new(unit)
+        .serve_tcp(listener)
+        .map_err(|e| e.to_string())
+}
+
+fn modbus_read(args: &[String]) -> Result<()> {
+    match modbus_target(args)? {
+        (ModbusTarget::Tcp(addr), unit) => print_registers(&mut tcp_master(&addr, unit)?),
+        (ModbusTarget::Rtu(path), unit) => print_registers(&mut rtu_master(&path, unit)?),
+    }
+}
+
+fn print_registers<L: Link>(master: &mut Master<L>) -> Result<()> {
+    let err = |e: modbus::Error| e.to_string();
+    let input = master.read_input(0, map::INPUT_COUNT as u16).map_err(err)?;
+    let holding = master
+        .read_holding(0, map::HOLDING_COUNT as u16)
+        .map_err(err)?;
+    let inputs = map::decode_inputs(&input.try_into().map_err(|_| "short input read")?);
+    let holding = map::decode_holding(&holding.try_into().map_err(|_| "short holding read")?);
+
+    let opt = |v: Option<f32>, precision: usize| {
+        v.map_or("n/a".to_string(), |v| format!("{v:.precision$}"))
+    };
+    println!("temp:       {} C", opt(inputs.temp_c, 2));
+    println!("humidity:   {} %RH", opt(inputs.humidity_rh, 1));
+    println!("co2:        {} ppm", opt(inputs.co2_ppm, 0));
+    println!("flow:       {} ml/min", opt(inputs.flow_ml_min, 2));
+    println!(
+        "heater:     {} ({:.1}%)",
+        if inputs.heater_on { "on" } else { "off" },
+        inputs.heater_duty_pct
+    );
+    println!("co2 valve:  {:.1}%", inputs.co2_valve_duty_pct);
+    println!("bursts:     {}", inputs.co2_bursts);
+    println!("alarms:     {:#06x}", inputs.alarms);
+    println!("updated:    {} s", inputs.updated_at_s);
+    print(&Response::Setpoints(holding.setpoints));
+    println!(
+        "rh:       {:.1} %RH (±{:.1})",
+        holding.humidity_rh, holding.humidity_tolerance_rh
+    );
+    Ok(())
+}
+
+fn modbus_check(args: &[String]) -> Result<()> {
+    let mut failures = 0;
+    let mut check = |name: &str, ok: bool| {
+        println!("{:<28} {}", name, if ok { "ok" } else { "FAILED" });
+        if !ok {
+            failures += 1;
+        }
+    };
+    match modbus_target(args)? {
+        (ModbusTarget::Tcp(addr), unit) => {
+            let mut master = tcp_master(&addr, unit)?;
+            modbus_checks(&mut master, &mut check)?;
+            tcp_checks(&mut master, unit, &mut check)?;
+        }
+        (ModbusTarget::Rtu(path), unit) => {
+            let mut master = rtu_master(&path, unit)?;
+            modbus_checks(&mut master, &mut check)?;
+            rtu_checks(&mut master, unit, &mut check)?;
+        }
+    }
+    if failures == 0 {
+        println!("all Modbus checks passed");
+        Ok(())
+    } else {
+        Err(format!("{failures} Modbus check(s) failed"))
+    }
+}
+
+// Runs the conformance checks against the slave stand-in over a loopback TCP port and
+// a pty, exercising the same codec and register map as the firmware
+fn modbus_loopback() -> Result<()> {
+    let unit = icbm_protocol::modbus::DEFAULT_UNIT_ID;
+    let listener = TcpListener::bind("127.0.0.1:0").map_err(|e| e.to_string())?;
+    let address = listener.local_addr().map_err(|e| e.to_string())?;
+    thread::spawn(move || Slave::new(unit).serve_tcp(listener));
+
+    let (mut slave_port, mut master_port) = TTYPort::pair().map_err(|e| e.to_string())?;
+    slave_port
+        .set_timeout(RTU_FRAME_GAP)
+        .map_err(|e| e.to_string())?;
+    master_port
+        .set_timeout(MODBUS_TIMEOUT)
+        .map_err(|e| e.to_string())?;
+    thread::spawn(move || Slave::new(unit).serve_rtu(slave_port));
+
+    let mut failures = 0;
+    let mut check = |name: &str, ok: bool| {
+        println!("{:<28} {}", name, if ok { "ok" } else { "FAILED" });
+        if !ok {
+            failures += 1;
+        }
+    };
+
+    println!("-- TCP");
+    let mut master = tcp_master(&address.to_string(), unit)?;
+    modbus_checks(&mut master, &mut check)?;
+    tcp_checks(&mut master, unit, &mut check)?;
+
+    println!("-- RTU");
+    let mut master = Master::new(RtuLink::new(master_port), unit);
+    modbus_checks(&mut master, &mut check)?;
+    rtu_checks(&mut master, unit, &mut check)?;
+
+    if failures == 0 {
+        println!("all Modbus checks passed");
+        Ok(())
+    } else {
+        Err(format!("{failures} Modbus check(s) failed"))
+    }
+}
+
+fn exception<T>(result: std::result::Result<T, modbus::Error>, expected: Exception) -> bool {
+    matches!(result, Err(modbus::Error::Exception(e)) if e == expected)
+}
+
+// Transport independent checks of the register map and the exception responses. Only
+// setpoints are written, and they are restored at the end.
+fn modbus_checks<L: Link>(
+    master: &mut Master<L>,
+    check: &mut impl FnMut(&str, bool),
+) -> Result<()> {
+    let err = |e: modbus::Error| e.to_string();
+    let holding_count = map::HOLDING_COUNT as u16;
+    let input_count = map::INPUT_COUNT as u16;
+
+    let original = master.read_holding(0, holding_count).map_err(err)?;
+    check(
+        "read holding registers",
+        original.len() == map::HOLDING_COUNT,
+    );
+    check(
+        "read input registers",
+        master.read_input(0, input_count).map_err(err)?.len() == map::INPUT_COUNT,
+    );
+    check(
+        "read partial range",
+        master.read_holding(map::TEMP_SETPOINT, 1).map_err(err)?
+            == original[map::TEMP_SETPOINT as usize..][..1],
+    );
+
+    master.write_multiple(0, &original).map_err(err)?;
+    check(
+        "write back unchanged",
+        master.read_holding(0, holding_count).map_err(err)? == original,
+    );
+    master.write_single(map::TEMP_SETPOINT, 3650).map_err(err)?;
+    check(
+        "write single register",
+        master.read_holding(map::TEMP_SETPOINT, 1).map_err(err)? == [3650],
+    );
+    let co2_ppm: u32 = 80_000;
+    master
+        .write_multiple(map::CO2_SETPOINT, &[(co2_ppm >> 16) as u16, co2_ppm as u16])
+        .map_err(err)?;
+    check(
+        "write multiple registers",
+        master.read_holding(map::CO2_SETPOINT, 2).map_err(err)? == [1, 14_464],
+    );
+
+    check(
+        "illegal function",
+        exception(
+            master.request(&[0x01, 0, 0, 0, 1], &mut []),
+            Exception::IllegalFunction,
+        ),
+    );
+    check(
+        "read past the map",
+        exception(
+            master.read_input(input_count - 2, 5),
+            Exception::IllegalDataAddress,
+        ),
+    );
+    check(
+        "zero-length read",
+        exception(master.read_holding(0, 0), Exception::IllegalDataValue),
+    );
+    check(
+        "oversized read",
+        exception(
+            master.read_holding(0, MAX_READ_REGISTERS + 1),
+            Exception::IllegalDataValue,
+        ),
+    );
+    check(
+        "write past the map",
+        exception(
+            master.write_single(holding_count, 1),
+            Exception::IllegalDataAddress,
+        ),
+    );
+    check(
+        "byte count mismatch",
+        exception(
+            master.request(
+                &[
+                    function::WRITE_MULTIPLE_REGISTERS,
+                    0,
+                    2,
+                    0,
+                    1,
+                    4,
+                    0x0E,
+                    0x42,
+                    0,
+                    0,
+                ],
+                &mut [],
+            ),
+            Exception::IllegalDataValue,
+        ),
+    );
+    check(
+        "reject invalid setpoint",
+        exception(
+            master.write_single(map::TEMP_SETPOINT, 9000),
+            Exception::IllegalDataValue,
+        ) && master.read_holding(map::TEMP_SETPOINT, 1).map_err(err)? == [3650],
+    );
+    master
+        .write_multiple(map::HUMIDITY_SETPOINT, &[850, 50])
+        .map_err(err)?;
+    check(
+        "write humidity setpoints",
+        master
+            .read_holding(map::HUMIDITY_SETPOINT, 2)
+            .map_err(err)?
+            == [850, 50],
+    );
+    check(
+        "reject invalid humidity",
+        exception(
+            master.write_single(map::HUMIDITY_SETPOINT, 1010),
+            Exception::IllegalDataValue,
+        ) && exception(
+            master.write_single(map::HUMIDITY_TOLERANCE, 0),
+            Exception::IllegalDataValue,
+        ) && master
+            .read_holding(map::HUMIDITY_SETPOINT, 2)
+            .map_err(err)?
+            == [850, 50],
+    );
+
+    master.write_multiple(0, &original).map_err(err)?;
+    check(
+        "restore setpoints",
+        master.read_holding(0, holding_count).map_err(err)? == original,
+    );
+    Ok(())
+}
+
+fn tcp_checks(
+    master: &mut Master<TcpLink>,
+    unit: u8,
+    check: &mut impl FnMut(&str, bool),
+) -> Result<()> {
+    let mut holding = [0u8; 5];
+    let mut input = [0u8; 5];
+    icbm_protocol::modbus::read_request(function::READ_HOLDING_REGISTERS, 0, 2, &mut holding);
+    icbm_protocol::modbus::read_request(function::READ_INPUT_REGISTERS, map::ALARMS, 1, &mut input);
+    let responses = master
+        .link_mut()
+        .pipeline(unit, &[&holding, &input])
+        .map_err(|e| e.to_string())?;
+    check(
+        "pipelined requests",
+        responses.len() == 2
+            && responses[0][..2] == [function::READ_HOLDING_REGISTERS, 4]
+            && responses[1][..2] == [function::READ_INPUT_REGISTERS, 2],
+    );
+    Ok(())
+}
+
+fn rtu_checks<P: std::io::Read + std::io::Write>(
+    master: &mut Master<RtuLink<P>>,
+    unit: u8,
+    check: &mut impl FnMut(&str, bool),
+) -> Result<()> {
+    let err = |e: modbus::Error| e.to_string();
+    let io = |e: std::io::Error| e.to_string();
+    let mut pdu = [0u8; 5];
+    icbm_protocol::modbus::read_request(function::READ_HOLDING_REGISTERS, 0, 1, &mut pdu);
+
+    let mut frame = [0u8; rtu::MAX_ADU_SIZE];
+    let len = rtu::encode(unit, &pdu, &mut frame);
+    frame[len - 1] ^= 0xFF;
+    check(
+        "bad CRC ignored",
+        master
+            .link_mut()
+            .transact_frame(&frame[..len])
+            .map_err(io)?
+            .is_none(),
+    );
+    let other = if unit == 247 { 1 } else { unit + 1 };
+    check(
+        "other unit ignored",
+        master
+            .link_mut()
+            .transact(other, &pdu)
+            .map_err(io)?
+            .is_none(),
+    );
+
+    let burst = master.read_holding(map::CO2_BURST, 1).map_err(err)?;
+    let mut write = [0u8; 5];
+    icbm_protocol::modbus::write_single_request(map::CO2_BURST, 750, &mut write);
+    let silent = master
+        .link_mut()
+        .transact(rtu::BROADCAST, &write)
+        .map_err(io)?
+        .is_none();
+    check(
+        "broadcast write, no reply",
+        silent && master.read_holding(map::CO2_BURST, 1).map_err(err)? == [750],
+    );
+    master.write_single(map::CO2_BURST, burst[0]).map_err(err)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The loopback subcommands need no hardware, so `cargo test` runs them too
+
+    #[test]
+    fn protocol_loopback() -> Result<()> {
+        loopback()
+    }
+
+    #[test]
+    fn mqtt_broker_loopback() -> Result<()> {
+        mqtt_loopback()
+    }
+
+    #[test]
+    fn modbus_slave_loopback() -> Result<()> {
+        modbus_loopback()
+    }
+
+    #[test]
+    fn sntp_server_loopback() -> Result<()> {
+        ntp_loopback()
+    }
+}
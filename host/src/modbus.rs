@@ -0,0 +1,434 @@
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use icbm_protocol::message::{Request, Response, Setpoints};
+use icbm_protocol::modbus::{
+    self, function, map, rtu, tcp, Exception, Outcome, RegisterMap, ResponseError, MAX_PDU_SIZE,
+};
+
+use crate::simulator::Simulator;
+
+fn timed_out(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock)
+}
+
+// Transport carrying request PDUs to one slave; `None` means no response arrived
+// before the timeout
+pub trait Link {
+    fn transact(&mut self, unit: u8, pdu: &[u8]) -> io::Result<Option<Vec<u8>>>;
+}
+
+pub struct TcpLink {
+    stream: TcpStream,
+    next_transaction: u16,
+}
+
+impl TcpLink {
+    pub fn connect(addr: impl ToSocketAddrs, timeout: std::time::Duration) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_nodelay(true)?;
+        Ok(TcpLink {
+            stream,
+            next_transaction: 1,
+        })
+    }
+
+    // Sends all requests in one write before reading any response, as pipelining
+    // masters do
+    pub fn pipeline(&mut self, unit: u8, pdus: &[&[u8]]) -> io::Result<Vec<Vec<u8>>> {
+        let mut out = Vec::new();
+        let mut ids = Vec::new();
+        for pdu in pdus {
+            let header = self.next_header(unit);
+            let mut adu = [0u8; tcp::MAX_ADU_SIZE];
+            let len = tcp::encode(&header, pdu, &mut adu);
+            out.extend_from_slice(&adu[..len]);
+            ids.push(header.transaction_id);
+        }
+        self.stream.write_all(&out)?;
+        ids.into_iter()
+            .map(|id| {
+                self.read_response(id)?
+                    .ok_or_else(|| io::Error::new(ErrorKind::TimedOut, "no response"))
+            })
+            .collect()
+    }
+
+    fn next_header(&mut self, unit: u8) -> tcp::Header {
+        let header = tcp::Header {
+            transaction_id: self.next_transaction,
+            unit_id: unit,
+        };
+        self.next_transaction = self.next_transaction.wrapping_add(1);
+        header
+    }
+
+    fn read_response(&mut self, transaction_id: u16) -> io::Result<Option<Vec<u8>>> {
+        let mut adu = vec![0u8; tcp::HEADER_SIZE];
+        match self.stream.read_exact(&mut adu) {
+            Ok(()) => {}
+            Err(e) if timed_out(&e) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let len = tcp::adu_len(&adu).unwrap_or(0);
+        if len <= tcp::HEADER_SIZE || len > tcp::MAX_ADU_SIZE {
+            return Err(io::Error::new(ErrorKind::InvalidData, "bad MBAP length"));
+        }
+        adu.resize(len, 0);
+        self.stream.read_exact(&mut adu[tcp::HEADER_SIZE..])?;
+        let (header, pdu) = tcp::decode(&adu)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "bad MBAP header"))?;
+        if header.transaction_id != transaction_id {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "transaction id {} does not match request {transaction_id}",
+                    header.transaction_id
+                ),
+            ));
+        }
+        Ok(Some(pdu.to_vec()))
+    }
+}
+
+impl Link for TcpLink {
+    fn transact(&mut self, unit: u8, pdu: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let header = self.next_header(unit);
+        let mut adu = [0u8; tcp::MAX_ADU_SIZE];
+        let len = tcp::encode(&header, pdu, &mut adu);
+        self.stream.write_all(&adu[..len])?;
+        self.read_response(header.transaction_id)
+    }
+}
+
+// RTU master on a serial port opened with the bus settings and a response timeout
+pub struct RtuLink<P> {
+    port: P,
+}
+
+impl<P: Read + Write> RtuLink<P> {
+    pub fn new(port: P) -> Self {
+        RtuLink { port }
+    }
+
+    // Sends a raw frame, e.g. one with a corrupted CRC, and returns the response PDU
+    pub fn transact_frame(&mut self, frame: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        self.port.write_all(frame)?;
+        self.port.flush()?;
+
+        // The response length follows from the function code
+        let mut response = vec![0u8; 2];
+        match self.port.read_exact(&mut response[..1]) {
+            Ok(()) => {}
+            Err(e) if timed_out(&e) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        self.port.read_exact(&mut response[1..])?;
+        let remaining = match response[1] {
+            code if code & 0x80 != 0 => 1 + 2,
+            function::READ_HOLDING_REGISTERS | function::READ_INPUT_REGISTERS => {
+                let mut count = [0u8];
+                self.port.read_exact(&mut count)?;
+                response.push(count[0]);
+                count[0] as usize + 2
+            }
+            _ => 4 + 2,
+        };
+        let start = response.len();
+        response.resize(start + remaining, 0);
+        self.port.read_exact(&mut response[start..])?;
+
+        match rtu::decode(&response) {
+            Some((unit, pdu)) if unit == frame[0] => Ok(Some(pdu.to_vec())),
+            Some((unit, _)) => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("response from unit {unit}"),
+            )),
+            None => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "response CRC mismatch",
+            )),
+        }
+    }
+}
+
+impl<P: Read + Write> Link for RtuLink<P> {
+    fn transact(&mut self, unit: u8, pdu: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let mut frame = [0u8; rtu::MAX_ADU_SIZE];
+        let len = rtu::encode(unit, pdu, &mut frame);
+        self.transact_frame(&frame[..len])
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    NoReply,
+    Exception(Exception),
+    Malformed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::NoReply => write!(f, "no response"),
+            Error::Exception(e) => write!(f, "exception {} ({e:?})", *e as u8),
+            Error::Malformed => write!(f, "malformed response"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+// Blocking Modbus master for the host tools
+pub struct Master<L> {
+    link: L,
+    unit: u8,
+}
+
+impl<L: Link> Master<L> {
+    pub fn new(link: L, unit: u8) -> Self {
+        Master { link, unit }
+    }
+
+    pub fn link_mut(&mut self) -> &mut L {
+        &mut self.link
+    }
+
+    pub fn read_input(&mut self, start: u16, count: u16) -> Result<Vec<u16>, Error> {
+        self.read(function::READ_INPUT_REGISTERS, start, count)
+    }
+
+    pub fn read_holding(&mut self, start: u16, count: u16) -> Result<Vec<u16>, Error> {
+        self.read(function::READ_HOLDING_REGISTERS, start, count)
+    }
+
+    pub fn write_single(&mut self, addr: u16, value: u16) -> Result<(), Error> {
+        let mut request = [0u8; MAX_PDU_SIZE];
+        let len = modbus::write_single_request(addr, value, &mut request);
+        self.request(&request[..len], &mut []).map(|_| ())
+    }
+
+    pub fn write_multiple(&mut self, start: u16, values: &[u16]) -> Result<(), Error> {
+        let mut request = [0u8; MAX_PDU_SIZE];
+        let len = modbus::write_multiple_request(start, values, &mut request);
+        self.request(&request[..len], &mut []).map(|_| ())
+    }
+
+    // Sends an arbitrary request PDU; register values of read responses go to `values`
+    pub fn request(&mut self, request: &[u8], values: &mut [u16]) -> Result<usize, Error> {
+        let response = self
+            .link
+            .transact(self.unit, request)?
+            .ok_or(Error::NoReply)?;
+        modbus::parse_response(request, &response, values).map_err(|e| match e {
+            ResponseError::Exception(e) => Error::Exception(e),
+            ResponseError::Malformed => Error::Malformed,
+        })
+    }
+
+    fn read(&mut self, code: u8, start: u16, count: u16) -> Result<Vec<u16>, Error> {
+        let mut request = [0u8; 5];
+        modbus::read_request(code, start, count, &mut request);
+        let mut values = vec![0u16; count as usize];
+        let n = self.request(&request, &mut values)?;
+        values.truncate(n);
+        Ok(values)
+    }
+}
+
+// Modbus slave stand-in backed by the device simulator, with the firmware's register
+// map and write rules, for checking masters and building management configurations
+// on a Linux machine
+pub struct Slave {
+    simulator: Simulator,
+    unit: u8,
+    // The simulator has no humidity setpoint, so the slave holds it, with the
+    // firmware's defaults and limits
+    humidity_rh: f32,
+    humidity_tolerance_rh: f32,
+}
+
+impl Slave {
+    pub fn new(unit: u8) -> Self {
+        Slave {
+            simulator: Simulator::new(),
+            unit,
+            humidity_rh: 90.0,
+            humidity_tolerance_rh: 3.0,
+        }
+    }
+
+    // Handles one request PDU and returns the response PDU
+    pub fn handle(&mut self, request: &[u8]) -> Vec<u8> {
+        let setpoints = match self.simulator.handle(Request::GetSetpoints) {
+            Response::Setpoints(s) => s,
+            _ => unreachable!("simulator always answers setpoint queries"),
+        };
+        let holding = map::Holding {
+            setpoints,
+            humidity_rh: self.humidity_rh,
+            humidity_tolerance_rh: self.humidity_tolerance_rh,
+        };
+        let mut map = RegisterMap::new(&self.inputs(), &holding);
+        let before = map.holding;
+
+        let mut response = [0u8; MAX_PDU_SIZE];
+        let len = match modbus::process(&mut map, request, &mut response) {
+            Outcome::Reply(len) => len,
+            Outcome::Written(len) => match self.apply(&before, &map.holding, setpoints) {
+                Ok(()) => len,
+                Err(e) => modbus::exception(request[0], e, &mut response),
+            },
+        };
+        response[..len].to_vec()
+    }
+
+    // As in the firmware, only setpoints whose registers changed are taken over
+    fn apply(
+        &mut self,
+        before: &[u16; map::HOLDING_COUNT],
+        after: &[u16; map::HOLDING_COUNT],
+        mut setpoints: Setpoints,
+    ) -> Result<(), Exception> {
+        let changed = |addr: u16, words: usize| {
+            let range = addr as usize..addr as usize + words;
+            before[range.clone()] != after[range]
+        };
+        let written = map::decode_holding(after);
+        if changed(map::CO2_SETPOINT, 2) {
+            setpoints.co2_ppm = written.setpoints.co2_ppm;
+        }
+        if changed(map::TEMP_SETPOINT, 1) {
+            setpoints.temp_c = written.setpoints.temp_c;
+        }
+        if changed(map::CO2_TOLERANCE, 2) {
+            setpoints.co2_tolerance_ppm = written.setpoints.co2_tolerance_ppm;
+        }
+        if changed(map::TEMP_TOLERANCE, 1) {
+            setpoints.temp_tolerance_c = written.setpoints.temp_tolerance_c;
+        }
+        if changed(map::CO2_BURST, 1) {
+            setpoints.co2_burst_ms = written.setpoints.co2_burst_ms;
+        }
+        let mut humidity = (self.humidity_rh, self.humidity_tolerance_rh);
+        if changed(map::HUMIDITY_SETPOINT, 1) {
+            humidity.0 = written.humidity_rh;
+        }
+        if changed(map::HUMIDITY_TOLERANCE, 1) {
+            humidity.1 = written.humidity_tolerance_rh;
+        }
+        let (rh, tolerance_rh) = humidity;
+        if !(0.0..=100.0).contains(&rh) || tolerance_rh <= 0.0 || tolerance_rh > 20.0 {
+            return Err(Exception::IllegalDataValue);
+        }
+        match self.simulator.handle(Request::SetSetpoints(setpoints)) {
+            Response::Ack => {
+                (self.humidity_rh, self.humidity_tolerance_rh) = humidity;
+                Ok(())
+            }
+            _ => Err(Exception::IllegalDataValue),
+        }
+    }
+
+    fn inputs(&mut self) -> map::Inputs {
+        let Response::Readings(readings) = self.simulator.handle(Request::GetReadings) else {
+            unreachable!("simulator always answers reading queries");
+        };
+        let Response::Alarms(alarms) = self.simulator.handle(Request::GetAlarms) else {
+            unreachable!("simulator always answers alarm queries");
+        };
+        let duty = |on: bool| if on { 10.0 } else { 0.0 };
+        map::Inputs {
+            temp_c: readings.temp_c,
+            humidity_rh: Some(95.0),
+            co2_ppm: readings.co2_ppm,
+            flow_ml_min: None,
+            heater_on: readings.heater_on,
+            heater_duty_pct: duty(readings.heater_on),
+            co2_valve_duty_pct: 0.0,
+            co2_bursts: readings.co2_bursts,
+            alarms: alarms.active as u16,
+            updated_at_s: readings.updated_at_s as u32,
+        }
+    }
+
+    // Serves Modbus TCP, one thread per connection; the unit identifier is ignored
+    // like on the device
+    pub fn serve_tcp(self, listener: TcpListener) -> io::Result<()> {
+        let slave = Arc::new(Mutex::new(self));
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let slave = slave.clone();
+            thread::spawn(move || serve_connection(stream, &slave));
+        }
+        Ok(())
+    }
+
+    // Serves Modbus RTU on `port`, which must have a short read timeout: a timeout
+    // after received bytes marks the end of a frame, as the bus silence does on the wire
+    pub fn serve_rtu<P: Read + Write>(&mut self, mut port: P) -> io::Result<()> {
+        let mut frame = Vec::new();
+        loop {
+            let mut buf = [0u8; rtu::MAX_ADU_SIZE];
+            match port.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => {
+                    frame.extend_from_slice(&buf[..n]);
+                    continue;
+                }
+                Err(e) if timed_out(&e) => {}
+                Err(e) => return Err(e),
+            }
+            if frame.is_empty() {
+                continue;
+            }
+            let request = std::mem::take(&mut frame);
+            let Some((unit, pdu)) = rtu::decode(&request) else {
+                continue;
+            };
+            if unit != self.unit && unit != rtu::BROADCAST {
+                continue;
+            }
+            let response = self.handle(pdu);
+            if unit == rtu::BROADCAST {
+                continue;
+            }
+            let mut adu = [0u8; rtu::MAX_ADU_SIZE];
+            let len = rtu::encode(self.unit, &response, &mut adu);
+            port.write_all(&adu[..len])?;
+            port.flush()?;
+        }
+    }
+}
+
+fn serve_connection(mut stream: TcpStream, slave: &Mutex<Slave>) -> io::Result<()> {
+    let mut pending = Vec::new();
+    loop {
+        while let Some(len) = tcp::adu_len(&pending).filter(|&len| pending.len() >= len) {
+            let adu: Vec<u8> = pending.drain(..len).collect();
+            let Some((header, request)) = tcp::decode(&adu) else {
+                return Err(io::Error::new(ErrorKind::InvalidData, "not a Modbus frame"));
+            };
+            let response = slave.lock().unwrap().handle(request);
+            let mut out = [0u8; tcp::MAX_ADU_SIZE];
+            let len = tcp::encode(&header, &response, &mut out);
+            stream.write_all(&out[..len])?;
+        }
+        let mut buf = [0u8; 512];
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+        pending.extend_from_slice(&buf[..n]);
+    }
+}
@@ -0,0 +1,331 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use icbm_protocol::mqtt::{topic_matches, Packet, CONNACK_ACCEPTED};
+
+// Buffer for packets built on the host; large enough for any telemetry message
+const PACKET_BUFFER_SIZE: usize = 4096;
+
+fn mqtt_error(e: icbm_protocol::mqtt::Error) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("{e:?}"))
+}
+
+fn send(stream: &mut TcpStream, packet: &Packet) -> io::Result<()> {
+    let mut buf = [0u8; PACKET_BUFFER_SIZE];
+    let len = packet.encode(&mut buf).map_err(mqtt_error)?;
+    stream.write_all(&buf[..len])
+}
+
+// Reads from `stream` until `pending` holds a complete packet and returns its bytes
+fn read_packet(stream: &mut TcpStream, pending: &mut Vec<u8>) -> io::Result<Vec<u8>> {
+    loop {
+        if let Some((_, used)) = Packet::decode(pending).map_err(mqtt_error)? {
+            return Ok(pending.drain(..used).collect());
+        }
+        let mut buf = [0u8; 512];
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        pending.extend_from_slice(&buf[..n]);
+    }
+}
+
+fn decode(raw: &[u8]) -> io::Result<Packet<'_>> {
+    match Packet::decode(raw).map_err(mqtt_error)? {
+        Some((packet, _)) => Ok(packet),
+        None => Err(ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+struct Subscriber {
+    id: usize,
+    stream: TcpStream,
+    filters: Vec<String>,
+}
+
+#[derive(Default)]
+struct Shared {
+    subscribers: Vec<Subscriber>,
+    retained: Vec<(String, Vec<u8>)>,
+}
+
+// Minimal MQTT 3.1.1 broker stand-in for exercising the device publisher on a Linux
+// machine: QoS 0 delivery, retained messages and wildcard subscriptions, no
+// authentication or persistence. Every publish is printed unless quiet.
+pub struct Broker {
+    listener: TcpListener,
+    shared: Arc<Mutex<Shared>>,
+    quiet: bool,
+}
+
+impl Broker {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Broker {
+            listener: TcpListener::bind(addr)?,
+            shared: Arc::default(),
+            quiet: false,
+        })
+    }
+
+    pub fn quiet(mut self) -> Self {
+        self.quiet = true;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn serve(self) -> io::Result<()> {
+        for (id, stream) in self.listener.incoming().enumerate() {
+            let stream = stream?;
+            let shared = self.shared.clone();
+            let quiet = self.quiet;
+            thread::spawn(move || {
+                let peer = stream.peer_addr().ok();
+                if let Err(e) = serve_client(id, stream, &shared, quiet) {
+                    if !quiet && e.kind() != ErrorKind::UnexpectedEof {
+                        eprintln!("client {peer:?}: {e}");
+                    }
+                }
+                let mut shared = shared.lock().unwrap();
+                shared.subscribers.retain(|s| s.id != id);
+            });
+        }
+        Ok(())
+    }
+}
+
+fn serve_client(
+    id: usize,
+    mut stream: TcpStream,
+    shared: &Mutex<Shared>,
+    quiet: bool,
+) -> io::Result<()> {
+    let mut pending = Vec::new();
+
+    let raw = read_packet(&mut stream, &mut pending)?;
+    let Packet::Connect { client_id, .. } = decode(&raw)? else {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "first packet is not CONNECT",
+        ));
+    };
+    if !quiet {
+        println!("connected: {client_id}");
+    }
+    send(
+        &mut stream,
+        &Packet::ConnAck {
+            session_present: false,
+            return_code: CONNACK_ACCEPTED,
+        },
+    )?;
+
+    loop {
+        let raw = read_packet(&mut stream, &mut pending)?;
+        match decode(&raw)? {
+            Packet::Publish {
+                topic,
+                payload,
+                retain,
+                ..
+            } => {
+                if !quiet {
+                    println!("{topic} {}", String::from_utf8_lossy(payload));
+                }
+                let mut shared = shared.lock().unwrap();
+                if retain {
+                    shared.retained.retain(|(t, _)| t != topic);
+                    // An empty retained payload clears the topic
+                    if !payload.is_empty() {
+                        shared.retained.push((topic.to_string(), payload.to_vec()));
+                    }
+                }
+                let forward = Packet::Publish {
+                    topic,
+                    payload,
+                    qos: 0,
+                    retain: false,
+                    packet_id: None,
+                };
+                for subscriber in shared.subscribers.iter_mut() {
+                    if subscriber.filters.iter().any(|f| topic_matches(f, topic)) {
+                        // A subscriber that went away is removed by its own thread
+                        let _ = send(&mut subscriber.stream, &forward);
+                    }
+                }
+            }
+            Packet::Subscribe {
+                packet_id, filter, ..
+            } => {
+                if !quiet {
+                    println!("subscribed: {client_id} -> {filter}");
+                }
+                send(
+                    &mut stream,
+                    &Packet::SubAck {
+                        packet_id,
+                        return_code: 0,
+                    },
+                )?;
+                let mut shared = shared.lock().unwrap();
+                for (topic, payload) in shared.retained.iter() {
+                    if topic_matches(filter, topic) {
+                        send(
+                            &mut stream,
+                            &Packet::Publish {
+                                topic,
+                                payload,
+                                qos: 0,
+                                retain: true,
+                                packet_id: None,
+                            },
+                        )?;
+                    }
+                }
+                match shared.subscribers.iter_mut().find(|s| s.id == id) {
+                    Some(subscriber) => subscriber.filters.push(filter.to_string()),
+                    None => shared.subscribers.push(Subscriber {
+                        id,
+                        stream: stream.try_clone()?,
+                        filters: vec![filter.to_string()],
+                    }),
+                }
+            }
+            Packet::PingReq => send(&mut stream, &Packet::PingResp)?,
+            Packet::Disconnect => {
+                if !quiet {
+                    println!("disconnected: {client_id}");
+                }
+                return Ok(());
+            }
+            other => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unexpected packet {other:?}"),
+                ))
+            }
+        }
+    }
+}
+
+// Message received on a subscription
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+// Blocking MQTT client for the host tools; QoS 0 only
+pub struct MqttClient {
+    stream: TcpStream,
+    pending: Vec<u8>,
+    next_packet_id: u16,
+}
+
+impl MqttClient {
+    pub fn connect(addr: impl ToSocketAddrs, client_id: &str) -> io::Result<Self> {
+        let mut stream = TcpStream::connect(addr)?;
+        send(
+            &mut stream,
+            &Packet::Connect {
+                client_id,
+                keep_alive_s: 0,
+                clean_session: true,
+            },
+        )?;
+        let mut client = MqttClient {
+            stream,
+            pending: Vec::new(),
+            next_packet_id: 1,
+        };
+        let raw = read_packet(&mut client.stream, &mut client.pending)?;
+        match decode(&raw)? {
+            Packet::ConnAck {
+                return_code: CONNACK_ACCEPTED,
+                ..
+            } => Ok(client),
+            other => Err(io::Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("broker replied {other:?}"),
+            )),
+        }
+    }
+
+    pub fn stream_mut(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+
+    pub fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> io::Result<()> {
+        send(
+            &mut self.stream,
+            &Packet::Publish {
+                topic,
+                payload,
+                qos: 0,
+                retain,
+                packet_id: None,
+            },
+        )
+    }
+
+    // Waits for the SUBACK; publishes arriving before it are dropped
+    pub fn subscribe(&mut self, filter: &str) -> io::Result<()> {
+        let packet_id = self.next_packet_id;
+        self.next_packet_id = self.next_packet_id.wrapping_add(1).max(1);
+        send(
+            &mut self.stream,
+            &Packet::Subscribe {
+                packet_id,
+                filter,
+                qos: 0,
+            },
+        )?;
+        loop {
+            let raw = read_packet(&mut self.stream, &mut self.pending)?;
+            if let Packet::SubAck {
+                packet_id: id,
+                return_code,
+            } = decode(&raw)?
+            {
+                if id != packet_id {
+                    continue;
+                }
+                if return_code > 2 {
+                    return Err(io::Error::other(format!(
+                        "subscription to {filter} refused"
+                    )));
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    pub fn next_message(&mut self) -> io::Result<Message> {
+        loop {
+            let raw = read_packet(&mut self.stream, &mut self.pending)?;
+            if let Packet::Publish {
+                topic,
+                payload,
+                retain,
+                ..
+            } = decode(&raw)?
+            {
+                return Ok(Message {
+                    topic: topic.to_string(),
+                    payload: payload.to_vec(),
+                    retain,
+                });
+            }
+        }
+    }
+
+    pub fn disconnect(mut self) -> io::Result<()> {
+        send(&mut self.stream, &Packet::Disconnect)
+    }
+}
@@ -0,0 +1,515 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::time::Instant;
+
+use icbm_protocol::datalog::{self, Sample};
+use icbm_protocol::frame::{self, FrameDecoder, MAX_FRAME_SIZE, MAX_MESSAGE_SIZE};
+use icbm_protocol::message::{
+    alarm, diagnostics_text, peek_request_id, Alarms, CalibrationEntry, DecodeError, Device,
+    Diagnostics, ErrorCode, LogChunk, ProfileStatus, Readings, Request, Response, Setpoints,
+    TimeSync, EXPLORIR_SERIAL_LENGTH, LOG_CHUNK_SIZE,
+};
+use icbm_protocol::profile::{Command, Profile, RunState, Variable, VARIABLES};
+use icbm_protocol::PROTOCOL_VERSION;
+
+// Rate at which the simulated chamber approaches the setpoints, per second
+const APPROACH_RATE: f32 = 0.01;
+// The simulated chamber starts with a freshly filled water pan, so the humidity climbs
+// from the room's towards what the pan holds. Below the firmware's default alarm
+// threshold the low-humidity alarm is raised.
+const PAN_HUMIDITY_RH: f32 = 92.0;
+const HUMIDITY_ALARM_RH: f32 = 80.0;
+// Synthetic data log: the oldest held block and, per boot, the number of samples and
+// the wall-clock time at boot if the clock was set
+const LOG_FIRST_SEQ: u32 = 5;
+const LOG_BOOTS: [(u16, u32, Option<u32>); 2] = [(3, 700, None), (4, 300, Some(1_760_000_000))];
+// Range the device RTC accepts: 2024-01-01 to 2099-12-31
+const MIN_UNIX_TIME: u32 = 1_704_067_200;
+const MAX_UNIX_TIME: u32 = 4_102_444_799;
+const LOG_PERIOD_S: u32 = 10;
+// The firmware reads the thermistors every 10 s and the other sensors once per 50 s
+// control cycle
+const PROBE_PERIOD_S: u32 = 10;
+const CONTROL_PERIOD_S: u32 = 50;
+
+// Device stand-in answering protocol requests, used to develop and check host tooling
+// without hardware. Mirrors the validation done by the firmware.
+pub struct Simulator {
+    setpoints: Setpoints,
+    temp_c: f32,
+    co2_ppm: f32,
+    humidity_rh: f32,
+    co2_bursts: u32,
+    calibrations: Vec<CalibrationEntry>,
+    log: Vec<Vec<u8>>,
+    // Wall-clock time at `started`, once set
+    clock: Option<u64>,
+    started: Instant,
+    last_update: Instant,
+    // Setpoint profile, run like the firmware does with the elapsed running time taken
+    // at `profile_since`
+    profile: Profile,
+    profile_state: RunState,
+    profile_elapsed_s: u32,
+    profile_since: Instant,
+    profile_starts_at: Option<u32>,
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Simulator {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Simulator {
+            setpoints: Setpoints {
+                co2_ppm: 50_000.0,
+                temp_c: 37.0,
+                co2_tolerance_ppm: 2000.0,
+                temp_tolerance_c: 1.0,
+                co2_burst_ms: 500,
+            },
+            temp_c: 25.0,
+            co2_ppm: 400.0,
+            humidity_rh: 45.0,
+            co2_bursts: 0,
+            calibrations: Vec::new(),
+            log: simulated_log(),
+            clock: None,
+            started: now,
+            last_update: now,
+            profile: Profile::EMPTY,
+            profile_state: RunState::Idle,
+            profile_elapsed_s: 0,
+            profile_since: now,
+            profile_starts_at: None,
+        }
+    }
+
+    pub fn handle(&mut self, request: Request) -> Response {
+        self.step();
+        match request {
+            Request::Hello => Response::Hello {
+                protocol_version: PROTOCOL_VERSION,
+            },
+            Request::GetReadings => Response::Readings(Readings {
+                temp_c: Some(self.temp_c),
+                co2_ppm: Some(self.co2_ppm),
+                heater_on: self.targets()[0] - self.temp_c > self.setpoints.temp_tolerance_c,
+                co2_bursts: self.co2_bursts,
+                updated_at_s: self.started.elapsed().as_secs(),
+                humidity_rh: Some(self.humidity_rh),
+                humidifier_on: false,
+            }),
+            Request::GetSetpoints => Response::Setpoints(self.setpoints),
+            Request::SetSetpoints(setpoints) => {
+                if !valid_target(Variable::Co2, setpoints.co2_ppm)
+                    || !valid_target(Variable::Temp, setpoints.temp_c)
+                    || setpoints.co2_tolerance_ppm <= 0.0
+                    || setpoints.temp_tolerance_c <= 0.0
+                    || setpoints.co2_burst_ms == 0
+                    || setpoints.co2_burst_ms > 10_000
+                {
+                    return Response::Error(ErrorCode::InvalidValue);
+                }
+                self.setpoints = setpoints;
+                Response::Ack
+            }
+            Request::GetAlarms => {
+                let [temp_c, co2_ppm] = self.targets();
+                let mut active = 0;
+                if (self.temp_c - temp_c).abs() > 3.0 * self.setpoints.temp_tolerance_c {
+                    active |= alarm::TEMP_OUT_OF_RANGE;
+                }
+                if (self.co2_ppm - co2_ppm).abs() > 3.0 * self.setpoints.co2_tolerance_ppm {
+                    active |= alarm::CO2_OUT_OF_RANGE;
+                }
+                if self.humidity_rh < HUMIDITY_ALARM_RH {
+                    active |= alarm::HUMIDITY_LOW;
+                }
+                Response::Alarms(Alarms { active })
+            }
+            Request::StartCalibration => {
+                let seq = self.calibrations.len() as u32 + 1;
+                let mut explorir_serial = [0u8; EXPLORIR_SERIAL_LENGTH];
+                let serial = b"SIM-EXPLORIR";
+                explorir_serial[..serial.len()].copy_from_slice(serial);
+                self.calibrations.push(CalibrationEntry {
+                    seq,
+                    timestamp_s: self.started.elapsed().as_secs(),
+                    scd41_serial: 0x0000_5349_4D00,
+                    explorir_serial,
+                    explorir_serial_len: serial.len() as u8,
+                    reference_temp_c: 37.1,
+                    reference_co2_ppm: 51_000,
+                    pressure_pa: 102_133,
+                    altitude_m: 142,
+                    temp_before_c: Some(self.temp_c),
+                    temp_after_c: Some(self.temp_c),
+                    temp_offset_c: Some(4.0),
+                    co2_before_ppm: Some(self.co2_ppm as i32),
+                    co2_after_ppm: Some(51_000),
+                    ..Default::default()
+                });
+                Response::Calibrated { seq }
+            }
+            Request::GetCalibration { index } => {
+                let total = self.calibrations.len() as u32;
+                match self.calibrations.get(index as usize) {
+                    Some(entry) => Response::CalibrationEntry(CalibrationEntry { total, ..*entry }),
+                    None => Response::Error(ErrorCode::InvalidValue),
+                }
+            }
+            Request::SelfTest => Response::SelfTest {
+                scd41: true,
+                explorir: true,
+            },
+            Request::GetLogInfo => Response::LogInfo {
+                blocks: self.log.len() as u32,
+                first_seq: LOG_FIRST_SEQ,
+                last_seq: LOG_FIRST_SEQ + self.log.len() as u32 - 1,
+            },
+            Request::ReadLog { seq, offset } => {
+                let block = seq
+                    .checked_sub(LOG_FIRST_SEQ)
+                    .and_then(|i| self.log.get(i as usize));
+                let offset_len = offset as usize;
+                match block {
+                    Some(block) if offset_len <= block.len() => {
+                        let len = (block.len() - offset_len).min(LOG_CHUNK_SIZE);
+                        let mut data = [0u8; LOG_CHUNK_SIZE];
+                        data[..len].copy_from_slice(&block[offset_len..offset_len + len]);
+                        Response::LogChunk(LogChunk {
+                            seq,
+                            offset,
+                            block_len: block.len() as u16,
+                            len: len as u8,
+                            data,
+                        })
+                    }
+                    _ => Response::Error(ErrorCode::InvalidValue),
+                }
+            }
+            Request::GetTime => {
+                let uptime_s = self.started.elapsed().as_secs();
+                Response::Time {
+                    unix_s: self.clock.map(|clock| (clock + uptime_s) as u32),
+                    uptime_s,
+                }
+            }
+            Request::SetTime { unix_s } => {
+                if !(MIN_UNIX_TIME..=MAX_UNIX_TIME).contains(&unix_s) {
+                    return Response::Error(ErrorCode::InvalidValue);
+                }
+                self.clock = Some(unix_s as u64 - self.started.elapsed().as_secs());
+                Response::Ack
+            }
+            // The simulator has no network, so sync is always off
+            Request::GetTimeSync => Response::TimeSync(TimeSync::default()),
+            Request::GetProfile => Response::Profile(self.profile_status()),
+            Request::GetProfileSegment { variable, index } => {
+                let segments = self.profile.track(variable).segments();
+                match segments.get(index as usize) {
+                    Some(&segment) => Response::ProfileSegment {
+                        variable,
+                        index,
+                        count: segments.len() as u8,
+                        segment,
+                    },
+                    None => Response::Error(ErrorCode::InvalidValue),
+                }
+            }
+            Request::ClearProfile => {
+                if !self.profile_editable() {
+                    return Response::Error(ErrorCode::InvalidValue);
+                }
+                self.profile = Profile::EMPTY;
+                self.profile_state = RunState::Idle;
+                self.profile_elapsed_s = 0;
+                Response::Ack
+            }
+            Request::AddProfileSegment { variable, segment } => {
+                if !self.profile_editable()
+                    || !segment.target().is_none_or(|t| valid_target(variable, t))
+                    || self.profile.track_mut(variable).push(segment).is_err()
+                {
+                    return Response::Error(ErrorCode::InvalidValue);
+                }
+                self.profile_state = RunState::Idle;
+                self.profile_elapsed_s = 0;
+                Response::Ack
+            }
+            Request::ProfileControl(command) => self.profile_control(command),
+            Request::GetDiagnostics { device } => Response::Diagnostics(self.diagnostics(device)),
+        }
+    }
+
+    // Every sensor reads fine from boot; the heater, not the Peltier, is in use
+    fn diagnostics(&self, device: Device) -> Diagnostics {
+        let uptime_s = self.started.elapsed().as_secs() as u32;
+        let (period_s, self_test, serial) = match device {
+            Device::Scd41 => (Some(CONTROL_PERIOD_S), Some(true), "7D6BAB7B0737"),
+            Device::ExplorIr => (Some(CONTROL_PERIOD_S), Some(true), "SIM-EXPLORIR"),
+            Device::FlowSensor => (Some(CONTROL_PERIOD_S), None, "SIM-SLF3S"),
+            Device::Thermistors => (Some(PROBE_PERIOD_S), None, ""),
+            Device::PeltierBridge
+            | Device::Co2Valve
+            | Device::Humidifier
+            | Device::PressureTransducer => (None, None, ""),
+            Device::Heater => (Some(CONTROL_PERIOD_S), None, ""),
+        };
+        let (serial, serial_len) = diagnostics_text(serial);
+        let (last_error, last_error_len) = diagnostics_text("");
+        Diagnostics {
+            device,
+            reads: period_s.map_or(0, |period_s| uptime_s / period_s + 1),
+            timeouts: 0,
+            crc_errors: 0,
+            parse_errors: 0,
+            other_errors: 0,
+            since_good_s: period_s.map(|period_s| uptime_s % period_s),
+            self_test,
+            serial,
+            serial_len,
+            last_error,
+            last_error_len,
+        }
+    }
+
+    fn unix_time(&self) -> Option<u32> {
+        self.clock
+            .map(|clock| (clock + self.started.elapsed().as_secs()) as u32)
+    }
+
+    // Setpoints in effect, indexed by `Variable`
+    fn targets(&self) -> [f32; VARIABLES] {
+        self.profile_setpoints().0
+    }
+
+    fn profile_elapsed_s(&self) -> u32 {
+        match self.profile_state {
+            RunState::Running => {
+                self.profile_elapsed_s + self.profile_since.elapsed().as_secs() as u32
+            }
+            _ => self.profile_elapsed_s,
+        }
+    }
+
+    fn profile_setpoints(&self) -> ([f32; VARIABLES], [u8; VARIABLES]) {
+        let mut values = [self.setpoints.temp_c, self.setpoints.co2_ppm];
+        let mut segments = [0; VARIABLES];
+        if self.profile_state.is_active() {
+            let elapsed_s = self.profile_elapsed_s() as u64;
+            for (i, track) in self.profile.tracks.iter().enumerate() {
+                let (value, segment) = track.setpoint(values[i], elapsed_s);
+                values[i] = value;
+                segments[i] = segment as u8;
+            }
+        }
+        (values, segments)
+    }
+
+    fn profile_status(&self) -> ProfileStatus {
+        let ([temp_c, co2_ppm], segment) = self.profile_setpoints();
+        ProfileStatus {
+            state: self.profile_state,
+            elapsed_s: self.profile_elapsed_s(),
+            duration_s: self.profile.duration_s() as u32,
+            starts_at: self.profile_starts_at,
+            segments: self.profile.tracks.map(|t| t.segments().len() as u8),
+            segment,
+            temp_c,
+            co2_ppm,
+        }
+    }
+
+    fn profile_editable(&self) -> bool {
+        matches!(self.profile_state, RunState::Idle | RunState::Finished)
+    }
+
+    fn profile_control(&mut self, command: Command) -> Response {
+        let elapsed_s = self.profile_elapsed_s();
+        match (command, self.profile_state) {
+            (Command::Start { .. }, RunState::Waiting | RunState::Running | RunState::Paused) => {
+                return Response::Error(ErrorCode::InvalidValue)
+            }
+            (Command::Start { .. }, _) if self.profile.is_empty() => {
+                return Response::Error(ErrorCode::InvalidValue)
+            }
+            (Command::Start { at: None }, _) => {
+                self.profile_state = RunState::Running;
+                self.profile_elapsed_s = 0;
+            }
+            (Command::Start { at: Some(at) }, _) => {
+                if self.clock.is_none() {
+                    return Response::Error(ErrorCode::InvalidValue);
+                }
+                self.profile_state = RunState::Waiting;
+                self.profile_elapsed_s = 0;
+                self.profile_starts_at = Some(at);
+            }
+            (Command::Pause, RunState::Running) => {
+                self.profile_state = RunState::Paused;
+                self.profile_elapsed_s = elapsed_s;
+            }
+            (Command::Resume, RunState::Paused) => self.profile_state = RunState::Running,
+            (Command::Pause | Command::Resume, _) => {
+                return Response::Error(ErrorCode::InvalidValue)
+            }
+            (Command::Stop, _) => {
+                self.profile_state = RunState::Idle;
+                self.profile_elapsed_s = 0;
+                self.profile_starts_at = None;
+            }
+        }
+        self.profile_since = Instant::now();
+        self.advance_profile();
+        Response::Ack
+    }
+
+    // Starts a waiting profile once due, counting the time since, and finishes a
+    // running one once all tracks are done
+    fn advance_profile(&mut self) {
+        if let (RunState::Waiting, Some(at), Some(now)) =
+            (self.profile_state, self.profile_starts_at, self.unix_time())
+        {
+            if now >= at {
+                self.profile_state = RunState::Running;
+                self.profile_elapsed_s = now - at;
+                self.profile_since = Instant::now();
+                self.profile_starts_at = None;
+            }
+        }
+        let duration_s = self.profile.duration_s();
+        if self.profile_state == RunState::Running && self.profile_elapsed_s() as u64 >= duration_s
+        {
+            self.profile_state = RunState::Finished;
+            self.profile_elapsed_s = duration_s as u32;
+        }
+    }
+
+    // First-order approach towards the setpoints since the last request
+    fn step(&mut self) {
+        self.advance_profile();
+        let [temp_c, co2_ppm] = self.targets();
+        let dt = self.last_update.elapsed().as_secs_f32();
+        self.last_update = Instant::now();
+        let k = (APPROACH_RATE * dt).min(1.0);
+        self.temp_c += (temp_c - self.temp_c) * k;
+        self.humidity_rh += (PAN_HUMIDITY_RH - self.humidity_rh) * k;
+        let co2_before = self.co2_ppm;
+        self.co2_ppm += (co2_ppm - self.co2_ppm) * k;
+        if self.co2_ppm > co2_before + 1.0 {
+            self.co2_bursts += 1;
+        }
+    }
+
+    // Serves requests until the port is closed. Read timeouts are ignored.
+    pub fn serve<P: Read + Write>(&mut self, mut port: P) -> io::Result<()> {
+        let mut decoder = FrameDecoder::new();
+        let mut message = [0u8; MAX_MESSAGE_SIZE];
+        let mut buf = [0u8; 64];
+
+        loop {
+            let n = match port.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e),
+            };
+
+            for &byte in &buf[..n] {
+                let len = match decoder.feed(byte, &mut message) {
+                    Some(Ok(len)) => len,
+                    Some(Err(e)) => {
+                        eprintln!("simulator: dropping corrupt frame: {e:?}");
+                        continue;
+                    }
+                    None => continue,
+                };
+
+                let message = &message[..len];
+                let (request_id, response) = match Request::decode(message) {
+                    Ok((request_id, request)) => (request_id, self.handle(request)),
+                    Err(e) => {
+                        let code = match e {
+                            DecodeError::UnsupportedVersion(_) => ErrorCode::UnsupportedVersion,
+                            DecodeError::UnknownTag(_) => ErrorCode::UnknownMessage,
+                            _ => ErrorCode::Malformed,
+                        };
+                        (peek_request_id(message).unwrap_or(0), Response::Error(code))
+                    }
+                };
+
+                let mut out = [0u8; MAX_MESSAGE_SIZE];
+                let mut frame_buf = [0u8; MAX_FRAME_SIZE];
+                let len = response
+                    .encode(request_id, &mut out)
+                    .map_err(|e| io::Error::other(format!("{e:?}")))?;
+                let frame_len = frame::encode(&out[..len], &mut frame_buf)
+                    .map_err(|e| io::Error::other(format!("{e:?}")))?;
+                port.write_all(&frame_buf[..frame_len])?;
+                port.flush()?;
+            }
+        }
+    }
+}
+
+// The firmware's setpoint limits, which also bound profile targets
+fn valid_target(variable: Variable, value: f32) -> bool {
+    match variable {
+        Variable::Temp => (20.0..=45.0).contains(&value),
+        Variable::Co2 => (0.0..=200_000.0).contains(&value),
+    }
+}
+
+// Samples logged by the simulator per boot: a chamber warming up and being dosed
+pub fn simulated_samples() -> Vec<(u16, Vec<Sample>)> {
+    LOG_BOOTS
+        .iter()
+        .map(|&(boot, count, clock)| {
+            let samples = (0..count).map(|i| simulated_sample(i, clock)).collect();
+            (boot, samples)
+        })
+        .collect()
+}
+
+// Raw blocks of the simulator's data log, oldest first starting at LOG_FIRST_SEQ
+pub fn simulated_log() -> Vec<Vec<u8>> {
+    let mut blocks = Vec::new();
+    for (boot, samples) in simulated_samples() {
+        let seq = LOG_FIRST_SEQ + blocks.len() as u32;
+        blocks.extend(crate::datalog::encode_blocks(boot, seq, &samples));
+    }
+    blocks
+}
+
+fn simulated_sample(i: u32, clock: Option<u32>) -> Sample {
+    let t = i as f32 / 100.0;
+    let temp_c = 37.0 - 12.0 * (-t).exp();
+    let co2_ppm = 50_000.0 - 49_600.0 * (-t).exp();
+    let mut state = 0;
+    if temp_c < 36.0 {
+        state |= datalog::state::HEATER_ON;
+    }
+    if co2_ppm < 48_000.0 && i.is_multiple_of(3) {
+        state |= datalog::state::CO2_DOSED;
+    }
+    Sample {
+        time_s: i * LOG_PERIOD_S,
+        unix_s: clock.map(|clock| clock + i * LOG_PERIOD_S),
+        temp_c: Some(temp_c),
+        humidity_rh: Some(85.0 + 5.0 * (t * 3.0).sin()),
+        co2_ppm: Some(co2_ppm),
+        // The flow sensor drops out now and then
+        flow_ml_min: (i % 50 != 49).then_some(120.0 + 0.37 * (i % 7) as f32),
+        state,
+        alarms: if temp_c < 34.0 {
+            alarm::TEMP_OUT_OF_RANGE
+        } else {
+            0
+        },
+    }
+}
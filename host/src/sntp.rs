@@ -0,0 +1,196 @@
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use icbm_protocol::sntp::{
+    self, offset_delay, Packet, Timestamp, LEAP_UNSYNCHRONIZED, MODE_CLIENT, MODE_SERVER,
+    STRATUM_KISS,
+};
+
+// Datagrams larger than a header with extension fields are truncated, which is harmless
+const BUFFER_SIZE: usize = 512;
+
+// This computer's clock in microseconds since the Unix epoch
+pub fn unix_us_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.as_micros() as u64)
+}
+
+// How the stand-in answers, to exercise the client's checks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Behaviour {
+    Normal,
+    // Kiss-o'-death "RATE"
+    Kiss,
+    // Leap indicator "clock not synchronized"
+    Unsynchronized,
+    // Originate timestamp that does not match the request
+    WrongOriginate,
+}
+
+// SNTP server stand-in for exercising the device's clock sync on a Linux machine. Serves
+// this computer's clock, optionally shifted by an offset so the device has something to
+// correct, as a stratum 1 server with reference "LOCL". Every request is printed unless
+// quiet.
+pub struct Server {
+    socket: UdpSocket,
+    offset_us: i64,
+    behaviour: Behaviour,
+    quiet: bool,
+}
+
+impl Server {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Server {
+            socket: UdpSocket::bind(addr)?,
+            offset_us: 0,
+            behaviour: Behaviour::Normal,
+            quiet: false,
+        })
+    }
+
+    pub fn offset(mut self, offset_us: i64) -> Self {
+        self.offset_us = offset_us;
+        self
+    }
+
+    pub fn behaviour(mut self, behaviour: Behaviour) -> Self {
+        self.behaviour = behaviour;
+        self
+    }
+
+    pub fn quiet(mut self) -> Self {
+        self.quiet = true;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn serve(self) -> io::Result<()> {
+        let mut buf = [0u8; BUFFER_SIZE];
+        loop {
+            let (n, peer) = self.socket.recv_from(&mut buf)?;
+            let received = self.now();
+            // Anything but a client request is dropped, as a real server would
+            let Ok(request) = Packet::decode(&buf[..n]) else {
+                continue;
+            };
+            if request.mode != MODE_CLIENT {
+                continue;
+            }
+            let reply = self.answer(&request, received);
+            if !self.quiet {
+                println!(
+                    "{peer}: request v{}, replied {}",
+                    request.version,
+                    crate::datalog::iso8601((reply.transmit.to_unix_us() / 1_000_000) as u32)
+                );
+            }
+            self.socket.send_to(&reply.encode(), peer)?;
+        }
+    }
+
+    fn now(&self) -> Timestamp {
+        Timestamp::from_unix_us(unix_us_now().saturating_add_signed(self.offset_us))
+    }
+
+    fn answer(&self, request: &Packet, received: Timestamp) -> Packet {
+        let mut reply = Packet {
+            version: request.version,
+            mode: MODE_SERVER,
+            stratum: 1,
+            poll: request.poll,
+            precision: -20, // about a microsecond
+            reference_id: *b"LOCL",
+            reference: received,
+            originate: request.transmit,
+            receive: received,
+            ..Packet::default()
+        };
+        match self.behaviour {
+            Behaviour::Normal => {}
+            Behaviour::Kiss => {
+                reply.stratum = STRATUM_KISS;
+                reply.reference_id = *b"RATE";
+            }
+            Behaviour::Unsynchronized => reply.leap = LEAP_UNSYNCHRONIZED,
+            Behaviour::WrongOriginate => reply.originate.fraction ^= 1,
+        }
+        reply.transmit = self.now();
+        reply
+    }
+}
+
+// Result of one client exchange
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sync {
+    pub offset_us: i64,
+    pub delay_us: i64,
+    pub stratum: u8,
+    pub reference_id: [u8; 4],
+}
+
+#[derive(Debug)]
+pub enum QueryError {
+    Io(io::Error),
+    Reply(sntp::Error),
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryError::Io(e) => write!(f, "{e}"),
+            QueryError::Reply(sntp::Error::Kiss(code)) => {
+                write!(f, "kiss-o'-death {}", String::from_utf8_lossy(code))
+            }
+            QueryError::Reply(e) => write!(f, "rejected reply: {e:?}"),
+        }
+    }
+}
+
+impl From<io::Error> for QueryError {
+    fn from(e: io::Error) -> Self {
+        QueryError::Io(e)
+    }
+}
+
+// One SNTP exchange with `server` against this computer's clock, with the checks the
+// firmware applies. Replies to other requests are skipped until `timeout`.
+pub fn query(server: impl ToSocketAddrs, timeout: Duration) -> Result<Sync, QueryError> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.connect(server)?;
+
+    let sent = unix_us_now();
+    let transmit = Timestamp::from_unix_us(sent);
+    socket.send(&Packet::request(transmit).encode())?;
+
+    let mut buf = [0u8; BUFFER_SIZE];
+    loop {
+        let n = socket.recv(&mut buf)?;
+        let received = unix_us_now();
+        let reply = Packet::decode(&buf[..n]).map_err(QueryError::Reply)?;
+        match reply.check_reply(transmit) {
+            Ok(()) => {
+                let (offset_us, delay_us) = offset_delay(
+                    sent,
+                    reply.receive.to_unix_us(),
+                    reply.transmit.to_unix_us(),
+                    received,
+                );
+                return Ok(Sync {
+                    offset_us,
+                    delay_us,
+                    stratum: reply.stratum,
+                    reference_id: reply.reference_id,
+                });
+            }
+            Err(sntp::Error::Mismatch) => continue,
+            Err(e) => return Err(QueryError::Reply(e)),
+        }
+    }
+}
@@ -1,7 +1,15 @@
 /* Linker script for the STM32F407VGTx */
+/* Sectors 6-11 (0x08040000 - 0x080FFFFF) are reserved for persistent storage,
+   see src/storage/mod.rs for the layout */
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 1024K
+  FLASH : ORIGIN = 0x08000000, LENGTH = 256K
   RAM : ORIGIN = 0x20000000, LENGTH = 128K
   CCMRAM : ORIGIN = 0x10000000, LENGTH = 64K
-}
\ No newline at end of file
+}
+
+/* The linker fails an image that overflows FLASH; this keeps FLASH itself from
+   being grown into the storage sectors */
+_storage_start = 0x08040000;
+ASSERT(ORIGIN(FLASH) + LENGTH(FLASH) <= _storage_start,
+       "FLASH overlaps the persistent storage sectors, see src/storage/mod.rs")
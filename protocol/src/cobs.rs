@@ -0,0 +1,134 @@
+// Consistent Overhead Byte Stuffing: removes every 0x00 from the data so it can be used
+// as the frame delimiter. Encoding adds at most one byte per 254 bytes of input plus one.
+
+pub const fn max_encoded_len(len: usize) -> usize {
+    len + len / 254 + 1
+}
+
+// Returns the encoded length, or `None` if `dst` is too small
+pub fn encode(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    if dst.len() < max_encoded_len(src.len()) {
+        return None;
+    }
+
+    let mut code_idx = 0;
+    let mut out = 1;
+    let mut code: u8 = 1;
+
+    for &byte in src {
+        if byte == 0 {
+            dst[code_idx] = code;
+            code_idx = out;
+            out += 1;
+            code = 1;
+        } else {
+            dst[out] = byte;
+            out += 1;
+            code += 1;
+            if code == 0xFF {
+                dst[code_idx] = code;
+                code_idx = out;
+                out += 1;
+                code = 1;
+            }
+        }
+    }
+    dst[code_idx] = code;
+    Some(out)
+}
+
+// Decodes one frame (without the delimiter); returns the decoded length
+pub fn decode(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    let mut i = 0;
+    let mut out = 0;
+
+    while i < src.len() {
+        let code = src[i];
+        if code == 0 {
+            return None;
+        }
+        i += 1;
+
+        let run = code as usize - 1;
+        if i + run > src.len() || out + run > dst.len() {
+            return None;
+        }
+        for &byte in &src[i..i + run] {
+            if byte == 0 {
+                return None;
+            }
+        }
+        dst[out..out + run].copy_from_slice(&src[i..i + run]);
+        i += run;
+        out += run;
+
+        // A block shorter than 0xFF implies a zero, except at the very end
+        if code != 0xFF && i < src.len() {
+            if out >= dst.len() {
+                return None;
+            }
+            dst[out] = 0;
+            out += 1;
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8]) {
+        let mut encoded = [0u8; 1024];
+        let len = encode(data, &mut encoded).unwrap();
+        assert!(len <= max_encoded_len(data.len()));
+        assert!(!encoded[..len].contains(&0));
+        let mut decoded = [0u8; 1024];
+        let decoded_len = decode(&encoded[..len], &mut decoded).unwrap();
+        assert_eq!(&decoded[..decoded_len], data);
+    }
+
+    #[test]
+    fn known_vector() {
+        let mut encoded = [0u8; 8];
+        let len = encode(&[0x11, 0x22, 0x00, 0x33], &mut encoded).unwrap();
+        assert_eq!(&encoded[..len], &[0x03, 0x11, 0x22, 0x02, 0x33]);
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip(&[]);
+        round_trip(&[0]);
+        round_trip(&[0, 0, 0]);
+        round_trip(&[1, 2, 3]);
+
+        // Runs on either side of the 254 byte block limit
+        let mut data = [0x5Au8; 600];
+        for len in [253, 254, 255, 508, 509, 600] {
+            round_trip(&data[..len]);
+        }
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = (i % 7) as u8;
+        }
+        round_trip(&data);
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        let mut decoded = [0u8; 16];
+        // A zero inside the frame
+        assert_eq!(decode(&[0x03, 0x11, 0x00], &mut decoded), None);
+        // A code byte of zero
+        assert_eq!(decode(&[0x00, 0x11], &mut decoded), None);
+        // A run longer than what is left
+        assert_eq!(decode(&[0x05, 0x11, 0x22], &mut decoded), None);
+        // Output larger than `dst`
+        assert_eq!(decode(&[0x04, 0x11, 0x22, 0x33], &mut decoded[..2]), None);
+    }
+
+    #[test]
+    fn rejects_small_dst() {
+        let mut encoded = [0u8; 3];
+        assert_eq!(encode(&[1, 2, 3], &mut encoded), None);
+    }
+}
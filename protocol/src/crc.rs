@@ -0,0 +1,49 @@
+// CRC-16/CCITT-FALSE (poly 0x1021, init 0xFFFF, no reflection, no final XOR) for the
+// framed protocol
+
+const CRC16_INIT: u16 = 0xFFFF;
+const CRC16_POLYNOMIAL: u16 = 0x1021;
+
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = CRC16_INIT;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ CRC16_POLYNOMIAL;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+// CRC-16/MODBUS (poly 0xA001 reflected, init 0xFFFF), transmitted low byte first
+pub fn crc16_modbus(data: &[u8]) -> u16 {
+    let mut crc = CRC16_INIT;
+    for &byte in data {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            if crc & 0x0001 != 0 {
+                crc = (crc >> 1) ^ 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Check values of the CRC catalogue for "123456789"
+    #[test]
+    fn known_answers() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        assert_eq!(crc16_modbus(b"123456789"), 0x4B37);
+        assert_eq!(crc16(&[]), 0xFFFF);
+    }
+}
@@ -0,0 +1,479 @@
+// Time-series data log format, shared by the firmware's flash logger and the host
+// download.
+//
+// The log is a ring of fixed-size blocks. Each block starts with a header followed by
+// entries appended one sample at a time:
+//
+//   header  magic(4) version(2) boot(2) seq(4) reserved(2) crc16(2), little endian
+//   entry   len(1) body(len) check(1), zero padded to a multiple of ENTRY_ALIGN bytes
+//   body    present(1) state(1) alarms(varint) dt(varint) [clock(varint)]
+//           deltas(zigzag varint)...
+//
+// `dt` is the time since the previous sample and there is one delta per reading flagged
+// in `present`, taken from the previous value of that reading in the block. The first
+// entry of a block is encoded against zero, so every block decodes on its own and the
+// ring can drop the oldest blocks without breaking the rest. Blocks never span a reset:
+// `boot` changes and sample times restart from the new boot.
+//
+// `clock` (flagged by CLOCK in `present`, version 2) is the wall-clock time at uptime
+// zero in Unix seconds, 0 if the clock is not set. It is only stored when it changes,
+// i.e. in the first entry of a block and after the clock was set.
+
+use crate::crc::crc16;
+use crate::round;
+
+pub const BLOCK_SIZE: usize = 4096;
+pub const BLOCK_HEADER_SIZE: usize = 16;
+pub const ENTRY_ALIGN: usize = 4;
+// len + present + state + 7 varints of up to 5 bytes + check, padded
+pub const MAX_ENTRY_SIZE: usize = 40;
+
+pub const VERSION: u16 = 2;
+const MAGIC: u32 = 0x474F_4C49; // "ILOG"
+const ERASED: u8 = 0xFF;
+
+// Actuator state flags of a sample
+pub mod state {
+    pub const HEATER_ON: u8 = 1 << 0;
+    pub const CO2_DOSED: u8 = 1 << 1;
+    pub const HUMIDIFIER_ON: u8 = 1 << 2;
+}
+
+// Readings in the `present` mask, in body order, and their stored resolution
+const CHANNELS: usize = 4;
+const TEMP: usize = 0; // 0.01 °C
+const HUMIDITY: usize = 1; // 0.1 %RH
+const CO2: usize = 2; // 1 ppm
+const FLOW: usize = 3; // 0.01 ml/min
+const SCALE: [f32; CHANNELS] = [100.0, 10.0, 1.0, 100.0];
+const CLOCK: u8 = 1 << 7;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Sample {
+    pub time_s: u32,         // seconds since boot
+    pub unix_s: Option<u32>, // wall-clock time if the clock was set
+    pub temp_c: Option<f32>,
+    pub humidity_rh: Option<f32>,
+    pub co2_ppm: Option<f32>,
+    pub flow_ml_min: Option<f32>,
+    pub state: u8,   // `state` flags
+    pub alarms: u32, // message::alarm flags
+}
+
+impl Sample {
+    fn channels(&self) -> [Option<f32>; CHANNELS] {
+        [
+            self.temp_c,
+            self.humidity_rh,
+            self.co2_ppm,
+            self.flow_ml_min,
+        ]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BlockHeader {
+    pub boot: u16,
+    pub seq: u32,
+}
+
+impl BlockHeader {
+    pub fn encode(&self) -> [u8; BLOCK_HEADER_SIZE] {
+        let mut out = [0xFFu8; BLOCK_HEADER_SIZE];
+        out[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        out[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        out[6..8].copy_from_slice(&self.boot.to_le_bytes());
+        out[8..12].copy_from_slice(&self.seq.to_le_bytes());
+        let crc = crc16(&out[..14]);
+        out[14..16].copy_from_slice(&crc.to_le_bytes());
+        out
+    }
+
+    // `None` for erased or corrupted headers and for blocks from a newer format
+    pub fn decode(block: &[u8]) -> Option<Self> {
+        let header = block.get(..BLOCK_HEADER_SIZE)?;
+        let word = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]);
+        if header[0..4] != MAGIC.to_le_bytes()
+            || word(4) == 0
+            || word(4) > VERSION
+            || crc16(&header[..14]) != word(14)
+        {
+            return None;
+        }
+        Some(BlockHeader {
+            boot: word(6),
+            seq: u32::from_le_bytes([header[8], header[9], header[10], header[11]]),
+        })
+    }
+}
+
+// Padded size of the entry starting with `len`, or `None` at the end of the entries
+pub fn entry_size(len: u8) -> Option<usize> {
+    if len == ERASED || len == 0 || 2 + len as usize > MAX_ENTRY_SIZE {
+        return None;
+    }
+    Some((2 + len as usize).next_multiple_of(ENTRY_ALIGN))
+}
+
+// Delta encoder for the samples of one block; start a new one with every block
+#[derive(Clone, Debug, Default)]
+pub struct Encoder {
+    time_s: u32,
+    clock: u32,
+    last: [i32; CHANNELS],
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Writes one padded entry to `out` and returns its size
+    pub fn encode(&mut self, sample: &Sample, out: &mut [u8; MAX_ENTRY_SIZE]) -> usize {
+        out.fill(0);
+        let mut pos = 1;
+        let mut present = 0u8;
+        let values = sample.channels().map(|v| v.filter(|v| v.is_finite()));
+        for (i, value) in values.iter().enumerate() {
+            if value.is_some() {
+                present |= 1 << i;
+            }
+        }
+        let clock = sample
+            .unix_s
+            .map_or(0, |unix_s| unix_s.wrapping_sub(sample.time_s));
+        if clock != self.clock {
+            present |= CLOCK;
+        }
+        out[pos] = present;
+        out[pos + 1] = sample.state;
+        pos += 2;
+        pos += put_varint(sample.alarms, &mut out[pos..]);
+        pos += put_varint(sample.time_s.wrapping_sub(self.time_s), &mut out[pos..]);
+        self.time_s = sample.time_s;
+        if present & CLOCK != 0 {
+            pos += put_varint(clock, &mut out[pos..]);
+            self.clock = clock;
+        }
+
+        for (i, value) in values.iter().enumerate() {
+            let Some(value) = value else { continue };
+            let scaled = round(value * SCALE[i]).clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+            pos += put_varint(zigzag(scaled.wrapping_sub(self.last[i])), &mut out[pos..]);
+            self.last[i] = scaled;
+        }
+
+        let len = pos - 1;
+        out[0] = len as u8;
+        out[pos] = crc16(&out[1..pos]) as u8;
+        (pos + 1).next_multiple_of(ENTRY_ALIGN)
+    }
+}
+
+// Iterates over the samples of a block (including its header); stops at the first
+// erased, torn or corrupted entry
+pub struct Entries<'a> {
+    block: &'a [u8],
+    pos: usize,
+    time_s: u32,
+    clock: u32,
+    last: [i32; CHANNELS],
+}
+
+pub fn entries(block: &[u8]) -> Entries<'_> {
+    Entries {
+        block,
+        pos: BLOCK_HEADER_SIZE,
+        time_s: 0,
+        clock: 0,
+        last: [0; CHANNELS],
+    }
+}
+
+impl Entries<'_> {
+    // Offset just past the last entry decoded so far
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+}
+
+impl Iterator for Entries<'_> {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Sample> {
+        let len = *self.block.get(self.pos)?;
+        let size = entry_size(len)?;
+        let entry = self.block.get(self.pos..self.pos + size)?;
+        let body = &entry[1..1 + len as usize];
+        if crc16(body) as u8 != entry[1 + len as usize] {
+            return None;
+        }
+
+        let present = body[0];
+        let mut sample = Sample {
+            state: body[1],
+            ..Sample::default()
+        };
+        let mut pos = 2;
+        sample.alarms = take_varint(body, &mut pos)?;
+        self.time_s = self.time_s.wrapping_add(take_varint(body, &mut pos)?);
+        sample.time_s = self.time_s;
+        if present & CLOCK != 0 {
+            self.clock = take_varint(body, &mut pos)?;
+        }
+        sample.unix_s = (self.clock != 0).then(|| self.clock.wrapping_add(self.time_s));
+
+        let mut values = [None; CHANNELS];
+        for (i, value) in values.iter_mut().enumerate() {
+            if present & (1 << i) == 0 {
+                continue;
+            }
+            let delta = unzigzag(take_varint(body, &mut pos)?);
+            self.last[i] = self.last[i].wrapping_add(delta);
+            *value = Some(self.last[i] as f32 / SCALE[i]);
+        }
+        if pos != body.len() {
+            return None;
+        }
+        [
+            sample.temp_c,
+            sample.humidity_rh,
+            sample.co2_ppm,
+            sample.flow_ml_min,
+        ] = [values[TEMP], values[HUMIDITY], values[CO2], values[FLOW]];
+
+        self.pos += size;
+        Some(sample)
+    }
+}
+
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+fn unzigzag(value: u32) -> i32 {
+    (value >> 1) as i32 ^ -((value & 1) as i32)
+}
+
+fn put_varint(mut value: u32, out: &mut [u8]) -> usize {
+    let mut n = 0;
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out[n] = byte;
+            return n + 1;
+        }
+        out[n] = byte | 0x80;
+        n += 1;
+    }
+}
+
+fn take_varint(buf: &[u8], pos: &mut usize) -> Option<u32> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        let byte = *buf.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 0x7F) as u32).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Values at the stored resolution, so they decode exactly
+    const SAMPLES: [Sample; 4] = [
+        Sample {
+            time_s: 5,
+            unix_s: None,
+            temp_c: Some(36.91),
+            humidity_rh: Some(92.5),
+            co2_ppm: Some(50_123.0),
+            flow_ml_min: None,
+            state: state::HEATER_ON,
+            alarms: 0,
+        },
+        Sample {
+            time_s: 65,
+            unix_s: None,
+            temp_c: Some(36.87),
+            humidity_rh: None,
+            co2_ppm: Some(49_800.0),
+            flow_ml_min: Some(-1.25),
+            state: state::CO2_DOSED | state::HUMIDIFIER_ON,
+            alarms: 1 << 8,
+        },
+        // The clock was set
+        Sample {
+            time_s: 125,
+            unix_s: Some(1_700_000_125),
+            temp_c: None,
+            humidity_rh: Some(93.0),
+            co2_ppm: Some(50_000.0),
+            flow_ml_min: None,
+            state: 0,
+            alarms: 0,
+        },
+        Sample {
+            time_s: 185,
+            unix_s: Some(1_700_000_185),
+            temp_c: Some(-40.0),
+            humidity_rh: Some(0.0),
+            co2_ppm: Some(0.0),
+            flow_ml_min: Some(20.0),
+            state: state::HEATER_ON,
+            alarms: u32::MAX,
+        },
+    ];
+
+    // A block holding `samples`, erased after them; returns the end of the entries
+    fn write_block(samples: &[Sample], block: &mut [u8; BLOCK_SIZE]) -> usize {
+        block.fill(ERASED);
+        block[..BLOCK_HEADER_SIZE].copy_from_slice(&BlockHeader { boot: 3, seq: 42 }.encode());
+        let mut encoder = Encoder::new();
+        let mut pos = BLOCK_HEADER_SIZE;
+        for sample in samples {
+            let mut entry = [0u8; MAX_ENTRY_SIZE];
+            let size = encoder.encode(sample, &mut entry);
+            assert_eq!(size % ENTRY_ALIGN, 0);
+            assert_eq!(entry_size(entry[0]), Some(size));
+            block[pos..pos + size].copy_from_slice(&entry[..size]);
+            pos += size;
+        }
+        pos
+    }
+
+    #[test]
+    fn samples_round_trip() {
+        let mut block = [0u8; BLOCK_SIZE];
+        let end = write_block(&SAMPLES, &mut block);
+        assert_eq!(
+            BlockHeader::decode(&block),
+            Some(BlockHeader { boot: 3, seq: 42 })
+        );
+
+        let mut decoded = entries(&block);
+        for sample in &SAMPLES {
+            assert_eq!(decoded.next().as_ref(), Some(sample));
+        }
+        assert_eq!(decoded.next(), None);
+        assert_eq!(decoded.position(), end);
+    }
+
+    #[test]
+    fn extreme_values() {
+        let samples = [
+            Sample {
+                time_s: u32::MAX - 1,
+                unix_s: Some(u32::MAX),
+                co2_ppm: Some(2_000_000_000.0),
+                ..Default::default()
+            },
+            Sample {
+                time_s: 0,
+                co2_ppm: Some(-2_000_000_000.0),
+                ..Default::default()
+            },
+        ];
+        let mut block = [0u8; BLOCK_SIZE];
+        write_block(&samples, &mut block);
+        let decoded: [Option<Sample>; 2] = {
+            let mut entries = entries(&block);
+            [entries.next(), entries.next()]
+        };
+        assert_eq!(decoded, samples.map(Some));
+    }
+
+    #[test]
+    fn non_finite_readings_are_not_stored() {
+        let mut entry = [0u8; MAX_ENTRY_SIZE];
+        Encoder::new().encode(
+            &Sample {
+                temp_c: Some(f32::NAN),
+                co2_ppm: Some(f32::INFINITY),
+                ..Default::default()
+            },
+            &mut entry,
+        );
+        assert_eq!(entry[1], 0, "present mask");
+    }
+
+    #[test]
+    fn entry_size_stops_at_the_end() {
+        assert_eq!(entry_size(ERASED), None);
+        assert_eq!(entry_size(0), None);
+        assert_eq!(entry_size((MAX_ENTRY_SIZE - 1) as u8), None);
+        assert_eq!(entry_size(2), Some(4));
+        assert_eq!(entry_size(3), Some(8));
+        assert_eq!(entry_size((MAX_ENTRY_SIZE - 2) as u8), Some(MAX_ENTRY_SIZE));
+    }
+
+    #[test]
+    fn stops_at_a_torn_entry() {
+        let mut block = [0u8; BLOCK_SIZE];
+        write_block(&SAMPLES, &mut block);
+        let second = BLOCK_HEADER_SIZE + entry_size(block[BLOCK_HEADER_SIZE]).unwrap();
+        block[second + 3] ^= 0x01;
+
+        let mut decoded = entries(&block);
+        assert_eq!(decoded.next(), Some(SAMPLES[0]));
+        assert_eq!(decoded.next(), None);
+        assert_eq!(decoded.position(), second);
+    }
+
+    #[test]
+    fn empty_block() {
+        let mut block = [0u8; BLOCK_SIZE];
+        write_block(&[], &mut block);
+        assert_eq!(entries(&block).next(), None);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let header = BlockHeader { boot: 1, seq: 7 }.encode();
+        assert_eq!(
+            BlockHeader::decode(&header),
+            Some(BlockHeader { boot: 1, seq: 7 })
+        );
+        assert_eq!(BlockHeader::decode(&[ERASED; BLOCK_HEADER_SIZE]), None);
+        assert_eq!(BlockHeader::decode(&header[..BLOCK_HEADER_SIZE - 1]), None);
+
+        let mut corrupted = header;
+        corrupted[9] ^= 0x01;
+        assert_eq!(BlockHeader::decode(&corrupted), None);
+
+        // A newer format, with a valid CRC
+        let mut newer = header;
+        newer[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let crc = crc16(&newer[..14]);
+        newer[14..16].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(BlockHeader::decode(&newer), None);
+    }
+
+    #[test]
+    fn varints() {
+        let mut buf = [0u8; 5];
+        for value in [0, 1, 127, 128, 16_383, 16_384, u32::MAX] {
+            let len = put_varint(value, &mut buf);
+            let mut pos = 0;
+            assert_eq!(take_varint(&buf[..len], &mut pos), Some(value));
+            assert_eq!(pos, len);
+        }
+        // Truncated, and longer than five bytes
+        assert_eq!(take_varint(&[0x80], &mut 0), None);
+        assert_eq!(take_varint(&[0x80; 6], &mut 0), None);
+
+        for value in [0, 1, -1, i32::MAX, i32::MIN] {
+            assert_eq!(unzigzag(zigzag(value)), value);
+        }
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(zigzag(1), 2);
+    }
+}
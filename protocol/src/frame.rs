@@ -0,0 +1,222 @@
+use crate::cobs;
+use crate::crc::crc16;
+
+pub const DELIMITER: u8 = 0x00;
+pub const MAX_MESSAGE_SIZE: usize = 192;
+const CRC_SIZE: usize = 2;
+// COBS overhead plus the delimiter
+pub const MAX_FRAME_SIZE: usize = cobs::max_encoded_len(MAX_MESSAGE_SIZE + CRC_SIZE) + 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FrameError {
+    TooLong,
+    Encoding,
+    Crc,
+}
+
+// Wraps an encoded message into a delimited frame; returns the frame length
+pub fn encode(message: &[u8], frame: &mut [u8]) -> Result<usize, FrameError> {
+    if message.len() > MAX_MESSAGE_SIZE {
+        return Err(FrameError::TooLong);
+    }
+
+    let mut raw = [0u8; MAX_MESSAGE_SIZE + CRC_SIZE];
+    raw[..message.len()].copy_from_slice(message);
+    raw[message.len()..message.len() + CRC_SIZE].copy_from_slice(&crc16(message).to_be_bytes());
+
+    let len = cobs::encode(&raw[..message.len() + CRC_SIZE], frame).ok_or(FrameError::TooLong)?;
+    if len >= frame.len() {
+        return Err(FrameError::TooLong);
+    }
+    frame[len] = DELIMITER;
+    Ok(len + 1)
+}
+
+// Reassembles frames from a byte stream
+pub struct FrameDecoder {
+    buf: [u8; MAX_FRAME_SIZE],
+    len: usize,
+    overflow: bool,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameDecoder {
+    pub const fn new() -> Self {
+        FrameDecoder {
+            buf: [0; MAX_FRAME_SIZE],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    // Feeds one byte; when it completes a frame, the message is decoded into `message` and
+    // its length returned. Empty frames (back-to-back delimiters) are ignored.
+    pub fn feed(&mut self, byte: u8, message: &mut [u8]) -> Option<Result<usize, FrameError>> {
+        if byte != DELIMITER {
+            if self.len < self.buf.len() {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.overflow = true;
+            }
+            return None;
+        }
+
+        let len = core::mem::replace(&mut self.len, 0);
+        if core::mem::replace(&mut self.overflow, false) {
+            return Some(Err(FrameError::TooLong));
+        }
+        if len == 0 {
+            return None;
+        }
+        Some(Self::decode(&self.buf[..len], message))
+    }
+
+    fn decode(encoded: &[u8], message: &mut [u8]) -> Result<usize, FrameError> {
+        let mut raw = [0u8; MAX_MESSAGE_SIZE + CRC_SIZE];
+        let len = cobs::decode(encoded, &mut raw).ok_or(FrameError::Encoding)?;
+        if len < CRC_SIZE {
+            return Err(FrameError::Encoding);
+        }
+
+        let payload_len = len - CRC_SIZE;
+        let crc = u16::from_be_bytes([raw[payload_len], raw[payload_len + 1]]);
+        if crc16(&raw[..payload_len]) != crc {
+            return Err(FrameError::Crc);
+        }
+        if payload_len > message.len() {
+            return Err(FrameError::TooLong);
+        }
+        message[..payload_len].copy_from_slice(&raw[..payload_len]);
+        Ok(payload_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed_all(
+        decoder: &mut FrameDecoder,
+        bytes: &[u8],
+        message: &mut [u8],
+    ) -> Option<Result<usize, FrameError>> {
+        let mut result = None;
+        for &byte in bytes {
+            if let Some(r) = decoder.feed(byte, message) {
+                assert!(result.is_none(), "more than one frame");
+                result = Some(r);
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut frame = [0u8; MAX_FRAME_SIZE];
+        let message = [0x02, 0x01, 0x00, 0x34, 0x12, 0xFF];
+        let len = encode(&message, &mut frame).unwrap();
+        assert_eq!(frame[len - 1], DELIMITER);
+        assert!(!frame[..len - 1].contains(&DELIMITER));
+
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = [0u8; MAX_MESSAGE_SIZE];
+        assert_eq!(
+            feed_all(&mut decoder, &frame[..len], &mut decoded),
+            Some(Ok(message.len()))
+        );
+        assert_eq!(&decoded[..message.len()], &message);
+    }
+
+    #[test]
+    fn largest_message() {
+        let mut frame = [0u8; MAX_FRAME_SIZE];
+        let message = [0xA5u8; MAX_MESSAGE_SIZE];
+        let len = encode(&message, &mut frame).unwrap();
+
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = [0u8; MAX_MESSAGE_SIZE];
+        assert_eq!(
+            feed_all(&mut decoder, &frame[..len], &mut decoded),
+            Some(Ok(MAX_MESSAGE_SIZE))
+        );
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn corrupted_frame() {
+        let mut frame = [0u8; MAX_FRAME_SIZE];
+        // No zeros, so every byte but the first code byte is data
+        let len = encode(&[1, 2, 3, 4], &mut frame).unwrap();
+        frame[2] ^= 0x80;
+
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = [0u8; MAX_MESSAGE_SIZE];
+        assert_eq!(
+            feed_all(&mut decoder, &frame[..len], &mut decoded),
+            Some(Err(FrameError::Crc))
+        );
+    }
+
+    #[test]
+    fn ignores_empty_frames() {
+        let mut frame = [0u8; MAX_FRAME_SIZE + 2];
+        frame[0] = DELIMITER;
+        frame[1] = DELIMITER;
+        let len = encode(&[7, 8, 9], &mut frame[2..]).unwrap() + 2;
+
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = [0u8; MAX_MESSAGE_SIZE];
+        assert_eq!(
+            feed_all(&mut decoder, &frame[..len], &mut decoded),
+            Some(Ok(3))
+        );
+    }
+
+    #[test]
+    fn overflow_then_recovers() {
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = [0u8; MAX_MESSAGE_SIZE];
+        let garbage = [0x55u8; MAX_FRAME_SIZE + 10];
+        assert_eq!(feed_all(&mut decoder, &garbage, &mut decoded), None);
+        assert_eq!(
+            decoder.feed(DELIMITER, &mut decoded),
+            Some(Err(FrameError::TooLong))
+        );
+
+        let mut frame = [0u8; MAX_FRAME_SIZE];
+        let len = encode(&[1, 2], &mut frame).unwrap();
+        assert_eq!(
+            feed_all(&mut decoder, &frame[..len], &mut decoded),
+            Some(Ok(2))
+        );
+    }
+
+    #[test]
+    fn message_too_long() {
+        let mut frame = [0u8; MAX_FRAME_SIZE + 8];
+        let message = [1u8; MAX_MESSAGE_SIZE + 1];
+        assert_eq!(encode(&message, &mut frame), Err(FrameError::TooLong));
+
+        // A frame too small for the message
+        assert_eq!(
+            encode(&[1, 2, 3], &mut frame[..4]),
+            Err(FrameError::TooLong)
+        );
+
+        // A message larger than the buffer it is decoded into
+        let len = encode(&[1, 2, 3, 4], &mut frame).unwrap();
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = [0u8; 2];
+        assert_eq!(
+            feed_all(&mut decoder, &frame[..len], &mut decoded),
+            Some(Err(FrameError::TooLong))
+        );
+    }
+}
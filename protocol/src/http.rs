@@ -0,0 +1,251 @@
+// Request framing and head parsing for the firmware's HTTP/1.1 server (one request per
+// connection). Kept free of sockets so the limits on what a client can make the device
+// buffer are unit-tested on the host.
+
+// Largest request body accepted; setpoint changes are a small flat JSON object
+pub const MAX_BODY_SIZE: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Head,
+    Put,
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Status {
+    pub code: u16,
+    pub reason: &'static str,
+}
+
+impl Status {
+    pub const BAD_REQUEST: Status = Status {
+        code: 400,
+        reason: "Bad Request",
+    };
+    pub const PAYLOAD_TOO_LARGE: Status = Status {
+        code: 413,
+        reason: "Payload Too Large",
+    };
+    pub const HEADER_FIELDS_TOO_LARGE: Status = Status {
+        code: 431,
+        reason: "Request Header Fields Too Large",
+    };
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestHead<'a> {
+    pub method: Method,
+    // Without the query string, which no route uses
+    pub path: &'a str,
+    pub content_length: usize,
+    pub bearer: Option<&'a str>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Framing {
+    // More bytes are needed
+    Incomplete,
+    // The head is `..header_end` and the body `header_end..body_end`
+    Complete { header_end: usize, body_end: usize },
+    // Answer with this status and close
+    Reject(Status),
+}
+
+// Frames the bytes received so far into a buffer of `capacity` bytes. The head and the
+// body have to fit into the buffer together.
+pub fn frame(received: &[u8], capacity: usize) -> Framing {
+    let Some(header_end) = received
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|pos| pos + 4)
+    else {
+        return if received.len() >= capacity {
+            Framing::Reject(Status::HEADER_FIELDS_TOO_LARGE)
+        } else {
+            Framing::Incomplete
+        };
+    };
+
+    let Some(head) = parse_head(&received[..header_end]) else {
+        return Framing::Reject(Status::BAD_REQUEST);
+    };
+    if head.content_length > MAX_BODY_SIZE || header_end + head.content_length > capacity {
+        return Framing::Reject(Status::PAYLOAD_TOO_LARGE);
+    }
+    let body_end = header_end + head.content_length;
+    if received.len() < body_end {
+        return Framing::Incomplete;
+    }
+    Framing::Complete {
+        header_end,
+        body_end,
+    }
+}
+
+// Parses a request line and headers, up to and including the blank line
+pub fn parse_head(head: &[u8]) -> Option<RequestHead<'_>> {
+    let head = core::str::from_utf8(head).ok()?;
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next()?.split(' ');
+    let method = match request_line.next()? {
+        "GET" => Method::Get,
+        "HEAD" => Method::Head,
+        "PUT" => Method::Put,
+        _ => Method::Other,
+    };
+    let target = request_line.next()?;
+    if !request_line.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+
+    let mut request = RequestHead {
+        method,
+        path: target.split('?').next()?,
+        content_length: 0,
+        bearer: None,
+    };
+    for line in lines.filter(|l| !l.is_empty()) {
+        let (name, value) = line.split_once(':')?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            request.content_length = value.parse().ok()?;
+        } else if name.eq_ignore_ascii_case("authorization") {
+            if let Some(token) = value.strip_prefix("Bearer ") {
+                request.bearer = Some(token.trim());
+            }
+        }
+    }
+    Some(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAPACITY: usize = 1024;
+
+    // A PUT with `padding` bytes of filler headers and the body it announces
+    fn put(padding: usize, content_length: usize) -> ([u8; 2048], usize) {
+        let mut buf = [0u8; 2048];
+        let mut len = 0;
+        let mut push = |bytes: &[u8]| {
+            buf[len..len + bytes.len()].copy_from_slice(bytes);
+            len += bytes.len();
+        };
+        push(b"PUT /api/setpoints HTTP/1.1\r\nX-Filler: ");
+        for _ in 0..padding {
+            push(b"a");
+        }
+        push(b"\r\nContent-Length: ");
+        let mut digits = [0u8; 3];
+        digits[0] = b'0' + (content_length / 100) as u8;
+        digits[1] = b'0' + (content_length / 10 % 10) as u8;
+        digits[2] = b'0' + (content_length % 10) as u8;
+        push(&digits);
+        push(b"\r\n\r\n");
+        for _ in 0..content_length {
+            push(b" ");
+        }
+        (buf, len)
+    }
+
+    #[test]
+    fn parses_head() {
+        let head = parse_head(
+            b"PUT /api/setpoints?x=1 HTTP/1.1\r\nContent-Length: 14\r\n\
+              authorization: Bearer  secret \r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            head,
+            RequestHead {
+                method: Method::Put,
+                path: "/api/setpoints",
+                content_length: 14,
+                bearer: Some("secret"),
+            }
+        );
+        assert_eq!(
+            parse_head(b"DELETE / HTTP/1.0\r\n\r\n").unwrap().method,
+            Method::Other
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_heads() {
+        assert_eq!(parse_head(b"GET /\r\n\r\n"), None);
+        assert_eq!(parse_head(b"GET / SPDY/3\r\n\r\n"), None);
+        assert_eq!(parse_head(b"GET / HTTP/1.1\r\nNoColon\r\n\r\n"), None);
+        assert_eq!(
+            parse_head(b"GET / HTTP/1.1\r\nContent-Length: -1\r\n\r\n"),
+            None
+        );
+        assert_eq!(
+            frame(b"GET /\r\n\r\n", CAPACITY),
+            Framing::Reject(Status::BAD_REQUEST)
+        );
+    }
+
+    #[test]
+    fn waits_for_head_and_body() {
+        assert_eq!(frame(b"GET / HTTP/1.1\r\n", CAPACITY), Framing::Incomplete);
+        let request = b"PUT / HTTP/1.1\r\nContent-Length: 4\r\n\r\n{}";
+        assert_eq!(frame(request, CAPACITY), Framing::Incomplete);
+        let request = b"PUT / HTTP/1.1\r\nContent-Length: 4\r\n\r\n{  }";
+        assert_eq!(
+            frame(request, CAPACITY),
+            Framing::Complete {
+                header_end: 37,
+                body_end: 41,
+            }
+        );
+    }
+
+    #[test]
+    fn head_filling_the_buffer() {
+        let (buf, _) = put(1100, 0);
+        assert_eq!(
+            frame(&buf[..CAPACITY], CAPACITY),
+            Framing::Reject(Status::HEADER_FIELDS_TOO_LARGE)
+        );
+    }
+
+    #[test]
+    fn body_limit() {
+        let (buf, len) = put(0, MAX_BODY_SIZE);
+        assert!(matches!(
+            frame(&buf[..len], CAPACITY),
+            Framing::Complete { .. }
+        ));
+        let (buf, len) = put(0, MAX_BODY_SIZE + 1);
+        assert_eq!(
+            frame(&buf[..len], CAPACITY),
+            Framing::Reject(Status::PAYLOAD_TOO_LARGE)
+        );
+    }
+
+    // An allowed body behind a long head would end past the buffer
+    #[test]
+    fn long_head_with_body() {
+        let (buf, len) = put(900, MAX_BODY_SIZE);
+        let header_end = len - MAX_BODY_SIZE;
+        assert!(header_end < CAPACITY && header_end + MAX_BODY_SIZE > CAPACITY);
+        assert_eq!(
+            frame(&buf[..header_end], CAPACITY),
+            Framing::Reject(Status::PAYLOAD_TOO_LARGE)
+        );
+
+        // The same head with a body that still fits
+        let (buf, len) = put(900, CAPACITY - header_end);
+        assert_eq!(
+            frame(&buf[..len], CAPACITY),
+            Framing::Complete {
+                header_end,
+                body_end: CAPACITY,
+            }
+        );
+    }
+}
@@ -0,0 +1,36 @@
+#![no_std]
+
+// Framed binary protocol shared by the firmware and the host tools.
+//
+// Each message is encoded as [version][tag][request id (u16 LE)][body], followed by a
+// CRC-16/CCITT-FALSE (big endian), COBS-encoded and terminated by a 0x00 delimiter.
+//
+// The MQTT codec used by the network publisher lives here too so the host-side broker
+// stand-in speaks exactly what the firmware sends, and so is the Modbus register map used
+// by building management systems, the format of the flash data log that the host
+// downloads, the SNTP packets of the clock sync and the setpoint profile model, so the
+// simulator runs profiles the same way as the firmware. The HTTP request framing of the
+// device's web server is here only so that it is unit-tested on the host.
+
+pub mod cobs;
+pub mod crc;
+pub mod datalog;
+pub mod frame;
+pub mod http;
+pub mod message;
+pub mod modbus;
+pub mod mqtt;
+pub mod profile;
+pub mod sntp;
+
+// 2: readings carry humidity
+pub const PROTOCOL_VERSION: u8 = 2;
+
+// Rounds half away from zero; core has no float rounding without libm
+pub(crate) fn round(value: f32) -> i64 {
+    if value >= 0.0 {
+        (value + 0.5) as i64
+    } else {
+        (value - 0.5) as i64
+    }
+}
@@ -0,0 +1,1263 @@
+use crate::profile::{Command, RunState, Segment, Variable, VARIABLES};
+use crate::PROTOCOL_VERSION;
+
+// Message envelope: version(1) + tag(1) + request id(2)
+pub const HEADER_SIZE: usize = 4;
+pub const EXPLORIR_SERIAL_LENGTH: usize = 32;
+// Data log bytes carried by one `LogChunk`
+pub const LOG_CHUNK_SIZE: usize = 128;
+// Serial number and error message of `Diagnostics`, truncated to this many bytes
+pub const DIAGNOSTICS_TEXT_LENGTH: usize = 32;
+pub const DEVICES: usize = 9;
+
+// Alarm flags reported in `Alarms::active`
+pub mod alarm {
+    pub const TEMP_SENSOR_FAULT: u32 = 1 << 0;
+    pub const CO2_SENSOR_FAULT: u32 = 1 << 1;
+    pub const TEMP_OUT_OF_RANGE: u32 = 1 << 2;
+    pub const CO2_OUT_OF_RANGE: u32 = 1 << 3;
+    // Relative humidity below the alarm threshold, usually a dry water pan
+    pub const HUMIDITY_LOW: u32 = 1 << 4;
+    // The Peltier's H-bridge reported a fault and was disabled
+    pub const ACTUATOR_FAULT: u32 = 1 << 5;
+    // The ExplorIR and the SCD41 disagree on CO2 where both can measure it
+    pub const CO2_SENSOR_MISMATCH: u32 = 1 << 6;
+    // The door is open (by its switch or a rapid CO2 drop); CO2 dosing is suspended
+    pub const DOOR_OPEN: u32 = 1 << 7;
+    // CO2 bursts no longer raise the CO2, or the supply pressure is low: the cylinder is
+    // (nearly) empty
+    pub const CO2_SUPPLY_EMPTY: u32 = 1 << 8;
+    // The SCD41 cannot be read, so the humidity is unknown; the temperature can still come
+    // from the thermistor
+    pub const HUMIDITY_SENSOR_FAULT: u32 = 1 << 9;
+
+    pub const ALL: [u32; 10] = [
+        TEMP_SENSOR_FAULT,
+        CO2_SENSOR_FAULT,
+        TEMP_OUT_OF_RANGE,
+        CO2_OUT_OF_RANGE,
+        HUMIDITY_LOW,
+        ACTUATOR_FAULT,
+        CO2_SENSOR_MISMATCH,
+        DOOR_OPEN,
+        CO2_SUPPLY_EMPTY,
+        HUMIDITY_SENSOR_FAULT,
+    ];
+
+    // Machine-readable name of a single flag, as published over MQTT
+    pub fn name(flag: u32) -> &'static str {
+        match flag {
+            TEMP_SENSOR_FAULT => "temp_sensor_fault",
+            CO2_SENSOR_FAULT => "co2_sensor_fault",
+            TEMP_OUT_OF_RANGE => "temp_out_of_range",
+            CO2_OUT_OF_RANGE => "co2_out_of_range",
+            HUMIDITY_LOW => "humidity_low",
+            ACTUATOR_FAULT => "actuator_fault",
+            CO2_SENSOR_MISMATCH => "co2_sensor_mismatch",
+            DOOR_OPEN => "door_open",
+            CO2_SUPPLY_EMPTY => "co2_supply_empty",
+            HUMIDITY_SENSOR_FAULT => "humidity_sensor_fault",
+            _ => "unknown",
+        }
+    }
+}
+
+// Sensors and actuators in the firmware's diagnostics registry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Device {
+    Scd41 = 0,
+    ExplorIr = 1,
+    FlowSensor = 2,
+    Thermistors = 3,
+    PeltierBridge = 4,
+    Co2Valve = 5,
+    Humidifier = 6,
+    Heater = 7,
+    PressureTransducer = 8,
+}
+
+impl Device {
+    pub const ALL: [Device; DEVICES] = [
+        Device::Scd41,
+        Device::ExplorIr,
+        Device::FlowSensor,
+        Device::Thermistors,
+        Device::PeltierBridge,
+        Device::Co2Valve,
+        Device::Humidifier,
+        Device::Heater,
+        Device::PressureTransducer,
+    ];
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Device::ALL.get(value as usize).copied()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Device::Scd41 => "scd41",
+            Device::ExplorIr => "explorir",
+            Device::FlowSensor => "flow",
+            Device::Thermistors => "thermistors",
+            Device::PeltierBridge => "peltier",
+            Device::Co2Valve => "co2valve",
+            Device::Humidifier => "humidifier",
+            Device::Heater => "heater",
+            Device::PressureTransducer => "pressure",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DecodeError {
+    Truncated,
+    UnsupportedVersion(u8),
+    UnknownTag(u8),
+    InvalidValue,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EncodeError {
+    BufferTooSmall,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum ErrorCode {
+    Malformed = 1,
+    UnsupportedVersion = 2,
+    UnknownMessage = 3,
+    InvalidValue = 4,
+    Failed = 5,
+}
+
+impl ErrorCode {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => ErrorCode::Malformed,
+            2 => ErrorCode::UnsupportedVersion,
+            3 => ErrorCode::UnknownMessage,
+            4 => ErrorCode::InvalidValue,
+            5 => ErrorCode::Failed,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Readings {
+    pub temp_c: Option<f32>,
+    pub co2_ppm: Option<f32>,
+    pub heater_on: bool,
+    pub co2_bursts: u32,
+    pub updated_at_s: u64,
+    pub humidity_rh: Option<f32>,
+    pub humidifier_on: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Setpoints {
+    pub co2_ppm: f32,
+    pub temp_c: f32,
+    pub co2_tolerance_ppm: f32,
+    pub temp_tolerance_c: f32,
+    pub co2_burst_ms: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Alarms {
+    pub active: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CalibrationEntry {
+    pub seq: u32,
+    pub total: u32,
+    pub timestamp_s: u64,
+    pub scd41_serial: u64,
+    pub explorir_serial: [u8; EXPLORIR_SERIAL_LENGTH],
+    pub explorir_serial_len: u8,
+    pub reference_temp_c: f32,
+    pub reference_co2_ppm: u32,
+    pub pressure_pa: u32,
+    pub altitude_m: u16,
+    pub temp_before_c: Option<f32>,
+    pub temp_after_c: Option<f32>,
+    pub temp_offset_c: Option<f32>,
+    pub co2_before_ppm: Option<i32>,
+    pub co2_after_ppm: Option<i32>,
+}
+
+impl CalibrationEntry {
+    pub fn explorir_serial(&self) -> &str {
+        let len = (self.explorir_serial_len as usize).min(EXPLORIR_SERIAL_LENGTH);
+        core::str::from_utf8(&self.explorir_serial[..len]).unwrap_or("")
+    }
+}
+
+// Raw bytes of a data log block (see datalog), starting at `offset`. `block_len` is the
+// number of bytes in use, so a download is complete once `offset + len` reaches it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LogChunk {
+    pub seq: u32,
+    pub offset: u16,
+    pub block_len: u16,
+    pub len: u8,
+    pub data: [u8; LOG_CHUNK_SIZE],
+}
+
+impl LogChunk {
+    pub fn data(&self) -> &[u8] {
+        &self.data[..(self.len as usize).min(LOG_CHUNK_SIZE)]
+    }
+}
+
+// SNTP clock sync status. `server` is 0.0.0.0 while sync is disabled; the other fields
+// describe the last successful exchange and are unset until there was one.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TimeSync {
+    pub server: [u8; 4],
+    pub since_sync_s: Option<u32>,
+    // Server minus device clock before correcting it; unset if the clock was not set
+    pub offset_ms: Option<i32>,
+    pub delay_ms: u32,
+    pub stratum: u8,
+    // Rate of the device clock against the server since SNTP last set it, positive if
+    // it runs fast
+    pub drift_ppm: Option<f32>,
+    pub syncs: u32,
+    pub failures: u32,
+}
+
+// Health of one device since boot. Failed reads are counted by kind; `since_good_s` is
+// unset until a read succeeded and `self_test` until one ran, or for devices without.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Diagnostics {
+    pub device: Device,
+    pub reads: u32,
+    pub timeouts: u32,
+    pub crc_errors: u32,
+    pub parse_errors: u32,
+    pub other_errors: u32,
+    pub since_good_s: Option<u32>,
+    pub self_test: Option<bool>,
+    pub serial: [u8; DIAGNOSTICS_TEXT_LENGTH],
+    pub serial_len: u8,
+    pub last_error: [u8; DIAGNOSTICS_TEXT_LENGTH],
+    pub last_error_len: u8,
+}
+
+impl Diagnostics {
+    pub fn serial(&self) -> &str {
+        text(&self.serial, self.serial_len)
+    }
+
+    pub fn last_error(&self) -> &str {
+        text(&self.last_error, self.last_error_len)
+    }
+}
+
+// A text field of `Diagnostics` and its length. Device texts are ASCII, so truncating at
+// a byte index is safe.
+pub fn diagnostics_text(value: &str) -> ([u8; DIAGNOSTICS_TEXT_LENGTH], u8) {
+    let len = value.len().min(DIAGNOSTICS_TEXT_LENGTH);
+    let mut field = [0u8; DIAGNOSTICS_TEXT_LENGTH];
+    field[..len].copy_from_slice(&value.as_bytes()[..len]);
+    (field, len as u8)
+}
+
+fn text(field: &[u8; DIAGNOSTICS_TEXT_LENGTH], len: u8) -> &str {
+    let len = (len as usize).min(DIAGNOSTICS_TEXT_LENGTH);
+    core::str::from_utf8(&field[..len]).unwrap_or("")
+}
+
+// Setpoint profile progress. `segments` and `segment` are indexed by `Variable`; a
+// track's segment index equals its segment count once it is done. The setpoints are
+// those in effect, i.e. the configured ones unless a profile is active.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ProfileStatus {
+    pub state: RunState,
+    pub elapsed_s: u32,
+    pub duration_s: u32,
+    // Scheduled start while waiting, in Unix seconds
+    pub starts_at: Option<u32>,
+    pub segments: [u8; VARIABLES],
+    pub segment: [u8; VARIABLES],
+    pub temp_c: f32,
+    pub co2_ppm: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Request {
+    Hello,
+    GetReadings,
+    GetSetpoints,
+    SetSetpoints(Setpoints),
+    GetAlarms,
+    StartCalibration,
+    GetCalibration {
+        index: u32,
+    },
+    SelfTest,
+    GetLogInfo,
+    // Blocks are addressed by sequence number so a download is not disturbed by the
+    // logger dropping the oldest block meanwhile
+    ReadLog {
+        seq: u32,
+        offset: u16,
+    },
+    GetTime,
+    // Wall-clock time in seconds since the Unix epoch (UTC)
+    SetTime {
+        unix_s: u32,
+    },
+    GetTimeSync,
+    GetProfile,
+    GetProfileSegment {
+        variable: Variable,
+        index: u8,
+    },
+    // Editing is refused while a profile is waiting, running or paused
+    ClearProfile,
+    AddProfileSegment {
+        variable: Variable,
+        segment: Segment,
+    },
+    ProfileControl(Command),
+    GetDiagnostics {
+        device: Device,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Response {
+    Hello {
+        protocol_version: u8,
+    },
+    Readings(Readings),
+    Setpoints(Setpoints),
+    Alarms(Alarms),
+    Calibrated {
+        seq: u32,
+    },
+    CalibrationEntry(CalibrationEntry),
+    SelfTest {
+        scd41: bool,
+        explorir: bool,
+    },
+    // Sequence numbers of the oldest and newest stored blocks; `blocks` is 0 when empty
+    LogInfo {
+        blocks: u32,
+        first_seq: u32,
+        last_seq: u32,
+    },
+    LogChunk(LogChunk),
+    // `unix_s` is `None` while the device clock has not been set
+    Time {
+        unix_s: Option<u32>,
+        uptime_s: u64,
+    },
+    TimeSync(TimeSync),
+    Profile(ProfileStatus),
+    // `count` is the number of segments in the variable's track
+    ProfileSegment {
+        variable: Variable,
+        index: u8,
+        count: u8,
+        segment: Segment,
+    },
+    Diagnostics(Diagnostics),
+    Ack,
+    Error(ErrorCode),
+}
+
+// Request id of a message that could not be decoded, so an error reply can still be matched
+pub fn peek_request_id(buf: &[u8]) -> Option<u16> {
+    buf.get(2..HEADER_SIZE)
+        .map(|id| u16::from_le_bytes([id[0], id[1]]))
+}
+
+// Tags are part of the wire format; never reuse or renumber them
+mod tag {
+    pub const HELLO: u8 = 0x01;
+    pub const GET_READINGS: u8 = 0x02;
+    pub const GET_SETPOINTS: u8 = 0x03;
+    pub const SET_SETPOINTS: u8 = 0x04;
+    pub const GET_ALARMS: u8 = 0x05;
+    pub const START_CALIBRATION: u8 = 0x06;
+    pub const GET_CALIBRATION: u8 = 0x07;
+    pub const SELF_TEST: u8 = 0x08;
+    pub const GET_LOG_INFO: u8 = 0x09;
+    pub const READ_LOG: u8 = 0x0A;
+    pub const GET_TIME: u8 = 0x0B;
+    pub const SET_TIME: u8 = 0x0C;
+    pub const GET_TIME_SYNC: u8 = 0x0D;
+    pub const GET_PROFILE: u8 = 0x0E;
+    pub const GET_PROFILE_SEGMENT: u8 = 0x0F;
+    pub const CLEAR_PROFILE: u8 = 0x10;
+    pub const ADD_PROFILE_SEGMENT: u8 = 0x11;
+    pub const PROFILE_CONTROL: u8 = 0x12;
+    pub const GET_DIAGNOSTICS: u8 = 0x13;
+
+    pub const R_HELLO: u8 = 0x81;
+    pub const R_READINGS: u8 = 0x82;
+    pub const R_SETPOINTS: u8 = 0x83;
+    pub const R_ALARMS: u8 = 0x84;
+    pub const R_CALIBRATED: u8 = 0x85;
+    pub const R_CALIBRATION_ENTRY: u8 = 0x86;
+    pub const R_SELF_TEST: u8 = 0x87;
+    pub const R_LOG_INFO: u8 = 0x88;
+    pub const R_LOG_CHUNK: u8 = 0x89;
+    pub const R_TIME: u8 = 0x8A;
+    pub const R_TIME_SYNC: u8 = 0x8B;
+    pub const R_PROFILE: u8 = 0x8C;
+    pub const R_PROFILE_SEGMENT: u8 = 0x8D;
+    pub const R_DIAGNOSTICS: u8 = 0x8E;
+    pub const R_ACK: u8 = 0xFE;
+    pub const R_ERROR: u8 = 0xFF;
+}
+
+impl Request {
+    pub fn encode(&self, request_id: u16, buf: &mut [u8]) -> Result<usize, EncodeError> {
+        let mut w = Writer::new(buf);
+        let tag = match self {
+            Request::Hello => tag::HELLO,
+            Request::GetReadings => tag::GET_READINGS,
+            Request::GetSetpoints => tag::GET_SETPOINTS,
+            Request::SetSetpoints(_) => tag::SET_SETPOINTS,
+            Request::GetAlarms => tag::GET_ALARMS,
+            Request::StartCalibration => tag::START_CALIBRATION,
+            Request::GetCalibration { .. } => tag::GET_CALIBRATION,
+            Request::SelfTest => tag::SELF_TEST,
+            Request::GetLogInfo => tag::GET_LOG_INFO,
+            Request::ReadLog { .. } => tag::READ_LOG,
+            Request::GetTime => tag::GET_TIME,
+            Request::SetTime { .. } => tag::SET_TIME,
+            Request::GetTimeSync => tag::GET_TIME_SYNC,
+            Request::GetProfile => tag::GET_PROFILE,
+            Request::GetProfileSegment { .. } => tag::GET_PROFILE_SEGMENT,
+            Request::ClearProfile => tag::CLEAR_PROFILE,
+            Request::AddProfileSegment { .. } => tag::ADD_PROFILE_SEGMENT,
+            Request::ProfileControl(_) => tag::PROFILE_CONTROL,
+            Request::GetDiagnostics { .. } => tag::GET_DIAGNOSTICS,
+        };
+        w.header(tag, request_id)?;
+
+        match self {
+            Request::SetSetpoints(setpoints) => w.setpoints(setpoints)?,
+            Request::GetCalibration { index } => w.u32(*index)?,
+            Request::ReadLog { seq, offset } => {
+                w.u32(*seq)?;
+                w.u16(*offset)?;
+            }
+            Request::SetTime { unix_s } => w.u32(*unix_s)?,
+            Request::GetProfileSegment { variable, index } => {
+                w.u8(*variable as u8)?;
+                w.u8(*index)?;
+            }
+            Request::AddProfileSegment { variable, segment } => {
+                w.u8(*variable as u8)?;
+                w.segment(segment)?;
+            }
+            Request::ProfileControl(command) => w.command(command)?,
+            Request::GetDiagnostics { device } => w.u8(*device as u8)?,
+            _ => {}
+        }
+        Ok(w.pos)
+    }
+
+    // Returns the request id together with the request
+    pub fn decode(buf: &[u8]) -> Result<(u16, Self), DecodeError> {
+        let mut r = Reader::new(buf);
+        let (tag, request_id) = r.header()?;
+        let request = match tag {
+            tag::HELLO => Request::Hello,
+            tag::GET_READINGS => Request::GetReadings,
+            tag::GET_SETPOINTS => Request::GetSetpoints,
+            tag::SET_SETPOINTS => Request::SetSetpoints(r.setpoints()?),
+            tag::GET_ALARMS => Request::GetAlarms,
+            tag::START_CALIBRATION => Request::StartCalibration,
+            tag::GET_CALIBRATION => Request::GetCalibration { index: r.u32()? },
+            tag::SELF_TEST => Request::SelfTest,
+            tag::GET_LOG_INFO => Request::GetLogInfo,
+            tag::READ_LOG => Request::ReadLog {
+                seq: r.u32()?,
+                offset: r.u16()?,
+            },
+            tag::GET_TIME => Request::GetTime,
+            tag::SET_TIME => Request::SetTime { unix_s: r.u32()? },
+            tag::GET_TIME_SYNC => Request::GetTimeSync,
+            tag::GET_PROFILE => Request::GetProfile,
+            tag::GET_PROFILE_SEGMENT => Request::GetProfileSegment {
+                variable: r.variable()?,
+                index: r.u8()?,
+            },
+            tag::CLEAR_PROFILE => Request::ClearProfile,
+            tag::ADD_PROFILE_SEGMENT => Request::AddProfileSegment {
+                variable: r.variable()?,
+                segment: r.segment()?,
+            },
+            tag::PROFILE_CONTROL => Request::ProfileControl(r.command()?),
+            tag::GET_DIAGNOSTICS => Request::GetDiagnostics {
+                device: r.device()?,
+            },
+            other => return Err(DecodeError::UnknownTag(other)),
+        };
+        Ok((request_id, request))
+    }
+}
+
+impl Response {
+    pub fn encode(&self, request_id: u16, buf: &mut [u8]) -> Result<usize, EncodeError> {
+        let mut w = Writer::new(buf);
+        match self {
+            Response::Hello { protocol_version } => {
+                w.header(tag::R_HELLO, request_id)?;
+                w.u8(*protocol_version)?;
+            }
+            Response::Readings(readings) => {
+                w.header(tag::R_READINGS, request_id)?;
+                w.opt_f32(readings.temp_c)?;
+                w.opt_f32(readings.co2_ppm)?;
+                w.u8(readings.heater_on as u8)?;
+                w.u32(readings.co2_bursts)?;
+                w.u64(readings.updated_at_s)?;
+                w.opt_f32(readings.humidity_rh)?;
+                w.u8(readings.humidifier_on as u8)?;
+            }
+            Response::Setpoints(setpoints) => {
+                w.header(tag::R_SETPOINTS, request_id)?;
+                w.setpoints(setpoints)?;
+            }
+            Response::Alarms(alarms) => {
+                w.header(tag::R_ALARMS, request_id)?;
+                w.u32(alarms.active)?;
+            }
+            Response::Calibrated { seq } => {
+                w.header(tag::R_CALIBRATED, request_id)?;
+                w.u32(*seq)?;
+            }
+            Response::CalibrationEntry(entry) => {
+                w.header(tag::R_CALIBRATION_ENTRY, request_id)?;
+                w.u32(entry.seq)?;
+                w.u32(entry.total)?;
+                w.u64(entry.timestamp_s)?;
+                w.u64(entry.scd41_serial)?;
+                w.u8(entry.explorir_serial_len)?;
+                w.bytes(&entry.explorir_serial)?;
+                w.f32(entry.reference_temp_c)?;
+                w.u32(entry.reference_co2_ppm)?;
+                w.u32(entry.pressure_pa)?;
+                w.u16(entry.altitude_m)?;
+                w.opt_f32(entry.temp_before_c)?;
+                w.opt_f32(entry.temp_after_c)?;
+                w.opt_f32(entry.temp_offset_c)?;
+                w.opt_i32(entry.co2_before_ppm)?;
+                w.opt_i32(entry.co2_after_ppm)?;
+            }
+            Response::SelfTest { scd41, explorir } => {
+                w.header(tag::R_SELF_TEST, request_id)?;
+                w.u8(*scd41 as u8)?;
+                w.u8(*explorir as u8)?;
+            }
+            Response::LogInfo {
+                blocks,
+                first_seq,
+                last_seq,
+            } => {
+                w.header(tag::R_LOG_INFO, request_id)?;
+                w.u32(*blocks)?;
+                w.u32(*first_seq)?;
+                w.u32(*last_seq)?;
+            }
+            Response::LogChunk(chunk) => {
+                w.header(tag::R_LOG_CHUNK, request_id)?;
+                w.u32(chunk.seq)?;
+                w.u16(chunk.offset)?;
+                w.u16(chunk.block_len)?;
+                w.u8(chunk.len)?;
+                w.bytes(&chunk.data)?;
+            }
+            Response::Time { unix_s, uptime_s } => {
+                w.header(tag::R_TIME, request_id)?;
+                w.opt_u32(*unix_s)?;
+                w.u64(*uptime_s)?;
+            }
+            Response::TimeSync(sync) => {
+                w.header(tag::R_TIME_SYNC, request_id)?;
+                w.bytes(&sync.server)?;
+                w.opt_u32(sync.since_sync_s)?;
+                w.opt_i32(sync.offset_ms)?;
+                w.u32(sync.delay_ms)?;
+                w.u8(sync.stratum)?;
+                w.opt_f32(sync.drift_ppm)?;
+                w.u32(sync.syncs)?;
+                w.u32(sync.failures)?;
+            }
+            Response::Profile(status) => {
+                w.header(tag::R_PROFILE, request_id)?;
+                w.u8(status.state as u8)?;
+                w.u32(status.elapsed_s)?;
+                w.u32(status.duration_s)?;
+                w.opt_u32(status.starts_at)?;
+                w.bytes(&status.segments)?;
+                w.bytes(&status.segment)?;
+                w.f32(status.temp_c)?;
+                w.f32(status.co2_ppm)?;
+            }
+            Response::ProfileSegment {
+                variable,
+                index,
+                count,
+                segment,
+            } => {
+                w.header(tag::R_PROFILE_SEGMENT, request_id)?;
+                w.u8(*variable as u8)?;
+                w.u8(*index)?;
+                w.u8(*count)?;
+                w.segment(segment)?;
+            }
+            Response::Diagnostics(d) => {
+                w.header(tag::R_DIAGNOSTICS, request_id)?;
+                w.u8(d.device as u8)?;
+                w.u32(d.reads)?;
+                w.u32(d.timeouts)?;
+                w.u32(d.crc_errors)?;
+                w.u32(d.parse_errors)?;
+                w.u32(d.other_errors)?;
+                w.opt_u32(d.since_good_s)?;
+                w.opt_bool(d.self_test)?;
+                w.u8(d.serial_len)?;
+                w.bytes(&d.serial)?;
+                w.u8(d.last_error_len)?;
+                w.bytes(&d.last_error)?;
+            }
+            Response::Ack => w.header(tag::R_ACK, request_id)?,
+            Response::Error(code) => {
+                w.header(tag::R_ERROR, request_id)?;
+                w.u8(*code as u8)?;
+            }
+        }
+        Ok(w.pos)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<(u16, Self), DecodeError> {
+        let mut r = Reader::new(buf);
+        let (tag, request_id) = r.header()?;
+        let response = match tag {
+            tag::R_HELLO => Response::Hello {
+                protocol_version: r.u8()?,
+            },
+            tag::R_READINGS => Response::Readings(Readings {
+                temp_c: r.opt_f32()?,
+                co2_ppm: r.opt_f32()?,
+                heater_on: r.bool()?,
+                co2_bursts: r.u32()?,
+                updated_at_s: r.u64()?,
+                humidity_rh: r.opt_f32()?,
+                humidifier_on: r.bool()?,
+            }),
+            tag::R_SETPOINTS => Response::Setpoints(r.setpoints()?),
+            tag::R_ALARMS => Response::Alarms(Alarms { active: r.u32()? }),
+            tag::R_CALIBRATED => Response::Calibrated { seq: r.u32()? },
+            tag::R_CALIBRATION_ENTRY => Response::CalibrationEntry(CalibrationEntry {
+                seq: r.u32()?,
+                total: r.u32()?,
+                timestamp_s: r.u64()?,
+                scd41_serial: r.u64()?,
+                explorir_serial_len: r.u8()?,
+                explorir_serial: r.bytes()?,
+                reference_temp_c: r.f32()?,
+                reference_co2_ppm: r.u32()?,
+                pressure_pa: r.u32()?,
+                altitude_m: r.u16()?,
+                temp_before_c: r.opt_f32()?,
+                temp_after_c: r.opt_f32()?,
+                temp_offset_c: r.opt_f32()?,
+                co2_before_ppm: r.opt_i32()?,
+                co2_after_ppm: r.opt_i32()?,
+            }),
+            tag::R_SELF_TEST => Response::SelfTest {
+                scd41: r.bool()?,
+                explorir: r.bool()?,
+            },
+            tag::R_LOG_INFO => Response::LogInfo {
+                blocks: r.u32()?,
+                first_seq: r.u32()?,
+                last_seq: r.u32()?,
+            },
+            tag::R_LOG_CHUNK => {
+                let chunk = LogChunk {
+                    seq: r.u32()?,
+                    offset: r.u16()?,
+                    block_len: r.u16()?,
+                    len: r.u8()?,
+                    data: r.bytes()?,
+                };
+                if chunk.len as usize > LOG_CHUNK_SIZE {
+                    return Err(DecodeError::InvalidValue);
+                }
+                Response::LogChunk(chunk)
+            }
+            tag::R_TIME => Response::Time {
+                unix_s: r.opt_u32()?,
+                uptime_s: r.u64()?,
+            },
+            tag::R_TIME_SYNC => Response::TimeSync(TimeSync {
+                server: r.bytes()?,
+                since_sync_s: r.opt_u32()?,
+                offset_ms: r.opt_i32()?,
+                delay_ms: r.u32()?,
+                stratum: r.u8()?,
+                drift_ppm: r.opt_f32()?,
+                syncs: r.u32()?,
+                failures: r.u32()?,
+            }),
+            tag::R_PROFILE => Response::Profile(ProfileStatus {
+                state: RunState::from_u8(r.u8()?).ok_or(DecodeError::InvalidValue)?,
+                elapsed_s: r.u32()?,
+                duration_s: r.u32()?,
+                starts_at: r.opt_u32()?,
+                segments: r.bytes()?,
+                segment: r.bytes()?,
+                temp_c: r.f32()?,
+                co2_ppm: r.f32()?,
+            }),
+            tag::R_PROFILE_SEGMENT => Response::ProfileSegment {
+                variable: r.variable()?,
+                index: r.u8()?,
+                count: r.u8()?,
+                segment: r.segment()?,
+            },
+            tag::R_DIAGNOSTICS => {
+                let d = Diagnostics {
+                    device: r.device()?,
+                    reads: r.u32()?,
+                    timeouts: r.u32()?,
+                    crc_errors: r.u32()?,
+                    parse_errors: r.u32()?,
+                    other_errors: r.u32()?,
+                    since_good_s: r.opt_u32()?,
+                    self_test: r.opt_bool()?,
+                    serial_len: r.u8()?,
+                    serial: r.bytes()?,
+                    last_error_len: r.u8()?,
+                    last_error: r.bytes()?,
+                };
+                if d.serial_len as usize > DIAGNOSTICS_TEXT_LENGTH
+                    || d.last_error_len as usize > DIAGNOSTICS_TEXT_LENGTH
+                {
+                    return Err(DecodeError::InvalidValue);
+                }
+                Response::Diagnostics(d)
+            }
+            tag::R_ACK => Response::Ack,
+            tag::R_ERROR => {
+                Response::Error(ErrorCode::from_u8(r.u8()?).ok_or(DecodeError::InvalidValue)?)
+            }
+            other => return Err(DecodeError::UnknownTag(other)),
+        };
+        Ok((request_id, response))
+    }
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Writer { buf, pos: 0 }
+    }
+
+    fn bytes(&mut self, data: &[u8]) -> Result<(), EncodeError> {
+        let end = self.pos + data.len();
+        if end > self.buf.len() {
+            return Err(EncodeError::BufferTooSmall);
+        }
+        self.buf[self.pos..end].copy_from_slice(data);
+        self.pos = end;
+        Ok(())
+    }
+
+    fn header(&mut self, tag: u8, request_id: u16) -> Result<(), EncodeError> {
+        self.u8(PROTOCOL_VERSION)?;
+        self.u8(tag)?;
+        self.u16(request_id)
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), EncodeError> {
+        self.bytes(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> Result<(), EncodeError> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn u32(&mut self, value: u32) -> Result<(), EncodeError> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn u64(&mut self, value: u64) -> Result<(), EncodeError> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn f32(&mut self, value: f32) -> Result<(), EncodeError> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    // Optional values are prefixed with a presence byte
+    fn opt_f32(&mut self, value: Option<f32>) -> Result<(), EncodeError> {
+        self.u8(value.is_some() as u8)?;
+        self.f32(value.unwrap_or(0.0))
+    }
+
+    fn opt_i32(&mut self, value: Option<i32>) -> Result<(), EncodeError> {
+        self.u8(value.is_some() as u8)?;
+        self.bytes(&value.unwrap_or(0).to_le_bytes())
+    }
+
+    fn opt_u32(&mut self, value: Option<u32>) -> Result<(), EncodeError> {
+        self.u8(value.is_some() as u8)?;
+        self.u32(value.unwrap_or(0))
+    }
+
+    fn opt_bool(&mut self, value: Option<bool>) -> Result<(), EncodeError> {
+        self.u8(value.is_some() as u8)?;
+        self.u8(value.unwrap_or(false) as u8)
+    }
+
+    fn setpoints(&mut self, setpoints: &Setpoints) -> Result<(), EncodeError> {
+        self.f32(setpoints.co2_ppm)?;
+        self.f32(setpoints.temp_c)?;
+        self.f32(setpoints.co2_tolerance_ppm)?;
+        self.f32(setpoints.temp_tolerance_c)?;
+        self.u32(setpoints.co2_burst_ms)
+    }
+
+    fn segment(&mut self, segment: &Segment) -> Result<(), EncodeError> {
+        let (kind, target, duration_s) = segment.to_parts();
+        self.u8(kind)?;
+        self.f32(target)?;
+        self.u32(duration_s)
+    }
+
+    fn command(&mut self, command: &Command) -> Result<(), EncodeError> {
+        let (kind, at) = match command {
+            Command::Start { at } => (0, *at),
+            Command::Pause => (1, None),
+            Command::Resume => (2, None),
+            Command::Stop => (3, None),
+        };
+        self.u8(kind)?;
+        self.opt_u32(at)
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        let end = self.pos + N;
+        if end > self.buf.len() {
+            return Err(DecodeError::Truncated);
+        }
+        let mut out = [0u8; N];
+        out.copy_from_slice(&self.buf[self.pos..end]);
+        self.pos = end;
+        Ok(out)
+    }
+
+    fn header(&mut self) -> Result<(u8, u16), DecodeError> {
+        let version = self.u8()?;
+        if version != PROTOCOL_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let tag = self.u8()?;
+        let request_id = self.u16()?;
+        Ok((tag, request_id))
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        self.bytes::<1>().map(|b| b[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, DecodeError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::InvalidValue),
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        self.bytes().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64, DecodeError> {
+        self.bytes().map(u64::from_le_bytes)
+    }
+
+    fn f32(&mut self) -> Result<f32, DecodeError> {
+        self.bytes().map(f32::from_le_bytes)
+    }
+
+    fn opt_f32(&mut self) -> Result<Option<f32>, DecodeError> {
+        let present = self.bool()?;
+        let value = self.f32()?;
+        Ok(present.then_some(value))
+    }
+
+    fn opt_i32(&mut self) -> Result<Option<i32>, DecodeError> {
+        let present = self.bool()?;
+        let value = self.bytes().map(i32::from_le_bytes)?;
+        Ok(present.then_some(value))
+    }
+
+    fn opt_u32(&mut self) -> Result<Option<u32>, DecodeError> {
+        let present = self.bool()?;
+        let value = self.u32()?;
+        Ok(present.then_some(value))
+    }
+
+    fn opt_bool(&mut self) -> Result<Option<bool>, DecodeError> {
+        let present = self.bool()?;
+        let value = self.bool()?;
+        Ok(present.then_some(value))
+    }
+
+    fn setpoints(&mut self) -> Result<Setpoints, DecodeError> {
+        Ok(Setpoints {
+            co2_ppm: self.f32()?,
+            temp_c: self.f32()?,
+            co2_tolerance_ppm: self.f32()?,
+            temp_tolerance_c: self.f32()?,
+            co2_burst_ms: self.u32()?,
+        })
+    }
+
+    fn device(&mut self) -> Result<Device, DecodeError> {
+        Device::from_u8(self.u8()?).ok_or(DecodeError::InvalidValue)
+    }
+
+    fn variable(&mut self) -> Result<Variable, DecodeError> {
+        Variable::from_u8(self.u8()?).ok_or(DecodeError::InvalidValue)
+    }
+
+    fn segment(&mut self) -> Result<Segment, DecodeError> {
+        let kind = self.u8()?;
+        let target = self.f32()?;
+        let duration_s = self.u32()?;
+        Segment::from_parts(kind, target, duration_s).ok_or(DecodeError::InvalidValue)
+    }
+
+    fn command(&mut self) -> Result<Command, DecodeError> {
+        let kind = self.u8()?;
+        let at = self.opt_u32()?;
+        Ok(match kind {
+            0 => Command::Start { at },
+            1 => Command::Pause,
+            2 => Command::Resume,
+            3 => Command::Stop,
+            _ => return Err(DecodeError::InvalidValue),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::MAX_MESSAGE_SIZE;
+
+    const SETPOINTS: Setpoints = Setpoints {
+        co2_ppm: 80_000.0,
+        temp_c: 36.5,
+        co2_tolerance_ppm: 1500.0,
+        temp_tolerance_c: 0.5,
+        co2_burst_ms: 750,
+    };
+
+    fn request_round_trip(request: Request) {
+        let mut buf = [0u8; MAX_MESSAGE_SIZE];
+        let len = request.encode(0x1234, &mut buf).unwrap();
+        assert_eq!(Request::decode(&buf[..len]), Ok((0x1234, request)));
+        assert_eq!(peek_request_id(&buf[..len]), Some(0x1234));
+        // Every proper prefix is missing something
+        for end in 0..len {
+            assert_eq!(
+                Request::decode(&buf[..end]),
+                Err(DecodeError::Truncated),
+                "{:?} cut at {}",
+                request,
+                end
+            );
+        }
+    }
+
+    fn response_round_trip(response: Response) {
+        let mut buf = [0u8; MAX_MESSAGE_SIZE];
+        let len = response.encode(0xBEEF, &mut buf).unwrap();
+        assert_eq!(Response::decode(&buf[..len]), Ok((0xBEEF, response)));
+        for end in 0..len {
+            assert_eq!(
+                Response::decode(&buf[..end]),
+                Err(DecodeError::Truncated),
+                "{:?} cut at {}",
+                response,
+                end
+            );
+        }
+    }
+
+    #[test]
+    fn requests() {
+        for request in [
+            Request::Hello,
+            Request::GetReadings,
+            Request::GetSetpoints,
+            Request::SetSetpoints(SETPOINTS),
+            Request::GetAlarms,
+            Request::StartCalibration,
+            Request::GetCalibration { index: 7 },
+            Request::SelfTest,
+            Request::GetLogInfo,
+            Request::ReadLog {
+                seq: 123_456,
+                offset: 384,
+            },
+            Request::GetTime,
+            Request::SetTime {
+                unix_s: 1_700_000_000,
+            },
+            Request::GetTimeSync,
+            Request::GetProfile,
+            Request::GetProfileSegment {
+                variable: Variable::Co2,
+                index: 3,
+            },
+            Request::ClearProfile,
+            Request::AddProfileSegment {
+                variable: Variable::Temp,
+                segment: Segment::Ramp {
+                    target: 37.0,
+                    duration_s: 3600,
+                },
+            },
+            Request::AddProfileSegment {
+                variable: Variable::Co2,
+                segment: Segment::Hold { duration_s: 60 },
+            },
+            Request::ProfileControl(Command::Start {
+                at: Some(1_700_000_000),
+            }),
+            Request::ProfileControl(Command::Start { at: None }),
+            Request::ProfileControl(Command::Pause),
+            Request::ProfileControl(Command::Resume),
+            Request::ProfileControl(Command::Stop),
+            Request::GetDiagnostics {
+                device: Device::PeltierBridge,
+            },
+        ] {
+            request_round_trip(request);
+        }
+    }
+
+    #[test]
+    fn responses() {
+        let mut explorir_serial = [0u8; EXPLORIR_SERIAL_LENGTH];
+        explorir_serial[..6].copy_from_slice(b"EXP-42");
+        let (serial, serial_len) = diagnostics_text("0x1234abcd");
+        let (last_error, last_error_len) = diagnostics_text("Timeout waiting for response");
+
+        for response in [
+            Response::Hello {
+                protocol_version: PROTOCOL_VERSION,
+            },
+            Response::Readings(Readings {
+                temp_c: Some(36.9),
+                co2_ppm: None,
+                heater_on: true,
+                co2_bursts: 12,
+                updated_at_s: 86_400,
+                humidity_rh: Some(91.5),
+                humidifier_on: false,
+            }),
+            Response::Setpoints(SETPOINTS),
+            Response::Alarms(Alarms {
+                active: alarm::DOOR_OPEN | alarm::CO2_SUPPLY_EMPTY,
+            }),
+            Response::Calibrated { seq: 3 },
+            Response::CalibrationEntry(CalibrationEntry {
+                seq: 3,
+                total: 4,
+                timestamp_s: 1_700_000_000,
+                scd41_serial: 0x0102_0304_0506,
+                explorir_serial,
+                explorir_serial_len: 6,
+                reference_temp_c: 37.0,
+                reference_co2_ppm: 400,
+                pressure_pa: 101_325,
+                altitude_m: 120,
+                temp_before_c: Some(36.2),
+                temp_after_c: Some(37.0),
+                temp_offset_c: None,
+                co2_before_ppm: Some(-25),
+                co2_after_ppm: None,
+            }),
+            Response::SelfTest {
+                scd41: true,
+                explorir: false,
+            },
+            Response::LogInfo {
+                blocks: 2,
+                first_seq: 10,
+                last_seq: 11,
+            },
+            Response::LogChunk(LogChunk {
+                seq: 11,
+                offset: 128,
+                block_len: 300,
+                len: 4,
+                data: {
+                    let mut data = [0u8; LOG_CHUNK_SIZE];
+                    data[..4].copy_from_slice(&[1, 2, 3, 4]);
+                    data
+                },
+            }),
+            Response::Time {
+                unix_s: None,
+                uptime_s: 42,
+            },
+            Response::TimeSync(TimeSync {
+                server: [192, 168, 1, 1],
+                since_sync_s: Some(30),
+                offset_ms: Some(-120),
+                delay_ms: 8,
+                stratum: 2,
+                drift_ppm: Some(-3.5),
+                syncs: 5,
+                failures: 1,
+            }),
+            Response::Profile(ProfileStatus {
+                state: RunState::Running,
+                elapsed_s: 600,
+                duration_s: 7200,
+                starts_at: None,
+                segments: [2, 1],
+                segment: [1, 0],
+                temp_c: 36.8,
+                co2_ppm: 50_000.0,
+            }),
+            Response::ProfileSegment {
+                variable: Variable::Temp,
+                index: 1,
+                count: 2,
+                segment: Segment::Step {
+                    target: 38.0,
+                    duration_s: 900,
+                },
+            },
+            Response::Diagnostics(Diagnostics {
+                device: Device::ExplorIr,
+                reads: 1000,
+                timeouts: 2,
+                crc_errors: 0,
+                parse_errors: 1,
+                other_errors: 0,
+                since_good_s: Some(1),
+                self_test: Some(true),
+                serial,
+                serial_len,
+                last_error,
+                last_error_len,
+            }),
+            Response::Ack,
+            Response::Error(ErrorCode::InvalidValue),
+        ] {
+            response_round_trip(response);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_tag_and_version() {
+        let mut buf = [0u8; MAX_MESSAGE_SIZE];
+        let len = Request::GetReadings.encode(1, &mut buf).unwrap();
+
+        buf[1] = 0x7F;
+        assert_eq!(
+            Request::decode(&buf[..len]),
+            Err(DecodeError::UnknownTag(0x7F))
+        );
+        // Requests are not responses
+        let len = Request::Hello.encode(1, &mut buf).unwrap();
+        assert_eq!(
+            Response::decode(&buf[..len]),
+            Err(DecodeError::UnknownTag(tag::HELLO))
+        );
+
+        buf[0] = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            Request::decode(&buf[..len]),
+            Err(DecodeError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+        );
+        // The id is still readable, so the error reply can be matched
+        assert_eq!(peek_request_id(&buf[..len]), Some(1));
+        assert_eq!(peek_request_id(&buf[..3]), None);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let mut buf = [0u8; MAX_MESSAGE_SIZE];
+        let len = Request::GetDiagnostics {
+            device: Device::Scd41,
+        }
+        .encode(1, &mut buf)
+        .unwrap();
+        buf[len - 1] = DEVICES as u8;
+        assert_eq!(Request::decode(&buf[..len]), Err(DecodeError::InvalidValue));
+    }
+
+    #[test]
+    fn buffer_too_small() {
+        let mut buf = [0u8; HEADER_SIZE + 4];
+        assert_eq!(
+            Request::SetSetpoints(SETPOINTS).encode(1, &mut buf),
+            Err(EncodeError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn diagnostics_text_truncates() {
+        let long = "a very long error message that does not fit";
+        let (field, len) = diagnostics_text(long);
+        assert_eq!(len as usize, DIAGNOSTICS_TEXT_LENGTH);
+        assert_eq!(text(&field, len), &long[..DIAGNOSTICS_TEXT_LENGTH]);
+    }
+}
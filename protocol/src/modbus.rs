@@ -0,0 +1,782 @@
+// Modbus application protocol for building management systems: PDU handling and the
+// register map shared by the device's TCP and RTU transports and the host-side master.
+//
+// Supported functions: read holding registers (0x03), read input registers (0x04),
+// write single register (0x06) and write multiple registers (0x10).
+
+use crate::crc::crc16_modbus;
+
+pub const TCP_PORT: u16 = 502;
+pub const DEFAULT_UNIT_ID: u8 = 1;
+
+pub const MAX_PDU_SIZE: usize = 253;
+pub const MAX_READ_REGISTERS: u16 = 125;
+pub const MAX_WRITE_REGISTERS: u16 = 123;
+
+pub mod function {
+    pub const READ_HOLDING_REGISTERS: u8 = 0x03;
+    pub const READ_INPUT_REGISTERS: u8 = 0x04;
+    pub const WRITE_SINGLE_REGISTER: u8 = 0x06;
+    pub const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+}
+
+const EXCEPTION_FLAG: u8 = 0x80;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Exception {
+    IllegalFunction = 1,
+    IllegalDataAddress = 2,
+    IllegalDataValue = 3,
+    ServerDeviceFailure = 4,
+}
+
+impl Exception {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => Exception::IllegalFunction,
+            2 => Exception::IllegalDataAddress,
+            3 => Exception::IllegalDataValue,
+            4 => Exception::ServerDeviceFailure,
+            _ => return None,
+        })
+    }
+}
+
+// Register map. Values that do not fit one register use two, high word first.
+// Unavailable readings read as the `UNAVAILABLE_*` sentinels.
+pub mod map {
+    use crate::message::Setpoints;
+    use crate::round;
+
+    pub const UNAVAILABLE_I16: u16 = 0x8000;
+    pub const UNAVAILABLE_U16: u16 = 0xFFFF;
+    pub const UNAVAILABLE_U32: u32 = 0xFFFF_FFFF;
+
+    // Input registers (read only)
+    pub const TEMP: u16 = 0; // i16, 0.01 °C
+    pub const HUMIDITY: u16 = 1; // u16, 0.1 %RH
+    pub const CO2: u16 = 2; // u32, ppm
+    pub const FLOW: u16 = 4; // i16, 0.01 ml/min
+    pub const HEATER_ON: u16 = 5; // 0 or 1
+    pub const HEATER_DUTY: u16 = 6; // u16, 0.1 % of the control period
+    pub const CO2_VALVE_DUTY: u16 = 7; // u16, 0.1 % of the control period
+    pub const CO2_BURSTS: u16 = 8; // u32
+    pub const ALARMS: u16 = 10; // message::alarm flags
+    pub const UPDATED_AT: u16 = 11; // u32, seconds since boot
+    pub const INPUT_COUNT: usize = 13;
+
+    // Holding registers (read/write)
+    pub const CO2_SETPOINT: u16 = 0; // u32, ppm
+    pub const TEMP_SETPOINT: u16 = 2; // i16, 0.01 °C
+    pub const CO2_TOLERANCE: u16 = 3; // u32, ppm
+    pub const TEMP_TOLERANCE: u16 = 5; // u16, 0.01 °C
+    pub const CO2_BURST: u16 = 6; // u16, ms
+    pub const HUMIDITY_SETPOINT: u16 = 7; // u16, 0.1 %RH
+    pub const HUMIDITY_TOLERANCE: u16 = 8; // u16, 0.1 %RH
+    pub const HOLDING_COUNT: usize = 9;
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub struct Inputs {
+        pub temp_c: Option<f32>,
+        pub humidity_rh: Option<f32>,
+        pub co2_ppm: Option<f32>,
+        pub flow_ml_min: Option<f32>,
+        pub heater_on: bool,
+        pub heater_duty_pct: f32,
+        pub co2_valve_duty_pct: f32,
+        pub co2_bursts: u32,
+        pub alarms: u16,
+        pub updated_at_s: u32,
+    }
+
+    pub fn encode_inputs(inputs: &Inputs) -> [u16; INPUT_COUNT] {
+        let mut regs = [0u16; INPUT_COUNT];
+        regs[TEMP as usize] = scaled_i16(inputs.temp_c, 100.0);
+        regs[HUMIDITY as usize] = inputs
+            .humidity_rh
+            .map_or(UNAVAILABLE_U16, |rh| scaled_u16(rh, 10.0));
+        put_u32(
+            &mut regs,
+            CO2,
+            inputs.co2_ppm.map_or(UNAVAILABLE_U32, |ppm| {
+                round(ppm).clamp(0, u32::MAX as i64 - 1) as u32
+            }),
+        );
+        regs[FLOW as usize] = scaled_i16(inputs.flow_ml_min, 100.0);
+        regs[HEATER_ON as usize] = inputs.heater_on as u16;
+        regs[HEATER_DUTY as usize] = scaled_u16(inputs.heater_duty_pct, 10.0);
+        regs[CO2_VALVE_DUTY as usize] = scaled_u16(inputs.co2_valve_duty_pct, 10.0);
+        put_u32(&mut regs, CO2_BURSTS, inputs.co2_bursts);
+        regs[ALARMS as usize] = inputs.alarms;
+        put_u32(&mut regs, UPDATED_AT, inputs.updated_at_s);
+        regs
+    }
+
+    pub fn decode_inputs(regs: &[u16; INPUT_COUNT]) -> Inputs {
+        let i16_value = |addr: u16, scale: f32| {
+            let raw = regs[addr as usize];
+            (raw != UNAVAILABLE_I16).then(|| raw as i16 as f32 / scale)
+        };
+        let co2 = get_u32(regs, CO2);
+        let humidity = regs[HUMIDITY as usize];
+        Inputs {
+            temp_c: i16_value(TEMP, 100.0),
+            humidity_rh: (humidity != UNAVAILABLE_U16).then(|| humidity as f32 / 10.0),
+            co2_ppm: (co2 != UNAVAILABLE_U32).then_some(co2 as f32),
+            flow_ml_min: i16_value(FLOW, 100.0),
+            heater_on: regs[HEATER_ON as usize] != 0,
+            heater_duty_pct: regs[HEATER_DUTY as usize] as f32 / 10.0,
+            co2_valve_duty_pct: regs[CO2_VALVE_DUTY as usize] as f32 / 10.0,
+            co2_bursts: get_u32(regs, CO2_BURSTS),
+            alarms: regs[ALARMS as usize],
+            updated_at_s: get_u32(regs, UPDATED_AT),
+        }
+    }
+
+    // The holding registers: the message protocol's setpoints and the humidity
+    // setpoint, which that protocol does not carry
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub struct Holding {
+        pub setpoints: Setpoints,
+        pub humidity_rh: f32,
+        pub humidity_tolerance_rh: f32,
+    }
+
+    // Setpoints are rounded to the register resolution
+    pub fn encode_holding(holding: &Holding) -> [u16; HOLDING_COUNT] {
+        let setpoints = &holding.setpoints;
+        let mut regs = [0u16; HOLDING_COUNT];
+        put_u32(
+            &mut regs,
+            CO2_SETPOINT,
+            round(setpoints.co2_ppm).clamp(0, u32::MAX as i64) as u32,
+        );
+        regs[TEMP_SETPOINT as usize] = round(setpoints.temp_c * 100.0)
+            .clamp(i16::MIN as i64 + 1, i16::MAX as i64)
+            as i16 as u16;
+        put_u32(
+            &mut regs,
+            CO2_TOLERANCE,
+            round(setpoints.co2_tolerance_ppm).clamp(0, u32::MAX as i64) as u32,
+        );
+        regs[TEMP_TOLERANCE as usize] = scaled_u16(setpoints.temp_tolerance_c, 100.0);
+        regs[CO2_BURST as usize] = setpoints.co2_burst_ms.min(u16::MAX as u32) as u16;
+        regs[HUMIDITY_SETPOINT as usize] = scaled_u16(holding.humidity_rh, 10.0);
+        regs[HUMIDITY_TOLERANCE as usize] = scaled_u16(holding.humidity_tolerance_rh, 10.0);
+        regs
+    }
+
+    pub fn decode_holding(regs: &[u16; HOLDING_COUNT]) -> Holding {
+        Holding {
+            setpoints: Setpoints {
+                co2_ppm: get_u32(regs, CO2_SETPOINT) as f32,
+                temp_c: regs[TEMP_SETPOINT as usize] as i16 as f32 / 100.0,
+                co2_tolerance_ppm: get_u32(regs, CO2_TOLERANCE) as f32,
+                temp_tolerance_c: regs[TEMP_TOLERANCE as usize] as f32 / 100.0,
+                co2_burst_ms: regs[CO2_BURST as usize] as u32,
+            },
+            humidity_rh: regs[HUMIDITY_SETPOINT as usize] as f32 / 10.0,
+            humidity_tolerance_rh: regs[HUMIDITY_TOLERANCE as usize] as f32 / 10.0,
+        }
+    }
+
+    fn scaled_i16(value: Option<f32>, scale: f32) -> u16 {
+        match value {
+            Some(v) if v.is_finite() => {
+                round(v * scale).clamp(i16::MIN as i64 + 1, i16::MAX as i64) as i16 as u16
+            }
+            _ => UNAVAILABLE_I16,
+        }
+    }
+
+    fn scaled_u16(value: f32, scale: f32) -> u16 {
+        round(value * scale).clamp(0, u16::MAX as i64 - 1) as u16
+    }
+
+    fn put_u32(regs: &mut [u16], addr: u16, value: u32) {
+        regs[addr as usize] = (value >> 16) as u16;
+        regs[addr as usize + 1] = value as u16;
+    }
+
+    fn get_u32(regs: &[u16], addr: u16) -> u32 {
+        (regs[addr as usize] as u32) << 16 | regs[addr as usize + 1] as u32
+    }
+}
+
+// Current register contents as seen by a request
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RegisterMap {
+    pub input: [u16; map::INPUT_COUNT],
+    pub holding: [u16; map::HOLDING_COUNT],
+}
+
+impl RegisterMap {
+    pub fn new(inputs: &map::Inputs, holding: &map::Holding) -> Self {
+        RegisterMap {
+            input: map::encode_inputs(inputs),
+            holding: map::encode_holding(holding),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Outcome {
+    // Response PDU of this length is ready
+    Reply(usize),
+    // Holding registers were changed in the map and must be applied before sending the
+    // (success) response of this length; if they cannot be, reply with an exception
+    Written(usize),
+}
+
+// Handles one request PDU against `map`, writing the response PDU into `response`
+// (at least MAX_PDU_SIZE bytes)
+pub fn process(map: &mut RegisterMap, request: &[u8], response: &mut [u8]) -> Outcome {
+    let Some(&code) = request.first() else {
+        return Outcome::Reply(exception(0, Exception::IllegalFunction, response));
+    };
+    match handle(map, code, &request[1..], response) {
+        Ok(outcome) => outcome,
+        Err(e) => Outcome::Reply(exception(code, e, response)),
+    }
+}
+
+fn handle(
+    map: &mut RegisterMap,
+    code: u8,
+    data: &[u8],
+    response: &mut [u8],
+) -> Result<Outcome, Exception> {
+    let word = |i: usize| -> Result<u16, Exception> {
+        data.get(i..i + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or(Exception::IllegalDataValue)
+    };
+
+    match code {
+        function::READ_HOLDING_REGISTERS | function::READ_INPUT_REGISTERS => {
+            let start = word(0)?;
+            let count = word(2)?;
+            if count == 0 || count > MAX_READ_REGISTERS {
+                return Err(Exception::IllegalDataValue);
+            }
+            let registers: &[u16] = if code == function::READ_HOLDING_REGISTERS {
+                &map.holding
+            } else {
+                &map.input
+            };
+            let values = registers
+                .get(start as usize..start as usize + count as usize)
+                .ok_or(Exception::IllegalDataAddress)?;
+            response[0] = code;
+            response[1] = (count * 2) as u8;
+            for (i, value) in values.iter().enumerate() {
+                response[2 + i * 2..4 + i * 2].copy_from_slice(&value.to_be_bytes());
+            }
+            Ok(Outcome::Reply(2 + count as usize * 2))
+        }
+        function::WRITE_SINGLE_REGISTER => {
+            let addr = word(0)?;
+            let value = word(2)?;
+            *map.holding
+                .get_mut(addr as usize)
+                .ok_or(Exception::IllegalDataAddress)? = value;
+            response[0] = code;
+            response[1..5].copy_from_slice(&data[..4]);
+            Ok(Outcome::Written(5))
+        }
+        function::WRITE_MULTIPLE_REGISTERS => {
+            let start = word(0)?;
+            let count = word(2)?;
+            let byte_count = *data.get(4).ok_or(Exception::IllegalDataValue)? as usize;
+            if count == 0
+                || count > MAX_WRITE_REGISTERS
+                || byte_count != count as usize * 2
+                || data.len() < 5 + byte_count
+            {
+                return Err(Exception::IllegalDataValue);
+            }
+            let targets = map
+                .holding
+                .get_mut(start as usize..start as usize + count as usize)
+                .ok_or(Exception::IllegalDataAddress)?;
+            for (i, target) in targets.iter_mut().enumerate() {
+                *target = u16::from_be_bytes([data[5 + i * 2], data[6 + i * 2]]);
+            }
+            response[0] = code;
+            response[1..5].copy_from_slice(&data[..4]);
+            Ok(Outcome::Written(5))
+        }
+        _ => Err(Exception::IllegalFunction),
+    }
+}
+
+pub fn exception(code: u8, exception: Exception, response: &mut [u8]) -> usize {
+    response[0] = code | EXCEPTION_FLAG;
+    response[1] = exception as u8;
+    2
+}
+
+// Master side
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ResponseError {
+    Exception(Exception),
+    Malformed,
+}
+
+pub fn read_request(code: u8, start: u16, count: u16, out: &mut [u8]) -> usize {
+    out[0] = code;
+    out[1..3].copy_from_slice(&start.to_be_bytes());
+    out[3..5].copy_from_slice(&count.to_be_bytes());
+    5
+}
+
+pub fn write_single_request(addr: u16, value: u16, out: &mut [u8]) -> usize {
+    out[0] = function::WRITE_SINGLE_REGISTER;
+    out[1..3].copy_from_slice(&addr.to_be_bytes());
+    out[3..5].copy_from_slice(&value.to_be_bytes());
+    5
+}
+
+pub fn write_multiple_request(start: u16, values: &[u16], out: &mut [u8]) -> usize {
+    out[0] = function::WRITE_MULTIPLE_REGISTERS;
+    out[1..3].copy_from_slice(&start.to_be_bytes());
+    out[3..5].copy_from_slice(&(values.len() as u16).to_be_bytes());
+    out[5] = (values.len() * 2) as u8;
+    for (i, value) in values.iter().enumerate() {
+        out[6 + i * 2..8 + i * 2].copy_from_slice(&value.to_be_bytes());
+    }
+    6 + values.len() * 2
+}
+
+// Checks a response against the request that produced it; for reads the register values
+// are returned in `values`
+pub fn parse_response(
+    request: &[u8],
+    response: &[u8],
+    values: &mut [u16],
+) -> Result<usize, ResponseError> {
+    let (&code, &sent) = (
+        response.first().ok_or(ResponseError::Malformed)?,
+        request.first().ok_or(ResponseError::Malformed)?,
+    );
+    if code == sent | EXCEPTION_FLAG {
+        let exception = response
+            .get(1)
+            .and_then(|&e| Exception::from_u8(e))
+            .ok_or(ResponseError::Malformed)?;
+        return Err(ResponseError::Exception(exception));
+    }
+    if code != sent {
+        return Err(ResponseError::Malformed);
+    }
+    match code {
+        function::READ_HOLDING_REGISTERS | function::READ_INPUT_REGISTERS => {
+            let count = u16::from_be_bytes([request[3], request[4]]) as usize;
+            if response.get(1).copied() != Some((count * 2) as u8)
+                || response.len() != 2 + count * 2
+                || values.len() < count
+            {
+                return Err(ResponseError::Malformed);
+            }
+            for (i, value) in values[..count].iter_mut().enumerate() {
+                *value = u16::from_be_bytes([response[2 + i * 2], response[3 + i * 2]]);
+            }
+            Ok(count)
+        }
+        _ => {
+            // Writes echo the address and value / quantity
+            if response.len() != 5 || response[1..5] != request[1..5] {
+                return Err(ResponseError::Malformed);
+            }
+            Ok(0)
+        }
+    }
+}
+
+// Modbus TCP: MBAP header (transaction id, protocol id 0, length, unit id) + PDU
+pub mod tcp {
+    pub const HEADER_SIZE: usize = 7;
+    pub const MAX_ADU_SIZE: usize = HEADER_SIZE + super::MAX_PDU_SIZE;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    #[cfg_attr(feature = "defmt", derive(defmt::Format))]
+    pub struct Header {
+        pub transaction_id: u16,
+        pub unit_id: u8,
+    }
+
+    // Length of the ADU at the start of `buf` once its header has arrived; used to split a
+    // TCP stream into requests. `None` if more bytes are needed.
+    pub fn adu_len(buf: &[u8]) -> Option<usize> {
+        let length = u16::from_be_bytes([*buf.get(4)?, *buf.get(5)?]) as usize;
+        Some(6 + length)
+    }
+
+    // Returns `None` for ADUs that are not Modbus (protocol id) or inconsistent
+    pub fn decode(adu: &[u8]) -> Option<(Header, &[u8])> {
+        if adu.len() < HEADER_SIZE + 1 || adu[2..4] != [0, 0] || adu_len(adu)? != adu.len() {
+            return None;
+        }
+        let header = Header {
+            transaction_id: u16::from_be_bytes([adu[0], adu[1]]),
+            unit_id: adu[6],
+        };
+        Some((header, &adu[HEADER_SIZE..]))
+    }
+
+    pub fn encode(header: &Header, pdu: &[u8], out: &mut [u8]) -> usize {
+        out[0..2].copy_from_slice(&header.transaction_id.to_be_bytes());
+        out[2..4].copy_from_slice(&[0, 0]);
+        out[4..6].copy_from_slice(&(pdu.len() as u16 + 1).to_be_bytes());
+        out[6] = header.unit_id;
+        out[HEADER_SIZE..HEADER_SIZE + pdu.len()].copy_from_slice(pdu);
+        HEADER_SIZE + pdu.len()
+    }
+}
+
+// Modbus RTU: unit address + PDU + CRC-16/MODBUS (low byte first)
+pub mod rtu {
+    use super::crc16_modbus;
+
+    pub const BROADCAST: u8 = 0;
+    pub const BAUD_RATE: u32 = 19_200; // 8E1, the Modbus RTU default
+    pub const MAX_ADU_SIZE: usize = 1 + super::MAX_PDU_SIZE + 2;
+
+    // Returns `None` for frames that are too short or fail the CRC check
+    pub fn decode(frame: &[u8]) -> Option<(u8, &[u8])> {
+        if frame.len() < 4 {
+            return None;
+        }
+        let (body, crc) = frame.split_at(frame.len() - 2);
+        if crc16_modbus(body) != u16::from_le_bytes([crc[0], crc[1]]) {
+            return None;
+        }
+        Some((body[0], &body[1..]))
+    }
+
+    pub fn encode(unit: u8, pdu: &[u8], out: &mut [u8]) -> usize {
+        out[0] = unit;
+        out[1..1 + pdu.len()].copy_from_slice(pdu);
+        let crc = crc16_modbus(&out[..1 + pdu.len()]);
+        out[1 + pdu.len()..3 + pdu.len()].copy_from_slice(&crc.to_le_bytes());
+        3 + pdu.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Setpoints;
+
+    const HOLDING: map::Holding = map::Holding {
+        setpoints: Setpoints {
+            co2_ppm: 80_000.0,
+            temp_c: 36.5,
+            co2_tolerance_ppm: 1500.0,
+            temp_tolerance_c: 0.5,
+            co2_burst_ms: 750,
+        },
+        humidity_rh: 92.5,
+        humidity_tolerance_rh: 2.0,
+    };
+
+    fn register_map() -> RegisterMap {
+        RegisterMap::new(
+            &map::Inputs {
+                temp_c: Some(36.92),
+                co2_ppm: Some(51_234.0),
+                ..Default::default()
+            },
+            &HOLDING,
+        )
+    }
+
+    // Runs a request PDU through `process` and returns the outcome and response PDU
+    fn run(map: &mut RegisterMap, request: &[u8]) -> (Outcome, [u8; MAX_PDU_SIZE]) {
+        let mut response = [0u8; MAX_PDU_SIZE];
+        let outcome = process(map, request, &mut response);
+        (outcome, response)
+    }
+
+    fn assert_exception(request: &[u8], expected: Exception) {
+        let mut map = register_map();
+        let before = map;
+        let (outcome, response) = run(&mut map, request);
+        assert_eq!(outcome, Outcome::Reply(2), "{:02x?}", request);
+        assert_eq!(response[..2], [request[0] | EXCEPTION_FLAG, expected as u8]);
+        assert_eq!(map, before, "{:02x?} changed the map", request);
+    }
+
+    #[test]
+    fn inputs_round_trip() {
+        let inputs = map::Inputs {
+            temp_c: Some(-12.34),
+            humidity_rh: Some(93.4),
+            co2_ppm: Some(123_456.0),
+            flow_ml_min: Some(15.25),
+            heater_on: true,
+            heater_duty_pct: 42.5,
+            co2_valve_duty_pct: 1.2,
+            co2_bursts: 70_000,
+            alarms: 0x0105,
+            updated_at_s: 86_400 * 400,
+        };
+        let regs = map::encode_inputs(&inputs);
+        assert_eq!(regs[map::TEMP as usize], -1234i16 as u16);
+        assert_eq!(regs[map::CO2 as usize..][..2], [1, 57_920]);
+        assert_eq!(map::decode_inputs(&regs), inputs);
+    }
+
+    #[test]
+    fn unavailable_inputs() {
+        let inputs = map::Inputs {
+            temp_c: Some(f32::NAN),
+            ..Default::default()
+        };
+        let regs = map::encode_inputs(&inputs);
+        assert_eq!(regs[map::TEMP as usize], map::UNAVAILABLE_I16);
+        assert_eq!(regs[map::HUMIDITY as usize], map::UNAVAILABLE_U16);
+        assert_eq!(regs[map::CO2 as usize..][..2], [0xFFFF, 0xFFFF]);
+        assert_eq!(regs[map::FLOW as usize], map::UNAVAILABLE_I16);
+
+        let decoded = map::decode_inputs(&regs);
+        assert_eq!(decoded.temp_c, None);
+        assert_eq!(decoded.humidity_rh, None);
+        assert_eq!(decoded.co2_ppm, None);
+        assert_eq!(decoded.flow_ml_min, None);
+    }
+
+    #[test]
+    fn values_are_clamped_short_of_the_sentinels() {
+        let inputs = map::Inputs {
+            temp_c: Some(-1000.0),
+            humidity_rh: Some(1e9),
+            co2_ppm: Some(1e12),
+            ..Default::default()
+        };
+        let regs = map::encode_inputs(&inputs);
+        assert_eq!(regs[map::TEMP as usize], (i16::MIN + 1) as u16);
+        assert_eq!(regs[map::HUMIDITY as usize], map::UNAVAILABLE_U16 - 1);
+        assert_eq!(regs[map::CO2 as usize..][..2], [0xFFFF, 0xFFFE]);
+    }
+
+    #[test]
+    fn holding_round_trip() {
+        let regs = map::encode_holding(&HOLDING);
+        assert_eq!(
+            regs,
+            [1, 14_464, 3650, 0, 1500, 50, 750, 925, 20],
+            "CO2 80000 ppm, 36.50 °C, 1500 ppm, 0.50 °C, 750 ms, 92.5 %RH, 2.0 %RH"
+        );
+        assert_eq!(map::decode_holding(&regs), HOLDING);
+
+        // Rounded to the register resolution
+        let rounded = map::decode_holding(&map::encode_holding(&map::Holding {
+            setpoints: Setpoints {
+                temp_c: 36.504,
+                co2_ppm: 79_999.6,
+                ..HOLDING.setpoints
+            },
+            humidity_rh: 92.46,
+            humidity_tolerance_rh: 2.04,
+        }));
+        assert_eq!(rounded, HOLDING);
+    }
+
+    #[test]
+    fn reads() {
+        let mut map = register_map();
+        let mut request = [0u8; 5];
+        let len = read_request(function::READ_HOLDING_REGISTERS, 0, 9, &mut request);
+        let (outcome, response) = run(&mut map, &request[..len]);
+        assert_eq!(outcome, Outcome::Reply(20));
+        assert_eq!(response[..4], [0x03, 18, 0, 1]);
+
+        let mut values = [0u16; 9];
+        assert_eq!(
+            parse_response(&request, &response[..20], &mut values),
+            Ok(9)
+        );
+        assert_eq!(values, map.holding);
+
+        let len = read_request(function::READ_INPUT_REGISTERS, map::TEMP, 1, &mut request);
+        let (outcome, response) = run(&mut map, &request[..len]);
+        assert_eq!(outcome, Outcome::Reply(4));
+        assert_eq!(response[..4], [0x04, 2, 0x0E, 0x6C]);
+    }
+
+    #[test]
+    fn writes() {
+        let mut map = register_map();
+        let mut request = [0u8; 16];
+        let len = write_single_request(map::TEMP_SETPOINT, 3700, &mut request);
+        let (outcome, response) = run(&mut map, &request[..len]);
+        assert_eq!(outcome, Outcome::Written(5));
+        // Echoes the request
+        assert_eq!(response[..5], request[..5]);
+        assert_eq!(map.holding[map::TEMP_SETPOINT as usize], 3700);
+        assert_eq!(
+            parse_response(&request[..len], &response[..5], &mut []),
+            Ok(0)
+        );
+
+        let len = write_multiple_request(map::CO2_TOLERANCE, &[0, 2000], &mut request);
+        assert_eq!(request[..len], [0x10, 0, 3, 0, 2, 4, 0, 0, 0x07, 0xD0]);
+        let (outcome, response) = run(&mut map, &request[..len]);
+        assert_eq!(outcome, Outcome::Written(5));
+        assert_eq!(response[..5], request[..5]);
+        assert_eq!(
+            map::decode_holding(&map.holding)
+                .setpoints
+                .co2_tolerance_ppm,
+            2000.0
+        );
+
+        let len = write_multiple_request(map::HUMIDITY_SETPOINT, &[850, 50], &mut request);
+        let (outcome, _) = run(&mut map, &request[..len]);
+        assert_eq!(outcome, Outcome::Written(5));
+        let holding = map::decode_holding(&map.holding);
+        assert_eq!(
+            (holding.humidity_rh, holding.humidity_tolerance_rh),
+            (85.0, 5.0)
+        );
+    }
+
+    #[test]
+    fn exceptions() {
+        let input_count = map::INPUT_COUNT as u8;
+        let holding_count = map::HOLDING_COUNT as u8;
+
+        assert_exception(&[0x01, 0, 0, 0, 1], Exception::IllegalFunction);
+        assert_exception(&[0x05, 0, 0, 0xFF, 0], Exception::IllegalFunction);
+        // Read past the end of the map, or of nothing, or too much at once
+        assert_exception(
+            &[0x04, 0, input_count - 2, 0, 5],
+            Exception::IllegalDataAddress,
+        );
+        assert_exception(
+            &[0x03, 0, holding_count, 0, 1],
+            Exception::IllegalDataAddress,
+        );
+        assert_exception(&[0x03, 0, 0, 0, 0], Exception::IllegalDataValue);
+        assert_exception(&[0x03, 0, 0, 0, 126], Exception::IllegalDataValue);
+        // Request too short
+        assert_exception(&[0x03, 0, 0, 0], Exception::IllegalDataValue);
+        assert_exception(&[0x06, 0, 2], Exception::IllegalDataValue);
+        // Write past the end of the map
+        assert_exception(
+            &[0x06, 0, holding_count, 0, 1],
+            Exception::IllegalDataAddress,
+        );
+        assert_exception(
+            &[0x10, 0, holding_count - 1, 0, 2, 4, 0, 1, 0, 2],
+            Exception::IllegalDataAddress,
+        );
+        // Byte count not matching the quantity, or data missing
+        assert_exception(
+            &[0x10, 0, 2, 0, 1, 4, 0x0E, 0x42, 0, 0],
+            Exception::IllegalDataValue,
+        );
+        assert_exception(
+            &[0x10, 0, 2, 0, 2, 4, 0x0E, 0x42],
+            Exception::IllegalDataValue,
+        );
+        assert_exception(&[0x10, 0, 2, 0, 0, 0], Exception::IllegalDataValue);
+
+        // An empty PDU
+        let mut map = register_map();
+        let (outcome, response) = run(&mut map, &[]);
+        assert_eq!(outcome, Outcome::Reply(2));
+        assert_eq!(response[..2], [0x80, Exception::IllegalFunction as u8]);
+    }
+
+    #[test]
+    fn parses_exceptions_and_mismatches() {
+        let request = [0x03, 0, 0, 0, 2];
+        let mut values = [0u16; 2];
+        assert_eq!(
+            parse_response(&request, &[0x83, 2], &mut values),
+            Err(ResponseError::Exception(Exception::IllegalDataAddress))
+        );
+        assert_eq!(
+            parse_response(&request, &[0x83, 9], &mut values),
+            Err(ResponseError::Malformed)
+        );
+        // Other function, wrong byte count, short data
+        assert_eq!(
+            parse_response(&request, &[0x04, 4, 0, 1, 0, 2], &mut values),
+            Err(ResponseError::Malformed)
+        );
+        assert_eq!(
+            parse_response(&request, &[0x03, 2, 0, 1], &mut values),
+            Err(ResponseError::Malformed)
+        );
+        assert_eq!(
+            parse_response(&request, &[0x03, 4, 0, 1, 0], &mut values),
+            Err(ResponseError::Malformed)
+        );
+        // A write echoing something else
+        assert_eq!(
+            parse_response(
+                &[0x06, 0, 2, 0x0E, 0x42],
+                &[0x06, 0, 2, 0x0E, 0x43],
+                &mut []
+            ),
+            Err(ResponseError::Malformed)
+        );
+    }
+
+    #[test]
+    fn tcp_framing() {
+        let header = tcp::Header {
+            transaction_id: 0x1234,
+            unit_id: 7,
+        };
+        let mut adu = [0u8; tcp::MAX_ADU_SIZE];
+        let len = tcp::encode(&header, &[0x03, 0, 0, 0, 1], &mut adu);
+        assert_eq!(adu[..len], [0x12, 0x34, 0, 0, 0, 6, 7, 0x03, 0, 0, 0, 1]);
+        assert_eq!(tcp::adu_len(&adu[..6]), Some(len));
+        assert_eq!(tcp::adu_len(&adu[..5]), None);
+        assert_eq!(
+            tcp::decode(&adu[..len]),
+            Some((header, &[0x03, 0, 0, 0, 1][..]))
+        );
+
+        // Length not matching the ADU
+        assert_eq!(tcp::decode(&adu[..len - 1]), None);
+        // Another protocol id
+        adu[3] = 1;
+        assert_eq!(tcp::decode(&adu[..len]), None);
+    }
+
+    // Read 10 holding registers from unit 1, as in the Modbus serial line guide
+    #[test]
+    fn rtu_crc_known_answer() {
+        let mut frame = [0u8; rtu::MAX_ADU_SIZE];
+        let len = rtu::encode(1, &[0x03, 0, 0, 0, 0x0A], &mut frame);
+        assert_eq!(frame[..len], [0x01, 0x03, 0, 0, 0, 0x0A, 0xC5, 0xCD]);
+        assert_eq!(
+            rtu::decode(&frame[..len]),
+            Some((1, &[0x03, 0, 0, 0, 0x0A][..]))
+        );
+    }
+
+    #[test]
+    fn rtu_rejects_bad_frames() {
+        let mut frame = [0u8; rtu::MAX_ADU_SIZE];
+        let len = rtu::encode(1, &[0x06, 0, 2, 0x0E, 0x42], &mut frame);
+        for i in 0..len {
+            let mut corrupted = frame;
+            corrupted[i] ^= 0x01;
+            assert_eq!(rtu::decode(&corrupted[..len]), None, "byte {} flipped", i);
+        }
+        assert_eq!(rtu::decode(&frame[..3]), None);
+    }
+}
@@ -0,0 +1,600 @@
+// MQTT 3.1.1 control packets needed by the telemetry publisher and the host-side broker
+// stand-in: connect, publish, subscribe (one topic filter per packet), ping and
+// disconnect. Usernames, passwords and wills are skipped when decoding and never encoded.
+
+pub const PROTOCOL_LEVEL: u8 = 4; // 3.1.1
+pub const DEFAULT_PORT: u16 = 1883;
+
+pub const CONNACK_ACCEPTED: u8 = 0;
+pub const SUBACK_FAILURE: u8 = 0x80;
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+const CONNECT_CLEAN_SESSION: u8 = 0x02;
+const CONNECT_WILL: u8 = 0x04;
+const CONNECT_PASSWORD: u8 = 0x40;
+const CONNECT_USERNAME: u8 = 0x80;
+
+// Largest remaining length representable in four bytes
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    BufferTooSmall,
+    Malformed,
+    UnsupportedPacket(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Packet<'a> {
+    Connect {
+        client_id: &'a str,
+        keep_alive_s: u16,
+        clean_session: bool,
+    },
+    ConnAck {
+        session_present: bool,
+        return_code: u8,
+    },
+    Publish {
+        topic: &'a str,
+        payload: &'a [u8],
+        qos: u8,
+        retain: bool,
+        // Only present for QoS 1 and 2
+        packet_id: Option<u16>,
+    },
+    Subscribe {
+        packet_id: u16,
+        filter: &'a str,
+        qos: u8,
+    },
+    SubAck {
+        packet_id: u16,
+        return_code: u8,
+    },
+    PingReq,
+    PingResp,
+    Disconnect,
+}
+
+impl<'a> Packet<'a> {
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        // The body is written after a maximum-size header and moved down once its
+        // length, and so the header size, is known
+        let (first, body_len) = {
+            let mut w = Writer::new(buf.get_mut(5..).ok_or(Error::BufferTooSmall)?);
+            let first = match *self {
+                Packet::Connect {
+                    client_id,
+                    keep_alive_s,
+                    clean_session,
+                } => {
+                    w.str("MQTT")?;
+                    w.u8(PROTOCOL_LEVEL)?;
+                    w.u8(if clean_session {
+                        CONNECT_CLEAN_SESSION
+                    } else {
+                        0
+                    })?;
+                    w.u16(keep_alive_s)?;
+                    w.str(client_id)?;
+                    CONNECT << 4
+                }
+                Packet::ConnAck {
+                    session_present,
+                    return_code,
+                } => {
+                    w.u8(session_present as u8)?;
+                    w.u8(return_code)?;
+                    CONNACK << 4
+                }
+                Packet::Publish {
+                    topic,
+                    payload,
+                    qos,
+                    retain,
+                    packet_id,
+                } => {
+                    if qos > 2 || (qos > 0) != packet_id.is_some() {
+                        return Err(Error::Malformed);
+                    }
+                    w.str(topic)?;
+                    if let Some(id) = packet_id {
+                        w.u16(id)?;
+                    }
+                    w.bytes(payload)?;
+                    PUBLISH << 4 | qos << 1 | retain as u8
+                }
+                Packet::Subscribe {
+                    packet_id,
+                    filter,
+                    qos,
+                } => {
+                    w.u16(packet_id)?;
+                    w.str(filter)?;
+                    w.u8(qos)?;
+                    SUBSCRIBE << 4 | 0x02
+                }
+                Packet::SubAck {
+                    packet_id,
+                    return_code,
+                } => {
+                    w.u16(packet_id)?;
+                    w.u8(return_code)?;
+                    SUBACK << 4
+                }
+                Packet::PingReq => PINGREQ << 4,
+                Packet::PingResp => PINGRESP << 4,
+                Packet::Disconnect => DISCONNECT << 4,
+            };
+            (first, w.pos)
+        };
+
+        let mut header = [0u8; 5];
+        header[0] = first;
+        let header_len = 1 + encode_remaining_length(body_len, &mut header[1..])?;
+        buf.copy_within(5..5 + body_len, header_len);
+        buf[..header_len].copy_from_slice(&header[..header_len]);
+        Ok(header_len + body_len)
+    }
+
+    // Returns the packet and the number of bytes it used, or `Ok(None)` if `buf` does not
+    // yet hold a complete packet
+    pub fn decode(buf: &'a [u8]) -> Result<Option<(Self, usize)>, Error> {
+        let Some(&first) = buf.first() else {
+            return Ok(None);
+        };
+        let Some((remaining, length_len)) = decode_remaining_length(&buf[1..])? else {
+            return Ok(None);
+        };
+        let total = 1 + length_len + remaining;
+        if buf.len() < total {
+            return Ok(None);
+        }
+
+        let flags = first & 0x0F;
+        let mut r = Reader::new(&buf[1 + length_len..total]);
+        let packet = match first >> 4 {
+            CONNECT => {
+                if r.str()? != "MQTT" || r.u8()? != PROTOCOL_LEVEL {
+                    return Err(Error::Malformed);
+                }
+                let connect_flags = r.u8()?;
+                let keep_alive_s = r.u16()?;
+                let client_id = r.str()?;
+                if connect_flags & CONNECT_WILL != 0 {
+                    r.str()?; // will topic
+                    r.binary()?; // will message
+                }
+                if connect_flags & CONNECT_USERNAME != 0 {
+                    r.str()?;
+                }
+                if connect_flags & CONNECT_PASSWORD != 0 {
+                    r.binary()?;
+                }
+                Packet::Connect {
+                    client_id,
+                    keep_alive_s,
+                    clean_session: connect_flags & CONNECT_CLEAN_SESSION != 0,
+                }
+            }
+            CONNACK => Packet::ConnAck {
+                session_present: r.u8()? & 0x01 != 0,
+                return_code: r.u8()?,
+            },
+            PUBLISH => {
+                let qos = (flags >> 1) & 0x03;
+                if qos > 2 {
+                    return Err(Error::Malformed);
+                }
+                let topic = r.str()?;
+                let packet_id = if qos > 0 { Some(r.u16()?) } else { None };
+                Packet::Publish {
+                    topic,
+                    payload: r.rest(),
+                    qos,
+                    retain: flags & 0x01 != 0,
+                    packet_id,
+                }
+            }
+            SUBSCRIBE => {
+                // Additional topic filters in the same packet are ignored
+                let packet_id = r.u16()?;
+                let filter = r.str()?;
+                Packet::Subscribe {
+                    packet_id,
+                    filter,
+                    qos: r.u8()? & 0x03,
+                }
+            }
+            SUBACK => Packet::SubAck {
+                packet_id: r.u16()?,
+                return_code: r.u8()?,
+            },
+            PINGREQ => Packet::PingReq,
+            PINGRESP => Packet::PingResp,
+            DISCONNECT => Packet::Disconnect,
+            other => return Err(Error::UnsupportedPacket(other)),
+        };
+        Ok(Some((packet, total)))
+    }
+}
+
+// Topic filter matching with the `+` (one level) and `#` (all remaining levels) wildcards
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+fn encode_remaining_length(mut len: usize, buf: &mut [u8]) -> Result<usize, Error> {
+    if len > MAX_REMAINING_LENGTH {
+        return Err(Error::BufferTooSmall);
+    }
+    let mut i = 0;
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        buf[i] = byte;
+        i += 1;
+        if len == 0 {
+            return Ok(i);
+        }
+    }
+}
+
+fn decode_remaining_length(buf: &[u8]) -> Result<Option<(usize, usize)>, Error> {
+    let mut len = 0usize;
+    for (i, &byte) in buf.iter().enumerate().take(4) {
+        len |= ((byte & 0x7F) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((len, i + 1)));
+        }
+    }
+    if buf.len() >= 4 {
+        Err(Error::Malformed)
+    } else {
+        Ok(None)
+    }
+}
+
+struct Writer<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+}
+
+impl<'b> Writer<'b> {
+    fn new(buf: &'b mut [u8]) -> Self {
+        Writer { buf, pos: 0 }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let end = self.pos + bytes.len();
+        self.buf
+            .get_mut(self.pos..end)
+            .ok_or(Error::BufferTooSmall)?
+            .copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), Error> {
+        self.bytes(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> Result<(), Error> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn str(&mut self, value: &str) -> Result<(), Error> {
+        let len = u16::try_from(value.len()).map_err(|_| Error::Malformed)?;
+        self.u16(len)?;
+        self.bytes(value.as_bytes())
+    }
+}
+
+struct Reader<'b> {
+    buf: &'b [u8],
+    pos: usize,
+}
+
+impl<'b> Reader<'b> {
+    fn new(buf: &'b [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'b [u8], Error> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or(Error::Malformed)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn binary(&mut self) -> Result<&'b [u8], Error> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+
+    fn str(&mut self) -> Result<&'b str, Error> {
+        core::str::from_utf8(self.binary()?).map_err(|_| Error::Malformed)
+    }
+
+    fn rest(&mut self) -> &'b [u8] {
+        let rest = &self.buf[self.pos..];
+        self.pos = self.buf.len();
+        rest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(packet: Packet) {
+        let mut buf = [0u8; 512];
+        let len = packet.encode(&mut buf).unwrap();
+        assert_eq!(Packet::decode(&buf[..len]), Ok(Some((packet, len))));
+        // Nothing is decoded before the packet is complete
+        for end in 0..len {
+            assert_eq!(
+                Packet::decode(&buf[..end]),
+                Ok(None),
+                "{:?} cut at {}",
+                packet,
+                end
+            );
+        }
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        let payload = [b'x'; 300];
+        for packet in [
+            Packet::Connect {
+                client_id: "icbm-0001",
+                keep_alive_s: 60,
+                clean_session: true,
+            },
+            Packet::ConnAck {
+                session_present: false,
+                return_code: CONNACK_ACCEPTED,
+            },
+            Packet::Publish {
+                topic: "icbm/co2",
+                payload: b"50000",
+                qos: 0,
+                retain: true,
+                packet_id: None,
+            },
+            Packet::Publish {
+                topic: "icbm/large",
+                payload: &payload,
+                qos: 1,
+                retain: false,
+                packet_id: Some(7),
+            },
+            Packet::Subscribe {
+                packet_id: 1,
+                filter: "icbm/setpoints/set",
+                qos: 0,
+            },
+            Packet::SubAck {
+                packet_id: 1,
+                return_code: SUBACK_FAILURE,
+            },
+            Packet::PingReq,
+            Packet::PingResp,
+            Packet::Disconnect,
+        ] {
+            round_trip(packet);
+        }
+    }
+
+    #[test]
+    fn wire_format() {
+        let mut buf = [0u8; 64];
+        let len = Packet::Connect {
+            client_id: "ab",
+            keep_alive_s: 60,
+            clean_session: true,
+        }
+        .encode(&mut buf)
+        .unwrap();
+        assert_eq!(
+            &buf[..len],
+            &[0x10, 14, 0, 4, b'M', b'Q', b'T', b'T', 4, 0x02, 0, 60, 0, 2, b'a', b'b']
+        );
+
+        let len = Packet::Subscribe {
+            packet_id: 0x0102,
+            filter: "a/#",
+            qos: 0,
+        }
+        .encode(&mut buf)
+        .unwrap();
+        // SUBSCRIBE requires the reserved flags to be 0b0010
+        assert_eq!(&buf[..len], &[0x82, 8, 1, 2, 0, 3, b'a', b'/', b'#', 0]);
+
+        let len = Packet::PingReq.encode(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &[0xC0, 0]);
+    }
+
+    #[test]
+    fn remaining_length() {
+        for (len, encoded) in [
+            (0, &[0x00][..]),
+            (127, &[0x7F]),
+            (128, &[0x80, 0x01]),
+            (16_383, &[0xFF, 0x7F]),
+            (16_384, &[0x80, 0x80, 0x01]),
+            (MAX_REMAINING_LENGTH, &[0xFF, 0xFF, 0xFF, 0x7F]),
+        ] {
+            let mut buf = [0u8; 4];
+            let n = encode_remaining_length(len, &mut buf).unwrap();
+            assert_eq!(&buf[..n], encoded);
+            assert_eq!(decode_remaining_length(encoded), Ok(Some((len, n))));
+        }
+        assert_eq!(
+            encode_remaining_length(MAX_REMAINING_LENGTH + 1, &mut [0u8; 4]),
+            Err(Error::BufferTooSmall)
+        );
+        // A fifth length byte is not allowed
+        assert_eq!(
+            decode_remaining_length(&[0x80, 0x80, 0x80, 0x80, 0x01]),
+            Err(Error::Malformed)
+        );
+        assert_eq!(decode_remaining_length(&[0x80, 0x80]), Ok(None));
+    }
+
+    #[test]
+    fn connect_skips_credentials_and_will() {
+        let mut packet = [0u8; 64];
+        let body: &[u8] = &[
+            0, 4, b'M', b'Q', b'T', b'T', 4, 0xC6, 0, 30, 0, 2, b'i', b'd', // client id
+            0, 1, b't', // will topic
+            0, 1, b'w', // will message
+            0, 1, b'u', // username
+            0, 1, b'p', // password
+        ];
+        packet[0] = CONNECT << 4;
+        packet[1] = body.len() as u8;
+        packet[2..2 + body.len()].copy_from_slice(body);
+        let len = 2 + body.len();
+        assert_eq!(
+            Packet::decode(&packet[..len]),
+            Ok(Some((
+                Packet::Connect {
+                    client_id: "id",
+                    keep_alive_s: 30,
+                    clean_session: true,
+                },
+                len
+            )))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_packets() {
+        let mut buf = [0u8; 64];
+        // QoS 0 has no packet id, QoS 1 and 2 need one
+        assert_eq!(
+            Packet::Publish {
+                topic: "t",
+                payload: b"",
+                qos: 1,
+                retain: false,
+                packet_id: None,
+            }
+            .encode(&mut buf),
+            Err(Error::Malformed)
+        );
+        assert_eq!(
+            Packet::Publish {
+                topic: "t",
+                payload: b"",
+                qos: 0,
+                retain: false,
+                packet_id: Some(1),
+            }
+            .encode(&mut buf),
+            Err(Error::Malformed)
+        );
+        assert_eq!(
+            Packet::Publish {
+                topic: "icbm/co2",
+                payload: b"50000",
+                qos: 0,
+                retain: false,
+                packet_id: None,
+            }
+            .encode(&mut buf[..8]),
+            Err(Error::BufferTooSmall)
+        );
+
+        // UNSUBSCRIBE is not supported
+        assert_eq!(
+            Packet::decode(&[0xA2, 2, 0, 1]),
+            Err(Error::UnsupportedPacket(10))
+        );
+        // Wrong protocol name
+        assert_eq!(
+            Packet::decode(&[0x10, 10, 0, 4, b'M', b'Q', b'I', b's', 4, 0x02, 0, 60]),
+            Err(Error::Malformed)
+        );
+        // Topic longer than the packet
+        assert_eq!(
+            Packet::decode(&[0x30, 3, 0, 9, b'a']),
+            Err(Error::Malformed)
+        );
+    }
+
+    #[test]
+    fn decodes_back_to_back_packets() {
+        let mut buf = [0u8; 64];
+        let first = Packet::PingResp.encode(&mut buf).unwrap();
+        let second = Packet::SubAck {
+            packet_id: 3,
+            return_code: 0,
+        }
+        .encode(&mut buf[first..])
+        .unwrap();
+        let (packet, used) = Packet::decode(&buf[..first + second]).unwrap().unwrap();
+        assert_eq!((packet, used), (Packet::PingResp, first));
+        assert_eq!(
+            Packet::decode(&buf[used..first + second]),
+            Ok(Some((
+                Packet::SubAck {
+                    packet_id: 3,
+                    return_code: 0
+                },
+                second
+            )))
+        );
+    }
+
+    #[test]
+    fn topic_filters() {
+        assert!(topic_matches("icbm/co2", "icbm/co2"));
+        assert!(!topic_matches("icbm/co2", "icbm/temp"));
+        assert!(topic_matches("icbm/+", "icbm/co2"));
+        assert!(!topic_matches("icbm/+", "icbm/heater/duty"));
+        assert!(topic_matches("icbm/+/duty", "icbm/heater/duty"));
+        assert!(topic_matches("icbm/#", "icbm/heater/duty"));
+        assert!(topic_matches("icbm/#", "icbm"));
+        assert!(topic_matches("#", "anything/at/all"));
+        assert!(!topic_matches("icbm/co2", "icbm/co2/extra"));
+        assert!(!topic_matches("icbm/co2/extra", "icbm/co2"));
+    }
+}
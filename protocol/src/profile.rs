@@ -0,0 +1,464 @@
+// Setpoint profiles, shared by the firmware's profile engine, the host tool and the
+// simulator.
+//
+// A profile holds one track of segments per controlled variable. All tracks run from
+// the same start, each from the configured setpoint of its variable:
+//
+//   hold <min>              keep the current setpoint
+//   ramp <target> <min>     move linearly from the current setpoint to `target`
+//   step <target> <min>     jump to `target`, then keep it
+//
+// Once a track's segments are done its last setpoint stays in effect until the profile
+// is stopped.
+
+pub const MAX_SEGMENTS: usize = 16;
+pub const VARIABLES: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum Variable {
+    Temp = 0,
+    Co2 = 1,
+}
+
+impl Variable {
+    pub const ALL: [Variable; VARIABLES] = [Variable::Temp, Variable::Co2];
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Variable::ALL.get(value as usize).copied()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Variable::Temp => "temp",
+            Variable::Co2 => "co2",
+        }
+    }
+
+    pub fn unit(self) -> &'static str {
+        match self {
+            Variable::Temp => "C",
+            Variable::Co2 => "ppm",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Segment {
+    Hold { duration_s: u32 },
+    Ramp { target: f32, duration_s: u32 },
+    Step { target: f32, duration_s: u32 },
+}
+
+// Wire and flash encoding of the segment kinds
+const HOLD: u8 = 0;
+const RAMP: u8 = 1;
+const STEP: u8 = 2;
+
+impl Segment {
+    pub fn duration_s(&self) -> u32 {
+        match *self {
+            Segment::Hold { duration_s }
+            | Segment::Ramp { duration_s, .. }
+            | Segment::Step { duration_s, .. } => duration_s,
+        }
+    }
+
+    pub fn target(&self) -> Option<f32> {
+        match *self {
+            Segment::Hold { .. } => None,
+            Segment::Ramp { target, .. } | Segment::Step { target, .. } => Some(target),
+        }
+    }
+
+    // Kind, target (0 for holds) and duration, as stored and transferred
+    pub fn to_parts(&self) -> (u8, f32, u32) {
+        let kind = match self {
+            Segment::Hold { .. } => HOLD,
+            Segment::Ramp { .. } => RAMP,
+            Segment::Step { .. } => STEP,
+        };
+        (kind, self.target().unwrap_or(0.0), self.duration_s())
+    }
+
+    pub fn from_parts(kind: u8, target: f32, duration_s: u32) -> Option<Self> {
+        if kind != HOLD && !target.is_finite() {
+            return None;
+        }
+        Some(match kind {
+            HOLD => Segment::Hold { duration_s },
+            RAMP => Segment::Ramp { target, duration_s },
+            STEP => Segment::Step { target, duration_s },
+            _ => return None,
+        })
+    }
+}
+
+// Parses `<temp|co2> hold <min>`, `<temp|co2> ramp <target> <min>` or
+// `<temp|co2> step <target> <min>`, as entered on the console or in a profile file.
+// Minutes may be fractional.
+pub fn parse_segment(line: &str) -> Result<(Variable, Segment), &'static str> {
+    let mut words = line.split_whitespace();
+    let variable = match words.next() {
+        Some("temp") => Variable::Temp,
+        Some("co2") => Variable::Co2,
+        _ => return Err("expected temp or co2"),
+    };
+    let kind = words.next().ok_or("expected hold, ramp or step")?;
+    let mut number = || -> Result<f32, &'static str> {
+        words
+            .next()
+            .and_then(|word| word.parse::<f32>().ok())
+            .filter(|value| value.is_finite())
+            .ok_or("expected a number")
+    };
+    let segment = match kind {
+        "hold" => Segment::Hold {
+            duration_s: seconds(number()?)?,
+        },
+        "ramp" => Segment::Ramp {
+            target: number()?,
+            duration_s: seconds(number()?)?,
+        },
+        "step" => Segment::Step {
+            target: number()?,
+            duration_s: seconds(number()?)?,
+        },
+        _ => return Err("expected hold, ramp or step"),
+    };
+    if words.next().is_some() {
+        return Err("unexpected trailing text");
+    }
+    Ok((variable, segment))
+}
+
+fn seconds(minutes: f32) -> Result<u32, &'static str> {
+    if !(0.0..=u32::MAX as f32 / 60.0).contains(&minutes) {
+        return Err("duration out of range");
+    }
+    Ok(crate::round(minutes * 60.0) as u32)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Track {
+    segments: [Segment; MAX_SEGMENTS],
+    len: u8,
+}
+
+impl Default for Track {
+    fn default() -> Self {
+        Track::EMPTY
+    }
+}
+
+impl Track {
+    pub const EMPTY: Track = Track {
+        segments: [Segment::Hold { duration_s: 0 }; MAX_SEGMENTS],
+        len: 0,
+    };
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments[..self.len as usize]
+    }
+
+    pub fn push(&mut self, segment: Segment) -> Result<(), &'static str> {
+        let slot = self
+            .segments
+            .get_mut(self.len as usize)
+            .ok_or("profile track is full")?;
+        *slot = segment;
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn duration_s(&self) -> u64 {
+        self.segments().iter().map(|s| s.duration_s() as u64).sum()
+    }
+
+    // Setpoint `elapsed_s` into the profile when the track starts from `initial`, and
+    // the index of the segment in effect (the number of segments once done)
+    pub fn setpoint(&self, initial: f32, elapsed_s: u64) -> (f32, usize) {
+        let mut value = initial;
+        let mut start = 0u64;
+        for (i, segment) in self.segments().iter().enumerate() {
+            let end = start + segment.duration_s() as u64;
+            if let Segment::Step { target, .. } = *segment {
+                value = target;
+            }
+            if elapsed_s < end {
+                if let Segment::Ramp { target, duration_s } = *segment {
+                    let progress = (elapsed_s - start) as f32 / duration_s as f32;
+                    value += (target - value) * progress;
+                }
+                return (value, i);
+            }
+            if let Segment::Ramp { target, .. } = *segment {
+                value = target;
+            }
+            start = end;
+        }
+        (value, self.segments().len())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Profile {
+    pub tracks: [Track; VARIABLES],
+}
+
+impl Profile {
+    pub const EMPTY: Profile = Profile {
+        tracks: [Track::EMPTY; VARIABLES],
+    };
+
+    pub fn track(&self, variable: Variable) -> &Track {
+        &self.tracks[variable as usize]
+    }
+
+    pub fn track_mut(&mut self, variable: Variable) -> &mut Track {
+        &mut self.tracks[variable as usize]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.iter().all(|track| track.segments().is_empty())
+    }
+
+    // Until the last track is done
+    pub fn duration_s(&self) -> u64 {
+        self.tracks.iter().map(Track::duration_s).max().unwrap_or(0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum RunState {
+    // Not started; the configured setpoints apply
+    #[default]
+    Idle = 0,
+    // Start scheduled at a wall-clock time
+    Waiting = 1,
+    Running = 2,
+    Paused = 3,
+    // All tracks done, their last setpoints stay in effect
+    Finished = 4,
+}
+
+impl RunState {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => RunState::Idle,
+            1 => RunState::Waiting,
+            2 => RunState::Running,
+            3 => RunState::Paused,
+            4 => RunState::Finished,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RunState::Idle => "idle",
+            RunState::Waiting => "waiting",
+            RunState::Running => "running",
+            RunState::Paused => "paused",
+            RunState::Finished => "finished",
+        }
+    }
+
+    // Whether the profile's setpoints are in effect
+    pub fn is_active(self) -> bool {
+        matches!(
+            self,
+            RunState::Running | RunState::Paused | RunState::Finished
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command {
+    // Now, or at a wall-clock time in Unix seconds
+    Start { at: Option<u32> },
+    Pause,
+    Resume,
+    Stop,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track_of(segments: &[Segment]) -> Track {
+        let mut track = Track::EMPTY;
+        for &segment in segments {
+            track.push(segment).unwrap();
+        }
+        track
+    }
+
+    // From 37: hold 10 min, ramp to 39 over 20 min, step to 36 for 5 min
+    fn temp_track() -> Track {
+        track_of(&[
+            Segment::Hold { duration_s: 600 },
+            Segment::Ramp {
+                target: 39.0,
+                duration_s: 1200,
+            },
+            Segment::Step {
+                target: 36.0,
+                duration_s: 300,
+            },
+        ])
+    }
+
+    #[test]
+    fn hold_keeps_the_initial_setpoint() {
+        let track = temp_track();
+        assert_eq!(track.setpoint(37.0, 0), (37.0, 0));
+        assert_eq!(track.setpoint(37.0, 599), (37.0, 0));
+    }
+
+    #[test]
+    fn ramp_interpolates() {
+        let track = temp_track();
+        // Segment boundary: the ramp starts from the held value
+        assert_eq!(track.setpoint(37.0, 600), (37.0, 1));
+        assert_eq!(track.setpoint(37.0, 900), (37.5, 1));
+        assert_eq!(track.setpoint(37.0, 1200), (38.0, 1));
+        assert_eq!(track.setpoint(37.0, 1799), (39.0 - 2.0 / 1200.0, 1));
+    }
+
+    #[test]
+    fn step_jumps_at_its_start() {
+        let track = temp_track();
+        assert_eq!(track.setpoint(37.0, 1800), (36.0, 2));
+        assert_eq!(track.setpoint(37.0, 2099), (36.0, 2));
+    }
+
+    #[test]
+    fn last_setpoint_stays_after_the_end() {
+        let track = temp_track();
+        assert_eq!(track.duration_s(), 2100);
+        assert_eq!(track.setpoint(37.0, 2100), (36.0, 3));
+        assert_eq!(track.setpoint(37.0, u64::MAX), (36.0, 3));
+
+        // A ramp as the last segment ends on its target
+        let ramp = track_of(&[Segment::Ramp {
+            target: 60_000.0,
+            duration_s: 60,
+        }]);
+        assert_eq!(ramp.setpoint(50_000.0, 60), (60_000.0, 1));
+        assert_eq!(Track::EMPTY.setpoint(37.0, 10), (37.0, 0));
+    }
+
+    #[test]
+    fn zero_length_segments_are_passed_through() {
+        let track = track_of(&[
+            Segment::Ramp {
+                target: 40.0,
+                duration_s: 0,
+            },
+            Segment::Hold { duration_s: 60 },
+        ]);
+        assert_eq!(track.setpoint(37.0, 0), (40.0, 1));
+    }
+
+    #[test]
+    fn full_track() {
+        let mut track = Track::EMPTY;
+        for _ in 0..MAX_SEGMENTS {
+            track.push(Segment::Hold { duration_s: 1 }).unwrap();
+        }
+        assert!(track.push(Segment::Hold { duration_s: 1 }).is_err());
+    }
+
+    #[test]
+    fn parses_segments() {
+        assert_eq!(
+            parse_segment("temp hold 10"),
+            Ok((Variable::Temp, Segment::Hold { duration_s: 600 }))
+        );
+        assert_eq!(
+            parse_segment("  co2  ramp 60000 1.5 "),
+            Ok((
+                Variable::Co2,
+                Segment::Ramp {
+                    target: 60_000.0,
+                    duration_s: 90,
+                }
+            ))
+        );
+        assert_eq!(
+            parse_segment("temp step 38.5 0"),
+            Ok((
+                Variable::Temp,
+                Segment::Step {
+                    target: 38.5,
+                    duration_s: 0,
+                }
+            ))
+        );
+        // Rounded to whole seconds
+        assert_eq!(
+            parse_segment("temp hold 0.01"),
+            Ok((Variable::Temp, Segment::Hold { duration_s: 1 }))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_segments() {
+        assert_eq!(parse_segment(""), Err("expected temp or co2"));
+        assert_eq!(parse_segment("rh hold 10"), Err("expected temp or co2"));
+        assert_eq!(parse_segment("temp"), Err("expected hold, ramp or step"));
+        assert_eq!(
+            parse_segment("temp jump 38 10"),
+            Err("expected hold, ramp or step")
+        );
+        assert_eq!(parse_segment("temp ramp 38"), Err("expected a number"));
+        assert_eq!(parse_segment("temp step x 10"), Err("expected a number"));
+        assert_eq!(parse_segment("temp ramp NaN 10"), Err("expected a number"));
+        assert_eq!(parse_segment("temp hold inf"), Err("expected a number"));
+        assert_eq!(parse_segment("temp hold -1"), Err("duration out of range"));
+        assert_eq!(
+            parse_segment("temp hold 1e12"),
+            Err("duration out of range")
+        );
+        assert_eq!(
+            parse_segment("temp hold 10 20"),
+            Err("unexpected trailing text")
+        );
+    }
+
+    #[test]
+    fn seconds_from_minutes() {
+        assert_eq!(seconds(0.0), Ok(0));
+        assert_eq!(seconds(2.5), Ok(150));
+        assert_eq!(seconds(-0.1), Err("duration out of range"));
+        assert_eq!(seconds(f32::NAN), Err("duration out of range"));
+    }
+
+    #[test]
+    fn segment_parts_round_trip() {
+        for segment in [
+            Segment::Hold { duration_s: 60 },
+            Segment::Ramp {
+                target: 38.0,
+                duration_s: 120,
+            },
+            Segment::Step {
+                target: 50_000.0,
+                duration_s: 0,
+            },
+        ] {
+            let (kind, target, duration_s) = segment.to_parts();
+            assert_eq!(Segment::from_parts(kind, target, duration_s), Some(segment));
+        }
+        assert_eq!(Segment::from_parts(3, 0.0, 60), None);
+        assert_eq!(Segment::from_parts(RAMP, f32::NAN, 60), None);
+    }
+}
@@ -0,0 +1,410 @@
+// SNTPv4 (RFC 4330) packets for the firmware's clock sync and the host-side NTP
+// stand-in. Only the fixed 48-byte header is used; extension fields and authentication
+// are neither sent nor checked.
+//
+// Timestamps are converted to and from microseconds since the Unix epoch. NTP seconds
+// wrap in 2036; following RFC 4330, values with the top bit clear are taken to be in the
+// next era, which covers 1968 to 2104.
+
+pub const PORT: u16 = 123;
+pub const PACKET_SIZE: usize = 48;
+pub const VERSION: u8 = 4;
+
+pub const MODE_CLIENT: u8 = 3;
+pub const MODE_SERVER: u8 = 4;
+pub const LEAP_UNSYNCHRONIZED: u8 = 3;
+// Stratum 0 in a server reply is a "kiss-o'-death" asking the client to back off
+pub const STRATUM_KISS: u8 = 0;
+pub const MAX_STRATUM: u8 = 15;
+
+// Seconds from the NTP epoch (1900-01-01) to the Unix epoch
+const UNIX_OFFSET_S: u64 = 2_208_988_800;
+const ERA_S: u64 = 1 << 32;
+const MICROS: u64 = 1_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    Truncated,
+    // Not a server reply (mode) or from an unsupported version
+    NotServer,
+    // Kiss-o'-death with its code, e.g. "RATE" or "DENY"
+    Kiss([u8; 4]),
+    // The server's own clock is not synchronized
+    Unsynchronized,
+    // Does not answer our request: the originate timestamp differs from what we sent
+    Mismatch,
+    // Zero transmit timestamp or a stratum out of range
+    Invalid,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Timestamp {
+    pub seconds: u32,
+    pub fraction: u32,
+}
+
+impl Timestamp {
+    pub const ZERO: Timestamp = Timestamp {
+        seconds: 0,
+        fraction: 0,
+    };
+
+    pub fn from_unix_us(unix_us: u64) -> Self {
+        let seconds = (unix_us / MICROS + UNIX_OFFSET_S) % ERA_S;
+        let fraction = ((unix_us % MICROS) << 32) / MICROS;
+        Timestamp {
+            seconds: seconds as u32,
+            fraction: fraction as u32,
+        }
+    }
+
+    pub fn to_unix_us(&self) -> u64 {
+        let mut seconds = self.seconds as u64;
+        if seconds & 0x8000_0000 == 0 {
+            seconds += ERA_S;
+        }
+        let micros = (self.fraction as u64 * MICROS) >> 32;
+        seconds.saturating_sub(UNIX_OFFSET_S) * MICROS + micros
+    }
+
+    fn encode(&self, out: &mut [u8]) {
+        out[..4].copy_from_slice(&self.seconds.to_be_bytes());
+        out[4..8].copy_from_slice(&self.fraction.to_be_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        Timestamp {
+            seconds: u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            fraction: u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Packet {
+    pub leap: u8,
+    pub version: u8,
+    pub mode: u8,
+    pub stratum: u8,
+    pub poll: i8,
+    pub precision: i8,
+    // 16.16 fixed point seconds
+    pub root_delay: u32,
+    pub root_dispersion: u32,
+    // ASCII source for stratum 1, kiss code for stratum 0, the upstream address otherwise
+    pub reference_id: [u8; 4],
+    pub reference: Timestamp,
+    pub originate: Timestamp,
+    pub receive: Timestamp,
+    pub transmit: Timestamp,
+}
+
+impl Packet {
+    // Client request; the server echoes `transmit` as the originate timestamp
+    pub fn request(transmit: Timestamp) -> Self {
+        Packet {
+            version: VERSION,
+            mode: MODE_CLIENT,
+            transmit,
+            ..Packet::default()
+        }
+    }
+
+    pub fn encode(&self) -> [u8; PACKET_SIZE] {
+        let mut out = [0u8; PACKET_SIZE];
+        out[0] = (self.leap & 0x03) << 6 | (self.version & 0x07) << 3 | (self.mode & 0x07);
+        out[1] = self.stratum;
+        out[2] = self.poll as u8;
+        out[3] = self.precision as u8;
+        out[4..8].copy_from_slice(&self.root_delay.to_be_bytes());
+        out[8..12].copy_from_slice(&self.root_dispersion.to_be_bytes());
+        out[12..16].copy_from_slice(&self.reference_id);
+        self.reference.encode(&mut out[16..24]);
+        self.originate.encode(&mut out[24..32]);
+        self.receive.encode(&mut out[32..40]);
+        self.transmit.encode(&mut out[40..48]);
+        out
+    }
+
+    // Extension fields and MACs after the header are ignored
+    pub fn decode(buf: &[u8]) -> Result<Self, Error> {
+        let buf = buf.get(..PACKET_SIZE).ok_or(Error::Truncated)?;
+        let word = |i: usize| u32::from_be_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        Ok(Packet {
+            leap: buf[0] >> 6,
+            version: (buf[0] >> 3) & 0x07,
+            mode: buf[0] & 0x07,
+            stratum: buf[1],
+            poll: buf[2] as i8,
+            precision: buf[3] as i8,
+            root_delay: word(4),
+            root_dispersion: word(8),
+            reference_id: [buf[12], buf[13], buf[14], buf[15]],
+            reference: Timestamp::decode(&buf[16..24]),
+            originate: Timestamp::decode(&buf[24..32]),
+            receive: Timestamp::decode(&buf[32..40]),
+            transmit: Timestamp::decode(&buf[40..48]),
+        })
+    }
+
+    // The sanity checks RFC 4330 asks of a client before using a reply to the request
+    // sent with `transmit`
+    pub fn check_reply(&self, transmit: Timestamp) -> Result<(), Error> {
+        if self.mode != MODE_SERVER || !(1..=VERSION).contains(&self.version) {
+            return Err(Error::NotServer);
+        }
+        if self.originate != transmit {
+            return Err(Error::Mismatch);
+        }
+        if self.stratum == STRATUM_KISS {
+            return Err(Error::Kiss(self.reference_id));
+        }
+        if self.leap == LEAP_UNSYNCHRONIZED {
+            return Err(Error::Unsynchronized);
+        }
+        if self.stratum > MAX_STRATUM || self.transmit == Timestamp::ZERO {
+            return Err(Error::Invalid);
+        }
+        Ok(())
+    }
+}
+
+// Offset of the server clock from the local one and the round-trip delay, both in
+// microseconds, from the request's send time `t1`, the server's receive and transmit
+// times `t2` and `t3` and the reply's arrival time `t4`
+pub fn offset_delay(t1: u64, t2: u64, t3: u64, t4: u64) -> (i64, i64) {
+    let [t1, t2, t3, t4] = [t1, t2, t3, t4].map(|t| t as i64);
+    let offset = ((t2 - t1) + (t3 - t4)) / 2;
+    let delay = (t4 - t1) - (t3 - t2);
+    (offset, delay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SENT: Timestamp = Timestamp {
+        seconds: 0xE9F0_0000,
+        fraction: 0x1234_5678,
+    };
+
+    fn reply() -> Packet {
+        Packet {
+            version: VERSION,
+            mode: MODE_SERVER,
+            stratum: 1,
+            reference_id: *b"GPS\0",
+            originate: SENT,
+            receive: Timestamp::from_unix_us(1_717_243_200_100_000),
+            transmit: Timestamp::from_unix_us(1_717_243_200_100_050),
+            ..Packet::default()
+        }
+    }
+
+    #[test]
+    fn unix_epoch() {
+        assert_eq!(
+            Timestamp::from_unix_us(0),
+            Timestamp {
+                seconds: 2_208_988_800,
+                fraction: 0,
+            }
+        );
+        assert_eq!(
+            Timestamp {
+                seconds: 2_208_988_800,
+                fraction: 0,
+            }
+            .to_unix_us(),
+            0
+        );
+    }
+
+    #[test]
+    fn fractions() {
+        let half = Timestamp::from_unix_us(500_000);
+        assert_eq!(half.fraction, 0x8000_0000);
+        assert_eq!(half.to_unix_us(), 500_000);
+        // 2^32 / 10^6 units per microsecond, truncated
+        assert_eq!(Timestamp::from_unix_us(1).fraction, 4294);
+        assert_eq!(Timestamp::from_unix_us(999_999).fraction, 0xFFFF_EF39);
+    }
+
+    #[test]
+    fn era_rollover() {
+        // 2036-02-07 06:28:16 UTC starts NTP era 1
+        let rollover_s = ERA_S - UNIX_OFFSET_S;
+        assert_eq!(
+            Timestamp::from_unix_us(rollover_s * MICROS),
+            Timestamp::ZERO
+        );
+        assert_eq!(Timestamp::ZERO.to_unix_us(), rollover_s * MICROS);
+        assert_eq!(
+            Timestamp::from_unix_us((rollover_s - 1) * MICROS).seconds,
+            u32::MAX
+        );
+
+        // Top bit clear: the next era, up to 2104
+        let last = Timestamp {
+            seconds: 0x7FFF_FFFF,
+            fraction: 0,
+        };
+        assert_eq!(
+            last.to_unix_us(),
+            (ERA_S + 0x7FFF_FFFF - UNIX_OFFSET_S) * MICROS
+        );
+        // Top bit set: this era, from 1968; before the Unix epoch saturates to it
+        let first = Timestamp {
+            seconds: 0x8000_0000,
+            fraction: 0,
+        };
+        assert_eq!(first.to_unix_us(), 0);
+    }
+
+    #[test]
+    fn timestamps_round_trip() {
+        // 2024, just before the 2036 era rollover and within the next era
+        for unix_us in [
+            1_717_243_200_123_456,
+            2_085_978_495_999_999,
+            2_100_000_000_500_000,
+        ] {
+            let back = Timestamp::from_unix_us(unix_us).to_unix_us();
+            assert!(
+                unix_us.abs_diff(back) <= 1,
+                "{} came back as {}",
+                unix_us,
+                back
+            );
+        }
+    }
+
+    #[test]
+    fn packet_round_trip() {
+        let packet = Packet {
+            leap: 1,
+            poll: 6,
+            precision: -18,
+            root_delay: 0x0001_8000,
+            root_dispersion: 0x0000_0100,
+            reference_id: [192, 168, 1, 1],
+            reference: Timestamp::from_unix_us(1_717_243_100_000_000),
+            ..reply()
+        };
+        let encoded = packet.encode();
+        assert_eq!(encoded[0], 0x64, "LI 1, VN 4, mode 4");
+        assert_eq!(Packet::decode(&encoded), Ok(packet));
+
+        // Extension fields after the header are ignored
+        let mut extended = [0xAAu8; PACKET_SIZE + 20];
+        extended[..PACKET_SIZE].copy_from_slice(&encoded);
+        assert_eq!(Packet::decode(&extended), Ok(packet));
+
+        assert_eq!(
+            Packet::decode(&encoded[..PACKET_SIZE - 1]),
+            Err(Error::Truncated)
+        );
+    }
+
+    #[test]
+    fn request() {
+        let encoded = Packet::request(SENT).encode();
+        assert_eq!(encoded[0], 0x23, "LI 0, VN 4, mode 3");
+        assert!(encoded[1..40].iter().all(|&b| b == 0));
+        assert_eq!(Packet::decode(&encoded).unwrap().transmit, SENT);
+    }
+
+    #[test]
+    fn accepts_a_valid_reply() {
+        assert_eq!(reply().check_reply(SENT), Ok(()));
+        // Older versions answer too
+        let v3 = Packet {
+            version: 3,
+            ..reply()
+        };
+        assert_eq!(v3.check_reply(SENT), Ok(()));
+    }
+
+    #[test]
+    fn rejects_wrong_mode_or_version() {
+        for packet in [
+            Packet {
+                mode: MODE_CLIENT,
+                ..reply()
+            },
+            // Broadcast
+            Packet { mode: 5, ..reply() },
+            Packet {
+                version: 0,
+                ..reply()
+            },
+            Packet {
+                version: VERSION + 1,
+                ..reply()
+            },
+        ] {
+            assert_eq!(packet.check_reply(SENT), Err(Error::NotServer));
+        }
+    }
+
+    #[test]
+    fn rejects_other_requests() {
+        let other = Timestamp {
+            fraction: SENT.fraction + 1,
+            ..SENT
+        };
+        assert_eq!(reply().check_reply(other), Err(Error::Mismatch));
+    }
+
+    #[test]
+    fn kiss_of_death() {
+        let kiss = Packet {
+            stratum: STRATUM_KISS,
+            reference_id: *b"RATE",
+            ..reply()
+        };
+        assert_eq!(kiss.check_reply(SENT), Err(Error::Kiss(*b"RATE")));
+        // Even if it claims to be unsynchronized, as kisses usually do
+        let kiss = Packet {
+            leap: LEAP_UNSYNCHRONIZED,
+            transmit: Timestamp::ZERO,
+            ..kiss
+        };
+        assert_eq!(kiss.check_reply(SENT), Err(Error::Kiss(*b"RATE")));
+    }
+
+    #[test]
+    fn rejects_unusable_replies() {
+        let unsynchronized = Packet {
+            leap: LEAP_UNSYNCHRONIZED,
+            ..reply()
+        };
+        assert_eq!(unsynchronized.check_reply(SENT), Err(Error::Unsynchronized));
+        let stratum = Packet {
+            stratum: MAX_STRATUM + 1,
+            ..reply()
+        };
+        assert_eq!(stratum.check_reply(SENT), Err(Error::Invalid));
+        let no_time = Packet {
+            transmit: Timestamp::ZERO,
+            ..reply()
+        };
+        assert_eq!(no_time.check_reply(SENT), Err(Error::Invalid));
+    }
+
+    #[test]
+    fn offset_and_delay() {
+        // Server 1 s ahead, 20 ms each way, 5 ms in the server
+        let (offset, delay) = offset_delay(10_000_000, 11_020_000, 11_025_000, 10_045_000);
+        assert_eq!(offset, 1_000_000);
+        assert_eq!(delay, 40_000);
+
+        // Server behind
+        let (offset, delay) = offset_delay(10_000_000, 7_500_010, 7_500_010, 10_000_020);
+        assert_eq!(offset, -2_500_000);
+        assert_eq!(delay, 20);
+    }
+}
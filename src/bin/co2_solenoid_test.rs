@@ -35,7 +35,7 @@ async fn main(_spawner: Spawner) {
     }
 
     info!("Continuous start");
-    co2_solenoid.start_continuous();
+    co2_solenoid.start_continuous().await;
 
     match co2_solenoid.state() {
         Co2State::Continuous => info!("Continuous: Active"),
@@ -3,12 +3,19 @@
 
 use defmt::*;
 use embassy_executor::Spawner;
-use embassy_stm32::gpio::OutputType;
+use embassy_stm32::adc::Adc;
+use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::gpio::{Level, Output, OutputType, Pull, Speed};
 use embassy_stm32::timer::simple_pwm::PwmPin;
 use embassy_time::Timer;
-use icbm_firmware::drivers::drv8873::{PeltierController, ThermalState};
+use icbm_firmware::drivers::drv8873::{
+    BridgeMonitor, PeltierConfig, PeltierController, ThermalState,
+};
 use {defmt_rtt as _, panic_probe as _};
 
+// IPROPI reading of an undriven bridge, the driver's open-load threshold
+const STOPPED_MAX_A: f32 = 0.05;
+
 #[embassy_executor::main]
 async fn main(_spawner: Spawner) {
     let p = embassy_stm32::init(Default::default());
@@ -16,7 +23,20 @@ async fn main(_spawner: Spawner) {
     let ch2 = PwmPin::new_ch2(p.PA9, OutputType::PushPull);
     let ch3 = PwmPin::new_ch3(p.PA10, OutputType::PushPull);
 
-    let mut controller = PeltierController::new(p.TIM1, Some(ch2), Some(ch3));
+    let disable = Output::new(p.PB9, Level::Low, Speed::Low);
+
+    let mut controller = PeltierController::new(
+        p.TIM1,
+        Some(ch2),
+        Some(ch3),
+        disable,
+        PeltierConfig::default(),
+    );
+    let mut monitor = BridgeMonitor::new(
+        ExtiInput::new(p.PB8, p.EXTI8, Pull::Up),
+        Adc::new(p.ADC2),
+        p.PA6,
+    );
 
     // Basic sanity tests
     match controller.state() {
@@ -24,31 +44,60 @@ async fn main(_spawner: Spawner) {
         _ => error!("Init: Not in Off state"),
     }
 
-    info!("Starting heating test cycles");
-    for i in 1..=5 {
-        info!("Heat cycle {}/5", i);
-        controller.heat().await;
-        match controller.state() {
-            ThermalState::Off => info!("Heat cycle completed"),
-            _ => error!("Unexpected state after heating"),
+    // Heat, reverse through the dead time to cooling, then back to off; each step
+    // ramps at the default rate
+    for (output, expected) in [
+        (50.0, ThermalState::Heating),
+        (100.0, ThermalState::Heating),
+        (-50.0, ThermalState::Cooling),
+        (-100.0, ThermalState::Cooling),
+        (0.0, ThermalState::Off),
+    ] {
+        info!("Ramping to {} %", output);
+        controller.set_output(output).await;
+        if controller.state() == expected && controller.output_pct() == output {
+            info!("Reached {} %, {}", output, expected);
+        } else {
+            error!(
+                "Unexpected state {} at {} %",
+                controller.state(),
+                controller.output_pct()
+            );
+        }
+        info!("Holding for 5 seconds...");
+        Timer::after_secs(5).await;
+        match monitor.check(controller.output_pct()) {
+            (current, None) => info!("Bridge current {} A", current),
+            (current, Some(fault)) => {
+                error!("Bridge fault at {} A: {}", current, fault);
+                controller.trip(fault);
+                break;
+            }
         }
-        info!("Waiting 2 seconds...");
-        Timer::after_secs(2).await;
     }
 
-    info!("Starting cooling test cycles");
-    for i in 1..=5 {
-        info!("Cool cycle {}/5", i);
-        controller.cool().await;
-        match controller.state() {
-            ThermalState::Off => info!("Cool cycle completed"),
-            _ => error!("Unexpected state after cooling"),
-        }
-        info!("Waiting 2 seconds...");
+    // stop() cuts a driven output at once, without ramping down; skipped after a fault,
+    // which keeps the bridge disabled
+    if controller.fault().is_none() {
+        info!("Driving 30 % before stopping");
+        controller.set_output(30.0).await;
         Timer::after_secs(2).await;
+        match monitor.check(controller.output_pct()) {
+            (current, None) => info!("Bridge current {} A at 30 %", current),
+            (current, Some(fault)) => {
+                error!("Bridge fault at {} A: {}", current, fault);
+                controller.trip(fault);
+            }
+        }
+        controller.stop();
+        Timer::after_millis(100).await;
+        let current = monitor.current_a();
+        if current < STOPPED_MAX_A {
+            info!("Bridge current {} A after stop", current);
+        } else {
+            error!("Bridge still drawing {} A after stop", current);
+        }
     }
-
-    controller.stop();
     match controller.state() {
         ThermalState::Off => info!("Final state: Off (Success)"),
         _ => error!("Final state: Not Off (Failed)"),
@@ -3,21 +3,41 @@
 
 use defmt::*;
 use embassy_executor::Spawner;
-use embassy_stm32::adc::Adc;
+use embassy_stm32::adc::{Adc, AdcChannel};
 use embassy_time::Timer;
-use icbm_firmware::drivers::thermistor::Thermistor;
+use icbm_firmware::drivers::thermistor::{Probe, ThermistorBank, DMA_BUFFER_LEN};
 use {defmt_rtt as _, panic_probe as _};
 
 #[embassy_executor::main]
 async fn main(_spawner: Spawner) {
     let p = embassy_stm32::init(Default::default());
-    let adc = Adc::new(p.ADC1);
-    let adc_pin = p.PA7;
-    let mut thermistor = Thermistor::new(adc_pin, adc);
+    let mut dma_buf = [0u16; DMA_BUFFER_LEN];
+    let mut bank = ThermistorBank::new(
+        Adc::new(p.ADC1),
+        [
+            (Probe::ChamberAir, p.PA7.degrade_adc()),
+            (Probe::Plate, p.PC0.degrade_adc()),
+            (Probe::Heatsink, p.PC1.degrade_adc()),
+            (Probe::WaterJacket, p.PC2.degrade_adc()),
+        ],
+        p.DMA2_CH4,
+        &mut dma_buf,
+    );
 
     loop {
-        let temp = thermistor.get_temperature_celsius();
-        info!("temp: {}", temp);
+        match bank.read().await {
+            Ok(reading) => {
+                info!("VDDA: {} V", bank.supply_v());
+                // Resistance too, for taking the reference points of fit_steinhart_hart
+                for (probe, result) in Probe::ALL.into_iter().zip(reading) {
+                    match result {
+                        Ok(r) => info!("{}: {} C, {} ohms", probe.name(), r.temp_c, r.ohms),
+                        Err(e) => warn!("{}: {}", probe.name(), e),
+                    }
+                }
+            }
+            Err(e) => error!("Thermistor bank: {}", e),
+        }
         Timer::after_secs(10).await;
     }
 }
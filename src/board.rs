@@ -0,0 +1,32 @@
+use embassy_stm32::rcc::{
+    mux, AHBPrescaler, APBPrescaler, Hse, HseMode, LsConfig, Pll, PllMul, PllPDiv, PllPreDiv,
+    PllQDiv, PllSource, Sysclk,
+};
+use embassy_stm32::time::Hertz;
+use embassy_stm32::Config;
+
+// Clock tree for the 8 MHz HSE crystal: 168 MHz SYSCLK and the 48 MHz clock required
+// by the USB OTG FS peripheral. The RTC runs from the 32.768 kHz LSE crystal so it keeps
+// time across resets (and power loss with a VBAT cell).
+pub fn config() -> Config {
+    let mut config = Config::default();
+    config.rcc.hse = Some(Hse {
+        freq: Hertz(8_000_000),
+        mode: HseMode::Oscillator,
+    });
+    config.rcc.pll_src = PllSource::HSE;
+    config.rcc.pll = Some(Pll {
+        prediv: PllPreDiv::DIV4,
+        mul: PllMul::MUL168,
+        divp: Some(PllPDiv::DIV2), // 8 MHz / 4 * 168 / 2 = 168 MHz
+        divq: Some(PllQDiv::DIV7), // 8 MHz / 4 * 168 / 7 = 48 MHz
+        divr: None,
+    });
+    config.rcc.ahb_pre = AHBPrescaler::DIV1;
+    config.rcc.apb1_pre = APBPrescaler::DIV4;
+    config.rcc.apb2_pre = APBPrescaler::DIV2;
+    config.rcc.sys = Sysclk::PLL1_P;
+    config.rcc.mux.clk48sel = mux::Clk48sel::PLL1_Q;
+    config.rcc.ls = LsConfig::default_lse();
+    config
+}
@@ -0,0 +1,180 @@
+use defmt::{error, info};
+use embassy_time::{Instant, Timer};
+
+use crate::drivers::explorir_m_e_100::ExplorIrME100;
+use crate::drivers::scd41::{SensorSettings, SCD41};
+use crate::rtc;
+use crate::storage::calibration_log::{
+    scd41_serial_from_raw, CalibrationRecord, EXPLORIR_SERIAL_LENGTH,
+};
+use crate::storage::config::Config;
+
+// Longest single wait between two `pet` calls, kept well below the 30 s watchdog timeout
+const MAX_WAIT_SECS: u64 = 10;
+
+// Sleeps for `secs` seconds, calling `pet` at least every MAX_WAIT_SECS
+async fn wait_secs(secs: u64, pet: &mut impl FnMut()) {
+    let mut remaining = secs;
+    while remaining > 0 {
+        let step = remaining.min(MAX_WAIT_SECS);
+        Timer::after_secs(step).await;
+        pet();
+        remaining -= step;
+    }
+}
+
+// Calibrates the SCD41 temperature offset and the ExplorIR-M-E-100 CO2 reading against the
+// reference values in `config`. Both sensors must already be initialised and measuring.
+//
+// `pet` is called regularly so the procedure (~5 minutes) can run with the watchdog enabled.
+// The returned record describes what was applied and is meant to be stored in the
+// calibration log.
+pub async fn run(
+    scd41sensor: &mut SCD41<'_>,
+    co2_sensor: &mut ExplorIrME100<'_>,
+    config: &Config,
+    mut pet: impl FnMut(),
+) -> CalibrationRecord {
+    let mut record = CalibrationRecord {
+        reference_temp_c: config.cal_reference_temp_c,
+        pressure_pa: config.cal_pressure_pa,
+        altitude_m: config.cal_altitude_m,
+        co2_pressure_mbar: config.cal_co2_pressure_mbar,
+        reference_co2_ppm: config.cal_co2_reference_ppm,
+        ..Default::default()
+    };
+
+    info!("Taking initial SCD41 measurements");
+    let temp = match scd41sensor.read_measurement().await {
+        Ok((_, temp, humidity)) => {
+            info!(
+                "Initial readings - Temperature: {}°C, Humidity: {}%",
+                temp, humidity
+            );
+            record.temp_before_c = Some(temp);
+            temp
+        }
+        Err(e) => {
+            error!(
+                "SCD41 measurement error: {}, defaulting to fallback values",
+                e
+            );
+            config.cal_reference_temp_c
+        }
+    };
+    pet();
+
+    info!("Applying SCD41 calibration");
+    match scd41sensor
+        .init(Some(SensorSettings::Custom {
+            current_temp: temp,
+            reference_temp: config.cal_reference_temp_c,
+            pressure: config.cal_pressure_pa,
+            altitude: config.cal_altitude_m,
+        }))
+        .await
+    {
+        Ok(()) => info!("SCD41 environmental calibration successful"),
+        Err(e) => error!("SCD41 calibration error: {}", e),
+    }
+    pet();
+
+    match scd41sensor.stop_periodic_measurement().await {
+        Ok(()) => info!("Stopped periodic measurement"),
+        Err(e) => error!("Failed to stop periodic measurement: {}", e),
+    }
+
+    match scd41sensor.get_serial_number().await {
+        Ok(raw) => {
+            record.scd41_serial = scd41_serial_from_raw(&raw);
+            info!("SCD41 serial: {:#x}", record.scd41_serial);
+        }
+        Err(e) => error!("Failed to read SCD41 serial number: {}", e),
+    }
+
+    match scd41sensor.get_temp_offset().await {
+        Ok(offset) => record.temp_offset_c = Some(offset),
+        Err(e) => error!("Failed to read temperature offset: {}", e),
+    }
+
+    match scd41sensor.persist().await {
+        Ok(()) => info!("Settings persisted successfully"),
+        Err(e) => error!("Failed to persist settings: {}", e),
+    }
+
+    match scd41sensor.start_periodic_measurement().await {
+        Ok(()) => info!("Started periodic measurement"),
+        Err(e) => error!("Failed to start periodic measurement: {}", e),
+    }
+
+    wait_secs(60, &mut pet).await;
+    info!("Taking post-calibration SCD41 measurements");
+    match scd41sensor.read_measurement().await {
+        Ok((_, temp, humidity)) => {
+            info!(
+                "Calibrated readings - Temperature: {}°C, Humidity: {}%",
+                temp, humidity
+            );
+            record.temp_after_c = Some(temp);
+        }
+        Err(e) => error!("SCD41 measurement error: {}", e),
+    }
+    pet();
+
+    match co2_sensor.read_serial_no().await {
+        Ok(serial) => {
+            // Serial numbers are ASCII, so truncating at a byte index is safe
+            let serial = serial.trim();
+            let len = serial.len().min(EXPLORIR_SERIAL_LENGTH);
+            let _ = record.explorir_serial.push_str(&serial[..len]);
+            info!("ExplorIR serial: {}", record.explorir_serial);
+        }
+        Err(e) => error!("Failed to read ExplorIR serial number: {}", e),
+    }
+
+    info!("Setting CO2 sensor pressure compensation");
+
+    //In millibars (mBar), range 300-1100
+    match co2_sensor
+        .set_pressure_and_concentration(config.cal_co2_pressure_mbar)
+        .await
+    {
+        Ok(()) => info!("CO2 sensor pressure compensation set"),
+        Err(e) => error!("CO2 sensor pressure compensation error: {}", e),
+    }
+
+    wait_secs(60, &mut pet).await;
+
+    match co2_sensor.get_filtered_co2().await {
+        Ok(ppm) => {
+            info!("Initial CO2 reading: {} ppm", ppm);
+            record.co2_before_ppm = Some(ppm);
+
+            wait_secs(10, &mut pet).await;
+
+            info!("Starting CO2 sensor fine tuning");
+            // Target/reference Co2 for the incubator
+            match co2_sensor
+                .fine_tune(config.cal_co2_reference_ppm, ppm as u32)
+                .await
+            {
+                Ok(()) => info!("CO2 sensor fine tuning successful"),
+                Err(error_msg) => error!("CO2 sensor fine tuning failed: {}", error_msg),
+            }
+        }
+        Err(e) => error!("CO2 sensor error, skipping fine tuning: {}", e),
+    }
+
+    wait_secs(30, &mut pet).await;
+    match co2_sensor.get_filtered_co2().await {
+        Ok(ppm) => {
+            info!("Final calibrated CO2 reading: {} ppm", ppm);
+            record.co2_after_ppm = Some(ppm);
+        }
+        Err(e) => error!("CO2 sensor error: {}", e),
+    }
+
+    record.timestamp_s = Instant::now().as_secs();
+    record.datetime = rtc::now();
+    record
+}
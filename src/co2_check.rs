@@ -0,0 +1,76 @@
+use defmt::{info, warn};
+use libm::fabsf;
+
+use crate::storage::config::Config;
+
+// Plausibility check of the ExplorIR, the CO2 control sensor, against the SCD41.
+//
+// The SCD41 only measures up to 40 000 ppm, below the usual 5 % setpoint, but covers the
+// chamber at low concentrations: after the door has been open, during a purge, or once
+// the CO2 supply has run out. While it is in range the two should agree within their
+// combined accuracy; a lasting difference means one of them has drifted or failed and
+// raises a sensor-health warning (alarm::CO2_SENSOR_MISMATCH). The sensors respond at
+// different speeds, so right after a dose they briefly disagree; the warning therefore
+// needs several cycles in a row, and as many to clear.
+
+// The SCD41 saturates here; readings at the limit say nothing about the ExplorIR
+const SCD41_MAX_PPM: f32 = 40_000.0;
+// Differences below this never count, whatever the relative threshold: the sensors'
+// combined offset error at low concentrations
+const MIN_DIVERGENCE_PPM: f32 = 200.0;
+const CYCLES_TO_CHANGE: u8 = 3;
+
+pub struct Co2CrossCheck {
+    mismatch: bool,
+    // Consecutive cycles disagreeing with `mismatch`
+    cycles: u8,
+}
+
+impl Co2CrossCheck {
+    pub const fn new() -> Self {
+        Co2CrossCheck {
+            mismatch: false,
+            cycles: 0,
+        }
+    }
+
+    // Whether the warning is raised after this cycle. It holds while the sensors cannot
+    // be compared, i.e. without an SCD41 reading in range.
+    pub fn update(&mut self, config: &Config, explorir_ppm: f32, scd41_ppm: Option<f32>) -> bool {
+        if config.co2_crosscheck_pct <= 0.0 {
+            self.mismatch = false;
+            self.cycles = 0;
+            return false;
+        }
+        let Some(scd41_ppm) = scd41_ppm.filter(|&ppm| ppm > 0.0 && ppm < SCD41_MAX_PPM) else {
+            return self.mismatch;
+        };
+
+        let divergence = fabsf(explorir_ppm - scd41_ppm);
+        let threshold = (explorir_ppm.max(scd41_ppm) * config.co2_crosscheck_pct / 100.0)
+            .max(MIN_DIVERGENCE_PPM);
+        let diverging = divergence > threshold;
+        if diverging {
+            warn!(
+                "CO2 sensors disagree: ExplorIR {} ppm, SCD41 {} ppm",
+                explorir_ppm, scd41_ppm
+            );
+        }
+
+        self.cycles = if diverging == self.mismatch {
+            0
+        } else {
+            self.cycles + 1
+        };
+        if self.cycles >= CYCLES_TO_CHANGE {
+            self.mismatch = diverging;
+            self.cycles = 0;
+            if diverging {
+                warn!("CO2 sensor mismatch raised");
+            } else {
+                info!("CO2 sensors agree again");
+            }
+        }
+        self.mismatch
+    }
+}
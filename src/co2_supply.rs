@@ -0,0 +1,103 @@
+use defmt::{error, info, warn};
+
+use crate::door::DoorState;
+
+// Detection of an empty CO2 cylinder.
+//
+// With the supply exhausted the valve still opens on every burst, but the CO2 no longer
+// rises. Each burst is judged by the reading one control cycle later: the rise per
+// millisecond of burst is learned from the bursts that work, and a burst raising the CO2
+// by less than a fraction of what was learned (or than MIN_RISE_PPM, before anything was
+// learned) is a miss. Several misses in a row raise alarm::CO2_SUPPLY_EMPTY, which clears
+// with the first burst that works again. The rise is net of what leaks out meanwhile, so
+// the learned response is that of this chamber.
+//
+// Bursts followed by an open door say nothing about the supply and are not judged. A
+// pressure transducer on the supply, if fitted, raises the same alarm on its own (see
+// main.rs).
+
+// Rises below this are sensor noise, whatever was learned
+const MIN_RISE_PPM: f32 = 200.0;
+// Share of the learned rise a burst has to reach
+const MIN_RESPONSE: f32 = 0.2;
+// Weight of each new burst in the learned response
+const LEARNING_RATE: f32 = 0.2;
+const MISSES_TO_ALARM: u8 = 4;
+
+pub struct Co2SupplyMonitor {
+    // CO2 before the last cycle's burst, and the burst length
+    burst: Option<(f32, u32)>,
+    ppm_per_ms: Option<f32>,
+    misses: u8,
+    exhausted: bool,
+}
+
+impl Co2SupplyMonitor {
+    pub const fn new() -> Self {
+        Co2SupplyMonitor {
+            burst: None,
+            ppm_per_ms: None,
+            misses: 0,
+            exhausted: false,
+        }
+    }
+
+    // Once per control cycle after dosing, with the reading the dosing decision was
+    // based on and the burst fired (0 for none). Returns whether the supply is
+    // considered exhausted.
+    pub fn update(&mut self, door: DoorState, co2_ppm: f32, burst_ms: u32) -> bool {
+        if let Some((before_ppm, last_burst_ms)) = self.burst.take() {
+            if !door.is_open() {
+                self.judge(co2_ppm - before_ppm, last_burst_ms);
+            }
+        }
+        if burst_ms > 0 {
+            self.burst = Some((co2_ppm, burst_ms));
+        }
+        self.exhausted
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted
+    }
+
+    // Learned CO2 rise per second of burst
+    pub fn response_ppm_per_s(&self) -> Option<f32> {
+        self.ppm_per_ms.map(|ppm_per_ms| ppm_per_ms * 1000.0)
+    }
+
+    fn judge(&mut self, rise_ppm: f32, burst_ms: u32) {
+        let expected_ppm = self
+            .ppm_per_ms
+            .map_or(0.0, |ppm_per_ms| {
+                ppm_per_ms * burst_ms as f32 * MIN_RESPONSE
+            })
+            .max(MIN_RISE_PPM);
+        if rise_ppm >= expected_ppm {
+            let ppm_per_ms = rise_ppm / burst_ms as f32;
+            self.ppm_per_ms = Some(match self.ppm_per_ms {
+                Some(learned) => learned + LEARNING_RATE * (ppm_per_ms - learned),
+                None => ppm_per_ms,
+            });
+            self.misses = 0;
+            if self.exhausted {
+                self.exhausted = false;
+                info!("CO2 supply restored");
+            }
+            return;
+        }
+
+        self.misses = self.misses.saturating_add(1);
+        warn!(
+            "CO2 burst of {} ms raised the CO2 by {} ppm, expected at least {} ppm",
+            burst_ms, rise_ppm, expected_ppm
+        );
+        if self.misses >= MISSES_TO_ALARM && !self.exhausted {
+            self.exhausted = true;
+            error!(
+                "CO2 supply exhausted: {} bursts in a row without effect",
+                self.misses
+            );
+        }
+    }
+}
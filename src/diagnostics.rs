@@ -0,0 +1,114 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+use heapless::String;
+use icbm_protocol::message::{diagnostics_text, Diagnostics, DIAGNOSTICS_TEXT_LENGTH};
+pub use icbm_protocol::message::{Device, DEVICES};
+
+use crate::drivers::error::{DeviceError, ErrorKind};
+
+// Health of the sensors and actuators since boot, shown by `diag` on the console, the
+// host tool and the display's diagnostics screen.
+//
+// The control loop records the outcome of every read of a device. Failures are counted
+// by the kind the driver gave them (see drivers::error). The valve, humidifier and heater
+// have nothing to read back, so each use counts as a read. Self-test results and serial
+// numbers are recorded as they are obtained.
+
+#[derive(Clone, Debug)]
+pub struct Health {
+    pub reads: u32,
+    pub timeouts: u32,
+    pub crc_errors: u32,
+    pub parse_errors: u32,
+    pub other_errors: u32,
+    pub last_good: Option<Instant>,
+    pub last_error: Option<&'static str>,
+    // `None` until a self-test ran, and for devices without one
+    pub self_test: Option<bool>,
+    pub serial: Option<String<DIAGNOSTICS_TEXT_LENGTH>>,
+}
+
+impl Health {
+    const NEW: Health = Health {
+        reads: 0,
+        timeouts: 0,
+        crc_errors: 0,
+        parse_errors: 0,
+        other_errors: 0,
+        last_good: None,
+        last_error: None,
+        self_test: None,
+        serial: None,
+    };
+}
+
+static REGISTRY: Mutex<CriticalSectionRawMutex, RefCell<[Health; DEVICES]>> =
+    Mutex::new(RefCell::new([Health::NEW; DEVICES]));
+
+pub fn health(device: Device) -> Health {
+    REGISTRY.lock(|r| r.borrow()[device as usize].clone())
+}
+
+fn update(device: Device, f: impl FnOnce(&mut Health)) {
+    REGISTRY.lock(|r| f(&mut r.borrow_mut()[device as usize]));
+}
+
+pub fn record<T>(device: Device, result: &Result<T, DeviceError>) {
+    update(device, |h| match *result {
+        Ok(_) => {
+            h.reads = h.reads.saturating_add(1);
+            h.last_good = Some(Instant::now());
+        }
+        Err(e) => {
+            let counter = match e.kind {
+                ErrorKind::Timeout => &mut h.timeouts,
+                ErrorKind::Crc => &mut h.crc_errors,
+                ErrorKind::Parse => &mut h.parse_errors,
+                ErrorKind::Other => &mut h.other_errors,
+            };
+            *counter = counter.saturating_add(1);
+            h.last_error = Some(e.message);
+        }
+    });
+}
+
+// For actuators, each time they are switched on
+pub fn record_use(device: Device) {
+    record::<()>(device, &Ok(()));
+}
+
+pub fn set_self_test(device: Device, passed: bool) {
+    update(device, |h| h.self_test = Some(passed));
+}
+
+// Serial numbers are ASCII, so truncating at a byte index is safe
+pub fn set_serial(device: Device, serial: &str) {
+    let serial = serial.trim();
+    let len = serial.len().min(DIAGNOSTICS_TEXT_LENGTH);
+    let mut text = String::new();
+    let _ = text.push_str(&serial[..len]);
+    update(device, |h| h.serial = Some(text));
+}
+
+// Protocol representation of a device's health
+pub fn report(device: Device) -> Diagnostics {
+    let health = health(device);
+    let (serial, serial_len) = diagnostics_text(health.serial.as_deref().unwrap_or(""));
+    let (last_error, last_error_len) = diagnostics_text(health.last_error.unwrap_or(""));
+    Diagnostics {
+        device,
+        reads: health.reads,
+        timeouts: health.timeouts,
+        crc_errors: health.crc_errors,
+        parse_errors: health.parse_errors,
+        other_errors: health.other_errors,
+        since_good_s: health.last_good.map(|at| at.elapsed().as_secs() as u32),
+        self_test: health.self_test,
+        serial,
+        serial_len,
+        last_error,
+        last_error_len,
+    }
+}
@@ -0,0 +1,149 @@
+use defmt::{info, warn, Format};
+
+use crate::storage::config::Config;
+
+// Door handling for the CO2 control.
+//
+// An open door lets the CO2 escape within seconds, and bursts fired then are lost. The
+// door counts as open while its switch (drivers/door_switch.rs, if fitted) says so, or,
+// with or without a switch, when the CO2 falls faster between two control cycles than
+// dosing and leaks explain: `doordrop` percent of the previous reading, at least
+// MIN_DROP_PPM. Dosing is suspended while the door is open. Once it is closed, the
+// chamber is refilled with bursts up to `doorboost` times the normal length, the boost
+// shrinking as the CO2 nears its setpoint, until it is within tolerance or for at most
+// RECOVERY_MAX_CYCLES.
+
+// Smaller drops never count, whatever the relative threshold: sensor noise at low
+// setpoints
+const MIN_DROP_PPM: f32 = 1000.0;
+// Cycles the door stays suspected open after the last rapid drop; the CO2 levels off
+// once the chamber has equalised with the room, with the door still open
+const SUSPECT_HOLD_CYCLES: u8 = 2;
+// About 10 minutes at the 50 s control period
+const RECOVERY_MAX_CYCLES: u8 = 12;
+// The boost is full from this many tolerances below the setpoint and tapers off to
+// normal bursts at the tolerance
+const FULL_BOOST_TOLERANCES: f32 = 5.0;
+// Longest burst of any cycle, the upper limit of `burst` too
+const MAX_BURST_MS: u32 = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum DoorState {
+    Closed,
+    // By the switch
+    Open,
+    // By a rapid CO2 drop, the switch (if fitted) reading closed
+    Suspected,
+    // Closed again, refilling with boosted bursts
+    Recovering,
+}
+
+impl DoorState {
+    pub fn name(self) -> &'static str {
+        match self {
+            DoorState::Closed => "closed",
+            DoorState::Open => "open",
+            DoorState::Suspected => "open (CO2 drop)",
+            DoorState::Recovering => "closed, recovering",
+        }
+    }
+
+    pub fn is_open(self) -> bool {
+        matches!(self, DoorState::Open | DoorState::Suspected)
+    }
+}
+
+pub struct DoorMonitor {
+    state: DoorState,
+    last_co2_ppm: Option<f32>,
+    hold_cycles: u8,
+    recovery_cycles: u8,
+}
+
+impl DoorMonitor {
+    pub const fn new() -> Self {
+        DoorMonitor {
+            state: DoorState::Closed,
+            last_co2_ppm: None,
+            hold_cycles: 0,
+            recovery_cycles: 0,
+        }
+    }
+
+    // Once per control cycle, before dosing. `switch_open` is false without a switch.
+    pub fn update(&mut self, config: &Config, switch_open: bool, co2_ppm: f32) -> DoorState {
+        let dropping = self.last_co2_ppm.is_some_and(|last| {
+            let drop = last - co2_ppm;
+            config.door_drop_pct > 0.0
+                && drop > MIN_DROP_PPM
+                && drop > last * config.door_drop_pct / 100.0
+        });
+        self.last_co2_ppm = Some(co2_ppm);
+        self.hold_cycles = if dropping {
+            warn!("Rapid CO2 drop to {} ppm, door probably open", co2_ppm);
+            SUSPECT_HOLD_CYCLES
+        } else {
+            self.hold_cycles.saturating_sub(1)
+        };
+
+        let low = co2_ppm < config.target_co2_ppm - config.co2_tolerance_ppm;
+        let next = if switch_open {
+            DoorState::Open
+        } else if self.hold_cycles > 0 {
+            DoorState::Suspected
+        } else {
+            match self.state {
+                DoorState::Open | DoorState::Suspected if low => {
+                    self.recovery_cycles = 0;
+                    DoorState::Recovering
+                }
+                DoorState::Recovering if low && self.recovery_cycles < RECOVERY_MAX_CYCLES => {
+                    DoorState::Recovering
+                }
+                _ => DoorState::Closed,
+            }
+        };
+        if next == DoorState::Recovering {
+            self.recovery_cycles += 1;
+        }
+        match (self.state, next) {
+            (from, to) if to.is_open() && !from.is_open() => {
+                warn!("Door open, CO2 dosing suspended")
+            }
+            (from, DoorState::Recovering) if from.is_open() => {
+                info!("Door closed, recovering CO2")
+            }
+            (DoorState::Recovering, DoorState::Closed) if low => {
+                warn!("CO2 recovery gave up after {} cycles", self.recovery_cycles)
+            }
+            (DoorState::Recovering, DoorState::Closed) => {
+                info!("CO2 recovered after {} cycles", self.recovery_cycles)
+            }
+            (from, DoorState::Closed) if from.is_open() => info!("Door closed"),
+            _ => {}
+        }
+        self.state = next;
+        next
+    }
+
+    pub fn state(&self) -> DoorState {
+        self.state
+    }
+
+    // Burst for this cycle given how far the CO2 is below its setpoint: none while the
+    // door is open, boosted while recovering
+    pub fn burst_ms(&self, config: &Config, co2_error_ppm: f32) -> u32 {
+        match self.state {
+            DoorState::Open | DoorState::Suspected => 0,
+            DoorState::Closed => config.co2_burst_ms,
+            DoorState::Recovering => {
+                let tolerance = config.co2_tolerance_ppm;
+                let deficit = ((co2_error_ppm - tolerance)
+                    / ((FULL_BOOST_TOLERANCES - 1.0) * tolerance))
+                    .clamp(0.0, 1.0);
+                let boost = 1.0 + (config.door_boost - 1.0) * deficit;
+                ((config.co2_burst_ms as f32 * boost) as u32).min(MAX_BURST_MS)
+            }
+        }
+    }
+}
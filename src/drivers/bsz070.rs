@@ -2,6 +2,9 @@ use embassy_stm32::gpio::{Level, Output, Pin, Speed};
 use embassy_stm32::Peripheral;
 use embassy_time::Timer;
 
+// Length of one `heat()` pulse
+pub const HEAT_INTERVAL_MS: u64 = 5000;
+
 #[derive(Debug)]
 pub enum HeaterState {
     Off,
@@ -24,10 +27,9 @@ impl<'d> Heater<'d> {
     // call repeatedly in an async loop with SCD41 readings
     // recommended measure time 60 secs - to allow heat to diffuse
     pub async fn heat(&mut self) {
-        const INTERVAL_MS: u64 = 5000;
         self.state = HeaterState::Heating;
         self.pin.set_high();
-        Timer::after_millis(INTERVAL_MS).await;
+        Timer::after_millis(HEAT_INTERVAL_MS).await;
         self.pin.set_low();
     }
 
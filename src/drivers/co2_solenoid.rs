@@ -1,6 +1,15 @@
+use defmt::Format;
 use embassy_stm32::gpio::{Level, Output, Pin, Speed};
+use embassy_stm32::peripherals::TIM5;
+use embassy_stm32::time::Hertz;
+use embassy_stm32::timer::low_level::CountingMode;
+use embassy_stm32::timer::simple_pwm::{Ch1, PwmPin, SimplePwm};
+use embassy_stm32::timer::{Channel, GeneralInstance4Channel};
 use embassy_stm32::Peripheral;
-use embassy_time::Timer;
+use embassy_time::{Duration, Instant, Timer};
+
+// Above the audible range, and slow enough for the MOSFET to switch cleanly
+const PWM_FREQUENCY: Hertz = Hertz(20_000);
 
 pub enum Co2State {
     Idle,
@@ -8,20 +17,107 @@ pub enum Co2State {
     Continuous,
 }
 
-pub struct Co2Solenoid<'d> {
-    output: Output<'d>,
+// Coil drive of a valve on a timer output: full drive for `pull_in` after switching on,
+// then `hold_pct`. Holding a pulled-in plunger takes a fraction of the pull-in current,
+// and the rest only heats the coil.
+#[derive(Clone, Copy, Debug, PartialEq, Format)]
+pub struct HoldConfig {
+    pub pull_in: Duration,
+    // 100 keeps full drive throughout
+    pub hold_pct: f32,
+}
+
+impl Default for HoldConfig {
+    fn default() -> Self {
+        HoldConfig {
+            pull_in: Duration::from_millis(100),
+            hold_pct: 100.0,
+        }
+    }
+}
+
+// Cumulative use of the valve, for estimating its wear
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Format)]
+pub struct Wear {
+    pub on_time_ms: u64,
+    pub actuations: u32,
+}
+
+enum Drive<'d, T: GeneralInstance4Channel> {
+    Gpio(Output<'d>),
+    // On channel 1
+    Pwm(SimplePwm<'d, T>),
+}
+
+impl<'d, T: GeneralInstance4Channel> Drive<'d, T> {
+    fn set(&mut self, pct: f32) {
+        match self {
+            Drive::Gpio(output) if pct > 0.0 => output.set_high(),
+            Drive::Gpio(output) => output.set_low(),
+            Drive::Pwm(pwm) => {
+                let duty = (pwm.get_max_duty() as f32 * pct.clamp(0.0, 100.0) / 100.0) as u32;
+                pwm.set_duty(Channel::Ch1, duty);
+            }
+        }
+    }
+}
+
+pub struct Co2Solenoid<'d, T: GeneralInstance4Channel = TIM5> {
+    drive: Drive<'d, T>,
     state: Co2State,
+    hold: HoldConfig,
+    wear: Wear,
+    energised_at: Option<Instant>,
 }
 
 impl<'d> Co2Solenoid<'d> {
+    // Valve on a plain output, always at full drive
     pub fn new(pin: impl Peripheral<P = impl Pin> + 'd, level: Level, speed: Speed) -> Self {
-        let output = Output::new(pin, level, speed);
-        let state = match level {
-            Level::Low => Co2State::Idle,
-            Level::High => Co2State::Continuous,
-        };
+        let mut valve = Co2Solenoid::with_drive(Drive::Gpio(Output::new(pin, Level::Low, speed)));
+        if level == Level::High {
+            valve.energise();
+            valve.state = Co2State::Continuous;
+        }
+        valve
+    }
+}
 
-        Co2Solenoid { output, state }
+impl<'d, T: GeneralInstance4Channel> Co2Solenoid<'d, T> {
+    // Valve on channel 1 of a timer, switched off, with pull-in and hold drive
+    pub fn new_pwm(
+        tim: impl Peripheral<P = T> + 'd,
+        pin: PwmPin<'d, T, Ch1>,
+        hold: HoldConfig,
+    ) -> Self {
+        let mut pwm = SimplePwm::new(
+            tim,
+            Some(pin),
+            None,
+            None,
+            None,
+            PWM_FREQUENCY,
+            CountingMode::EdgeAlignedUp,
+        );
+        pwm.set_duty(Channel::Ch1, 0);
+        pwm.enable(Channel::Ch1);
+        let mut valve = Co2Solenoid::with_drive(Drive::Pwm(pwm));
+        valve.hold = hold;
+        valve
+    }
+
+    fn with_drive(drive: Drive<'d, T>) -> Self {
+        Co2Solenoid {
+            drive,
+            state: Co2State::Idle,
+            hold: HoldConfig::default(),
+            wear: Wear::default(),
+            energised_at: None,
+        }
+    }
+
+    // Takes effect from the next time the valve opens
+    pub fn configure(&mut self, hold: HoldConfig) {
+        self.hold = hold;
     }
 
     // call repeatedly in an async loop with ExplorIR M E 100 readings
@@ -30,26 +126,37 @@ impl<'d> Co2Solenoid<'d> {
         match self.state {
             Co2State::Idle => {}
             _ => {
-                self.output.set_low();
+                self.release();
                 self.state = Co2State::Idle;
             }
         }
 
         self.state = Co2State::Burst;
-        self.output.set_high();
-        Timer::after_millis(interval).await;
-        self.output.set_low();
+        let pull_in = self.hold.pull_in.as_millis().min(interval);
+        self.energise();
+        Timer::after_millis(pull_in).await;
+        if interval > pull_in {
+            self.drive.set(self.hold.hold_pct);
+            Timer::after_millis(interval - pull_in).await;
+        }
+        self.release();
         self.state = Co2State::Idle;
     }
 
-    pub fn start_continuous(&mut self) {
+    // Returns once the valve has pulled in and is held
+    pub async fn start_continuous(&mut self) {
+        if matches!(self.state, Co2State::Continuous) {
+            return;
+        }
         self.state = Co2State::Continuous;
-        self.output.set_high();
+        self.energise();
+        Timer::after(self.hold.pull_in).await;
+        self.drive.set(self.hold.hold_pct);
     }
 
     pub fn stop_continuous(&mut self) {
         if matches!(self.state, Co2State::Continuous) {
-            self.output.set_low();
+            self.release();
             self.state = Co2State::Idle;
         }
     }
@@ -57,4 +164,40 @@ impl<'d> Co2Solenoid<'d> {
     pub fn state(&self) -> &Co2State {
         &self.state
     }
+
+    // Including the current opening, if the valve is open
+    pub fn wear(&self) -> Wear {
+        let open_ms = self.energised_at.map_or(0, |at| at.elapsed().as_millis());
+        Wear {
+            on_time_ms: self.wear.on_time_ms.saturating_add(open_ms),
+            ..self.wear
+        }
+    }
+
+    // Continues from counts kept elsewhere, e.g. across resets, or restarts from zero
+    // for a new valve
+    pub fn set_wear(&mut self, wear: Wear) {
+        self.wear = wear;
+        if self.energised_at.is_some() {
+            self.energised_at = Some(Instant::now());
+        }
+    }
+
+    fn energise(&mut self) {
+        self.drive.set(100.0);
+        if self.energised_at.is_none() {
+            self.energised_at = Some(Instant::now());
+            self.wear.actuations = self.wear.actuations.saturating_add(1);
+        }
+    }
+
+    fn release(&mut self) {
+        self.drive.set(0.0);
+        if let Some(at) = self.energised_at.take() {
+            self.wear.on_time_ms = self
+                .wear
+                .on_time_ms
+                .saturating_add(at.elapsed().as_millis());
+        }
+    }
 }
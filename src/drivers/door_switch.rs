@@ -0,0 +1,44 @@
+use embassy_futures::select::{select, Either};
+use embassy_stm32::exti::ExtiInput;
+use embassy_time::{Duration, Timer};
+
+// Contacts bounce for a few milliseconds; a level counts once it held this long
+const DEBOUNCE: Duration = Duration::from_millis(50);
+
+// Door switch on an EXTI input with the pull-up enabled: a normally closed contact to
+// ground that the closed door holds shut. An open door and a broken wire both read
+// high, i.e. open.
+pub struct DoorSwitch<'d> {
+    input: ExtiInput<'d>,
+    open: bool,
+}
+
+impl<'d> DoorSwitch<'d> {
+    pub fn new(input: ExtiInput<'d>) -> Self {
+        let open = input.is_high();
+        Self { input, open }
+    }
+
+    // Debounced state as of the last `wait_for_change`
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    // Resolves with the new state once the switch has settled in one. Safe to cancel:
+    // a change missed meanwhile is picked up from the level on the next call.
+    pub async fn wait_for_change(&mut self) -> bool {
+        loop {
+            if self.input.is_high() == self.open {
+                self.input.wait_for_any_edge().await;
+            }
+            while let Either::Second(()) =
+                select(Timer::after(DEBOUNCE), self.input.wait_for_any_edge()).await
+            {}
+            let open = self.input.is_high();
+            if open != self.open {
+                self.open = open;
+                return open;
+            }
+        }
+    }
+}
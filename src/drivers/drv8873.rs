@@ -1,23 +1,94 @@
+use defmt::{error, info, Format};
+use embassy_stm32::adc::{self, Adc, AdcChannel, Resolution, SampleTime};
+use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::gpio::{Output, Pin};
 use embassy_stm32::time::Hertz;
 use embassy_stm32::timer::low_level::CountingMode;
 use embassy_stm32::timer::simple_pwm::{Ch2, Ch3, PwmPin, SimplePwm};
 use embassy_stm32::timer::{Channel, GeneralInstance4Channel};
 use embassy_stm32::Peripheral;
-use embassy_time::Timer;
+use embassy_time::{Duration, Timer};
 
-const INTERVAL_MS: u64 = 1000;
-const DUTY_CYCLE: u64 = 50; // must be [0,100]
+// Interval between output changes while ramping
+const RAMP_STEP: Duration = Duration::from_millis(20);
 
-#[derive(Debug, PartialEq)]
+// IPROPI1 and IPROPI2 are tied to one sense resistor; they source 1100 µA per A of
+// bridge current
+const IPROPI_UA_PER_A: f32 = 1100.0;
+const IPROPI_RESISTOR_OHMS: f32 = 680.0; // 4.4 A full scale at 3.3 V
+const ADC_REFERENCE_V: f32 = 3.3;
+const ADC_MAX: f32 = 4095.0;
+const CURRENT_SAMPLES: u32 = 8;
+// Software trip, below the bridge's own current limit; a module drawing this much
+// is shorted or the wrong one
+const OVERCURRENT_A: f32 = 4.0;
+// Driven at least this hard, a connected module draws well over OPEN_LOAD_A
+const OPEN_LOAD_MIN_OUTPUT_PCT: f32 = 20.0;
+const OPEN_LOAD_A: f32 = 0.05;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum BridgeFault {
+    OverCurrent,
+    // nFAULT without excess current at the IPROPI output. The bridge shuts down on
+    // over-temperature, undervoltage and its own (faster) current limit alike, which the
+    // hardware-configured part does not tell apart; over-temperature is the one a
+    // working module runs into.
+    OverTemperature,
+    OpenLoad,
+    // Hot side above its limit, from the heatsink thermistor (see thermal.rs)
+    HeatsinkOverTemperature,
+}
+
+impl BridgeFault {
+    pub fn name(self) -> &'static str {
+        match self {
+            BridgeFault::OverCurrent => "over-current",
+            BridgeFault::OverTemperature => "over-temperature",
+            BridgeFault::OpenLoad => "open load",
+            BridgeFault::HeatsinkOverTemperature => "heatsink over-temperature",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Format)]
 pub enum ThermalState {
     Off,
     Heating,
     Cooling,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Format)]
+pub struct PeltierConfig {
+    pub pwm_frequency: Hertz,
+    // Both half-bridges stay off this long before the current is reversed, so the module
+    // is not hit with the full reverse voltage while its thermal EMF opposes it
+    pub dead_time: Duration,
+    // Output slew limit; sudden swings thermally shock the module's solder joints
+    pub ramp_pct_per_s: f32,
+}
+
+impl Default for PeltierConfig {
+    fn default() -> Self {
+        PeltierConfig {
+            pwm_frequency: Hertz(50_000),
+            dead_time: Duration::from_millis(100),
+            ramp_pct_per_s: 20.0,
+        }
+    }
+}
+
+// Peltier module on a DRV8873 H-bridge in PWM mode: IN1 (CH2) high heats, IN2 (CH3)
+// high cools. The output is signed, from -100 % (full cooling) to +100 % (full heating),
+// and stays in effect until changed.
+//
+// A fault reported by BridgeMonitor latches: the bridge is switched to high impedance
+// through its DISABLE pin and stays off until the fault is cleared.
 pub struct PeltierController<'d, T: GeneralInstance4Channel> {
     pwm: SimplePwm<'d, T>,
-    state: ThermalState,
+    disable: Output<'d>,
+    config: PeltierConfig,
+    output_pct: f32,
+    fault: Option<BridgeFault>,
 }
 
 impl<'d, T: GeneralInstance4Channel> PeltierController<'d, T> {
@@ -25,6 +96,8 @@ impl<'d, T: GeneralInstance4Channel> PeltierController<'d, T> {
         tim: impl Peripheral<P = T> + 'd,
         ch2_pin: Option<PwmPin<'d, T, Ch2>>,
         ch3_pin: Option<PwmPin<'d, T, Ch3>>,
+        mut disable: Output<'d>,
+        config: PeltierConfig,
     ) -> Self {
         let mut pwm = SimplePwm::new(
             tim,
@@ -32,7 +105,7 @@ impl<'d, T: GeneralInstance4Channel> PeltierController<'d, T> {
             ch2_pin, // PA9 as Channel 2
             ch3_pin, // PA10 as Channel 3
             None,
-            Hertz(50_000),
+            config.pwm_frequency,
             CountingMode::EdgeAlignedUp,
         );
 
@@ -40,56 +113,153 @@ impl<'d, T: GeneralInstance4Channel> PeltierController<'d, T> {
         pwm.enable(Channel::Ch3);
         pwm.set_duty(Channel::Ch2, 0);
         pwm.set_duty(Channel::Ch3, 0);
+        disable.set_low();
 
         Self {
             pwm,
-            state: ThermalState::Off,
+            disable,
+            config,
+            output_pct: 0.0,
+            fault: None,
         }
     }
 
-    pub async fn heat(&mut self) {
-        if self.state == ThermalState::Cooling {
-            self.stop();
+    // Takes effect immediately; the duty cycle is kept across a frequency change
+    pub fn configure(&mut self, config: PeltierConfig) {
+        if config.pwm_frequency != self.config.pwm_frequency {
+            info!("Peltier PWM at {} Hz", config.pwm_frequency.0);
+            self.pwm.set_frequency(config.pwm_frequency);
+            self.apply(self.output_pct);
         }
+        self.config = config;
+    }
 
-        let duty = (self.pwm.get_max_duty() * DUTY_CYCLE as u32) / 100;
-        self.pwm.set_duty(Channel::Ch2, duty);
-        self.pwm.set_duty(Channel::Ch3, 0);
+    // Moves the output to `target_pct` at the configured ramp rate and returns once it
+    // is there. Crossing zero holds the bridge off for the dead time.
+    pub async fn set_output(&mut self, target_pct: f32) {
+        if self.fault.is_some() {
+            return;
+        }
+        let target_pct = target_pct.clamp(-100.0, 100.0);
+        let max_step = self.config.ramp_pct_per_s * RAMP_STEP.as_millis() as f32 / 1000.0;
 
-        self.state = if DUTY_CYCLE == 0 {
-            ThermalState::Off
-        } else {
-            ThermalState::Heating
-        };
-        Timer::after_millis(INTERVAL_MS).await;
-        self.stop();
+        while self.output_pct != target_pct {
+            let reversing = self.output_pct * target_pct < 0.0;
+            // Towards zero first when reversing
+            let goal = if reversing { 0.0 } else { target_pct };
+            let step = (goal - self.output_pct).clamp(-max_step, max_step);
+            self.apply(self.output_pct + step);
+            if self.output_pct == 0.0 && reversing {
+                Timer::after(self.config.dead_time).await;
+            } else {
+                Timer::after(RAMP_STEP).await;
+            }
+        }
+    }
+
+    // Switches the bridge off at once, bypassing the ramp
+    pub fn stop(&mut self) {
+        self.apply(0.0);
+    }
+
+    // Switches the bridge off and keeps it off until `clear_fault`
+    pub fn trip(&mut self, fault: BridgeFault) {
+        self.apply(0.0);
+        self.disable.set_high();
+        if self.fault.is_none() {
+            error!("Peltier bridge disabled: {}", fault);
+        }
+        self.fault = Some(fault);
     }
 
-    pub async fn cool(&mut self) {
-        if self.state == ThermalState::Heating {
-            self.stop();
+    pub fn clear_fault(&mut self) {
+        if let Some(fault) = self.fault.take() {
+            info!("Peltier bridge re-enabled after {}", fault);
         }
+        self.disable.set_low();
+    }
 
-        let duty = (self.pwm.get_max_duty() * DUTY_CYCLE as u32) / 100;
-        self.pwm.set_duty(Channel::Ch2, 0);
-        self.pwm.set_duty(Channel::Ch3, duty);
+    pub fn fault(&self) -> Option<BridgeFault> {
+        self.fault
+    }
 
-        self.state = if DUTY_CYCLE == 0 {
+    pub fn output_pct(&self) -> f32 {
+        self.output_pct
+    }
+
+    pub fn state(&self) -> ThermalState {
+        if self.output_pct > 0.0 {
+            ThermalState::Heating
+        } else if self.output_pct < 0.0 {
+            ThermalState::Cooling
+        } else {
             ThermalState::Off
+        }
+    }
+
+    fn apply(&mut self, output_pct: f32) {
+        let max_duty = self.pwm.get_max_duty();
+        let duty = (max_duty as f32 * output_pct.abs() / 100.0) as u32;
+        // Only one half-bridge is ever driven; the other input stays low
+        if output_pct > 0.0 {
+            self.pwm.set_duty(Channel::Ch3, 0);
+            self.pwm.set_duty(Channel::Ch2, duty);
         } else {
-            ThermalState::Cooling
-        };
-        Timer::after_millis(INTERVAL_MS).await;
-        self.stop();
+            self.pwm.set_duty(Channel::Ch2, 0);
+            self.pwm.set_duty(Channel::Ch3, duty);
+        }
+        self.output_pct = output_pct;
     }
+}
 
-    pub fn stop(&mut self) {
-        self.pwm.set_duty(Channel::Ch2, 0);
-        self.pwm.set_duty(Channel::Ch3, 0);
-        self.state = ThermalState::Off;
+// DRV8873 fault monitoring: the open-drain nFAULT output on an EXTI line, and the
+// IPROPI current mirror on an ADC channel.
+pub struct BridgeMonitor<'d, A: adc::Instance, P: AdcChannel<A> + Pin> {
+    nfault: ExtiInput<'d>,
+    adc: Adc<'d, A>,
+    ipropi: P,
+}
+
+impl<'d, A: adc::Instance, P: AdcChannel<A> + Pin> BridgeMonitor<'d, A, P> {
+    pub fn new(nfault: ExtiInput<'d>, mut adc: Adc<'d, A>, ipropi: P) -> Self {
+        adc.set_resolution(Resolution::BITS12);
+        // IPROPI is a high-impedance current output
+        adc.set_sample_time(SampleTime::CYCLES480);
+        BridgeMonitor {
+            nfault,
+            adc,
+            ipropi,
+        }
+    }
+
+    // Bridge current, averaged over a few samples to smooth the PWM ripple
+    pub fn current_a(&mut self) -> f32 {
+        let sum: u32 = (0..CURRENT_SAMPLES)
+            .map(|_| self.adc.blocking_read(&mut self.ipropi) as u32)
+            .sum();
+        let volts = sum as f32 / CURRENT_SAMPLES as f32 * ADC_REFERENCE_V / ADC_MAX;
+        volts / IPROPI_RESISTOR_OHMS * 1_000_000.0 / IPROPI_UA_PER_A
+    }
+
+    // Checks the bridge driven at `output_pct`; returns the current and any fault
+    pub fn check(&mut self, output_pct: f32) -> (f32, Option<BridgeFault>) {
+        let current_a = self.current_a();
+        let fault = if current_a > OVERCURRENT_A {
+            Some(BridgeFault::OverCurrent)
+        } else if self.nfault.is_low() {
+            Some(BridgeFault::OverTemperature)
+        } else if output_pct.abs() >= OPEN_LOAD_MIN_OUTPUT_PCT && current_a < OPEN_LOAD_A {
+            Some(BridgeFault::OpenLoad)
+        } else {
+            None
+        };
+        (current_a, fault)
     }
 
-    pub fn state(&self) -> &ThermalState {
-        &self.state
+    // Resolves when the bridge asserts nFAULT, with what `check` makes of it; None if
+    // nFAULT has been released again by then
+    pub async fn wait_for_fault(&mut self, output_pct: f32) -> Option<BridgeFault> {
+        self.nfault.wait_for_falling_edge().await;
+        self.check(output_pct).1
     }
 }
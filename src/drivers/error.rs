@@ -0,0 +1,64 @@
+use defmt::Format;
+
+// What went wrong reading a device, as counted by the diagnostics registry
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum ErrorKind {
+    // No answer in time, or data not ready when due
+    Timeout,
+    // A checksum of the bus or the device failed
+    Crc,
+    // The device answered, but not with anything the driver understands
+    Parse,
+    // Bus errors, faults and out-of-range readings
+    Other,
+}
+
+// Failure of a device read: its kind, and a message for the log and the last error
+// shown by `diag`. Functions that only report messages take it with `?`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceError {
+    pub kind: ErrorKind,
+    pub message: &'static str,
+}
+
+impl DeviceError {
+    pub const fn timeout(message: &'static str) -> Self {
+        DeviceError {
+            kind: ErrorKind::Timeout,
+            message,
+        }
+    }
+
+    pub const fn crc(message: &'static str) -> Self {
+        DeviceError {
+            kind: ErrorKind::Crc,
+            message,
+        }
+    }
+
+    pub const fn parse(message: &'static str) -> Self {
+        DeviceError {
+            kind: ErrorKind::Parse,
+            message,
+        }
+    }
+
+    pub const fn other(message: &'static str) -> Self {
+        DeviceError {
+            kind: ErrorKind::Other,
+            message,
+        }
+    }
+}
+
+impl Format for DeviceError {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{}", self.message)
+    }
+}
+
+impl From<DeviceError> for &'static str {
+    fn from(e: DeviceError) -> Self {
+        e.message
+    }
+}
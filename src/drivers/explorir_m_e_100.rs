@@ -5,6 +5,8 @@ use embassy_time::Timer;
 use heapless::String;
 use libm::pow;
 
+use crate::drivers::error::DeviceError;
+
 // Timing Constants
 const TIME_TO_FIRST_VAL: u64 = 1200; //ms
 
@@ -100,26 +102,26 @@ impl<'d> ExplorIrME100<'d> {
     }
 
     //returns the value in ppm as i32
-    pub async fn get_filtered_co2(&mut self) -> Result<i32, &'static str> {
+    pub async fn get_filtered_co2(&mut self) -> Result<i32, DeviceError> {
         self.uart
             .write(CMD_GET_FILTERED_CO2)
             .await
-            .map_err(|_| "Failed to write to UART")?;
+            .map_err(|_| DeviceError::other("Failed to write to UART"))?;
 
         let mut response = [0u8; RESPONSE_BUFFER_SIZE];
         self.uart
             .read(&mut response)
             .await
-            .map_err(|_| "Failed to read from UART")?;
+            .map_err(|_| DeviceError::other("Failed to read from UART"))?;
 
         let result = self
             .parse_response::<10>(&response, 'Z')
-            .map_err(Self::map_response_error)?;
+            .map_err(|e| DeviceError::parse(Self::map_response_error(e)))?;
 
         let value = result
             .trim()
             .parse::<i32>()
-            .map_err(|_| "Failed to parse CO2 reading as integer")?;
+            .map_err(|_| DeviceError::parse("Failed to parse CO2 reading as integer"))?;
 
         Ok(value * CO2_SCALE_VALUE)
     }
@@ -0,0 +1,31 @@
+use embassy_stm32::gpio::{Level, Output, Pin, Speed};
+use embassy_stm32::Peripheral;
+use embassy_time::Timer;
+
+// Humidifier (ultrasonic mister or water pan heater) switched through a MOSFET. It is
+// driven time-proportionally: one pulse per control cycle whose length is set by
+// humidity::HumidityController.
+pub struct Humidifier<'d> {
+    pin: Output<'d>,
+}
+
+impl<'d> Humidifier<'d> {
+    pub fn new(pin: impl Peripheral<P = impl Pin> + 'd, speed: Speed) -> Self {
+        Self {
+            pin: Output::new(pin, Level::Low, speed),
+        }
+    }
+
+    pub async fn pulse(&mut self, on_ms: u32) {
+        if on_ms == 0 {
+            return;
+        }
+        self.pin.set_high();
+        Timer::after_millis(on_ms as u64).await;
+        self.pin.set_low();
+    }
+
+    pub fn stop(&mut self) {
+        self.pin.set_low();
+    }
+}
@@ -1,7 +1,11 @@
 pub mod bsz070;
 pub mod co2_solenoid;
+pub mod door_switch;
 pub mod drv8873;
+pub mod error;
 pub mod explorir_m_e_100;
+pub mod humidifier;
+pub mod pressure_transducer;
 pub mod scd41;
 pub mod slf3s;
 pub mod thermistor;
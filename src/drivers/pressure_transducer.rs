@@ -0,0 +1,53 @@
+use embassy_stm32::adc::{self, Adc, AdcChannel, Resolution, SampleTime};
+use embassy_stm32::gpio::Pin;
+
+use crate::drivers::error::DeviceError;
+
+// Industrial pressure transducer with a 0.5-4.5 V output from a 5 V supply, read
+// through a 10 kΩ / 20 kΩ divider so full scale stays below the 3.3 V ADC reference.
+// The output is linear from 0 bar at 0.5 V to the transducer's full scale at 4.5 V;
+// a transducer working properly never leaves that span by much, so readings well
+// outside it are a disconnected or shorted sensor.
+const ADC_REFERENCE_V: f32 = 3.3;
+const ADC_MAX: f32 = 4095.0;
+const DIVIDER_RATIO: f32 = 20.0 / (10.0 + 20.0);
+const ZERO_V: f32 = 0.5;
+const SPAN_V: f32 = 4.0;
+// Below this the output is floating or the supply missing; above it, shorted to 5 V
+const MIN_VALID_V: f32 = 0.3;
+const MAX_VALID_V: f32 = 4.7;
+const SAMPLES: u32 = 16;
+
+pub struct PressureTransducer<'d, A: adc::Instance, P: AdcChannel<A> + Pin> {
+    adc: Adc<'d, A>,
+    pin: P,
+}
+
+impl<'d, A: adc::Instance, P: AdcChannel<A> + Pin> PressureTransducer<'d, A, P> {
+    pub fn new(mut adc: Adc<'d, A>, pin: P) -> Self {
+        adc.set_resolution(Resolution::BITS12);
+        // The divider has a high source impedance
+        adc.set_sample_time(SampleTime::CYCLES480);
+        PressureTransducer { adc, pin }
+    }
+
+    // Output voltage of the transducer itself, before the divider
+    fn output_v(&mut self) -> f32 {
+        let sum: u32 = (0..SAMPLES)
+            .map(|_| self.adc.blocking_read(&mut self.pin) as u32)
+            .sum();
+        sum as f32 / SAMPLES as f32 * ADC_REFERENCE_V / ADC_MAX / DIVIDER_RATIO
+    }
+
+    // Pressure in bar for a transducer of `full_scale_bar`
+    pub fn read_bar(&mut self, full_scale_bar: f32) -> Result<f32, DeviceError> {
+        let volts = self.output_v();
+        if volts < MIN_VALID_V {
+            return Err(DeviceError::other("Pressure transducer disconnected"));
+        }
+        if volts > MAX_VALID_V {
+            return Err(DeviceError::other("Pressure transducer shorted"));
+        }
+        Ok(((volts - ZERO_V) / SPAN_V * full_scale_bar).max(0.0))
+    }
+}
@@ -3,6 +3,8 @@ use embassy_stm32::i2c::{Error, I2c};
 use embassy_stm32::mode::Async;
 use embassy_time::Timer;
 
+use crate::drivers::error::DeviceError;
+
 // I2C Address
 const SCD41_I2C_ADDRESS: u8 = 0x62;
 
@@ -163,7 +165,7 @@ impl<'d> SCD41<'d> {
         Ok(())
     }
 
-    pub async fn read_measurement(&mut self) -> Result<(u16, f32, f32), &'static str> {
+    pub async fn read_measurement(&mut self) -> Result<(u16, f32, f32), DeviceError> {
         let mut buf = [0u8; 9];
         let mut attempts = 0;
 
@@ -194,12 +196,12 @@ impl<'d> SCD41<'d> {
                     Timer::after_millis(DATA_READY_LOOP_DELAY).await;
                     attempts += 1;
                 } else {
-                    return Err("Data not ready after max attempts");
+                    return Err(DeviceError::timeout("Data not ready after max attempts"));
                 }
             }
         }
 
-        Err("Unexpected error in read_measurement")
+        Err(DeviceError::other("Unexpected error in read_measurement"))
     }
 
     pub async fn stop_periodic_measurement(&mut self) -> Result<(), &'static str> {
@@ -228,7 +230,7 @@ impl<'d> SCD41<'d> {
                 let temp_offset = raw_offset as f32 * 175.0 / 65535.0;
                 Ok(temp_offset)
             }
-            Err(e) => Err(e),
+            Err(e) => Err(e.into()),
         }
     }
 
@@ -248,7 +250,7 @@ impl<'d> SCD41<'d> {
                 let altitude = u16::from_be_bytes([buf[0], buf[1]]);
                 Ok(altitude)
             }
-            Err(e) => Err(e),
+            Err(e) => Err(e.into()),
         }
     }
 
@@ -267,7 +269,7 @@ impl<'d> SCD41<'d> {
                 let pressure_pa = u32::from(raw_pressure) * 100;
                 Ok(pressure_pa)
             }
-            Err(e) => Err(e),
+            Err(e) => Err(e.into()),
         }
     }
 
@@ -341,7 +343,7 @@ impl<'d> SCD41<'d> {
             .await
         {
             Ok(()) => Ok(buf),
-            Err(e) => Err(e),
+            Err(e) => Err(e.into()),
         }
     }
 
@@ -382,7 +384,8 @@ impl<'d> SCD41<'d> {
         Ok(())
     }
 
-    async fn perform_self_test(&mut self) -> Result<bool, &'static str> {
+    // Takes ~10 s; the sensor must be idle
+    pub async fn perform_self_test(&mut self) -> Result<bool, &'static str> {
         self.ensure_idle().await?;
         let mut buf = [0u8; 3];
 
@@ -402,7 +405,7 @@ impl<'d> SCD41<'d> {
                     Ok(false)
                 }
             }
-            Err(e) => Err(e),
+            Err(e) => Err(e.into()),
         }
     }
 
@@ -438,7 +441,7 @@ impl<'d> SCD41<'d> {
                     Ok(true)
                 }
             }
-            Err(e) => Err(e),
+            Err(e) => Err(e.into()),
         }
     }
 
@@ -503,28 +506,29 @@ impl<'d> SCD41<'d> {
         address: &[u8],
         buf: &mut [u8],
         millis: u64,
-    ) -> Result<(), &'static str> {
+    ) -> Result<(), DeviceError> {
         self.i2c
             .write(self.i2c_address, address)
             .await
-            .map_err(|e| match e {
-                Error::Arbitration => "Error writing while reading sequence: Arbitration",
-                Error::Bus => "Error writing while reading sequence: Bus",
-                Error::Crc => "Error writing while reading sequence: CRC",
-                Error::Nack => "Error writing while reading sequence: NACK",
-                Error::Overrun => "Error writing while reading sequence: Overrun",
-                Error::Timeout => "Error writing while reading sequence: Timeout",
-                Error::ZeroLengthTransfer => {
-                    "Error writing while reading sequence: Zero Length Transfer"
-                }
+            .map_err(|e| {
+                let message = match e {
+                    Error::Arbitration => "Error writing while reading sequence: Arbitration",
+                    Error::Bus => "Error writing while reading sequence: Bus",
+                    Error::Crc => "Error writing while reading sequence: CRC",
+                    Error::Nack => "Error writing while reading sequence: NACK",
+                    Error::Overrun => "Error writing while reading sequence: Overrun",
+                    Error::Timeout => "Error writing while reading sequence: Timeout",
+                    Error::ZeroLengthTransfer => {
+                        "Error writing while reading sequence: Zero Length Transfer"
+                    }
+                };
+                i2c_error(e, message)
             })?;
 
         Timer::after_millis(millis).await;
 
-        self.i2c
-            .read(self.i2c_address, buf)
-            .await
-            .map_err(|e| match e {
+        self.i2c.read(self.i2c_address, buf).await.map_err(|e| {
+            let message = match e {
                 Error::Arbitration => "Error reading sequence: Arbitration",
                 Error::Bus => "Error reading sequence: Bus",
                 Error::Crc => "Error reading sequence: CRC",
@@ -532,7 +536,9 @@ impl<'d> SCD41<'d> {
                 Error::Overrun => "Error reading sequence: Overrun",
                 Error::Timeout => "Error reading sequence: Timeout",
                 Error::ZeroLengthTransfer => "Error reading sequence: Zero Length Transfer",
-            })?;
+            };
+            i2c_error(e, message)
+        })?;
 
         let mut i = 0;
 
@@ -544,7 +550,7 @@ impl<'d> SCD41<'d> {
                 let calculated_crc = self.crc8(data);
                 if calculated_crc != received_crc {
                     error!("Received data: {:#x}", buf);
-                    return Err("CRC mismatch in read data");
+                    return Err(DeviceError::crc("CRC mismatch in read data"));
                 }
                 i += 3;
             } else {
@@ -563,3 +569,12 @@ impl<'d> SCD41<'d> {
         }
     }
 }
+
+// Kind of an I2C failure, for the diagnostics
+fn i2c_error(e: Error, message: &'static str) -> DeviceError {
+    match e {
+        Error::Timeout => DeviceError::timeout(message),
+        Error::Crc => DeviceError::crc(message),
+        _ => DeviceError::other(message),
+    }
+}
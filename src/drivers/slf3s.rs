@@ -1,9 +1,11 @@
 use embassy_stm32::gpio::{Level, Output, Pin, Speed};
-use embassy_stm32::i2c::I2c;
+use embassy_stm32::i2c::{Error, I2c};
 use embassy_stm32::mode::Async;
 use embassy_stm32::Peripheral;
 use embassy_time::Timer;
 
+use crate::drivers::error::DeviceError;
+
 // Constants for scale factors
 const SLF3X_SCALE_FACTOR_FLOW: f32 = 500.0;
 const SLF3X_SCALE_FACTOR_TEMP: f32 = 200.0;
@@ -26,6 +28,12 @@ const CMD_SOFT_RESET_LENGTH: usize = 1;
 const CMD_SOFT_RESET: [u8; CMD_SOFT_RESET_LENGTH] = [0x06];
 const CHIP_RESET_DELAY: u64 = 50; // Milliseconds
 
+// Product identifier and serial number: two commands, then 6 words each with a CRC.
+// Only accepted while no measurement is running.
+const CMD_READ_PRODUCT_ID_1: [u8; 2] = [0x36, 0x7C];
+const CMD_READ_PRODUCT_ID_2: [u8; 2] = [0xE1, 0x02];
+const PRODUCT_ID_LENGTH: usize = 18;
+
 //Address Change Command
 const CMD_ADDR_CHANGE_LENGHT: usize = 2;
 const CMD_ADDR_CHANGE: [u8; CMD_ADDR_CHANGE_LENGHT] = [0x36, 0x61];
@@ -64,17 +72,52 @@ impl<'d> SLF3S<'d> {
         Ok(())
     }
 
-    pub async fn read_sample(&mut self) -> Result<(f32, f32), &'static str> {
+    pub async fn read_sample(&mut self) -> Result<(f32, f32), DeviceError> {
         let mut data = [0u8; DATA_LENGTH];
         self.i2c
             .read(self.i2c_address, &mut data)
             .await
-            .map_err(|_| "Failed to read data")?;
+            .map_err(|e| match e {
+                Error::Timeout => DeviceError::timeout("Flow sensor read timed out"),
+                _ => DeviceError::other("Failed to read data"),
+            })?;
         let flow = self.convert_and_scale(data[0], data[1], self.flow_scale_factor);
         let temp = self.convert_and_scale(data[3], data[4], self.temp_scale_factor);
         Ok((flow, temp))
     }
 
+    // Returns the 32-bit product number and the 64-bit serial number
+    pub async fn read_product_id(&mut self) -> Result<(u32, u64), &'static str> {
+        self.i2c
+            .write(self.i2c_address, &CMD_READ_PRODUCT_ID_1)
+            .await
+            .map_err(|_| "Failed to request product identifier")?;
+        self.i2c
+            .write(self.i2c_address, &CMD_READ_PRODUCT_ID_2)
+            .await
+            .map_err(|_| "Failed to request product identifier")?;
+        let mut data = [0u8; PRODUCT_ID_LENGTH];
+        self.i2c
+            .read(self.i2c_address, &mut data)
+            .await
+            .map_err(|_| "Failed to read product identifier")?;
+
+        let mut words = [0u16; PRODUCT_ID_LENGTH / 3];
+        for (word, chunk) in words.iter_mut().zip(data.chunks_exact(3)) {
+            if Self::crc8(&chunk[..2]) != chunk[2] {
+                return Err("CRC mismatch in product identifier");
+            }
+            *word = u16::from_be_bytes([chunk[0], chunk[1]]);
+        }
+        let product = words[..2]
+            .iter()
+            .fold(0u32, |acc, &w| (acc << 16) | u32::from(w));
+        let serial = words[2..]
+            .iter()
+            .fold(0u64, |acc, &w| (acc << 16) | u64::from(w));
+        Ok((product, serial))
+    }
+
     fn convert_and_scale(&self, b1: u8, b2: u8, scale_factor: f32) -> f32 {
         let value = i16::from_be_bytes([b1, b2]);
         value as f32 / scale_factor
@@ -1,58 +1,377 @@
-use defmt::info;
-use embassy_stm32::adc::{Adc, AdcChannel, Instance, Resolution};
-use embassy_stm32::gpio::Pin;
-use libm::logf;
-
-const THERMISTOR_R_DIVIDER: f32 = 10_000.0; // Voltage divider resistor (ohms)
-const THERMISTOR_BETA: f32 = 3950.0; // Beta coefficient
-const THERMISTOR_T0: f32 = 298.15; // Reference temperature (Kelvin)
-const THERMISTOR_R0: f32 = 100_000.0; // Reference resistance at T0 (ohms)
+use defmt::Format;
+use embassy_stm32::adc::{
+    Adc, AnyAdcChannel, Resolution, RingBufferedAdc, RxDma, SampleTime, Sequence,
+};
+use embassy_stm32::peripherals::ADC1;
+use embassy_stm32::Peripheral;
+use libm::{log, logf};
+
+use crate::drivers::error::DeviceError;
+
 const KELVIN_TO_CELSIUS: f32 = 273.15; // Conversion constant
 
-pub struct Thermistor<'a, AdcReadPin: Pin + AdcChannel<AdcInstance>, AdcInstance: Instance> {
-    adc_pin: AdcReadPin,
-    adc: Adc<'a, AdcInstance>,
-    resolution: Resolution,
+const ADC_MAX: f32 = 4095.0; // 2^12 - 1
+
+// Conversions per channel in one reading, about 4 ms for the whole burst
+const SAMPLES: usize = 32;
+// VREFINT and one slot per probe, scanned in turn. Slots of probes that are not fitted
+// convert VREFINT again, so every scan has the same length.
+const SEQUENCE_LEN: usize = PROBE_COUNT + 1;
+// Twice one reading, so the DMA can fill one half while the other is copied out
+pub const DMA_BUFFER_LEN: usize = 2 * SAMPLES * SEQUENCE_LEN;
+// Readings this close to either rail mean an open or shorted probe rather than a
+// temperature: 0.5 % of full scale is below -40 °C or above 150 °C for the usual
+// probes
+const RAIL_MARGIN: f32 = 0.005;
+
+// VREFINT raw reading taken in production at VDDA = 3.3 V (RM0090, device electronic
+// signature)
+const VREFINT_CAL_ADDR: *const u16 = 0x1FFF_7A2A as *const u16;
+const VREFINT_CAL_VDDA: f32 = 3.3;
+
+// The original probe: 100 kΩ NTC, beta 3950, to ground from a 10 kΩ pull-up
+pub const DEFAULT_BETA: f32 = 3950.0;
+pub const DEFAULT_R0_OHMS: f32 = 100_000.0;
+pub const DEFAULT_T0_C: f32 = 25.0;
+pub const DEFAULT_SERIES_OHMS: f32 = 10_000.0;
+
+// Resistance-to-temperature relation of an NTC thermistor. Both are a few numbers, so
+// a probe's model is stored with the configuration (see storage/config.rs).
+#[derive(Clone, Copy, Debug, PartialEq, Format)]
+pub enum Model {
+    // 1/T = a + b ln(R) + c ln(R)^3, T in kelvin; see `fit_steinhart_hart`
+    SteinhartHart { a: f32, b: f32, c: f32 },
+    // 1/T = 1/T0 + ln(R/R0) / beta, the datasheet's two-parameter approximation
+    Beta { beta: f32, r0_ohms: f32, t0_c: f32 },
 }
 
-impl<'a, AdcReadPin: Pin + AdcChannel<AdcInstance>, AdcInstance: Instance>
-    Thermistor<'a, AdcReadPin, AdcInstance>
-{
-    pub fn new(adc_pin: AdcReadPin, mut adc: Adc<'a, AdcInstance>) -> Self {
-        let resolution = Resolution::BITS12;
-        adc.set_resolution(resolution);
+impl Model {
+    pub fn temperature_c(&self, ohms: f32) -> f32 {
+        match *self {
+            Model::SteinhartHart { a, b, c } => {
+                // In f64: c is ~1e-7 against ln(R)^3 of ~1e3
+                let ln_r = log(ohms as f64);
+                let inv_t = a as f64 + b as f64 * ln_r + c as f64 * ln_r * ln_r * ln_r;
+                (1.0 / inv_t) as f32 - KELVIN_TO_CELSIUS
+            }
+            Model::Beta {
+                beta,
+                r0_ohms,
+                t0_c,
+            } => {
+                let inv_t = 1.0 / (t0_c + KELVIN_TO_CELSIUS) + logf(ohms / r0_ohms) / beta;
+                1.0 / inv_t - KELVIN_TO_CELSIUS
+            }
+        }
+    }
+}
 
-        Thermistor {
-            adc_pin,
+// Fits Steinhart-Hart coefficients exactly through three (temperature, resistance)
+// reference points, e.g. ice bath, room and body temperature against a reference
+// thermometer. The points should span the working range and be at least a few
+// degrees apart.
+pub fn fit_steinhart_hart(points: [(f32, f32); 3]) -> Result<Model, &'static str> {
+    if !points.iter().all(|&(_, ohms)| ohms > 0.0) {
+        return Err("Reference resistances must be positive");
+    }
+    let l = points.map(|(_, ohms)| log(ohms as f64));
+    let y = points.map(|(temp_c, _)| 1.0 / (temp_c as f64 + KELVIN_TO_CELSIUS as f64));
+    let (dl2, dl3) = (l[1] - l[0], l[2] - l[0]);
+    if dl2.abs() < 1e-3 || dl3.abs() < 1e-3 || (l[2] - l[1]).abs() < 1e-3 {
+        return Err("Reference points are too close together");
+    }
+
+    // Standard closed form: y = a + b L + c L^3 solved through divided differences
+    let g2 = (y[1] - y[0]) / dl2;
+    let g3 = (y[2] - y[0]) / dl3;
+    let c = (g3 - g2) / (l[2] - l[1]) / (l[0] + l[1] + l[2]);
+    let b = g2 - c * (l[0] * l[0] + l[0] * l[1] + l[1] * l[1]);
+    let a = y[0] - (b + c * l[0] * l[0]) * l[0];
+
+    let model = Model::SteinhartHart {
+        a: a as f32,
+        b: b as f32,
+        c: c as f32,
+    };
+    // Non-monotonic fits come from inconsistent points (e.g. swapped readings)
+    if b <= 0.0
+        || points
+            .iter()
+            .any(|&(_, ohms)| !model.temperature_c(ohms).is_finite())
+    {
+        return Err("Reference points do not describe an NTC thermistor");
+    }
+    Ok(model)
+}
+
+// Where the thermistor sits in the divider with the fixed series resistor
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum Divider {
+    // Thermistor from the ADC input to ground, series resistor to the reference
+    LowSide,
+    // Thermistor from the reference to the ADC input, series resistor to ground
+    HighSide,
+}
+
+impl Divider {
+    // Thermistor resistance from the ADC reading as a fraction of full scale
+    pub fn resistance_ohms(self, series_ohms: f32, ratio: f32) -> f32 {
+        match self {
+            Divider::LowSide => series_ohms * ratio / (1.0 - ratio),
+            Divider::HighSide => series_ohms * (1.0 / ratio - 1.0),
+        }
+    }
+
+    // An open thermistor pulls its node to the series resistor's rail, a shorted one to
+    // the opposite rail
+    pub fn probe_fault(self, ratio: f32) -> Option<&'static str> {
+        let (low_rail, high_rail) = match self {
+            Divider::LowSide => ("Thermistor short circuit", "Thermistor open circuit"),
+            Divider::HighSide => ("Thermistor open circuit", "Thermistor short circuit"),
+        };
+        if ratio <= RAIL_MARGIN {
+            Some(low_rail)
+        } else if ratio >= 1.0 - RAIL_MARGIN {
+            Some(high_rail)
+        } else {
+            None
+        }
+    }
+}
+
+// How the burst of conversions behind one reading is reduced to one value
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum Filter {
+    Mean,
+    // Ignores the odd conversion hit by a switching edge (the Peltier PWM, solenoids)
+    Median,
+}
+
+impl Filter {
+    pub fn apply(self, samples: &mut [u16]) -> f32 {
+        match self {
+            Filter::Mean => {
+                samples.iter().map(|&s| s as u32).sum::<u32>() as f32 / samples.len() as f32
+            }
+            Filter::Median => {
+                samples.sort_unstable();
+                let mid = samples.len() / 2;
+                if samples.len() % 2 == 0 {
+                    (samples[mid - 1] as f32 + samples[mid] as f32) / 2.0
+                } else {
+                    samples[mid] as f32
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Format)]
+pub struct ThermistorConfig {
+    pub model: Model,
+    pub divider: Divider,
+    pub series_ohms: f32,
+    // Supply of the divider when it is not VDDA, e.g. a separate 2.5 V reference. None
+    // for the usual divider across VDDA, whose reading does not depend on the supply.
+    pub excitation_v: Option<f32>,
+    pub filter: Filter,
+}
+
+impl Default for ThermistorConfig {
+    fn default() -> Self {
+        ThermistorConfig {
+            model: Model::Beta {
+                beta: DEFAULT_BETA,
+                r0_ohms: DEFAULT_R0_OHMS,
+                t0_c: DEFAULT_T0_C,
+            },
+            divider: Divider::LowSide,
+            series_ohms: DEFAULT_SERIES_OHMS,
+            excitation_v: None,
+            filter: Filter::Median,
+        }
+    }
+}
+
+// Where a probe of the thermistor bank sits
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum Probe {
+    ChamberAir,
+    // Shelf plate the cultureware stands on
+    Plate,
+    // Hot side of the Peltier module
+    Heatsink,
+    WaterJacket,
+}
+
+pub const PROBE_COUNT: usize = 4;
+
+impl Probe {
+    pub const ALL: [Probe; PROBE_COUNT] = [
+        Probe::ChamberAir,
+        Probe::Plate,
+        Probe::Heatsink,
+        Probe::WaterJacket,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Probe::ChamberAir => "air",
+            Probe::Plate => "plate",
+            Probe::Heatsink => "heatsink",
+            Probe::WaterJacket => "jacket",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Probe::ALL.into_iter().find(|probe| probe.name() == name)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Format)]
+pub struct ProbeReading {
+    pub ohms: f32,
+    pub temp_c: f32,
+}
+
+// One reading per probe, indexed like `Probe::ALL`
+pub type BankReading = [Result<ProbeReading, &'static str>; PROBE_COUNT];
+
+// Up to PROBE_COUNT thermistor dividers on ADC1, read with VREFINT in bursts of
+// DMA-driven conversions. VREFINT gives the actual VDDA, the ADC reference, which
+// corrects dividers run from another supply and is kept for diagnostics. ADC1 is the
+// only ADC with VREFINT.
+pub struct ThermistorBank<'d> {
+    adc: RingBufferedAdc<'d, ADC1>,
+    // Sequence slot of each probe
+    slots: [Option<usize>; PROBE_COUNT],
+    configs: [ThermistorConfig; PROBE_COUNT],
+    supply_v: f32,
+}
+
+impl<'d> ThermistorBank<'d> {
+    // Each probe may appear once; all start with the default configuration
+    pub fn new<const N: usize>(
+        mut adc: Adc<'d, ADC1>,
+        probes: [(Probe, AnyAdcChannel<ADC1>); N],
+        dma: impl Peripheral<P = impl RxDma<ADC1>> + 'd,
+        dma_buf: &'d mut [u16; DMA_BUFFER_LEN],
+    ) -> Self {
+        assert!(N <= PROBE_COUNT);
+        adc.set_resolution(Resolution::BITS12);
+        let mut vrefint = adc.enable_vrefint();
+        let mut adc = adc.into_ring_buffered(dma, dma_buf);
+        // The longest sample time: the dividers are 10 kΩ sources and VREFINT needs 10 µs
+        for slot in 0..SEQUENCE_LEN {
+            adc.set_sample_sequence(sequence(slot), &mut vrefint, SampleTime::CYCLES480);
+        }
+        let mut slots = [None; PROBE_COUNT];
+        for (i, (probe, mut channel)) in probes.into_iter().enumerate() {
+            adc.set_sample_sequence(sequence(i + 1), &mut channel, SampleTime::CYCLES480);
+            slots[probe as usize] = Some(i + 1);
+        }
+
+        ThermistorBank {
             adc,
-            resolution,
+            slots,
+            configs: [ThermistorConfig::default(); PROBE_COUNT],
+            supply_v: VREFINT_CAL_VDDA,
         }
     }
 
-    fn get_thermistor_resistance(&mut self) -> f32 {
-        let adc_value = self.adc.blocking_read(&mut self.adc_pin) as f32;
-        info!("adc val {}", adc_value);
+    pub fn set_config(&mut self, probe: Probe, config: ThermistorConfig) {
+        self.configs[probe as usize] = config;
+    }
 
-        let adc_max = match self.resolution {
-            Resolution::BITS12 => 4095.0, // 2^12 - 1
-            Resolution::BITS10 => 1023.0, // 2^10 - 1
-            Resolution::BITS8 => 255.0,   // 2^8 - 1
-            Resolution::BITS6 => 63.0,    // 2^6 - 1
-        };
+    // VDDA as measured at the last reading
+    pub fn supply_v(&self) -> f32 {
+        self.supply_v
+    }
+
+    // Reads every probe; the error is for the acquisition as a whole
+    pub async fn read(&mut self) -> Result<BankReading, DeviceError> {
+        let mut frames = [0u16; SAMPLES * SEQUENCE_LEN];
+        let result = self.fill(&mut frames).await;
+        // Converting only while a reading is taken; the next read starts it again
+        self.adc.teardown_adc();
+        result?;
+
+        let mut samples = [[0u16; SAMPLES]; SEQUENCE_LEN];
+        for (i, frame) in frames.chunks_exact(SEQUENCE_LEN).enumerate() {
+            for (slot, &sample) in frame.iter().enumerate() {
+                samples[slot][i] = sample;
+            }
+        }
+        let vrefint_raw = Filter::Mean.apply(&mut samples[0]);
+        // Read-only factory data, always present on the F4
+        let vrefint_cal = unsafe { core::ptr::read_volatile(VREFINT_CAL_ADDR) };
+        if vrefint_raw > 0.0 {
+            self.supply_v = VREFINT_CAL_VDDA * vrefint_cal as f32 / vrefint_raw;
+        }
+
+        let mut reading: BankReading = [Err("Probe not fitted"); PROBE_COUNT];
+        for (i, slot) in self.slots.iter().enumerate() {
+            if let Some(slot) = *slot {
+                let raw = self.configs[i].filter.apply(&mut samples[slot]);
+                reading[i] = self.convert(&self.configs[i], raw);
+            }
+        }
+        Ok(reading)
+    }
 
-        THERMISTOR_R_DIVIDER * (adc_value / (adc_max - adc_value))
-        // THERMISTOR_R_DIVIDER * (adc_max / adc_value - 1.0) //config 2
+    // A read returns what the DMA has written so far, which is less than a full set of
+    // frames until the half-buffer fills. The conversions arrive in scan order, so the
+    // reads are joined until `frames` is full; only an overrun breaks the sequence.
+    async fn fill(
+        &mut self,
+        frames: &mut [u16; SAMPLES * SEQUENCE_LEN],
+    ) -> Result<(), DeviceError> {
+        let mut chunk = [0u16; SAMPLES * SEQUENCE_LEN];
+        let mut filled = 0;
+        while filled < frames.len() {
+            let count = self
+                .adc
+                .read(&mut chunk)
+                .await
+                .map_err(|_| DeviceError::other("Thermistor ADC overrun"))?;
+            let take = count.min(frames.len() - filled);
+            frames[filled..filled + take].copy_from_slice(&chunk[..take]);
+            filled += take;
+        }
+        Ok(())
     }
 
-    pub fn get_temperature_celsius(&mut self) -> f32 {
-        let resistance = self.get_thermistor_resistance();
+    fn convert(&self, config: &ThermistorConfig, raw: f32) -> Result<ProbeReading, &'static str> {
+        // Node voltage as a fraction of the divider's supply
+        let ratio = match config.excitation_v {
+            Some(excitation_v) => raw / ADC_MAX * self.supply_v / excitation_v,
+            None => raw / ADC_MAX,
+        };
+        if let Some(probe_fault) = config.divider.probe_fault(ratio) {
+            return Err(probe_fault);
+        }
+        let ohms = config.divider.resistance_ohms(config.series_ohms, ratio);
+        Ok(ProbeReading {
+            ohms,
+            temp_c: config.model.temperature_c(ohms),
+        })
+    }
+}
 
-        // Steinhart-Hart equation (simplified beta equation)
-        // 1/T = 1/T0 + (1/beta) * ln(R/R0)
-        let temp_kelvin = 1.0
-            / (1.0 / THERMISTOR_T0 + (1.0 / THERMISTOR_BETA) * logf(resistance / THERMISTOR_R0));
+fn sequence(slot: usize) -> Sequence {
+    match slot {
+        0 => Sequence::One,
+        1 => Sequence::Two,
+        2 => Sequence::Three,
+        3 => Sequence::Four,
+        _ => Sequence::Five,
+    }
+}
 
-        // Convert Kelvin to Celsius
-        temp_kelvin - KELVIN_TO_CELSIUS
+// Temperature of `probe` less that of `reference`, when both read
+pub fn gradient_c(reading: &BankReading, probe: Probe, reference: Probe) -> Option<f32> {
+    match (reading[probe as usize], reading[reference as usize]) {
+        (Ok(probe), Ok(reference)) => Some(probe.temp_c - reference.temp_c),
+        _ => None,
     }
 }
@@ -0,0 +1,143 @@
+use defmt::Format;
+use embassy_time::{Duration, Instant};
+use libm::sqrtf;
+
+// Chamber temperature from the SCD41 and the chamber air thermistor.
+//
+// A two-state Kalman filter tracks the temperature and the SCD41's offset from it: the
+// SCD41 warms itself and reads high by an amount that depends on airflow, while the
+// thermistor is fast and calibrated but needs the bank to be fitted and readable. The
+// thermistor measures the temperature, the SCD41 the temperature plus its offset.
+// While both report, the offset is learned, so the SCD41 alone still gives a corrected
+// temperature if the thermistor fails; the thermistor alone simply carries on.
+//
+// A sensor that has not reported for its stale time no longer counts, and without
+// either there is no estimate.
+
+// Random walk of the chamber temperature and of the SCD41 offset, variance per second
+const TEMP_DRIFT_C2_PER_S: f32 = 0.001;
+const OFFSET_DRIFT_C2_PER_S: f32 = 0.000_001;
+// Measurement noise
+const THERMISTOR_VARIANCE_C2: f32 = 0.05 * 0.05;
+const SCD41_VARIANCE_C2: f32 = 0.1 * 0.1;
+// Before the first reading; the SCD41 offset is within about ±1 °C
+const INITIAL_TEMP_VARIANCE_C2: f32 = 100.0;
+const INITIAL_OFFSET_VARIANCE_C2: f32 = 1.0;
+// The bank is read at least every 10 s, the SCD41 once per control cycle
+const THERMISTOR_STALE: Duration = Duration::from_secs(30);
+const SCD41_STALE: Duration = Duration::from_secs(120);
+
+// Sensors behind the estimate
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum TempSource {
+    Fused,
+    Thermistor,
+    Scd41,
+}
+
+impl TempSource {
+    pub fn name(self) -> &'static str {
+        match self {
+            TempSource::Fused => "fused",
+            TempSource::Thermistor => "thermistor",
+            TempSource::Scd41 => "scd41",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Format)]
+pub struct Estimate {
+    pub temp_c: f32,
+    // One standard deviation, grown by the time since the last reading
+    pub uncertainty_c: f32,
+    pub source: TempSource,
+    // What the SCD41 reads above the estimate
+    pub scd41_offset_c: f32,
+}
+
+pub struct TemperatureFusion {
+    // Temperature and SCD41 offset, and their covariance
+    x: [f32; 2],
+    p: [[f32; 2]; 2],
+    updated_at: Option<Instant>,
+    thermistor_at: Option<Instant>,
+    scd41_at: Option<Instant>,
+}
+
+impl TemperatureFusion {
+    pub const fn new() -> Self {
+        TemperatureFusion {
+            x: [0.0; 2],
+            p: [
+                [INITIAL_TEMP_VARIANCE_C2, 0.0],
+                [0.0, INITIAL_OFFSET_VARIANCE_C2],
+            ],
+            updated_at: None,
+            thermistor_at: None,
+            scd41_at: None,
+        }
+    }
+
+    pub fn thermistor(&mut self, temp_c: f32) {
+        self.update([1.0, 0.0], temp_c, THERMISTOR_VARIANCE_C2);
+        self.thermistor_at = self.updated_at;
+    }
+
+    pub fn scd41(&mut self, temp_c: f32) {
+        self.update([1.0, 1.0], temp_c, SCD41_VARIANCE_C2);
+        self.scd41_at = self.updated_at;
+    }
+
+    pub fn estimate(&self) -> Option<Estimate> {
+        let now = Instant::now();
+        let fresh = |at: Option<Instant>, stale| at.is_some_and(|at| now - at <= stale);
+        let source = match (
+            fresh(self.thermistor_at, THERMISTOR_STALE),
+            fresh(self.scd41_at, SCD41_STALE),
+        ) {
+            (true, true) => TempSource::Fused,
+            (true, false) => TempSource::Thermistor,
+            (false, true) => TempSource::Scd41,
+            (false, false) => return None,
+        };
+        let variance = self.p[0][0] + TEMP_DRIFT_C2_PER_S * self.elapsed_s(now);
+        Some(Estimate {
+            temp_c: self.x[0],
+            uncertainty_c: sqrtf(variance),
+            source,
+            scd41_offset_c: self.x[1],
+        })
+    }
+
+    fn elapsed_s(&self, now: Instant) -> f32 {
+        self.updated_at
+            .map_or(0.0, |at| (now - at).as_millis() as f32 / 1000.0)
+    }
+
+    // Predicts to now, then corrects with measurement `z` of `h`·x
+    fn update(&mut self, h: [f32; 2], z: f32, variance: f32) {
+        let now = Instant::now();
+        if self.updated_at.is_none() {
+            // Start from the first reading rather than pulling in from 0 °C
+            self.x[0] = z;
+        }
+        let dt = self.elapsed_s(now);
+        self.p[0][0] += TEMP_DRIFT_C2_PER_S * dt;
+        self.p[1][1] += OFFSET_DRIFT_C2_PER_S * dt;
+        self.updated_at = Some(now);
+
+        let ph = [
+            self.p[0][0] * h[0] + self.p[0][1] * h[1],
+            self.p[1][0] * h[0] + self.p[1][1] * h[1],
+        ];
+        let s = h[0] * ph[0] + h[1] * ph[1] + variance;
+        let k = [ph[0] / s, ph[1] / s];
+        let innovation = z - (h[0] * self.x[0] + h[1] * self.x[1]);
+        for ((x, p_row), k) in self.x.iter_mut().zip(self.p.iter_mut()).zip(k) {
+            *x += k * innovation;
+            for (p, ph) in p_row.iter_mut().zip(ph) {
+                *p -= k * ph;
+            }
+        }
+    }
+}
@@ -0,0 +1,742 @@
+use core::fmt::Write;
+
+use chrono::DateTime;
+use defmt::{info, warn};
+use embassy_time::Instant;
+use heapless::String;
+use icbm_protocol::profile::{self as setpoint_profile, Command, RunState, Segment, Variable};
+
+use super::{write_all, Disconnected, SerialClass, MAX_PACKET_SIZE};
+use crate::diagnostics::{self, Device};
+use crate::drivers::thermistor::{self, Divider, Model, Probe};
+use crate::net::sntp;
+use crate::profile;
+use crate::rtc;
+use crate::state::{self, Reply, ReplySignal, Request, Screen};
+use crate::storage::config::{Config, ThermalActuator};
+
+const LINE_BUFFER_SIZE: usize = 96;
+const OUTPUT_BUFFER_SIZE: usize = 768;
+const PROMPT: &str = "icbm> ";
+
+const HELP: &str = "\
+Commands:\r
+  help                      show this help\r
+  read                      current readings\r
+  config                    dump the active configuration\r
+  set <key> <value>         change and persist a configuration value\r
+  config reset              restore default configuration\r
+  calibrate                 run the sensor calibration (~5 min); heating, cooling,\r
+                            CO2 and humidity control are off until it is done\r
+  selftest                  run the sensor self-tests\r
+  diag                      read counters, self-tests and serial numbers per device\r
+  screen readings|diag      what the display shows\r
+  callog                    list stored calibration records\r
+  log                       data log status (download with the host tool)\r
+  sd                        SD card status\r
+  time                      wall-clock time (UTC)\r
+  time set <date>T<time>    set the clock, e.g. time set 2024-05-01T12:00:00\r
+  ntp                       SNTP clock sync status\r
+  sd newrun                 start a new SD card run directory\r
+  profile                   setpoint profile status and segments\r
+  profile add <segment>     append a segment, e.g. profile add temp ramp 38.5 120\r
+                            (temp|co2 hold <min> | ramp|step <target> <min>)\r
+  profile clear             remove all segments\r
+  profile start [<time>]    start now or at a time, e.g. 2024-05-01T08:00:00\r
+  profile pause|resume|stop\r
+  peltier clear             re-enable the Peltier bridge after a fault\r
+  valve reset               zero the CO2 valve wear counters after replacing it\r
+  probes                    thermistor bank readings and calibration\r
+  probe <name> fit <t1> <ohms1> <t2> <ohms2> <t3> <ohms3>\r
+                            fit a probe to three reference points (C, ohms)\r
+  probe <name> coeffs <a> <b> <c> | stock\r
+                            set Steinhart-Hart coefficients or the stock curve\r
+  probe <name> beta <beta> <r0> <t0>\r
+                            use a beta curve, R0 ohms at T0 (C)\r
+  probe <name> lowside|highside\r
+                            thermistor to ground or to the reference\r
+                            (probes: air, plate, heatsink, jacket)\r
+Keys: co2, temp, co2tol, temptol, burst, calref, calpressure, calaltitude,\r
+      calco2pressure, calco2ref, mqttbroker (a.b.c.d or off), mqttport, mqtttopic,\r
+      httptoken (or off), modbusunit, ntpserver (a.b.c.d or off), ntpinterval (min),\r
+      rh, rhtol, rhalarm (0 disables the alarm), humidifier (on or off),\r
+      actuator (heater or peltier), peltierfreq (Hz), peltierdead (ms),\r
+      peltierramp (%/s), sinkmax (C), co2check (%, 0 disables),\r
+      doorswitch (on or off), doordrop (%, 0 disables), doorboost (1-4),\r
+      co2psensor (on or off), co2pfull (bar), co2pmin (bar),\r
+      valvepullin (ms), valvehold (%, 100 disables the hold reduction)\r
+";
+
+static REPLY: ReplySignal = ReplySignal::new();
+
+// Line-oriented command console on the first USB CDC-ACM interface
+#[embassy_executor::task]
+pub async fn console_task(mut class: SerialClass) -> ! {
+    loop {
+        class.wait_connection().await;
+        info!("Console connected");
+        let _ = run(&mut class).await;
+        info!("Console disconnected");
+    }
+}
+
+async fn run(class: &mut SerialClass) -> Result<(), Disconnected> {
+    let mut line: String<LINE_BUFFER_SIZE> = String::new();
+    let mut packet = [0u8; MAX_PACKET_SIZE as usize];
+
+    write(
+        class,
+        "\r\nIon Concentration Bio-Modulator console, type 'help'\r\n",
+    )
+    .await?;
+    write(class, PROMPT).await?;
+
+    loop {
+        let n = class.read_packet(&mut packet).await?;
+        for &byte in &packet[..n] {
+            match byte {
+                b'\r' | b'\n' => {
+                    write(class, "\r\n").await?;
+                    if !line.is_empty() {
+                        execute(class, line.trim()).await?;
+                        line.clear();
+                    }
+                    write(class, PROMPT).await?;
+                }
+                // Backspace / delete
+                0x08 | 0x7F => {
+                    if line.pop().is_some() {
+                        write(class, "\x08 \x08").await?;
+                    }
+                }
+                0x20..=0x7E => {
+                    if line.push(byte as char).is_ok() {
+                        class.write_packet(&[byte]).await?;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+async fn write(class: &mut SerialClass, data: &str) -> Result<(), Disconnected> {
+    write_all(class, data.as_bytes()).await
+}
+
+// Output that does not fit into the buffer is truncated
+async fn execute(class: &mut SerialClass, line: &str) -> Result<(), Disconnected> {
+    let mut out: String<OUTPUT_BUFFER_SIZE> = String::new();
+    let out = &mut out;
+    let mut args = line.split_whitespace();
+    let command = args.next().unwrap_or("");
+
+    match (command, args.next(), args.next()) {
+        ("help", None, None) => return write(class, HELP).await,
+        ("read", None, None) => print_readings(out),
+        ("config", None, None) => print_config(&state::config(), out),
+        ("config", Some("reset"), None) => {
+            finish(state::request(Request::ResetConfig, &REPLY).await, out)
+        }
+        ("set", Some(key), Some(value)) => {
+            let mut config = state::config();
+            match config.set(key, value) {
+                Ok(()) => finish(
+                    state::request(Request::SetConfig(config), &REPLY).await,
+                    out,
+                ),
+                Err(e) => {
+                    let _ = write!(out, "error: {}\r\n", e);
+                }
+            }
+        }
+        ("calibrate", None, None) => {
+            info!("Calibration requested from console");
+            finish(state::request(Request::Calibrate, &REPLY).await, out)
+        }
+        ("selftest", None, None) => finish(state::request(Request::SelfTest, &REPLY).await, out),
+        ("callog", None, None) => return print_calibration_log(class).await,
+        ("diag", None, None) => return print_diagnostics(class).await,
+        ("screen", Some(name), None) => {
+            let screen = match name {
+                "readings" => Some(Screen::Readings),
+                "diag" => Some(Screen::Diagnostics),
+                _ => None,
+            };
+            let _ = match screen {
+                Some(screen) => {
+                    state::set_screen(screen);
+                    out.write_str("ok\r\n")
+                }
+                None => out.write_str("error: expected readings or diag\r\n"),
+            };
+        }
+        ("log", None, None) => finish(state::request(Request::LogInfo, &REPLY).await, out),
+        ("time", None, None) => print_time(out),
+        ("time", Some("set"), Some(value)) => match rtc::parse(value) {
+            Some(datetime) => match rtc::set(datetime, rtc::Source::Host) {
+                Ok(()) => print_time(out),
+                Err(e) => {
+                    let _ = write!(out, "error: {}\r\n", e);
+                }
+            },
+            None => {
+                let _ = out.write_str("error: expected YYYY-MM-DDTHH:MM:SS\r\n");
+            }
+        },
+        ("ntp", None, None) => print_sync_status(out),
+        ("sd", None, None) => finish(state::request(Request::SdStatus, &REPLY).await, out),
+        ("sd", Some("newrun"), None) => finish(state::request(Request::NewRun, &REPLY).await, out),
+        ("peltier", Some("clear"), None) => {
+            finish(state::request(Request::ClearBridgeFault, &REPLY).await, out)
+        }
+        ("valve", Some("reset"), None) => {
+            finish(state::request(Request::ResetValveWear, &REPLY).await, out)
+        }
+        ("probes", None, None) => print_probes(out),
+        ("probe", Some(name), Some(action)) => match calibrate_probe(name, action, args) {
+            Ok(config) => finish(
+                state::request(Request::SetConfig(config), &REPLY).await,
+                out,
+            ),
+            Err(e) => {
+                let _ = write!(out, "error: {}\r\n", e);
+            }
+        },
+        ("profile", None, None) => return print_profile(class).await,
+        ("profile", Some("add"), Some(_)) => {
+            let segment = line.split_once("add").map_or("", |(_, rest)| rest);
+            match setpoint_profile::parse_segment(segment) {
+                Ok((variable, segment)) => finish(
+                    state::request(Request::AddProfileSegment { variable, segment }, &REPLY).await,
+                    out,
+                ),
+                Err(e) => {
+                    let _ = write!(out, "error: {}\r\n", e);
+                }
+            }
+        }
+        ("profile", Some(action), at) => {
+            let request = match (action, at) {
+                ("clear", None) => Request::ClearProfile,
+                ("start", None) => Request::ProfileControl(Command::Start { at: None }),
+                ("start", Some(at)) => match rtc::parse(at) {
+                    Some(at) => Request::ProfileControl(Command::Start {
+                        at: Some(at.and_utc().timestamp() as u32),
+                    }),
+                    None => {
+                        let _ = out.write_str("error: expected YYYY-MM-DDTHH:MM:SS\r\n");
+                        return write(class, out).await;
+                    }
+                },
+                ("pause", None) => Request::ProfileControl(Command::Pause),
+                ("resume", None) => Request::ProfileControl(Command::Resume),
+                ("stop", None) => Request::ProfileControl(Command::Stop),
+                _ => {
+                    let _ = write!(out, "unknown command '{}', type 'help'\r\n", line);
+                    return write(class, out).await;
+                }
+            };
+            finish(state::request(request, &REPLY).await, out)
+        }
+        _ => {
+            let _ = write!(out, "unknown command '{}', type 'help'\r\n", line);
+        }
+    }
+    write(class, out).await
+}
+
+fn finish(reply: Reply, out: &mut String<OUTPUT_BUFFER_SIZE>) {
+    let _ = match reply {
+        Reply::Done => out.write_str("ok\r\n"),
+        Reply::Error(e) => write!(out, "error: {}\r\n", e),
+        Reply::SelfTest { scd41, explorir } => write!(
+            out,
+            "SCD41: {}\r\nExplorIR: {}\r\n",
+            pass_fail(scd41),
+            pass_fail(explorir)
+        ),
+        Reply::Calibrated { seq } => write!(out, "calibration stored as record #{}\r\n", seq),
+        Reply::LogInfo {
+            blocks,
+            first_seq,
+            last_seq,
+        } => write!(
+            out,
+            "data log: {} blocks (#{} - #{})\r\n",
+            blocks, first_seq, last_seq
+        ),
+        Reply::SdStatus(sd) if sd.mounted => write!(
+            out,
+            "sd: RUN{:04}/{}, {} lines, {} errors\r\n",
+            sd.run,
+            sd.file.as_str(),
+            sd.lines,
+            sd.errors
+        ),
+        Reply::SdStatus(sd) => write!(out, "sd: no card, {} errors\r\n", sd.errors),
+        Reply::CalibrationEntry { .. } | Reply::LogChunk(_) => {
+            warn!("Unexpected reply");
+            out.write_str("error: unexpected reply\r\n")
+        }
+    };
+}
+
+fn pass_fail(passed: bool) -> &'static str {
+    if passed {
+        "pass"
+    } else {
+        "FAIL"
+    }
+}
+
+fn print_readings(out: &mut String<OUTPUT_BUFFER_SIZE>) {
+    let readings = state::readings();
+    let _ = match (
+        readings.temp_c,
+        readings.temp_uncertainty_c,
+        readings.temp_source,
+    ) {
+        (Some(temp), Some(uncertainty), Some(source)) => write!(
+            out,
+            "temp:    {:.2} C +/- {:.2} ({})\r\n",
+            temp,
+            uncertainty,
+            source.name()
+        ),
+        _ => out.write_str("temp:    n/a\r\n"),
+    };
+    let _ = match readings.co2_ppm {
+        Some(co2) => write!(out, "co2:     {:.0} ppm", co2),
+        None => out.write_str("co2:     n/a"),
+    };
+    let _ = match readings.scd41_co2_ppm {
+        Some(co2) => write!(out, " (scd41 {:.0} ppm)\r\n", co2),
+        None => out.write_str("\r\n"),
+    };
+    let _ = match readings.humidity_rh {
+        Some(rh) => write!(out, "rh:      {:.1} %\r\n", rh),
+        None => out.write_str("rh:      n/a\r\n"),
+    };
+    if state::config().thermal_actuator == ThermalActuator::Peltier {
+        let _ = write!(out, "peltier: {:+.0} %", readings.peltier_output_pct);
+        let _ = match readings.peltier_current_a {
+            Some(current) => write!(out, ", {:.2} A\r\n", current),
+            None => out.write_str("\r\n"),
+        };
+    }
+    if let Some(fault) = readings.bridge_fault {
+        let _ = write!(out, "bridge:  {}, disabled\r\n", fault.name());
+    }
+    let _ = write!(out, "door:    {}\r\n", readings.door.name());
+    if let Some(bar) = readings.co2_supply_bar {
+        let _ = write!(out, "supply:  {:.1} bar\r\n", bar);
+    }
+    if let Some(response) = readings.co2_response_ppm_s {
+        let _ = write!(out, "dosing:  {:.0} ppm per s of burst\r\n", response);
+    }
+    let _ = write!(
+        out,
+        "valve:   {} openings, {:.1} h open\r\n",
+        readings.valve_wear.actuations,
+        readings.valve_wear.on_time_ms as f32 / 3_600_000.0
+    );
+    let _ = write!(
+        out,
+        "heater:  {}\r\nhumid:   {}\r\nbursts:  {}\r\nupdated: {} s\r\n",
+        if readings.heater_on { "on" } else { "off" },
+        if readings.humidifier_on { "on" } else { "off" },
+        readings.co2_bursts,
+        readings.updated_at_s
+    );
+    if readings.alarms != 0 {
+        let _ = write!(out, "alarms:  {:#x}", readings.alarms);
+        let _ = match readings.alarm_raised_at {
+            Some(at) => write!(out, " since {} UTC\r\n", at),
+            None => out.write_str("\r\n"),
+        };
+    }
+}
+
+fn print_probes(out: &mut String<OUTPUT_BUFFER_SIZE>) {
+    let readings = state::readings();
+    let config = state::config();
+    for probe in Probe::ALL {
+        let _ = write!(out, "{:<9} ", probe.name());
+        let _ = match readings.probes[probe as usize] {
+            Ok(reading) => write!(out, "{:.2} C, {:.0} ohms", reading.temp_c, reading.ohms),
+            Err(e) => out.write_str(e),
+        };
+        if probe != Probe::ChamberAir {
+            if let Some(gradient) =
+                thermistor::gradient_c(&readings.probes, probe, Probe::ChamberAir)
+            {
+                let _ = write!(out, ", {:+.2} C from air", gradient);
+            }
+        }
+        if config.probe_divider[probe as usize] == Divider::HighSide {
+            let _ = out.write_str(", high side");
+        }
+        let _ = match config.probe_calibration[probe as usize] {
+            Some(Model::SteinhartHart { a, b, c }) => {
+                write!(out, "\r\n          fitted: {:e} {:e} {:e}\r\n", a, b, c)
+            }
+            Some(Model::Beta {
+                beta,
+                r0_ohms,
+                t0_c,
+            }) => write!(
+                out,
+                "\r\n          beta {:.0}, {:.0} ohms at {:.1} C\r\n",
+                beta, r0_ohms, t0_c
+            ),
+            None => out.write_str(", stock\r\n"),
+        };
+    }
+}
+
+// Configuration with a probe's model or divider leg replaced, from
+// `probe <name> <action> ...`
+fn calibrate_probe<'a>(
+    name: &str,
+    action: &str,
+    args: impl Iterator<Item = &'a str>,
+) -> Result<Config, &'static str> {
+    let probe = Probe::from_name(name).ok_or("unknown probe, see help")?;
+    let mut values = [0.0f32; 6];
+    let mut count = 0;
+    for arg in args {
+        let value = values.get_mut(count).ok_or("too many values")?;
+        *value = arg.parse().map_err(|_| "value is not a number")?;
+        count += 1;
+    }
+    let mut config = state::config();
+    let calibration = &mut config.probe_calibration[probe as usize];
+    let divider = &mut config.probe_divider[probe as usize];
+    match (action, count) {
+        ("stock", 0) => *calibration = None,
+        ("coeffs", 3) => {
+            *calibration = Some(Model::SteinhartHart {
+                a: values[0],
+                b: values[1],
+                c: values[2],
+            })
+        }
+        ("beta", 3) => {
+            *calibration = Some(Model::Beta {
+                beta: values[0],
+                r0_ohms: values[1],
+                t0_c: values[2],
+            })
+        }
+        ("fit", 6) => {
+            *calibration = Some(thermistor::fit_steinhart_hart([
+                (values[0], values[1]),
+                (values[2], values[3]),
+                (values[4], values[5]),
+            ])?)
+        }
+        ("lowside", 0) => *divider = Divider::LowSide,
+        ("highside", 0) => *divider = Divider::HighSide,
+        _ => {
+            return Err("expected fit with three points, coeffs <a> <b> <c>, \
+                        beta <beta> <r0> <t0>, stock, lowside or highside")
+        }
+    }
+    config.validate()?;
+    Ok(config)
+}
+
+fn print_time(out: &mut String<OUTPUT_BUFFER_SIZE>) {
+    let _ = match rtc::now() {
+        Some(now) => write!(out, "time:    {} UTC\r\n", now),
+        None => out.write_str("time:    not set\r\n"),
+    };
+    let _ = write!(out, "uptime:  {} s\r\n", Instant::now().as_secs());
+}
+
+fn print_sync_status(out: &mut String<OUTPUT_BUFFER_SIZE>) {
+    let status = sntp::status();
+    let [a, b, c, d] = status.server;
+    if status.server == [0; 4] {
+        let _ = out.write_str("ntp:     off\r\n");
+        return;
+    }
+    let _ = write!(out, "server:  {}.{}.{}.{}\r\n", a, b, c, d);
+    let _ = match status.last_sync {
+        Some(at) => write!(
+            out,
+            "synced:  {} s ago, stratum {}, delay {} ms\r\n",
+            at.elapsed().as_secs(),
+            status.stratum,
+            status.delay_ms
+        ),
+        None => out.write_str("synced:  never\r\n"),
+    };
+    if let Some(offset) = status.offset_ms {
+        let _ = write!(out, "offset:  {} ms\r\n", offset);
+    }
+    if let Some(drift) = status.drift_ppm {
+        let _ = write!(out, "drift:   {:.1} ppm\r\n", drift);
+    }
+    let _ = write!(
+        out,
+        "syncs:   {}, {} failed\r\n",
+        status.syncs, status.failures
+    );
+}
+
+fn print_config(config: &Config, out: &mut String<OUTPUT_BUFFER_SIZE>) {
+    let _ = write!(
+        out,
+        "co2            {:.0} ppm\r\n\
+         temp           {:.2} C\r\n\
+         co2tol         {:.0} ppm\r\n\
+         temptol        {:.2} C\r\n\
+         burst          {} ms\r\n\
+         calref         {:.2} C\r\n\
+         calpressure    {} Pa\r\n\
+         calaltitude    {} m\r\n\
+         calco2pressure {:.2} mbar\r\n\
+         calco2ref      {} ppm\r\n\
+         mqttbroker     {}.{}.{}.{}\r\n\
+         mqttport       {}\r\n\
+         mqtttopic      {}\r\n\
+         httptoken      {}\r\n\
+         modbusunit     {}\r\n\
+         ntpserver      {}.{}.{}.{}\r\n\
+         ntpinterval    {} min\r\n\
+         rh             {:.1} %RH\r\n\
+         rhtol          {:.1} %RH\r\n\
+         rhalarm        {:.1} %RH\r\n\
+         humidifier     {}\r\n\
+         actuator       {}\r\n\
+         peltierfreq    {} Hz\r\n\
+         peltierdead    {} ms\r\n\
+         peltierramp    {:.0} %/s\r\n\
+         sinkmax        {:.1} C\r\n\
+         co2check       {:.0} %\r\n\
+         doorswitch     {}\r\n\
+         doordrop       {:.0} %\r\n\
+         doorboost      {:.1}\r\n\
+         co2psensor     {}\r\n\
+         co2pfull       {:.1} bar\r\n\
+         co2pmin        {:.1} bar\r\n\
+         valvepullin    {} ms\r\n\
+         valvehold      {:.0} %\r\n",
+        config.target_co2_ppm,
+        config.target_temp_c,
+        config.co2_tolerance_ppm,
+        config.temp_tolerance_c,
+        config.co2_burst_ms,
+        config.cal_reference_temp_c,
+        config.cal_pressure_pa,
+        config.cal_altitude_m,
+        config.cal_co2_pressure_mbar,
+        config.cal_co2_reference_ppm,
+        config.mqtt_broker[0],
+        config.mqtt_broker[1],
+        config.mqtt_broker[2],
+        config.mqtt_broker[3],
+        config.mqtt_port,
+        config.mqtt_topic.as_str(),
+        // The token itself is never echoed
+        if config.http_token.is_empty() {
+            "off"
+        } else {
+            "set"
+        },
+        config.modbus_unit,
+        config.ntp_server[0],
+        config.ntp_server[1],
+        config.ntp_server[2],
+        config.ntp_server[3],
+        config.ntp_interval_min,
+        config.target_humidity_rh,
+        config.humidity_tolerance_rh,
+        config.humidity_alarm_rh,
+        if config.humidifier_enabled {
+            "on"
+        } else {
+            "off"
+        },
+        config.thermal_actuator.name(),
+        config.peltier_pwm_hz,
+        config.peltier_dead_time_ms,
+        config.peltier_ramp_pct_s,
+        config.heatsink_max_c,
+        config.co2_crosscheck_pct,
+        if config.door_switch { "on" } else { "off" },
+        config.door_drop_pct,
+        config.door_boost,
+        if config.co2_pressure_sensor {
+            "on"
+        } else {
+            "off"
+        },
+        config.co2_pressure_full_bar,
+        config.co2_pressure_min_bar,
+        config.valve_pull_in_ms,
+        config.valve_hold_pct,
+    );
+}
+
+// Status followed by the segments, written one at a time since a full profile does not
+// fit the output buffer
+async fn print_profile(class: &mut SerialClass) -> Result<(), Disconnected> {
+    let status = profile::status();
+    let mut out: String<OUTPUT_BUFFER_SIZE> = String::new();
+    let _ = write!(out, "profile: {}", status.state.name());
+    match status.state {
+        RunState::Idle => {}
+        RunState::Waiting => {
+            let starts = status
+                .starts_at
+                .and_then(|at| DateTime::from_timestamp(at as i64, 0));
+            if let Some(starts) = starts {
+                let _ = write!(out, ", starts {} UTC", starts.naive_utc());
+            }
+        }
+        _ => {
+            let _ = out.write_str(", ");
+            write_hms(&mut out, status.elapsed_s);
+            let _ = out.write_str(" of ");
+            write_hms(&mut out, status.duration_s);
+        }
+    }
+    let _ = out.write_str("\r\n");
+    let setpoints = [status.temp_c, status.co2_ppm];
+    for variable in Variable::ALL {
+        let i = variable as usize;
+        let _ = write!(
+            out,
+            "{:<8} {:.2} {}",
+            variable.name(),
+            setpoints[i],
+            variable.unit()
+        );
+        if status.state.is_active() {
+            let _ = write!(
+                out,
+                ", segment {}/{}",
+                (status.segment[i] + 1).min(status.segments[i]),
+                status.segments[i]
+            );
+        }
+        let _ = out.write_str("\r\n");
+    }
+    write(class, &out).await?;
+
+    for variable in Variable::ALL {
+        let mut index = 0;
+        while let Some((segment, _)) = profile::segment(variable, index) {
+            let mut out: String<OUTPUT_BUFFER_SIZE> = String::new();
+            let _ = write!(out, "  {} {}: ", variable.name(), index + 1);
+            let _ = match segment {
+                Segment::Hold { .. } => out.write_str("hold"),
+                Segment::Ramp { target, .. } => {
+                    write!(out, "ramp to {:.2} {}", target, variable.unit())
+                }
+                Segment::Step { target, .. } => {
+                    write!(out, "step to {:.2} {}", target, variable.unit())
+                }
+            };
+            let _ = out.write_str(" for ");
+            write_hms(&mut out, segment.duration_s());
+            let _ = out.write_str("\r\n");
+            write(class, &out).await?;
+            index += 1;
+        }
+    }
+    Ok(())
+}
+
+fn write_hms(out: &mut String<OUTPUT_BUFFER_SIZE>, seconds: u32) {
+    let _ = write!(
+        out,
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    );
+}
+
+// Entries are written one at a time since the log can hold hundreds of records
+async fn print_diagnostics(class: &mut SerialClass) -> Result<(), Disconnected> {
+    for device in Device::ALL {
+        let health = diagnostics::health(device);
+        let mut out: String<OUTPUT_BUFFER_SIZE> = String::new();
+        let _ = write!(
+            out,
+            "{:<12} {} reads, {} timeouts, {} CRC, {} parse, {} other errors\r\n",
+            device.name(),
+            health.reads,
+            health.timeouts,
+            health.crc_errors,
+            health.parse_errors,
+            health.other_errors
+        );
+        let _ = match health.last_good {
+            Some(at) => write!(
+                out,
+                "             last good {} s ago",
+                at.elapsed().as_secs()
+            ),
+            None => out.write_str("             never read"),
+        };
+        if let Some(passed) = health.self_test {
+            let _ = write!(out, ", self-test {}", pass_fail(passed));
+        }
+        if let Some(serial) = &health.serial {
+            let _ = write!(out, ", serial {}", serial);
+        }
+        let _ = out.write_str("\r\n");
+        if let Some(e) = health.last_error {
+            let _ = write!(out, "             last error: {}\r\n", e);
+        }
+        write(class, &out).await?;
+    }
+    Ok(())
+}
+
+async fn print_calibration_log(class: &mut SerialClass) -> Result<(), Disconnected> {
+    let mut index = 0;
+    loop {
+        let mut out: String<OUTPUT_BUFFER_SIZE> = String::new();
+        match state::request(Request::ReadCalibration(index), &REPLY).await {
+            Reply::CalibrationEntry { seq, total, record } => {
+                let _ = write!(
+                    out,
+                    "#{} t={}s scd41={:012x} explorir={} ref={:.2}C/{}ppm/{}Pa \
+                     temp={:?}->{:?} offset={:?} co2={:?}->{:?}",
+                    seq,
+                    record.timestamp_s,
+                    record.scd41_serial,
+                    record.explorir_serial.as_str(),
+                    record.reference_temp_c,
+                    record.reference_co2_ppm,
+                    record.pressure_pa,
+                    record.temp_before_c,
+                    record.temp_after_c,
+                    record.temp_offset_c,
+                    record.co2_before_ppm,
+                    record.co2_after_ppm,
+                );
+                if let Some(datetime) = record.datetime {
+                    let _ = write!(out, " at {} UTC", datetime);
+                }
+                let _ = out.write_str("\r\n");
+                write(class, &out).await?;
+                index += 1;
+                if index >= total {
+                    return Ok(());
+                }
+            }
+            Reply::Error(e) => {
+                let _ = write!(out, "error: {}\r\n", e);
+                return write(class, &out).await;
+            }
+            _ => return Ok(()),
+        }
+    }
+}
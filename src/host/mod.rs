@@ -0,0 +1,120 @@
+pub mod console;
+pub mod protocol;
+
+use defmt::unwrap;
+use embassy_executor::Spawner;
+use embassy_stm32::peripherals::{PA11, PA12, USB_OTG_FS};
+use embassy_stm32::usb::{self, Driver};
+use embassy_stm32::{bind_interrupts, peripherals};
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use embassy_usb::driver::EndpointError;
+use embassy_usb::{Builder, UsbDevice};
+use static_cell::StaticCell;
+
+const USB_VID: u16 = 0xc0de;
+const USB_PID: u16 = 0xcafe;
+const EP_OUT_BUFFER_SIZE: usize = 256;
+const DESCRIPTOR_BUFFER_SIZE: usize = 256;
+const CONTROL_BUFFER_SIZE: usize = 64;
+pub const MAX_PACKET_SIZE: u16 = 64;
+
+bind_interrupts!(struct UsbIrqs {
+    OTG_FS => usb::InterruptHandler<peripherals::USB_OTG_FS>;
+});
+
+pub type UsbDriver = Driver<'static, USB_OTG_FS>;
+pub type SerialClass = CdcAcmClass<'static, UsbDriver>;
+
+struct Disconnected;
+
+impl From<EndpointError> for Disconnected {
+    fn from(e: EndpointError) -> Self {
+        match e {
+            EndpointError::BufferOverflow => defmt::panic!("USB buffer overflow"),
+            EndpointError::Disabled => Disconnected,
+        }
+    }
+}
+
+// Writes `data` split into max-size packets, terminating with a short packet
+async fn write_all(class: &mut SerialClass, data: &[u8]) -> Result<(), Disconnected> {
+    let chunk_size = class.max_packet_size() as usize;
+    for chunk in data.chunks(chunk_size) {
+        class.write_packet(chunk).await?;
+    }
+    if !data.is_empty() && data.len() % chunk_size == 0 {
+        class.write_packet(&[]).await?;
+    }
+    Ok(())
+}
+
+// Brings up the USB OTG FS device and spawns the host interface tasks: the console on
+// the first CDC-ACM interface and the binary protocol on the second.
+// Requires the 48 MHz clock from `board::config()`.
+pub fn init(spawner: &Spawner, usb_otg_fs: USB_OTG_FS, dp: PA12, dm: PA11) {
+    static EP_OUT_BUFFER: StaticCell<[u8; EP_OUT_BUFFER_SIZE]> = StaticCell::new();
+    static CONFIG_DESCRIPTOR: StaticCell<[u8; DESCRIPTOR_BUFFER_SIZE]> = StaticCell::new();
+    static BOS_DESCRIPTOR: StaticCell<[u8; DESCRIPTOR_BUFFER_SIZE]> = StaticCell::new();
+    static MSOS_DESCRIPTOR: StaticCell<[u8; 0]> = StaticCell::new();
+    static CONTROL_BUFFER: StaticCell<[u8; CONTROL_BUFFER_SIZE]> = StaticCell::new();
+    static CONSOLE_STATE: StaticCell<State> = StaticCell::new();
+    static PROTOCOL_STATE: StaticCell<State> = StaticCell::new();
+
+    let mut usb_config = usb::Config::default();
+    // VBUS sensing is not wired on the board
+    usb_config.vbus_detection = false;
+
+    let driver = Driver::new_fs(
+        usb_otg_fs,
+        UsbIrqs,
+        dp,
+        dm,
+        EP_OUT_BUFFER.init([0; EP_OUT_BUFFER_SIZE]),
+        usb_config,
+    );
+
+    let mut config = embassy_usb::Config::new(USB_VID, USB_PID);
+    config.manufacturer = Some("General Cybernetics Corporation");
+    config.product = Some("Ion Concentration Bio-Modulator");
+    config.serial_number = Some("ICBM");
+    config.max_power = 100;
+    config.max_packet_size_0 = 64;
+
+    // Interface association descriptors so the CDC-ACM functions enumerate on Windows
+    config.device_class = 0xEF;
+    config.device_sub_class = 0x02;
+    config.device_protocol = 0x01;
+    config.composite_with_iads = true;
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        CONFIG_DESCRIPTOR.init([0; DESCRIPTOR_BUFFER_SIZE]),
+        BOS_DESCRIPTOR.init([0; DESCRIPTOR_BUFFER_SIZE]),
+        MSOS_DESCRIPTOR.init([0; 0]),
+        CONTROL_BUFFER.init([0; CONTROL_BUFFER_SIZE]),
+    );
+
+    let console_class = CdcAcmClass::new(
+        &mut builder,
+        CONSOLE_STATE.init(State::new()),
+        MAX_PACKET_SIZE,
+    );
+
+    let protocol_class = CdcAcmClass::new(
+        &mut builder,
+        PROTOCOL_STATE.init(State::new()),
+        MAX_PACKET_SIZE,
+    );
+
+    let usb = builder.build();
+
+    unwrap!(spawner.spawn(usb_task(usb)));
+    unwrap!(spawner.spawn(console::console_task(console_class)));
+    unwrap!(spawner.spawn(protocol::protocol_task(protocol_class)));
+}
+
+#[embassy_executor::task]
+async fn usb_task(mut usb: UsbDevice<'static, UsbDriver>) -> ! {
+    usb.run().await
+}
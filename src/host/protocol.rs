@@ -0,0 +1,257 @@
+use defmt::{info, warn};
+use embassy_time::Instant;
+use icbm_protocol::frame::{self, FrameDecoder, MAX_FRAME_SIZE, MAX_MESSAGE_SIZE};
+use icbm_protocol::message::{
+    peek_request_id, Alarms, CalibrationEntry, DecodeError, ErrorCode, Readings, Request, Response,
+    Setpoints, TimeSync, EXPLORIR_SERIAL_LENGTH,
+};
+use icbm_protocol::PROTOCOL_VERSION;
+
+use super::{write_all, Disconnected, SerialClass, MAX_PACKET_SIZE};
+use crate::diagnostics;
+use crate::net::sntp;
+use crate::profile;
+use crate::rtc;
+use crate::state::{self, Reply, ReplySignal};
+use crate::storage::calibration_log::CalibrationRecord;
+
+static REPLY: ReplySignal = ReplySignal::new();
+
+// Framed binary protocol (see the icbm_protocol crate) on the second USB CDC-ACM interface
+#[embassy_executor::task]
+pub async fn protocol_task(mut class: SerialClass) -> ! {
+    loop {
+        class.wait_connection().await;
+        info!("Protocol host connected");
+        let _ = run(&mut class).await;
+        info!("Protocol host disconnected");
+    }
+}
+
+async fn run(class: &mut SerialClass) -> Result<(), Disconnected> {
+    let mut decoder = FrameDecoder::new();
+    let mut packet = [0u8; MAX_PACKET_SIZE as usize];
+    let mut message = [0u8; MAX_MESSAGE_SIZE];
+
+    loop {
+        let n = class.read_packet(&mut packet).await?;
+        for &byte in &packet[..n] {
+            let len = match decoder.feed(byte, &mut message) {
+                Some(Ok(len)) => len,
+                Some(Err(e)) => {
+                    // Without a valid CRC the request id cannot be trusted, so no reply
+                    warn!("Dropped protocol frame: {}", e);
+                    continue;
+                }
+                None => continue,
+            };
+
+            let (request_id, response) = match Request::decode(&message[..len]) {
+                Ok((request_id, request)) => (request_id, handle(request).await),
+                Err(e) => {
+                    warn!("Undecodable protocol message: {}", e);
+                    let code = match e {
+                        DecodeError::UnsupportedVersion(_) => ErrorCode::UnsupportedVersion,
+                        DecodeError::UnknownTag(_) => ErrorCode::UnknownMessage,
+                        _ => ErrorCode::Malformed,
+                    };
+                    let request_id = peek_request_id(&message[..len]).unwrap_or(0);
+                    (request_id, Response::Error(code))
+                }
+            };
+            send(class, request_id, &response).await?;
+        }
+    }
+}
+
+async fn send(
+    class: &mut SerialClass,
+    request_id: u16,
+    response: &Response,
+) -> Result<(), Disconnected> {
+    let mut message = [0u8; MAX_MESSAGE_SIZE];
+    let mut frame = [0u8; MAX_FRAME_SIZE];
+    let Ok(len) = response.encode(request_id, &mut message) else {
+        warn!("Response does not fit a protocol message");
+        return Ok(());
+    };
+    let Ok(len) = frame::encode(&message[..len], &mut frame) else {
+        warn!("Response does not fit a protocol frame");
+        return Ok(());
+    };
+    write_all(class, &frame[..len]).await
+}
+
+async fn handle(request: Request) -> Response {
+    match request {
+        Request::Hello => Response::Hello {
+            protocol_version: PROTOCOL_VERSION,
+        },
+        Request::GetReadings => {
+            let readings = state::readings();
+            Response::Readings(Readings {
+                temp_c: readings.temp_c,
+                co2_ppm: readings.co2_ppm,
+                heater_on: readings.heater_on,
+                co2_bursts: readings.co2_bursts,
+                updated_at_s: readings.updated_at_s,
+                humidity_rh: readings.humidity_rh,
+                humidifier_on: readings.humidifier_on,
+            })
+        }
+        Request::GetSetpoints => {
+            let config = state::config();
+            Response::Setpoints(Setpoints {
+                co2_ppm: config.target_co2_ppm,
+                temp_c: config.target_temp_c,
+                co2_tolerance_ppm: config.co2_tolerance_ppm,
+                temp_tolerance_c: config.temp_tolerance_c,
+                co2_burst_ms: config.co2_burst_ms,
+            })
+        }
+        Request::SetSetpoints(setpoints) => {
+            let mut config = state::config();
+            config.target_co2_ppm = setpoints.co2_ppm;
+            config.target_temp_c = setpoints.temp_c;
+            config.co2_tolerance_ppm = setpoints.co2_tolerance_ppm;
+            config.temp_tolerance_c = setpoints.temp_tolerance_c;
+            config.co2_burst_ms = setpoints.co2_burst_ms;
+            if let Err(e) = config.validate() {
+                warn!("Rejected setpoints: {}", e);
+                return Response::Error(ErrorCode::InvalidValue);
+            }
+            response(state::request(state::Request::SetConfig(config), &REPLY).await)
+        }
+        Request::GetAlarms => Response::Alarms(Alarms {
+            active: state::readings().alarms,
+        }),
+        Request::StartCalibration => {
+            info!("Calibration requested over protocol");
+            response(state::request(state::Request::Calibrate, &REPLY).await)
+        }
+        Request::GetCalibration { index } => {
+            match state::request(state::Request::ReadCalibration(index), &REPLY).await {
+                // Out-of-range indices and an empty log are reported as invalid values
+                Reply::Error(_) => Response::Error(ErrorCode::InvalidValue),
+                reply => response(reply),
+            }
+        }
+        Request::SelfTest => response(state::request(state::Request::SelfTest, &REPLY).await),
+        Request::GetLogInfo => response(state::request(state::Request::LogInfo, &REPLY).await),
+        Request::ReadLog { seq, offset } => {
+            match state::request(state::Request::ReadLog { seq, offset }, &REPLY).await {
+                // Blocks dropped from the ring and offsets past the end are invalid values
+                Reply::Error(_) => Response::Error(ErrorCode::InvalidValue),
+                reply => response(reply),
+            }
+        }
+        Request::GetTime => Response::Time {
+            unix_s: rtc::unix_time(),
+            uptime_s: Instant::now().as_secs(),
+        },
+        Request::SetTime { unix_s } => match rtc::set_unix(unix_s, rtc::Source::Host) {
+            Ok(()) => Response::Ack,
+            Err(e) => {
+                warn!("Rejected time: {}", e);
+                Response::Error(ErrorCode::InvalidValue)
+            }
+        },
+        Request::GetTimeSync => {
+            let status = sntp::status();
+            Response::TimeSync(TimeSync {
+                server: status.server,
+                since_sync_s: status.last_sync.map(|at| at.elapsed().as_secs() as u32),
+                offset_ms: status.offset_ms,
+                delay_ms: status.delay_ms,
+                stratum: status.stratum,
+                drift_ppm: status.drift_ppm,
+                syncs: status.syncs,
+                failures: status.failures,
+            })
+        }
+        Request::GetProfile => Response::Profile(profile::status()),
+        Request::GetProfileSegment { variable, index } => match profile::segment(variable, index) {
+            Some((segment, count)) => Response::ProfileSegment {
+                variable,
+                index,
+                count,
+                segment,
+            },
+            None => Response::Error(ErrorCode::InvalidValue),
+        },
+        Request::ClearProfile => rejectable(state::Request::ClearProfile).await,
+        Request::AddProfileSegment { variable, segment } => {
+            rejectable(state::Request::AddProfileSegment { variable, segment }).await
+        }
+        Request::ProfileControl(command) => {
+            rejectable(state::Request::ProfileControl(command)).await
+        }
+        Request::GetDiagnostics { device } => Response::Diagnostics(diagnostics::report(device)),
+    }
+}
+
+// Profile edits and commands that do not fit the profile's state or the setpoint limits
+// are invalid values rather than failures
+async fn rejectable(request: state::Request) -> Response {
+    match state::request(request, &REPLY).await {
+        Reply::Error(e) => {
+            warn!("Rejected profile request: {}", e);
+            Response::Error(ErrorCode::InvalidValue)
+        }
+        reply => response(reply),
+    }
+}
+
+fn response(reply: Reply) -> Response {
+    match reply {
+        Reply::Done => Response::Ack,
+        Reply::Error(e) => {
+            warn!("Request failed: {}", e);
+            Response::Error(ErrorCode::Failed)
+        }
+        Reply::SelfTest { scd41, explorir } => Response::SelfTest { scd41, explorir },
+        Reply::Calibrated { seq } => Response::Calibrated { seq },
+        Reply::CalibrationEntry { seq, total, record } => {
+            Response::CalibrationEntry(calibration_entry(seq, total, &record))
+        }
+        Reply::LogInfo {
+            blocks,
+            first_seq,
+            last_seq,
+        } => Response::LogInfo {
+            blocks,
+            first_seq,
+            last_seq,
+        },
+        Reply::LogChunk(chunk) => Response::LogChunk(chunk),
+        Reply::SdStatus(_) => {
+            warn!("Unexpected reply");
+            Response::Error(ErrorCode::Failed)
+        }
+    }
+}
+
+fn calibration_entry(seq: u32, total: u32, record: &CalibrationRecord) -> CalibrationEntry {
+    let serial = record.explorir_serial.as_bytes();
+    let serial_len = serial.len().min(EXPLORIR_SERIAL_LENGTH);
+    let mut explorir_serial = [0u8; EXPLORIR_SERIAL_LENGTH];
+    explorir_serial[..serial_len].copy_from_slice(&serial[..serial_len]);
+
+    CalibrationEntry {
+        seq,
+        total,
+        timestamp_s: record.timestamp_s,
+        scd41_serial: record.scd41_serial,
+        explorir_serial,
+        explorir_serial_len: serial_len as u8,
+        reference_temp_c: record.reference_temp_c,
+        reference_co2_ppm: record.reference_co2_ppm,
+        pressure_pa: record.pressure_pa,
+        altitude_m: record.altitude_m,
+        temp_before_c: record.temp_before_c,
+        temp_after_c: record.temp_after_c,
+        temp_offset_c: record.temp_offset_c,
+        co2_before_ppm: record.co2_before_ppm,
+        co2_after_ppm: record.co2_after_ppm,
+    }
+}
@@ -0,0 +1,64 @@
+use defmt::info;
+
+use crate::storage::config::Config;
+
+// Humidity control and the low-humidity (dry pan) alarm.
+//
+// The humidifier gets one pulse per control cycle. Its length is proportional to how far
+// the humidity is below the setpoint, plus an integral term that makes up for the steady
+// loss through the door seals and CO2 flushing. Above the tolerance band the humidifier
+// stays off and the integral bleeds off, since condensation on the cultureware is worse
+// than a slow approach.
+
+// Longest pulse, a fifth of the control period
+pub const MAX_PULSE_MS: u32 = 10_000;
+// A full pulse 10 %RH below the setpoint
+const PROPORTIONAL_MS_PER_RH: f32 = 1_000.0;
+const INTEGRAL_MS_PER_RH: f32 = 50.0;
+// The alarm clears only this far above its threshold so it does not chatter
+const ALARM_HYSTERESIS_RH: f32 = 2.0;
+
+pub struct HumidityController {
+    integral_ms: f32,
+}
+
+impl HumidityController {
+    pub const fn new() -> Self {
+        HumidityController { integral_ms: 0.0 }
+    }
+
+    // Humidifier on-time for this cycle
+    pub fn update(&mut self, config: &Config, humidity_rh: f32) -> u32 {
+        if !config.humidifier_enabled {
+            self.integral_ms = 0.0;
+            return 0;
+        }
+        let error = config.target_humidity_rh - humidity_rh;
+        if error < -config.humidity_tolerance_rh {
+            self.integral_ms *= 0.5;
+            return 0;
+        }
+        // Clamped so it cannot wind up while the pan is dry
+        self.integral_ms =
+            (self.integral_ms + INTEGRAL_MS_PER_RH * error).clamp(0.0, MAX_PULSE_MS as f32);
+        let on_ms = PROPORTIONAL_MS_PER_RH * error.max(0.0) + self.integral_ms;
+        let on_ms = on_ms.clamp(0.0, MAX_PULSE_MS as f32) as u32;
+        if on_ms > 0 {
+            info!("Humidifier: {} ms, {} %RH below setpoint", on_ms, error);
+        }
+        on_ms
+    }
+}
+
+// Whether the low-humidity alarm is raised, given whether it was so far
+pub fn humidity_low(config: &Config, humidity_rh: f32, was_low: bool) -> bool {
+    let threshold = config.humidity_alarm_rh;
+    if threshold <= 0.0 {
+        return false;
+    }
+    if was_low {
+        humidity_rh < threshold + ALARM_HYSTERESIS_RH
+    } else {
+        humidity_rh < threshold
+    }
+}
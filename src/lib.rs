@@ -1,3 +1,19 @@
 #![no_std]
 
+pub mod board;
+pub mod calibration;
+pub mod co2_check;
+pub mod co2_supply;
+pub mod diagnostics;
+pub mod door;
 pub mod drivers;
+pub mod fusion;
+pub mod host;
+pub mod humidity;
+pub mod modbus;
+pub mod net;
+pub mod profile;
+pub mod rtc;
+pub mod state;
+pub mod storage;
+pub mod thermal;
@@ -2,62 +2,231 @@
 #![no_main]
 
 use core::cell::RefCell;
+use core::fmt::Write;
+use core::future::Future;
 use defmt::*;
 use display_interface_spi::SPIInterface;
 use embassy_embedded_hal::shared_bus::blocking::spi::SpiDeviceWithConfig;
 use embassy_executor::Spawner;
+use embassy_futures::select::{select, select4, Either, Either4};
 use embassy_stm32::{
-    gpio::{Level, Output, Speed},
+    adc::{Adc, AdcChannel},
+    exti::ExtiInput,
+    flash::Flash,
+    gpio::{Level, Output, OutputType, Pull, Speed},
     i2c::{self, Config as I2cConfig, I2c},
     mode::Blocking,
+    rng::{self, Rng},
+    rtc::{Rtc, RtcConfig},
     spi::{self, Spi},
     time::{hz, Hertz},
+    timer::simple_pwm::PwmPin,
     usart::{Config as UartConfig, DataBits, Parity, StopBits, Uart},
     wdg::IndependentWatchdog,
     {bind_interrupts, peripherals, usart},
 };
 use embassy_sync::blocking_mutex::{raw::NoopRawMutex, Mutex};
-use embassy_time::{Delay, Timer};
+use embassy_time::{Delay, Duration, Instant, Timer};
 use embedded_graphics::{
-    mono_font::{ascii::FONT_10X20, MonoTextStyle},
+    mono_font::{
+        ascii::{FONT_10X20, FONT_6X10},
+        MonoTextStyle,
+    },
     pixelcolor::Rgb565,
     prelude::*,
     primitives::Rectangle,
     text::{Alignment, Text},
 };
+use embedded_hal_bus::spi::ExclusiveDevice;
 use heapless::String;
+use icbm_firmware::co2_check::Co2CrossCheck;
+use icbm_firmware::co2_supply::Co2SupplyMonitor;
+use icbm_firmware::diagnostics::{self, Device};
+use icbm_firmware::door::{DoorMonitor, DoorState};
 use icbm_firmware::drivers::{
-    bsz070::Heater, co2_solenoid::Co2Solenoid, explorir_m_e_100::ExplorIrME100, scd41::SCD41,
+    bsz070::{Heater, HEAT_INTERVAL_MS},
+    co2_solenoid::{Co2Solenoid, HoldConfig, Wear},
+    door_switch::DoorSwitch,
+    drv8873::{BridgeFault, BridgeMonitor, PeltierController},
+    error::DeviceError,
+    explorir_m_e_100::ExplorIrME100,
+    humidifier::Humidifier,
+    pressure_transducer::PressureTransducer,
+    scd41::SCD41,
+    slf3s::SLF3S,
+    thermistor::{Probe, ThermistorBank, DMA_BUFFER_LEN},
 };
+use icbm_firmware::fusion::{TempSource, TemperatureFusion};
+use icbm_firmware::humidity::{self, HumidityController};
+use icbm_firmware::profile::{self, ProfileRunner};
+use icbm_firmware::state::{self, Reply, Request, Screen};
+use icbm_firmware::storage::config::{Config, ThermalActuator};
+use icbm_firmware::storage::{
+    calibration_log::{scd41_serial_from_raw, CalibrationLog},
+    config::ConfigStore,
+    datalog::DataLog,
+    sd_log::{self, SdLog},
+};
+use icbm_firmware::thermal::{self, PeltierPid};
+use icbm_firmware::{board, calibration, host, modbus, net, rtc};
+use icbm_protocol::datalog::{state as actuator, Sample};
+use icbm_protocol::message::{alarm, LogChunk, LOG_CHUNK_SIZE};
 use ili9341::{DisplaySize240x320, Ili9341, Orientation};
 use itoa;
 use libm::fabsf;
 use {defmt_rtt as _, panic_probe as _};
 
-// Constants for control system
-const TARGET_CO2_PPM: f32 = 50_000.0; // 5%
-const TARGET_TEMP_C: f32 = 37.0; // Body temperature
-const CO2_TOLERANCE_PPM: f32 = 2000.0; // ±0.2%
-const TEMP_TOLERANCE_C: f32 = 1.0; // ±1°C
+const CONTROL_PERIOD_SECS: u64 = 50;
+const WATCHDOG_PET_SECS: u64 = 10;
+// Deviations beyond this many tolerances raise an out-of-range alarm
+const ALARM_TOLERANCE_FACTOR: f32 = 3.0;
+// The valve wear is written through to flash this often, if it changed
+const VALVE_WEAR_FLASH_SECS: u64 = 60 * 60;
+
+type Peltier<'d> = PeltierController<'d, peripherals::TIM1>;
+type Bridge<'d> = BridgeMonitor<'d, peripherals::ADC2, peripherals::PA6>;
 
 bind_interrupts!(struct UartIrqs {
     USART3 => usart::InterruptHandler<peripherals::USART3>;
 });
 
+bind_interrupts!(struct ModbusUartIrqs {
+    USART6 => usart::InterruptHandler<peripherals::USART6>;
+});
+
+bind_interrupts!(struct RngIrqs {
+    HASH_RNG => rng::InterruptHandler<peripherals::RNG>;
+});
+
 bind_interrupts!(struct I2cIrqs {
     I2C1_EV => i2c::EventInterruptHandler<peripherals::I2C1>;
     I2C1_ER => i2c::ErrorInterruptHandler<peripherals::I2C1>;
 });
 
+bind_interrupts!(struct FlowI2cIrqs {
+    I2C2_EV => i2c::EventInterruptHandler<peripherals::I2C2>;
+    I2C2_ER => i2c::ErrorInterruptHandler<peripherals::I2C2>;
+});
+
 #[embassy_executor::main]
-async fn main(_spawner: Spawner) {
+async fn main(spawner: Spawner) {
     info!("Starting Ion Concentration Bio-Modulator");
-    let p = embassy_stm32::init(Default::default());
+    let p = embassy_stm32::init(board::config());
 
-    let mut co2_valve = Co2Solenoid::new(p.PA0, Level::Low, Speed::VeryHigh);
+    // The CO2 valve is on TIM5 CH1 for the reduced hold drive
+    let mut co2_valve = Co2Solenoid::new_pwm(
+        p.TIM5,
+        PwmPin::new_ch1(p.PA0, OutputType::PushPull),
+        HoldConfig::default(),
+    );
     let mut heater = Heater::new(p.PA1, Level::Low, Speed::VeryHigh);
+    // Optional; only pulsed when enabled in the configuration
+    let mut humidifier = Humidifier::new(p.PA2, Speed::VeryHigh);
     heater.stop();
     co2_valve.stop_continuous();
+    humidifier.stop();
+
+    // Wall clock for log and alarm timestamps; keeps running across resets
+    rtc::init(Rtc::new(p.RTC, RtcConfig::default()));
+
+    // Setpoints and calibration references persisted in flash (defaults if none stored)
+    let mut flash = Flash::new_blocking(p.FLASH);
+    let (mut config_store, config) = ConfigStore::load(&mut flash);
+    let mut calibration_log = CalibrationLog::open(&mut flash);
+    let mut datalog = DataLog::open(&mut flash);
+    // Needs the RTC to account for the time spent off
+    let mut profile_runner = ProfileRunner::load(&mut flash);
+    info!("Active configuration: {}", config);
+    co2_valve.set_wear(load_valve_wear(config.valve_wear));
+    state::update_readings(|r| r.valve_wear = co2_valve.wear());
+    state::set_config(config);
+
+    // Peltier module on the DRV8873 bridge, used instead of the heater when configured.
+    // PB9 drives the bridge's DISABLE pin; nFAULT is on PB8 and the IPROPI sense voltage
+    // on PA6 (ADC2).
+    let mut peltier = PeltierController::new(
+        p.TIM1,
+        Some(PwmPin::new_ch2(p.PA9, OutputType::PushPull)),
+        Some(PwmPin::new_ch3(p.PA10, OutputType::PushPull)),
+        Output::new(p.PB9, Level::Low, Speed::Low),
+        thermal::peltier_config(&config),
+    );
+    let mut bridge_monitor = BridgeMonitor::new(
+        ExtiInput::new(p.PB8, p.EXTI8, Pull::Up),
+        Adc::new(p.ADC2),
+        p.PA6,
+    );
+    let mut peltier_pid = PeltierPid::new();
+
+    // Thermistor bank on ADC1: chamber air (PA7), shelf plate (PC0), Peltier heatsink
+    // (PC1) and water jacket (PC2)
+    let mut probe_dma_buf = [0u16; DMA_BUFFER_LEN];
+    let mut probes = ThermistorBank::new(
+        Adc::new(p.ADC1),
+        [
+            (Probe::ChamberAir, p.PA7.degrade_adc()),
+            (Probe::Plate, p.PC0.degrade_adc()),
+            (Probe::Heatsink, p.PC1.degrade_adc()),
+            (Probe::WaterJacket, p.PC2.degrade_adc()),
+        ],
+        p.DMA2_CH4,
+        &mut probe_dma_buf,
+    );
+
+    // Door switch on PB0, only read when fitted (see door.rs)
+    let mut door_switch = DoorSwitch::new(ExtiInput::new(p.PB0, p.EXTI0, Pull::Up));
+
+    // CO2 supply pressure transducer on PA3 (ADC3), only read when fitted
+    let mut supply_pressure = PressureTransducer::new(Adc::new(p.ADC3), p.PA3);
+
+    // USB console for operating the incubator from a laptop
+    host::init(&spawner, p.USB_OTG_FS, p.PA12, p.PA11);
+
+    // W5500 Ethernet on SPI1 for central monitoring
+    let mut eth_spi_config = spi::Config::default();
+    eth_spi_config.frequency = hz(21_000_000); // APB2 84 MHz / 4
+    let eth_spi = Spi::new(
+        p.SPI1,
+        p.PB3,
+        p.PB5,
+        p.PB4,
+        p.DMA2_CH3,
+        p.DMA2_CH0,
+        eth_spi_config,
+    );
+    let eth_cs = Output::new(p.PB12, Level::High, Speed::VeryHigh);
+    let eth_spi = unwrap!(ExclusiveDevice::new(eth_spi, eth_cs, Delay));
+    let eth_int = ExtiInput::new(p.PD10, p.EXTI10, Pull::Up);
+    let eth_reset = Output::new(p.PD11, Level::High, Speed::VeryHigh);
+    let mut rng = Rng::new(p.RNG, RngIrqs);
+    let mut seed = [0u8; 8];
+    unwrap!(rng.async_fill_bytes(&mut seed).await);
+    net::init(
+        &spawner,
+        eth_spi,
+        eth_int,
+        eth_reset,
+        u64::from_le_bytes(seed),
+    );
+
+    // Modbus RTU on USART6 through an RS-485 transceiver; PC8 drives its DE/!RE pins
+    let mut modbus_uart_config = UartConfig::default();
+    modbus_uart_config.baudrate = modbus::rtu::BAUD_RATE;
+    modbus_uart_config.parity = Parity::ParityEven;
+    modbus_uart_config.stop_bits = StopBits::STOP1;
+    modbus_uart_config.data_bits = DataBits::DataBits8;
+    let modbus_uart = Uart::new(
+        p.USART6,
+        p.PC7,
+        p.PC6,
+        ModbusUartIrqs,
+        p.DMA2_CH6,
+        p.DMA2_CH1,
+        modbus_uart_config,
+    )
+    .unwrap();
+    let modbus_de = Output::new(p.PC8, Level::Low, Speed::VeryHigh);
+    unwrap!(spawner.spawn(modbus::rtu::rtu_task(modbus_uart, modbus_de)));
 
     let mut watchdog = IndependentWatchdog::new(p.IWDG, 30_000_000); // 30 second timeout in microseconds
     watchdog.unleash(); //start the watchdog
@@ -94,6 +263,22 @@ async fn main(_spawner: Spawner) {
         i2c_config,
     );
 
+    // SLF3S liquid flow sensor on I2C2; the drivers own their bus and I2C1 belongs to
+    // the SCD41
+    let mut flow_i2c_config = I2cConfig::default();
+    flow_i2c_config.scl_pullup = true;
+    flow_i2c_config.sda_pullup = true;
+    let flow_i2c = I2c::new(
+        p.I2C2,
+        p.PB10,
+        p.PB11,
+        FlowI2cIrqs,
+        p.DMA1_CH7,
+        p.DMA1_CH2,
+        Hertz(100_000),
+        flow_i2c_config,
+    );
+
     let clk = p.PC10;
     let miso = p.PC11;
     let mosi = p.PC12;
@@ -118,6 +303,14 @@ async fn main(_spawner: Spawner) {
 
     let spi_iface = SPIInterface::new(lcd_spi, lcd_dc);
 
+    // SD card for CSV run logs on a second chip select of the LCD's bus
+    let sd_spi = SpiDeviceWithConfig::new(
+        &spi_bus,
+        Output::new(p.PD2, Level::High, Speed::Medium),
+        sd_log::init_config(),
+    );
+    let mut sd_log = SdLog::new(sd_spi);
+
     let mut lcd = Ili9341::new(
         spi_iface,
         lcd_reset,
@@ -164,8 +357,22 @@ async fn main(_spawner: Spawner) {
     info!("Starting SCD41 initialization");
     let mut scd41sensor = SCD41::new(i2c);
     match scd41sensor.init(None).await {
-        Ok(()) => info!("SCD41 base initialization successful"),
-        Err(e) => error!("SCD41 initialization error: {}", e),
+        Ok(()) => {
+            info!("SCD41 base initialization successful");
+            // Initialization includes the self-test
+            diagnostics::set_self_test(Device::Scd41, true);
+        }
+        Err(e) => {
+            error!("SCD41 initialization error: {}", e);
+            diagnostics::set_self_test(Device::Scd41, false);
+        }
+    }
+    // The serial number is only available while idle
+    if scd41sensor.stop_periodic_measurement().await.is_ok() {
+        record_scd41_serial(&mut scd41sensor).await;
+        if let Err(e) = scd41sensor.start_periodic_measurement().await {
+            error!("Failed to restart periodic measurement: {}", e);
+        }
     }
 
     watchdog.pet();
@@ -177,155 +384,909 @@ async fn main(_spawner: Spawner) {
         Ok(_) => info!("CO2 sensor initialization successful"),
         Err(e) => error!("CO2 sensor initialization failed: {}", e),
     }
+    record_explorir_serial(&mut co2_sensor).await;
+
+    info!("Starting SLF3S flow sensor initialization");
+    let mut flow_sensor = SLF3S::new(flow_i2c);
+    match flow_sensor.read_product_id().await {
+        Ok((product, serial)) => {
+            info!("Flow sensor product {:#x}, serial {:#x}", product, serial);
+            let mut text: String<16> = String::new();
+            let _ = write!(text, "{:016X}", serial);
+            diagnostics::set_serial(Device::FlowSensor, &text);
+        }
+        Err(e) => warn!("Failed to read flow sensor serial number: {}", e),
+    }
+    match flow_sensor.start_measurement().await {
+        Ok(()) => info!("Flow sensor initialization successful"),
+        Err(e) => error!("Flow sensor initialization failed: {}", e),
+    }
 
     let mut co2_str: String<32> = String::new();
     let mut temp_str: String<32> = String::new();
+    let mut rh_str: String<32> = String::new();
     let mut co2_buf = itoa::Buffer::new();
     let mut temp_buf = itoa::Buffer::new();
+    let mut rh_buf = itoa::Buffer::new();
     let mut fract_buf = itoa::Buffer::new();
+    let mut co2_bursts: u32 = 0;
+    let mut humidity_controller = HumidityController::new();
+    let mut fusion = TemperatureFusion::new();
+    let mut co2_check = Co2CrossCheck::new();
+    let mut door_monitor = DoorMonitor::new();
+    let mut supply_monitor = Co2SupplyMonitor::new();
+    let mut diagnostics_shown = false;
+    let mut valve_wear_flushed = Instant::now();
     watchdog.pet();
 
     loop {
-        Timer::after_secs(25).await;
-        watchdog.pet();
-        Timer::after_secs(25).await;
-        watchdog.pet();
+        // Wait for the next control cycle, servicing host requests in the meantime
+        let next_cycle = Instant::now() + Duration::from_secs(CONTROL_PERIOD_SECS);
+        loop {
+            let now = Instant::now();
+            if now >= next_cycle {
+                break;
+            }
+            let wake = next_cycle.min(now + Duration::from_secs(WATCHDOG_PET_SECS));
+            let event = select4(
+                Timer::at(wake),
+                state::COMMANDS.receive(),
+                bridge_fault(&mut bridge_monitor, &peltier),
+                door_change(&mut door_switch),
+            )
+            .await;
+            match event {
+                // The output holds between cycles, so the bridge is watched meanwhile
+                Either4::First(()) => {
+                    read_probes(&mut probes, &mut peltier, &mut fusion).await;
+                    if peltier_in_use(&peltier) {
+                        check_bridge(&mut bridge_monitor, &mut peltier, None);
+                    }
+                    if state::screen() == Screen::Diagnostics {
+                        draw_diagnostics(&mut lcd, style);
+                        diagnostics_shown = true;
+                    }
+                }
+                Either4::Second(command) => {
+                    let reply = handle_request(
+                        command.request,
+                        &mut scd41sensor,
+                        &mut co2_sensor,
+                        &mut flash,
+                        &mut config_store,
+                        &mut calibration_log,
+                        &mut datalog,
+                        &mut sd_log,
+                        &mut profile_runner,
+                        &mut peltier,
+                        &mut heater,
+                        &mut humidifier,
+                        &mut co2_valve,
+                        &mut watchdog,
+                    )
+                    .await;
+                    command.reply.signal(reply);
+                }
+                Either4::Third(fault) => check_bridge(&mut bridge_monitor, &mut peltier, fault),
+                // Published right away; dosing follows the door from the next cycle
+                Either4::Fourth(open) => {
+                    info!("Door switch {}", if open { "open" } else { "closed" });
+                    if open {
+                        state::update_readings(|r| {
+                            r.door = DoorState::Open;
+                            r.alarms |= alarm::DOOR_OPEN;
+                        });
+                    }
+                }
+            }
+            watchdog.pet();
+        }
+        // An active setpoint profile overrides the configured setpoints
+        profile_runner.update(&mut flash);
+        if valve_wear_flushed.elapsed().as_secs() >= VALVE_WEAR_FLASH_SECS {
+            valve_wear_flushed = Instant::now();
+            if let Err(e) = flush_valve_wear(&mut flash, &mut config_store, co2_valve.wear()) {
+                warn!("CO2 valve wear not saved: {}", e);
+            }
+        }
+        let config = profile::apply(state::config());
 
         co2_str.clear();
         temp_str.clear();
+        rh_str.clear();
 
-        lcd.fill_solid(
-            &Rectangle::new(Point::new(0, 80), Size::new(320, 100)),
-            Rgb565::BLACK,
-        )
-        .unwrap();
-
-        Text::with_alignment(
-            "ION CONCENTRATION BIO-MODULATOR",
-            Point::new(320 / 2, 40),
-            style,
-            Alignment::Center,
-        )
-        .draw(&mut lcd)
-        .unwrap();
-
-        let current_temp = match scd41sensor.read_measurement().await {
-            Ok((_, temp, _)) => {
-                info!("Temperature reading: {} C", temp);
-                temp
+        // Leaving the diagnostics screen clears the display for the readings
+        let show_readings = state::screen() == Screen::Readings;
+        if show_readings && diagnostics_shown {
+            lcd.clear(Rgb565::BLACK).unwrap();
+            diagnostics_shown = false;
+        }
+        if show_readings {
+            lcd.fill_solid(
+                &Rectangle::new(Point::new(0, 80), Size::new(320, 100)),
+                Rgb565::BLACK,
+            )
+            .unwrap();
+
+            Text::with_alignment(
+                "ION CONCENTRATION BIO-MODULATOR",
+                Point::new(320 / 2, 40),
+                style,
+                Alignment::Center,
+            )
+            .draw(&mut lcd)
+            .unwrap();
+        }
+
+        // Without the SCD41 there is no humidity, but the temperature can still come from
+        // the chamber air thermistor
+        let measurement = scd41sensor.read_measurement().await;
+        diagnostics::record(Device::Scd41, &measurement);
+        let (current_rh, scd41_co2) = match measurement {
+            Ok((co2, temp, rh)) => {
+                info!(
+                    "Temperature reading: {} C, humidity {} %RH, CO2 {} ppm",
+                    temp, rh, co2
+                );
+                fusion.scd41(temp);
+                (Some(rh), Some(co2 as f32))
             }
             Err(e) => {
                 error!("SCD41 measurement error: {}", e);
-                Text::with_alignment(
-                    "TEMP SENSOR ERROR",
-                    Point::new(320 / 2, 100),
-                    style,
-                    Alignment::Center,
-                )
-                .draw(&mut lcd)
-                .unwrap();
-                continue;
+                (None, None)
+            }
+        };
+        // Without any temperature source only the thermal actuator stops; CO2 and
+        // humidity control carry on
+        let estimate = fusion.estimate();
+        match estimate {
+            Some(estimate) => {
+                if estimate.source != TempSource::Fused {
+                    warn!(
+                        "Chamber temperature from the {} only",
+                        estimate.source.name()
+                    );
+                }
+                info!(
+                    "Chamber temperature: {} C ± {}, SCD41 offset {}",
+                    estimate.temp_c, estimate.uncertainty_c, estimate.scd41_offset_c
+                );
+            }
+            None => error!("No temperature sensor available"),
+        }
+        let current_temp = estimate.map(|estimate| estimate.temp_c);
+
+        // Temperature control only needs the temperature, so it goes ahead of the CO2
+        // reading and keeps going while the CO2 sensor fails
+        watchdog.pet();
+        Timer::after_secs(1).await;
+        let temp_error = current_temp.map(|temp| config.target_temp_c - temp);
+        let (heater_on, peltier_pct) = match (config.thermal_actuator, current_temp, temp_error) {
+            (ThermalActuator::Heater, Some(_), Some(temp_error)) => {
+                peltier.stop();
+                peltier_pid.reset();
+                let heater_on = temp_error > config.temp_tolerance_c;
+                if heater_on {
+                    info!("Activating heater: temp diff {}", temp_error);
+                    heater.heat().await;
+                    diagnostics::record_use(Device::Heater);
+                } else {
+                    heater.stop();
+                }
+                (heater_on, 0.0)
+            }
+            (ThermalActuator::Peltier, Some(temp), Some(temp_error)) => {
+                heater.stop();
+                peltier.configure(thermal::peltier_config(&config));
+                let output = peltier_pid.update(&config, temp);
+                info!("Peltier output {} %: temp diff {}", output, temp_error);
+                // A full reversal at the slowest ramp outlasts the watchdog timeout
+                pet_during(&mut watchdog, peltier.set_output(output)).await;
+                check_bridge(&mut bridge_monitor, &mut peltier, None);
+                // Heating with the Peltier is logged as the heater being on
+                (output > 0.0, output)
             }
+            // Unlike a heater pulse the Peltier output would stay on
+            _ => {
+                heater.stop();
+                peltier.stop();
+                peltier_pid.reset();
+                (false, 0.0)
+            }
+        };
+        let heater_duty_pct = match config.thermal_actuator {
+            ThermalActuator::Heater if heater_on => duty_pct(HEAT_INTERVAL_MS),
+            ThermalActuator::Heater => 0.0,
+            // The output holds for the whole period
+            ThermalActuator::Peltier => peltier_pct.max(0.0),
         };
         watchdog.pet();
+        Timer::after_secs(2).await;
 
-        let current_co2 = match co2_sensor.get_filtered_co2().await {
+        watchdog.pet();
+        let co2_reading = co2_sensor.get_filtered_co2().await;
+        diagnostics::record(Device::ExplorIr, &co2_reading);
+        // Without a CO2 reading only the dosing is skipped; humidity control and the
+        // other alarms carry on
+        let current_co2 = match co2_reading {
             Ok(ppm) => {
                 info!("CO2 reading: {} ppm", ppm);
-                ppm as f32
+                Some(ppm as f32)
             }
             Err(e) => {
                 error!("CO2 sensor error: {}", e);
-                Text::with_alignment(
-                    "CO2 SENSOR ERROR",
-                    Point::new(320 / 2, 120),
-                    style,
-                    Alignment::Center,
-                )
-                .draw(&mut lcd)
-                .unwrap();
-                continue;
+                None
             }
         };
 
-        watchdog.pet();
-        Timer::after_secs(1).await;
-        let temp_error = TARGET_TEMP_C - current_temp;
-        if temp_error > TEMP_TOLERANCE_C {
-            info!("Activating heater: temp diff {}", temp_error);
-            heater.heat().await; //3000ms heat cycle
+        // The flow reading is informational, so a failure does not skip the cycle
+        let flow_reading = flow_sensor.read_sample().await;
+        diagnostics::record(Device::FlowSensor, &flow_reading);
+        let current_flow = match flow_reading {
+            Ok((flow, _)) => {
+                info!("Flow reading: {} ml/min", flow);
+                Some(flow)
+            }
+            Err(e) => {
+                warn!("Flow sensor error: {}", e);
+                None
+            }
+        };
+        let supply_bar = if config.co2_pressure_sensor {
+            let pressure_reading = supply_pressure.read_bar(config.co2_pressure_full_bar);
+            diagnostics::record(Device::PressureTransducer, &pressure_reading);
+            match pressure_reading {
+                Ok(bar) => {
+                    info!("CO2 supply pressure: {} bar", bar);
+                    Some(bar)
+                }
+                Err(e) => {
+                    warn!("CO2 supply pressure: {}", e);
+                    None
+                }
+            }
         } else {
-            heater.stop();
-        }
-        Timer::after_secs(2).await;
+            None
+        };
 
         watchdog.pet();
-        let co2_error = TARGET_CO2_PPM - current_co2;
-        if co2_error > CO2_TOLERANCE_PPM {
-            info!("Activating CO2: diff {}", co2_error);
-            co2_valve.execute_burst(500).await;
-        }
-
-        let co2_num = co2_buf.format(current_co2 as i32);
-        co2_str.push_str("CO2: ").unwrap();
-        co2_str.push_str(co2_num).unwrap();
-        co2_str.push_str(" PPM").unwrap();
-
-        let temp_whole = temp_buf.format(current_temp as i32);
-        let temp_fract = ((libm::fmodf(current_temp, 1.0) * 10.0) as i32).abs();
-        let temp_fract_str = fract_buf.format(temp_fract);
-        temp_str.push_str("TEMP: ").unwrap();
-        temp_str.push_str(temp_whole).unwrap();
-        temp_str.push_str(".").unwrap();
-        temp_str.push_str(temp_fract_str).unwrap();
-        temp_str.push_str(" C").unwrap();
+        let switch_open = config.door_switch && door_switch.is_open();
+        let (door, burst_ms, co2_dosed, response_exhausted) = match current_co2 {
+            Some(co2) => {
+                let door = door_monitor.update(&config, switch_open, co2);
+                let co2_error = config.target_co2_ppm - co2;
+                let burst_ms = door_monitor.burst_ms(&config, co2_error);
+                let co2_dosed = burst_ms > 0 && co2_error > config.co2_tolerance_ppm;
+                if co2_dosed {
+                    info!("Activating CO2: diff {}, {} ms", co2_error, burst_ms);
+                    co2_valve.configure(valve_hold(&config));
+                    co2_valve.execute_burst(burst_ms as u64).await;
+                    diagnostics::record_use(Device::Co2Valve);
+                    co2_bursts += 1;
+                    save_valve_wear(co2_valve.wear());
+                }
+                let dosed_ms = if co2_dosed { burst_ms } else { 0 };
+                let exhausted = supply_monitor.update(door, co2, dosed_ms);
+                (door, burst_ms, co2_dosed, exhausted)
+            }
+            // Door and supply are held as they were while the CO2 is unknown
+            None => (
+                door_monitor.state(),
+                0,
+                false,
+                supply_monitor.is_exhausted(),
+            ),
+        };
+        let supply_exhausted =
+            response_exhausted || supply_bar.is_some_and(|bar| bar < config.co2_pressure_min_bar);
+
         watchdog.pet();
+        let humidifier_ms = match current_rh {
+            Some(rh) => humidity_controller.update(&config, rh),
+            None => 0,
+        };
+        humidifier.pulse(humidifier_ms).await;
+        if humidifier_ms > 0 {
+            diagnostics::record_use(Device::Humidifier);
+        }
+
+        let mut alarms = 0;
+        match temp_error {
+            Some(temp_error) => {
+                if fabsf(temp_error) > ALARM_TOLERANCE_FACTOR * config.temp_tolerance_c {
+                    alarms |= alarm::TEMP_OUT_OF_RANGE;
+                }
+            }
+            None => alarms |= alarm::TEMP_SENSOR_FAULT,
+        }
+        match current_co2 {
+            Some(co2) => {
+                let co2_error = config.target_co2_ppm - co2;
+                if fabsf(co2_error) > ALARM_TOLERANCE_FACTOR * config.co2_tolerance_ppm {
+                    alarms |= alarm::CO2_OUT_OF_RANGE;
+                }
+                if co2_check.update(&config, co2, scd41_co2) {
+                    alarms |= alarm::CO2_SENSOR_MISMATCH;
+                }
+            }
+            None => alarms |= alarm::CO2_SENSOR_FAULT,
+        }
+        // Without the SCD41 the temperature may still have been estimated, so the
+        // humidity is flagged on its own
+        if current_rh.is_none() {
+            alarms |= alarm::HUMIDITY_SENSOR_FAULT;
+        }
+        // Held as it was while the humidity is unknown
+        let was_dry = state::readings().alarms & alarm::HUMIDITY_LOW != 0;
+        if current_rh.map_or(was_dry, |rh| humidity::humidity_low(&config, rh, was_dry)) {
+            alarms |= alarm::HUMIDITY_LOW;
+        }
+        if peltier.fault().is_some() {
+            alarms |= alarm::ACTUATOR_FAULT;
+        }
+        if door.is_open() {
+            alarms |= alarm::DOOR_OPEN;
+        }
+        if supply_exhausted {
+            alarms |= alarm::CO2_SUPPLY_EMPTY;
+        }
+
+        state::update_readings(|r| {
+            r.temp_c = current_temp;
+            r.temp_uncertainty_c = estimate.map(|estimate| estimate.uncertainty_c);
+            r.temp_source = estimate.map(|estimate| estimate.source);
+            r.co2_ppm = current_co2;
+            r.scd41_co2_ppm = scd41_co2;
+            r.humidity_rh = current_rh;
+            r.flow_ml_min = current_flow;
+            r.heater_on = heater_on;
+            r.heater_duty_pct = heater_duty_pct;
+            r.door = door;
+            r.co2_supply_bar = supply_bar;
+            r.co2_response_ppm_s = supply_monitor.response_ppm_per_s();
+            r.valve_wear = co2_valve.wear();
+            r.co2_valve_duty_pct = if co2_dosed {
+                duty_pct(burst_ms as u64)
+            } else {
+                0.0
+            };
+            r.humidifier_on = humidifier_ms > 0;
+            r.humidifier_duty_pct = duty_pct(humidifier_ms as u64);
+            r.peltier_output_pct = peltier_pct;
+            r.co2_bursts = co2_bursts;
+            r.alarms = alarms;
+            r.updated_at_s = Instant::now().as_secs();
+        });
+        log_sample(&mut datalog, &mut flash, &mut sd_log);
+
+        if show_readings {
+            co2_str.push_str("CO2: ").unwrap();
+            match current_co2 {
+                Some(co2) => {
+                    co2_str.push_str(co2_buf.format(co2 as i32)).unwrap();
+                    co2_str.push_str(" PPM").unwrap();
+                }
+                None => co2_str.push_str("ERROR").unwrap(),
+            }
+
+            temp_str.push_str("TEMP: ").unwrap();
+            match current_temp {
+                Some(temp) => {
+                    let temp_fract = ((libm::fmodf(temp, 1.0) * 10.0) as i32).abs();
+                    temp_str.push_str(temp_buf.format(temp as i32)).unwrap();
+                    temp_str.push_str(".").unwrap();
+                    temp_str.push_str(fract_buf.format(temp_fract)).unwrap();
+                    temp_str.push_str(" C").unwrap();
+                }
+                None => temp_str.push_str("ERROR").unwrap(),
+            }
 
-        Text::with_alignment(&co2_str, Point::new(320 / 2, 100), style, Alignment::Center)
+            rh_str.push_str("RH: ").unwrap();
+            match current_rh {
+                Some(rh) => {
+                    let rh_fract = ((libm::fmodf(rh, 1.0) * 10.0) as i32).abs();
+                    rh_str.push_str(rh_buf.format(rh as i32)).unwrap();
+                    rh_str.push_str(".").unwrap();
+                    rh_str.push_str(fract_buf.format(rh_fract)).unwrap();
+                    rh_str.push_str(" %").unwrap();
+                }
+                None => rh_str.push_str("ERROR").unwrap(),
+            }
+            watchdog.pet();
+
+            Text::with_alignment(&co2_str, Point::new(320 / 2, 100), style, Alignment::Center)
+                .draw(&mut lcd)
+                .unwrap();
+
+            Text::with_alignment(
+                &temp_str,
+                Point::new(320 / 2, 125),
+                style,
+                Alignment::Center,
+            )
             .draw(&mut lcd)
             .unwrap();
 
-        Text::with_alignment(
-            &temp_str,
-            Point::new(320 / 2, 140),
-            style,
-            Alignment::Center,
-        )
-        .draw(&mut lcd)
-        .unwrap();
-
-        let temp_stable = fabsf(TARGET_TEMP_C - current_temp) <= TEMP_TOLERANCE_C;
-        let co2_stable = fabsf(TARGET_CO2_PPM - current_co2) <= CO2_TOLERANCE_PPM;
-
-        lcd.fill_solid(
-            &Rectangle::new(Point::new(0, 160), Size::new(320, 20)),
-            Rgb565::BLACK,
-        )
-        .unwrap();
-
-        Text::with_alignment(
-            if temp_stable && co2_stable {
-                "STABLE"
-            } else {
-                "ADJUSTING"
-            },
-            Point::new(320 / 2, 180),
-            style,
-            Alignment::Center,
-        )
-        .draw(&mut lcd)
-        .unwrap();
-
-        Text::with_alignment(
-            "GENERAL CYBERNETICS CORPORATION",
-            Point::new(320 / 2, 230),
-            style,
-            Alignment::Center,
-        )
-        .draw(&mut lcd)
-        .unwrap();
+            Text::with_alignment(&rh_str, Point::new(320 / 2, 150), style, Alignment::Center)
+                .draw(&mut lcd)
+                .unwrap();
+
+            let temp_stable =
+                temp_error.is_some_and(|error| fabsf(error) <= config.temp_tolerance_c);
+            let co2_stable = current_co2
+                .is_some_and(|co2| fabsf(config.target_co2_ppm - co2) <= config.co2_tolerance_ppm);
+            // Humidity only counts when it is controlled
+            let rh_stable = !config.humidifier_enabled
+                || current_rh.is_some_and(|rh| {
+                    fabsf(config.target_humidity_rh - rh) <= config.humidity_tolerance_rh
+                });
+
+            lcd.fill_solid(
+                &Rectangle::new(Point::new(0, 160), Size::new(320, 20)),
+                Rgb565::BLACK,
+            )
+            .unwrap();
+
+            Text::with_alignment(
+                if temp_stable && co2_stable && rh_stable {
+                    "STABLE"
+                } else {
+                    "ADJUSTING"
+                },
+                Point::new(320 / 2, 180),
+                style,
+                Alignment::Center,
+            )
+            .draw(&mut lcd)
+            .unwrap();
+
+            Text::with_alignment(
+                "GENERAL CYBERNETICS CORPORATION",
+                Point::new(320 / 2, 230),
+                style,
+                Alignment::Center,
+            )
+            .draw(&mut lcd)
+            .unwrap();
+        }
 
         info!("Loop iteration complete");
         watchdog.pet();
     }
 }
+
+// Counters, self-test results and serial numbers of every device, two lines each
+fn draw_diagnostics<D>(lcd: &mut D, style: MonoTextStyle<'_, Rgb565>)
+where
+    D: DrawTarget<Color = Rgb565>,
+    D::Error: core::fmt::Debug,
+{
+    let small = MonoTextStyle::new(&FONT_6X10, Rgb565::GREEN);
+    lcd.clear(Rgb565::BLACK).unwrap();
+    Text::with_alignment(
+        "DIAGNOSTICS",
+        Point::new(320 / 2, 20),
+        style,
+        Alignment::Center,
+    )
+    .draw(lcd)
+    .unwrap();
+
+    for (i, device) in Device::ALL.into_iter().enumerate() {
+        let health = diagnostics::health(device);
+        // One display line each; whatever does not fit is cut off
+        let mut lines: [String<53>; 2] = Default::default();
+        let _ = write!(lines[0], "{}", device.name());
+        let _ = match health.last_good {
+            Some(at) => write!(lines[0], "  good {} s ago", at.elapsed().as_secs()),
+            None => write!(lines[0], "  never read"),
+        };
+        if let Some(passed) = health.self_test {
+            let _ = write!(
+                lines[0],
+                "  self-test {}",
+                if passed { "pass" } else { "FAIL" }
+            );
+        }
+        if let Some(serial) = &health.serial {
+            let _ = write!(lines[0], "  sn {}", serial);
+        }
+        let _ = write!(
+            lines[1],
+            "  ok {} t/o {} crc {} parse {} other {}",
+            health.reads,
+            health.timeouts,
+            health.crc_errors,
+            health.parse_errors,
+            health.other_errors
+        );
+        if let Some(e) = health.last_error {
+            let _ = write!(lines[1], "  {}", e);
+        }
+        for (j, line) in lines.iter().enumerate() {
+            let y = 40 + 22 * i as i32 + 10 * j as i32;
+            Text::new(line, Point::new(4, y), small).draw(lcd).unwrap();
+        }
+    }
+}
+
+fn peltier_in_use(peltier: &Peltier<'_>) -> bool {
+    state::config().thermal_actuator == ThermalActuator::Peltier && peltier.fault().is_none()
+}
+
+// Resolves with the fault the bridge signals on nFAULT while the Peltier is in use
+async fn bridge_fault(monitor: &mut Bridge<'_>, peltier: &Peltier<'_>) -> Option<BridgeFault> {
+    if !peltier_in_use(peltier) {
+        return core::future::pending().await;
+    }
+    monitor.wait_for_fault(peltier.output_pct()).await
+}
+
+// Resolves with the new state of the door switch, if one is fitted
+async fn door_change(switch: &mut DoorSwitch<'_>) -> bool {
+    if !state::config().door_switch {
+        return core::future::pending().await;
+    }
+    switch.wait_for_change().await
+}
+
+// Runs `work`, petting the watchdog every WATCHDOG_PET_SECS until it completes
+async fn pet_during<F: Future>(
+    watchdog: &mut IndependentWatchdog<'_, peripherals::IWDG>,
+    work: F,
+) -> F::Output {
+    let pet = async {
+        loop {
+            Timer::after_secs(WATCHDOG_PET_SECS).await;
+            watchdog.pet();
+        }
+    };
+    match select(work, pet).await {
+        Either::First(output) => output,
+        Either::Second(_) => unreachable!(),
+    }
+}
+
+// Samples the bridge current, disables the bridge on `fault` or one found now, and
+// publishes the result
+fn check_bridge(monitor: &mut Bridge<'_>, peltier: &mut Peltier<'_>, fault: Option<BridgeFault>) {
+    let (current_a, found) = monitor.check(peltier.output_pct());
+    let fault = fault.or(found);
+    diagnostics::record(
+        Device::PeltierBridge,
+        &fault.map_or(Ok(()), |f| Err(DeviceError::other(f.name()))),
+    );
+    if let Some(fault) = fault {
+        peltier.trip(fault);
+    }
+    let fault = peltier.fault();
+    state::update_readings(|r| {
+        r.peltier_current_a = Some(current_a);
+        r.bridge_fault = fault;
+        if fault.is_some() {
+            r.alarms |= alarm::ACTUATOR_FAULT;
+            r.peltier_output_pct = 0.0;
+        }
+    });
+}
+
+// Reads the thermistor bank and disables the Peltier bridge when its heatsink runs
+// too hot. Without a readable heatsink probe only the bridge's own thermal shutdown
+// protects the module.
+async fn read_probes(
+    bank: &mut ThermistorBank<'_>,
+    peltier: &mut Peltier<'_>,
+    fusion: &mut TemperatureFusion,
+) {
+    let config = state::config();
+    for probe in Probe::ALL {
+        bank.set_config(probe, thermal::probe_config(&config, probe));
+    }
+    let reading = bank.read().await;
+    diagnostics::record(Device::Thermistors, &reading);
+    let reading = match reading {
+        Ok(reading) => reading,
+        Err(e) => {
+            warn!("Thermistor bank: {}", e);
+            return;
+        }
+    };
+    if let Ok(air) = reading[Probe::ChamberAir as usize] {
+        fusion.thermistor(air.temp_c);
+    }
+    if let Ok(heatsink) = reading[Probe::Heatsink as usize] {
+        if heatsink.temp_c > config.heatsink_max_c && peltier_in_use(peltier) {
+            peltier.trip(BridgeFault::HeatsinkOverTemperature);
+        }
+    }
+    let fault = peltier.fault();
+    state::update_readings(|r| {
+        r.probes = reading;
+        r.bridge_fault = fault;
+        if fault.is_some() {
+            r.alarms |= alarm::ACTUATOR_FAULT;
+            r.peltier_output_pct = 0.0;
+        }
+    });
+}
+
+fn valve_hold(config: &Config) -> HoldConfig {
+    HoldConfig {
+        pull_in: Duration::from_millis(config.valve_pull_in_ms as u64),
+        hold_pct: config.valve_hold_pct,
+    }
+}
+
+// RTC backup registers holding the valve wear: the open time in ms (low and high word)
+// and the number of openings. They are updated after every burst but lost with the
+// backup domain's power, so the configuration record keeps a copy in flash that is
+// written through every VALVE_WEAR_FLASH_SECS.
+const VALVE_WEAR_REGISTERS: [usize; 3] = [0, 1, 2];
+
+fn load_valve_wear(flushed: Wear) -> Wear {
+    let [low, high, actuations] = VALVE_WEAR_REGISTERS.map(rtc::backup_register);
+    let cached = Wear {
+        on_time_ms: (high as u64) << 32 | low as u64,
+        actuations,
+    };
+    // The registers are ahead of flash unless they were lost
+    let lost = cached.on_time_ms < flushed.on_time_ms || cached.actuations < flushed.actuations;
+    let wear = if lost { flushed } else { cached };
+    info!("CO2 valve wear: {}", wear);
+    wear
+}
+
+fn save_valve_wear(wear: Wear) {
+    let [low, high, actuations] = VALVE_WEAR_REGISTERS;
+    rtc::set_backup_register(low, wear.on_time_ms as u32);
+    rtc::set_backup_register(high, (wear.on_time_ms >> 32) as u32);
+    rtc::set_backup_register(actuations, wear.actuations);
+}
+
+// Saves the configuration with `wear` if that differs from what was saved last
+fn flush_valve_wear(
+    flash: &mut Flash<'_, Blocking>,
+    config_store: &mut ConfigStore,
+    wear: Wear,
+) -> Result<(), &'static str> {
+    let config = state::config();
+    if config.valve_wear == wear {
+        return Ok(());
+    }
+    let config = Config {
+        valve_wear: wear,
+        ..config
+    };
+    config_store.save(flash, &config)?;
+    state::set_config(config);
+    Ok(())
+}
+
+fn duty_pct(on_ms: u64) -> f32 {
+    on_ms as f32 * 100.0 / (CONTROL_PERIOD_SECS * 1000) as f32
+}
+
+// Appends the published readings to the flash data log and the SD card. Failures are
+// only reported so that logging never holds up the control loop.
+fn log_sample(datalog: &mut DataLog, flash: &mut Flash<'_, Blocking>, sd_log: &mut SdLog<'_>) {
+    let readings = state::readings();
+    let mut actuators = 0;
+    if readings.heater_on {
+        actuators |= actuator::HEATER_ON;
+    }
+    if readings.co2_valve_duty_pct > 0.0 {
+        actuators |= actuator::CO2_DOSED;
+    }
+    if readings.humidifier_on {
+        actuators |= actuator::HUMIDIFIER_ON;
+    }
+    let sample = Sample {
+        time_s: Instant::now().as_secs() as u32,
+        unix_s: rtc::unix_time(),
+        temp_c: readings.temp_c,
+        humidity_rh: readings.humidity_rh,
+        co2_ppm: readings.co2_ppm,
+        flow_ml_min: readings.flow_ml_min,
+        state: actuators,
+        alarms: readings.alarms,
+    };
+    if let Err(e) = datalog.append(flash, &sample) {
+        warn!("Data log: {}", e);
+    }
+    sd_log.append(&sample);
+}
+
+async fn handle_request(
+    request: Request,
+    scd41sensor: &mut SCD41<'_>,
+    co2_sensor: &mut ExplorIrME100<'_>,
+    flash: &mut Flash<'_, Blocking>,
+    config_store: &mut ConfigStore,
+    calibration_log: &mut CalibrationLog,
+    datalog: &mut DataLog,
+    sd_log: &mut SdLog<'_>,
+    profile_runner: &mut ProfileRunner,
+    peltier: &mut Peltier<'_>,
+    heater: &mut Heater<'_>,
+    humidifier: &mut Humidifier<'_>,
+    co2_valve: &mut Co2Solenoid<'_>,
+    watchdog: &mut IndependentWatchdog<'_, peripherals::IWDG>,
+) -> Reply {
+    match request {
+        Request::SetConfig(config) => {
+            // Whatever wear the requester copied may be stale
+            let config = Config {
+                valve_wear: co2_valve.wear(),
+                ..config
+            };
+            match config_store.save(flash, &config) {
+                Ok(()) => {
+                    info!("Configuration updated: {}", config);
+                    state::set_config(config);
+                    Reply::Done
+                }
+                Err(e) => Reply::Error(e),
+            }
+        }
+        // The valve wear is not a setting and is saved again right away
+        Request::ResetConfig => {
+            let config = Config {
+                valve_wear: co2_valve.wear(),
+                ..Config::default()
+            };
+            match config_store
+                .reset(flash)
+                .and_then(|()| config_store.save(flash, &config))
+            {
+                Ok(()) => {
+                    info!("Configuration reset to defaults");
+                    state::set_config(config);
+                    Reply::Done
+                }
+                Err(e) => Reply::Error(e),
+            }
+        }
+        Request::Calibrate => {
+            // Nothing but the watchdog is serviced for the minutes this takes, so all
+            // actuators are switched off rather than left at their last output
+            peltier.stop();
+            heater.stop();
+            humidifier.stop();
+            co2_valve.stop_continuous();
+            state::update_readings(|r| {
+                r.heater_on = false;
+                r.humidifier_on = false;
+                r.peltier_output_pct = 0.0;
+            });
+            let config = state::config();
+            let record =
+                calibration::run(scd41sensor, co2_sensor, &config, || watchdog.pet()).await;
+            match calibration_log.append(flash, &record) {
+                Ok(seq) => Reply::Calibrated { seq },
+                Err(e) => Reply::Error(e),
+            }
+        }
+        Request::SelfTest => {
+            // The SCD41 only accepts the self-test command while idle
+            let scd41 = match scd41sensor.stop_periodic_measurement().await {
+                Ok(()) => {
+                    let passed = matches!(scd41sensor.perform_self_test().await, Ok(true));
+                    record_scd41_serial(scd41sensor).await;
+                    if let Err(e) = scd41sensor.start_periodic_measurement().await {
+                        error!("Failed to restart periodic measurement: {}", e);
+                    }
+                    passed
+                }
+                Err(_) => false,
+            };
+            watchdog.pet();
+            let explorir = record_explorir_serial(co2_sensor).await;
+            info!("Self-test: SCD41 {}, ExplorIR {}", scd41, explorir);
+            diagnostics::set_self_test(Device::Scd41, scd41);
+            diagnostics::set_self_test(Device::ExplorIr, explorir);
+            Reply::SelfTest { scd41, explorir }
+        }
+        Request::ReadCalibration(index) => {
+            if calibration_log.is_empty() {
+                return Reply::Error("No calibration records");
+            }
+            match calibration_log.read(flash, index) {
+                Ok((seq, record)) => Reply::CalibrationEntry {
+                    seq,
+                    total: calibration_log.len(),
+                    record,
+                },
+                Err(e) => Reply::Error(e),
+            }
+        }
+        Request::LogInfo => {
+            let (blocks, first_seq, last_seq) = datalog.info(flash);
+            Reply::LogInfo {
+                blocks,
+                first_seq,
+                last_seq,
+            }
+        }
+        Request::ReadLog { seq, offset } => {
+            let mut chunk = LogChunk {
+                seq,
+                offset,
+                block_len: 0,
+                len: 0,
+                data: [0; LOG_CHUNK_SIZE],
+            };
+            match datalog.read(flash, seq, offset as usize, &mut chunk.data) {
+                Ok((block_len, len)) => {
+                    chunk.block_len = block_len as u16;
+                    chunk.len = len as u8;
+                    Reply::LogChunk(chunk)
+                }
+                Err(e) => Reply::Error(e),
+            }
+        }
+        Request::SdStatus => Reply::SdStatus(sd_log.status()),
+        Request::NewRun => match sd_log.new_run() {
+            Ok(_) => Reply::SdStatus(sd_log.status()),
+            Err(e) => Reply::Error(e),
+        },
+        Request::ClearProfile => done(profile_runner.clear(flash)),
+        Request::AddProfileSegment { variable, segment } => {
+            done(profile_runner.add(flash, variable, segment))
+        }
+        Request::ProfileControl(command) => done(profile_runner.control(flash, command)),
+        Request::ClearBridgeFault => {
+            if peltier.fault().is_none() {
+                return Reply::Error("No bridge fault");
+            }
+            peltier.clear_fault();
+            state::update_readings(|r| {
+                r.bridge_fault = None;
+                r.alarms &= !alarm::ACTUATOR_FAULT;
+            });
+            Reply::Done
+        }
+        Request::ResetValveWear => {
+            info!("CO2 valve wear reset at {}", co2_valve.wear());
+            co2_valve.set_wear(Wear::default());
+            save_valve_wear(Wear::default());
+            state::update_readings(|r| r.valve_wear = Wear::default());
+            match flush_valve_wear(flash, config_store, Wear::default()) {
+                Ok(()) => Reply::Done,
+                Err(e) => Reply::Error(e),
+            }
+        }
+    }
+}
+
+// Needs the SCD41 idle, i.e. its periodic measurement stopped
+async fn record_scd41_serial(scd41sensor: &mut SCD41<'_>) {
+    match scd41sensor.get_serial_number().await {
+        Ok(raw) => {
+            let mut serial: String<16> = String::new();
+            let _ = write!(serial, "{:012X}", scd41_serial_from_raw(&raw));
+            diagnostics::set_serial(Device::Scd41, &serial);
+        }
+        Err(e) => warn!("Failed to read SCD41 serial number: {}", e),
+    }
+}
+
+// The ExplorIR has no self-test; answering with its serial number counts as passing
+async fn record_explorir_serial(co2_sensor: &mut ExplorIrME100<'_>) -> bool {
+    match co2_sensor.read_serial_no().await {
+        Ok(serial) => {
+            diagnostics::set_serial(Device::ExplorIr, &serial);
+            true
+        }
+        Err(e) => {
+            warn!("Failed to read ExplorIR serial number: {}", e);
+            false
+        }
+    }
+}
+
+fn done(result: Result<(), &'static str>) -> Reply {
+    match result {
+        Ok(()) => Reply::Done,
+        Err(e) => Reply::Error(e),
+    }
+}
@@ -0,0 +1,113 @@
+pub mod rtu;
+
+use defmt::{info, warn};
+use icbm_protocol::message::Setpoints;
+use icbm_protocol::modbus::{self, map, Exception, Outcome, RegisterMap};
+
+use crate::state::{self, Readings, Reply, Request, SharedReply};
+use crate::storage::config::Config;
+
+// Shared by the RTU task and the TCP connection tasks
+static REPLY: SharedReply = SharedReply::new();
+
+// Modbus slave for building management systems. The TCP (net/modbus.rs) and RTU
+// (rtu.rs) transports serve one register map (see icbm_protocol::modbus::map): readings
+// and alarm flags as input registers, setpoints and tolerances (humidity included) as
+// holding registers.
+//
+// Handles one request PDU and returns the length of the response PDU in `response`.
+// Written setpoints are validated and persisted like a console `set`.
+pub async fn handle(request: &[u8], response: &mut [u8]) -> usize {
+    let config = state::config();
+    let mut map = RegisterMap::new(&inputs(&state::readings()), &holding(&config));
+    let before = map.holding;
+
+    match modbus::process(&mut map, request, response) {
+        Outcome::Reply(len) => len,
+        Outcome::Written(len) => match apply(&before, &map.holding, config).await {
+            Ok(()) => len,
+            Err(e) => modbus::exception(request[0], e, response),
+        },
+    }
+}
+
+async fn apply(
+    before: &[u16; map::HOLDING_COUNT],
+    after: &[u16; map::HOLDING_COUNT],
+    mut config: Config,
+) -> Result<(), Exception> {
+    // Only setpoints whose registers changed are taken over, so values finer than the
+    // register resolution survive writes to the other setpoints
+    let changed = |addr: u16, words: usize| {
+        before[addr as usize..addr as usize + words] != after[addr as usize..addr as usize + words]
+    };
+    let written = map::decode_holding(after);
+    if changed(map::CO2_SETPOINT, 2) {
+        config.target_co2_ppm = written.setpoints.co2_ppm;
+    }
+    if changed(map::TEMP_SETPOINT, 1) {
+        config.target_temp_c = written.setpoints.temp_c;
+    }
+    if changed(map::CO2_TOLERANCE, 2) {
+        config.co2_tolerance_ppm = written.setpoints.co2_tolerance_ppm;
+    }
+    if changed(map::TEMP_TOLERANCE, 1) {
+        config.temp_tolerance_c = written.setpoints.temp_tolerance_c;
+    }
+    if changed(map::CO2_BURST, 1) {
+        config.co2_burst_ms = written.setpoints.co2_burst_ms;
+    }
+    if changed(map::HUMIDITY_SETPOINT, 1) {
+        config.target_humidity_rh = written.humidity_rh;
+    }
+    if changed(map::HUMIDITY_TOLERANCE, 1) {
+        config.humidity_tolerance_rh = written.humidity_tolerance_rh;
+    }
+    if config == state::config() {
+        return Ok(());
+    }
+
+    if let Err(e) = config.validate() {
+        warn!("Rejected Modbus setpoint write: {}", e);
+        return Err(Exception::IllegalDataValue);
+    }
+    match REPLY.request(Request::SetConfig(config)).await {
+        Reply::Error(e) => {
+            warn!("Failed to apply Modbus setpoint write: {}", e);
+            Err(Exception::ServerDeviceFailure)
+        }
+        _ => {
+            info!("Setpoints changed over Modbus");
+            Ok(())
+        }
+    }
+}
+
+fn inputs(readings: &Readings) -> map::Inputs {
+    map::Inputs {
+        temp_c: readings.temp_c,
+        humidity_rh: readings.humidity_rh,
+        co2_ppm: readings.co2_ppm,
+        flow_ml_min: readings.flow_ml_min,
+        heater_on: readings.heater_on,
+        heater_duty_pct: readings.heater_duty_pct,
+        co2_valve_duty_pct: readings.co2_valve_duty_pct,
+        co2_bursts: readings.co2_bursts,
+        alarms: readings.alarms as u16,
+        updated_at_s: readings.updated_at_s as u32,
+    }
+}
+
+fn holding(config: &Config) -> map::Holding {
+    map::Holding {
+        setpoints: Setpoints {
+            co2_ppm: config.target_co2_ppm,
+            temp_c: config.target_temp_c,
+            co2_tolerance_ppm: config.co2_tolerance_ppm,
+            temp_tolerance_c: config.temp_tolerance_c,
+            co2_burst_ms: config.co2_burst_ms,
+        },
+        humidity_rh: config.target_humidity_rh,
+        humidity_tolerance_rh: config.humidity_tolerance_rh,
+    }
+}
@@ -0,0 +1,55 @@
+use defmt::{debug, warn};
+use embassy_stm32::gpio::Output;
+use embassy_stm32::mode::Async;
+use embassy_stm32::usart::Uart;
+use icbm_protocol::modbus::{rtu, MAX_PDU_SIZE};
+
+use crate::state;
+
+pub use icbm_protocol::modbus::rtu::BAUD_RATE;
+
+// Modbus RTU slave on an RS-485 transceiver. Frames are delimited by the UART idle line;
+// the slave address is the `modbusunit` console key. Broadcast (address 0) writes are
+// applied without a response.
+#[embassy_executor::task]
+pub async fn rtu_task(mut uart: Uart<'static, Async>, mut driver_enable: Output<'static>) -> ! {
+    let mut frame = [0u8; rtu::MAX_ADU_SIZE];
+    let mut pdu = [0u8; MAX_PDU_SIZE];
+    let mut reply = [0u8; rtu::MAX_ADU_SIZE];
+    driver_enable.set_low();
+
+    loop {
+        let len = match uart.read_until_idle(&mut frame).await {
+            Ok(len) => len,
+            Err(e) => {
+                debug!("Modbus RTU receive error: {}", e);
+                continue;
+            }
+        };
+        // Corrupted frames are dropped silently; the master retries after its timeout
+        let Some((unit, request)) = rtu::decode(&frame[..len]) else {
+            continue;
+        };
+        let own = state::config().modbus_unit;
+        if unit != own && unit != rtu::BROADCAST {
+            continue;
+        }
+
+        let pdu_len = super::handle(request, &mut pdu).await;
+        if unit == rtu::BROADCAST {
+            continue;
+        }
+        let reply_len = rtu::encode(own, &pdu[..pdu_len], &mut reply);
+
+        // The transceiver only drives the bus while the response is being sent
+        driver_enable.set_high();
+        let result = uart.write(&reply[..reply_len]).await;
+        if let Err(e) = uart.blocking_flush() {
+            warn!("Modbus RTU flush error: {}", e);
+        }
+        driver_enable.set_low();
+        if let Err(e) = result {
+            warn!("Modbus RTU transmit error: {}", e);
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use core::fmt::Write as _;
+
+use defmt::{info, warn};
+use embassy_net::tcp::{self, TcpSocket};
+use embassy_time::{Duration, Timer};
+use embedded_io_async::Write;
+use heapless::String;
+use icbm_protocol::http::{self, Framing, Method, RequestHead, Status};
+
+use super::{telemetry, NetStack};
+use crate::state::{self, Reply, Request, SharedReply};
+use crate::storage::config::Config;
+
+pub const PORT: u16 = 80;
+pub const MAX_CLIENTS: usize = 2;
+
+const SOCKET_BUFFER_SIZE: usize = 1024;
+// Head and body together
+const REQUEST_BUFFER_SIZE: usize = 1024;
+const RESPONSE_HEADER_SIZE: usize = 256;
+// Idle connections are dropped after this long
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+static REPLY: SharedReply = SharedReply::new();
+
+struct Response<'a> {
+    status: u16,
+    reason: &'static str,
+    content_type: &'static str,
+    extra_headers: &'static str,
+    body: &'a [u8],
+}
+
+// Minimal HTTP/1.1 server, one request per connection:
+//
+//   GET /                  HTML dashboard
+//   GET /api/readings      current readings, alarm flags and setpoints (JSON)
+//   GET /api/setpoints     setpoints (JSON)
+//   PUT /api/setpoints     change setpoints; body is a flat JSON object with any of the
+//                          keys returned by GET, e.g. {"temp_c":36.5}. Requires
+//                          `Authorization: Bearer <httptoken>` (console key).
+#[embassy_executor::task(pool_size = MAX_CLIENTS)]
+pub async fn http_task(stack: &'static NetStack) -> ! {
+    let mut rx_buffer = [0u8; SOCKET_BUFFER_SIZE];
+    let mut tx_buffer = [0u8; SOCKET_BUFFER_SIZE];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(TIMEOUT));
+
+        if let Err(e) = socket.accept(PORT).await {
+            warn!("HTTP accept error: {}", e);
+            Timer::after_secs(1).await;
+            continue;
+        }
+
+        if let Err(e) = serve(&mut socket).await {
+            warn!("HTTP connection error: {}", e);
+        }
+        socket.close();
+        let _ = socket.flush().await;
+        socket.abort();
+    }
+}
+
+async fn serve(socket: &mut TcpSocket<'_>) -> Result<(), tcp::Error> {
+    let mut buf = [0u8; REQUEST_BUFFER_SIZE];
+    let mut len = 0;
+
+    let (header_end, body_end) = loop {
+        match http::frame(&buf[..len], buf.len()) {
+            Framing::Complete {
+                header_end,
+                body_end,
+            } => break (header_end, body_end),
+            Framing::Reject(Status { code, reason }) => {
+                return respond(socket, error(code, reason)).await
+            }
+            Framing::Incomplete => {}
+        }
+        let n = socket.read(&mut buf[len..]).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        len += n;
+    };
+
+    // Framed above, so this cannot fail
+    let Some(head) = http::parse_head(&buf[..header_end]) else {
+        return respond(socket, error(400, "Bad Request")).await;
+    };
+    route(socket, &head, &buf[header_end..body_end]).await
+}
+
+async fn route(
+    socket: &mut TcpSocket<'_>,
+    head: &RequestHead<'_>,
+    body: &[u8],
+) -> Result<(), tcp::Error> {
+    let mut json: String<{ telemetry::STATUS_BUFFER_SIZE }> = String::new();
+
+    let response = match (head.path, head.method) {
+        ("/" | "/index.html", Method::Get | Method::Head) => Response {
+            status: 200,
+            reason: "OK",
+            content_type: "text/html; charset=utf-8",
+            extra_headers: "",
+            body: DASHBOARD.as_bytes(),
+        },
+        ("/api/readings", Method::Get | Method::Head) => {
+            // The buffer is sized for the largest status, so this cannot fail
+            let _ = telemetry::write_status(&state::readings(), &state::config(), &mut json);
+            ok_json(&json)
+        }
+        ("/api/setpoints", Method::Get | Method::Head) => {
+            let _ = telemetry::write_setpoints(&state::config(), &mut json);
+            ok_json(&json)
+        }
+        ("/api/setpoints", Method::Put) => {
+            let config = state::config();
+            if let Err(response) = authorize(head, &config) {
+                return respond(socket, response).await;
+            }
+            let mut config = config;
+            if let Err(e) = parse_setpoints(body, &mut config) {
+                let _ = write!(json, "{{\"error\":\"{}\"}}", e);
+                return respond(socket, json_status(400, "Bad Request", &json)).await;
+            }
+            if let Reply::Error(e) = REPLY.request(Request::SetConfig(config)).await {
+                warn!("HTTP setpoint change failed: {}", e);
+                let _ = write!(json, "{{\"error\":\"{}\"}}", e);
+                return respond(socket, json_status(500, "Internal Server Error", &json)).await;
+            }
+            info!("Setpoints changed over HTTP");
+            let _ = telemetry::write_setpoints(&state::config(), &mut json);
+            ok_json(&json)
+        }
+        ("/" | "/index.html" | "/api/readings" | "/api/setpoints", _) => Response {
+            extra_headers: if head.path == "/api/setpoints" {
+                "Allow: GET, HEAD, PUT\r\n"
+            } else {
+                "Allow: GET, HEAD\r\n"
+            },
+            ..error(405, "Method Not Allowed")
+        },
+        _ => error(404, "Not Found"),
+    };
+
+    if head.method == Method::Head {
+        return respond_head(socket, &response).await;
+    }
+    respond(socket, response).await
+}
+
+fn authorize(head: &RequestHead<'_>, config: &Config) -> Result<(), Response<'static>> {
+    if config.http_token.is_empty() {
+        return Err(error(403, "Forbidden"));
+    }
+    let expected = config.http_token.as_str().as_bytes();
+    let provided = head.bearer.map_or(&[][..], str::as_bytes);
+    // Compare without an early exit so the response time does not leak the token
+    let matches = expected.len() == provided.len()
+        && expected
+            .iter()
+            .zip(provided)
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0;
+    if !matches {
+        warn!("Rejected unauthenticated HTTP setpoint change");
+        return Err(Response {
+            extra_headers: "WWW-Authenticate: Bearer\r\n",
+            ..error(401, "Unauthorized")
+        });
+    }
+    Ok(())
+}
+
+// Accepts a flat JSON object of numbers, e.g. {"co2_ppm":50000,"temp_c":37.0}
+fn parse_setpoints(body: &[u8], config: &mut Config) -> Result<(), &'static str> {
+    fn number<T: core::str::FromStr>(value: &str) -> Result<T, &'static str> {
+        value.parse().map_err(|_| "values must be numbers")
+    }
+
+    let body = core::str::from_utf8(body).map_err(|_| "body is not UTF-8")?;
+    let fields = body
+        .trim()
+        .strip_prefix('{')
+        .and_then(|b| b.strip_suffix('}'))
+        .ok_or("body must be a JSON object")?;
+
+    for field in fields.split(',').filter(|f| !f.trim().is_empty()) {
+        let (key, value) = field.split_once(':').ok_or("malformed JSON object")?;
+        let key = key
+            .trim()
+            .strip_prefix('"')
+            .and_then(|k| k.strip_suffix('"'))
+            .ok_or("malformed JSON object")?;
+        let value = value.trim();
+        match key {
+            "co2_ppm" => config.target_co2_ppm = number(value)?,
+            "temp_c" => config.target_temp_c = number(value)?,
+            "co2_tolerance_ppm" => config.co2_tolerance_ppm = number(value)?,
+            "temp_tolerance_c" => config.temp_tolerance_c = number(value)?,
+            "co2_burst_ms" => config.co2_burst_ms = number(value)?,
+            "humidity_rh" => config.target_humidity_rh = number(value)?,
+            "humidity_tolerance_rh" => config.humidity_tolerance_rh = number(value)?,
+            _ => return Err("unknown setpoint"),
+        }
+    }
+    config.validate()
+}
+
+fn ok_json(json: &str) -> Response<'_> {
+    json_status(200, "OK", json)
+}
+
+fn json_status<'a>(status: u16, reason: &'static str, json: &'a str) -> Response<'a> {
+    Response {
+        status,
+        reason,
+        content_type: "application/json",
+        extra_headers: "",
+        body: json.as_bytes(),
+    }
+}
+
+fn error(status: u16, reason: &'static str) -> Response<'static> {
+    Response {
+        status,
+        reason,
+        content_type: "text/plain",
+        extra_headers: "",
+        body: reason.as_bytes(),
+    }
+}
+
+async fn respond(socket: &mut TcpSocket<'_>, response: Response<'_>) -> Result<(), tcp::Error> {
+    respond_head(socket, &response).await?;
+    socket.write_all(response.body).await
+}
+
+async fn respond_head(
+    socket: &mut TcpSocket<'_>,
+    response: &Response<'_>,
+) -> Result<(), tcp::Error> {
+    let mut header: String<RESPONSE_HEADER_SIZE> = String::new();
+    let _ = write!(
+        header,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}\
+         Cache-Control: no-store\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason,
+        response.content_type,
+        response.body.len(),
+        response.extra_headers,
+    );
+    socket.write_all(header.as_bytes()).await
+}
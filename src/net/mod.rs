@@ -0,0 +1,159 @@
+pub mod http;
+pub mod modbus;
+pub mod mqtt;
+pub mod sntp;
+pub mod telemetry;
+
+use defmt::{info, unwrap, warn};
+use embassy_executor::Spawner;
+use embassy_net::{Stack, StackResources};
+use embassy_net_wiznet::chip::W5500;
+use embassy_net_wiznet::{Device, Runner, State};
+use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::gpio::Output;
+use embassy_stm32::mode::Async;
+use embassy_stm32::spi::Spi;
+use embassy_time::{Delay, Timer};
+use embedded_hal_async::spi::{Operation, SpiDevice};
+use embedded_hal_bus::spi::ExclusiveDevice;
+use static_cell::StaticCell;
+
+// Frame buffers in the W5500 driver channel
+const RX_FRAMES: usize = 8;
+const TX_FRAMES: usize = 8;
+// DHCP, the MQTT connection, SNTP and one socket per telemetry, HTTP and Modbus client
+const SOCKETS: usize = 3 + telemetry::MAX_CLIENTS + http::MAX_CLIENTS + modbus::MAX_CLIENTS;
+
+// W5500 common register holding the silicon version, which always reads 0x04
+const VERSIONR: u16 = 0x0039;
+const W5500_VERSION: u8 = 0x04;
+// Wait between attempts to find a missing or unresponsive W5500
+const RETRY_SECS: u64 = 10;
+
+pub type EthernetSpi = ExclusiveDevice<Spi<'static, Async>, Output<'static>, Delay>;
+pub type EthernetDevice = Device<'static>;
+pub type NetStack = Stack<EthernetDevice>;
+
+// Spawns the W5500 bring-up, which starts DHCP and the network tasks once the chip
+// answers. Networking is optional, so a missing or faulty W5500 only delays this task
+// and never holds up boot or control. `seed` randomises TCP sequence numbers and ports
+// and should come from the hardware RNG.
+pub fn init(
+    spawner: &Spawner,
+    spi: EthernetSpi,
+    int: ExtiInput<'static>,
+    reset: Output<'static>,
+    seed: u64,
+) {
+    unwrap!(spawner.spawn(bring_up_task(*spawner, spi, int, reset, seed)));
+}
+
+#[embassy_executor::task]
+async fn bring_up_task(
+    spawner: Spawner,
+    mut spi: EthernetSpi,
+    int: ExtiInput<'static>,
+    mut reset: Output<'static>,
+    seed: u64,
+) {
+    static STATE: StaticCell<State<RX_FRAMES, TX_FRAMES>> = StaticCell::new();
+    static RESOURCES: StaticCell<StackResources<SOCKETS>> = StaticCell::new();
+    static STACK: StaticCell<NetStack> = StaticCell::new();
+
+    // The driver unwraps its chip initialization, so only hand it a W5500 that has
+    // already answered
+    while !chip_responds(&mut spi, &mut reset).await {
+        warn!("W5500 not responding, retrying in {}s", RETRY_SECS);
+        Timer::after_secs(RETRY_SECS).await;
+    }
+
+    let mac_addr = mac_address();
+    info!("Ethernet MAC address: {:02x}", mac_addr);
+
+    let (device, runner) =
+        embassy_net_wiznet::new(mac_addr, STATE.init(State::new()), spi, int, reset).await;
+    unwrap!(spawner.spawn(ethernet_task(runner)));
+
+    let stack = &*STACK.init(Stack::new(
+        device,
+        embassy_net::Config::dhcpv4(Default::default()),
+        RESOURCES.init(StackResources::new()),
+        seed,
+    ));
+    unwrap!(spawner.spawn(net_task(stack)));
+    unwrap!(spawner.spawn(link_task(stack)));
+    unwrap!(spawner.spawn(mqtt::mqtt_task(stack)));
+    unwrap!(spawner.spawn(sntp::sntp_task(stack)));
+
+    for _ in 0..telemetry::MAX_CLIENTS {
+        unwrap!(spawner.spawn(telemetry::telemetry_task(stack)));
+    }
+    for _ in 0..http::MAX_CLIENTS {
+        unwrap!(spawner.spawn(http::http_task(stack)));
+    }
+    for _ in 0..modbus::MAX_CLIENTS {
+        unwrap!(spawner.spawn(modbus::modbus_task(stack)));
+    }
+}
+
+// Resets the W5500 and reads its version register. A missing chip reads back as all
+// zeros or all ones, depending on how MISO floats.
+async fn chip_responds(spi: &mut EthernetSpi, reset: &mut Output<'static>) -> bool {
+    reset.set_low();
+    Timer::after_millis(1).await;
+    reset.set_high();
+    // PLL lock time after reset
+    Timer::after_millis(2).await;
+
+    // Address, then a control byte selecting the common block for a variable-length read
+    let [addr_hi, addr_lo] = VERSIONR.to_be_bytes();
+    let mut version = [0u8];
+    let read = spi
+        .transaction(&mut [
+            Operation::Write(&[addr_hi, addr_lo, 0x00]),
+            Operation::Read(&mut version),
+        ])
+        .await;
+    read.is_ok() && version[0] == W5500_VERSION
+}
+
+// Locally administered unicast address derived from the MCU unique ID so every
+// incubator gets a stable, distinct MAC
+fn mac_address() -> [u8; 6] {
+    let uid = embassy_stm32::uid::uid();
+    [
+        0x02,
+        uid[0] ^ uid[6],
+        uid[1] ^ uid[7],
+        uid[2] ^ uid[8],
+        uid[3] ^ uid[9],
+        uid[4] ^ uid[10] ^ uid[5] ^ uid[11],
+    ]
+}
+
+#[embassy_executor::task]
+async fn ethernet_task(
+    runner: Runner<'static, W5500, EthernetSpi, ExtiInput<'static>, Output<'static>>,
+) -> ! {
+    runner.run().await
+}
+
+#[embassy_executor::task]
+async fn net_task(stack: &'static NetStack) -> ! {
+    stack.run().await
+}
+
+// Logs address changes, e.g. after a DHCP lease is obtained or the cable is replugged
+#[embassy_executor::task]
+async fn link_task(stack: &'static NetStack) -> ! {
+    loop {
+        stack.wait_config_up().await;
+        if let Some(config) = stack.config_v4() {
+            info!("Network up, address {}", config.address);
+        }
+        while stack.is_config_up() {
+            embassy_time::Timer::after_secs(1).await;
+        }
+        info!("Network down");
+    }
+}
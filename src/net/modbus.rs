@@ -0,0 +1,79 @@
+use defmt::{info, warn};
+use embassy_net::tcp::{self, TcpSocket};
+use embassy_time::{Duration, Timer};
+use embedded_io_async::Write;
+use icbm_protocol::modbus::{tcp as mbap, MAX_PDU_SIZE, TCP_PORT};
+
+use super::NetStack;
+
+pub const MAX_CLIENTS: usize = 2;
+
+const SOCKET_BUFFER_SIZE: usize = 512;
+// Building management systems usually poll every few seconds over one connection;
+// silent masters are dropped after this long
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+// Modbus TCP slave on port 502 serving the shared register map (see modbus/mod.rs).
+// The unit identifier is echoed but otherwise ignored, as for any directly addressed
+// TCP device.
+#[embassy_executor::task(pool_size = MAX_CLIENTS)]
+pub async fn modbus_task(stack: &'static NetStack) -> ! {
+    let mut rx_buffer = [0u8; SOCKET_BUFFER_SIZE];
+    let mut tx_buffer = [0u8; SOCKET_BUFFER_SIZE];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(TIMEOUT));
+
+        if let Err(e) = socket.accept(TCP_PORT).await {
+            warn!("Modbus accept error: {}", e);
+            Timer::after_secs(1).await;
+            continue;
+        }
+        info!("Modbus master connected: {}", socket.remote_endpoint());
+
+        if let Err(e) = serve(&mut socket).await {
+            warn!("Modbus connection error: {}", e);
+        }
+        socket.close();
+        let _ = socket.flush().await;
+        socket.abort();
+        info!("Modbus master disconnected");
+    }
+}
+
+async fn serve(socket: &mut TcpSocket<'_>) -> Result<(), tcp::Error> {
+    let mut rx = [0u8; mbap::MAX_ADU_SIZE];
+    let mut rx_len = 0;
+    let mut pdu = [0u8; MAX_PDU_SIZE];
+    let mut tx = [0u8; mbap::MAX_ADU_SIZE];
+
+    loop {
+        // Masters may pipeline requests, so the stream is split on the MBAP length
+        while let Some(adu_len) = mbap::adu_len(&rx[..rx_len]) {
+            if adu_len > rx.len() {
+                warn!("Modbus request exceeds {} bytes", rx.len());
+                return Ok(());
+            }
+            if rx_len < adu_len {
+                break;
+            }
+            let Some((header, request)) = mbap::decode(&rx[..adu_len]) else {
+                warn!("Dropping connection after a non-Modbus frame");
+                return Ok(());
+            };
+            let pdu_len = crate::modbus::handle(request, &mut pdu).await;
+            let len = mbap::encode(&header, &pdu[..pdu_len], &mut tx);
+            socket.write_all(&tx[..len]).await?;
+
+            rx.copy_within(adu_len..rx_len, 0);
+            rx_len -= adu_len;
+        }
+
+        let n = socket.read(&mut rx[rx_len..]).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        rx_len += n;
+    }
+}
@@ -0,0 +1,359 @@
+use core::fmt::Write as _;
+
+use defmt::{info, warn, Format};
+use embassy_futures::select::{select, Either};
+use embassy_net::tcp::{self, TcpSocket};
+use embassy_net::Ipv4Address;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_io_async::Write;
+use heapless::String;
+use icbm_protocol::message::alarm;
+use icbm_protocol::mqtt::{self, Packet, CONNACK_ACCEPTED, SUBACK_FAILURE};
+
+use super::{telemetry, NetStack};
+use crate::drivers::thermistor::Probe;
+use crate::state::{self, Readings, ReplySignal};
+use crate::storage::config::{Config, ThermalActuator, TopicPrefix};
+
+const SOCKET_BUFFER_SIZE: usize = 1024;
+const PACKET_BUFFER_SIZE: usize = 256;
+const TOPIC_BUFFER_SIZE: usize = 64;
+const PAYLOAD_BUFFER_SIZE: usize = 192;
+
+const KEEP_ALIVE_S: u16 = 60;
+const CONNACK_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const SUBSCRIBE_PACKET_ID: u16 = 1;
+
+// Only setpoints may be changed over MQTT; calibration references and the broker
+// settings stay on the console
+const SETPOINT_KEYS: [&str; 7] = ["co2", "temp", "co2tol", "temptol", "burst", "rh", "rhtol"];
+
+static REPLY: ReplySignal = ReplySignal::new();
+
+#[derive(Debug, Format)]
+enum Error {
+    Tcp(tcp::Error),
+    Mqtt(mqtt::Error),
+    Refused(u8),
+    NoConnAck,
+    Closed,
+}
+
+impl From<tcp::Error> for Error {
+    fn from(e: tcp::Error) -> Self {
+        Error::Tcp(e)
+    }
+}
+
+impl From<mqtt::Error> for Error {
+    fn from(e: mqtt::Error) -> Self {
+        Error::Mqtt(e)
+    }
+}
+
+// MQTT 3.1.1 publisher. Topics are below the configured prefix (default "icbm"):
+//
+//   <prefix>/temperature         °C, after every control cycle
+//   <prefix>/humidity            %RH
+//   <prefix>/co2                 ppm
+//   <prefix>/heater/duty         % of the control period
+//   <prefix>/co2_valve/duty      % of the control period
+//   <prefix>/humidifier/duty     % of the control period
+//   <prefix>/peltier/output      %, negative while cooling; only with the Peltier
+//   <prefix>/probe/<name>        °C of each readable thermistor (air, plate, heatsink,
+//                                jacket)
+//   <prefix>/alarms              retained, comma separated alarm names or "none"
+//   <prefix>/setpoints           retained, JSON
+//   <prefix>/setpoints/set       subscribed, "<key> <value>" with the console keys
+//                                co2, temp, co2tol, temptol, burst, rh or rhtol
+//
+// Everything is sent with QoS 0. Publishing is disabled while the broker address is
+// 0.0.0.0; changing the broker settings reconnects.
+#[embassy_executor::task]
+pub async fn mqtt_task(stack: &'static NetStack) -> ! {
+    let mut rx_buffer = [0u8; SOCKET_BUFFER_SIZE];
+    let mut tx_buffer = [0u8; SOCKET_BUFFER_SIZE];
+
+    loop {
+        let config = state::config();
+        if config.mqtt_broker == [0; 4] {
+            Timer::after(RECONNECT_DELAY).await;
+            continue;
+        }
+        stack.wait_config_up().await;
+
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(KEEP_ALIVE_S as u64 * 3 / 2)));
+        let [a, b, c, d] = config.mqtt_broker;
+        let broker = (Ipv4Address::new(a, b, c, d), config.mqtt_port);
+
+        match socket.connect(broker).await {
+            Ok(()) => {
+                info!("MQTT connected to {}", broker);
+                match session(&mut socket, &config).await {
+                    Ok(()) => info!("MQTT settings changed, reconnecting"),
+                    Err(e) => warn!("MQTT session ended: {}", e),
+                }
+            }
+            Err(e) => warn!("MQTT connection to {} failed: {}", broker, e),
+        }
+        socket.close();
+        let _ = socket.flush().await;
+        socket.abort();
+        Timer::after(RECONNECT_DELAY).await;
+    }
+}
+
+// Runs until the connection fails or the broker settings change (`Ok`)
+async fn session(socket: &mut TcpSocket<'_>, config: &Config) -> Result<(), Error> {
+    let prefix = config.mqtt_topic;
+    let mut rx = [0u8; PACKET_BUFFER_SIZE];
+    let mut rx_len = 0;
+
+    let mut client_id: String<24> = String::new();
+    let _ = write!(client_id, "icbm-{}", &embassy_stm32::uid::uid_hex()[12..]);
+    send(
+        socket,
+        &Packet::Connect {
+            client_id: &client_id,
+            keep_alive_s: KEEP_ALIVE_S,
+            clean_session: true,
+        },
+    )
+    .await?;
+
+    let connack = async {
+        loop {
+            let n = socket.read(&mut rx[rx_len..]).await?;
+            if n == 0 {
+                return Err(Error::Closed);
+            }
+            rx_len += n;
+            match Packet::decode(&rx[..rx_len])? {
+                Some((Packet::ConnAck { return_code, .. }, used)) => {
+                    return Ok((return_code, used))
+                }
+                Some(_) => return Err(Error::NoConnAck),
+                None => {}
+            }
+        }
+    };
+    let (return_code, used) = with_timeout(CONNACK_TIMEOUT, connack)
+        .await
+        .map_err(|_| Error::NoConnAck)??;
+    if return_code != CONNACK_ACCEPTED {
+        return Err(Error::Refused(return_code));
+    }
+    rx.copy_within(used..rx_len, 0);
+    rx_len -= used;
+
+    send(
+        socket,
+        &Packet::Subscribe {
+            packet_id: SUBSCRIBE_PACKET_ID,
+            filter: &topic(&prefix, "setpoints/set"),
+            qos: 0,
+        },
+    )
+    .await?;
+
+    let mut last_update = None;
+    let mut last_alarms = None;
+    let mut last_setpoints = None;
+    let mut last_sent = Instant::now();
+
+    loop {
+        let current = state::config();
+        if current.mqtt_broker != config.mqtt_broker
+            || current.mqtt_port != config.mqtt_port
+            || current.mqtt_topic != prefix
+        {
+            send(socket, &Packet::Disconnect).await?;
+            return Ok(());
+        }
+
+        let readings = state::readings();
+        if last_update != Some(readings.updated_at_s) && readings.updated_at_s > 0 {
+            publish_readings(socket, &prefix, &readings).await?;
+            last_update = Some(readings.updated_at_s);
+            last_sent = Instant::now();
+        }
+        if last_alarms != Some(readings.alarms) {
+            publish_alarms(socket, &prefix, readings.alarms).await?;
+            last_alarms = Some(readings.alarms);
+            last_sent = Instant::now();
+        }
+        let setpoints = (
+            current.target_co2_ppm,
+            current.target_temp_c,
+            current.co2_tolerance_ppm,
+            current.temp_tolerance_c,
+            current.co2_burst_ms,
+            current.target_humidity_rh,
+            current.humidity_tolerance_rh,
+        );
+        if last_setpoints != Some(setpoints) {
+            let mut payload: String<PAYLOAD_BUFFER_SIZE> = String::new();
+            let _ = telemetry::write_setpoints(&current, &mut payload);
+            publish(
+                socket,
+                &topic(&prefix, "setpoints"),
+                payload.as_bytes(),
+                true,
+            )
+            .await?;
+            last_setpoints = Some(setpoints);
+            last_sent = Instant::now();
+        }
+        if last_sent.elapsed() >= Duration::from_secs(KEEP_ALIVE_S as u64 / 2) {
+            send(socket, &Packet::PingReq).await?;
+            last_sent = Instant::now();
+        }
+
+        match select(socket.read(&mut rx[rx_len..]), Timer::after(POLL_INTERVAL)).await {
+            Either::First(Ok(0)) => return Err(Error::Closed),
+            Either::First(Ok(n)) => rx_len += n,
+            Either::First(Err(e)) => return Err(e.into()),
+            Either::Second(()) => continue,
+        }
+
+        let mut consumed = 0;
+        while let Some((packet, used)) = Packet::decode(&rx[consumed..rx_len])? {
+            handle(packet, &prefix).await;
+            consumed += used;
+        }
+        rx.copy_within(consumed..rx_len, 0);
+        rx_len -= consumed;
+        if rx_len == rx.len() {
+            // A packet larger than the buffer can never complete
+            warn!("MQTT packet exceeds {} bytes", PACKET_BUFFER_SIZE);
+            return Err(Error::Mqtt(mqtt::Error::BufferTooSmall));
+        }
+    }
+}
+
+async fn handle(packet: Packet<'_>, prefix: &TopicPrefix) {
+    match packet {
+        Packet::Publish {
+            topic: t, payload, ..
+        } if t == topic(prefix, "setpoints/set").as_str() => {
+            let Ok(command) = core::str::from_utf8(payload) else {
+                warn!("Ignoring non-UTF-8 setpoint message");
+                return;
+            };
+            let mut args = command.split_whitespace();
+            let (Some(key), Some(value), None) = (args.next(), args.next(), args.next()) else {
+                warn!("Ignoring malformed setpoint message '{=str}'", command);
+                return;
+            };
+            if !SETPOINT_KEYS.contains(&key) {
+                warn!("Ignoring MQTT change of '{=str}'", key);
+                return;
+            }
+            let mut config = state::config();
+            if let Err(e) = config.set(key, value) {
+                warn!("Rejected MQTT setpoint '{=str}': {}", command, e);
+                return;
+            }
+            info!("Setpoint change over MQTT: {=str}", command);
+            if let state::Reply::Error(e) =
+                state::request(state::Request::SetConfig(config), &REPLY).await
+            {
+                warn!("Failed to apply MQTT setpoint: {}", e);
+            }
+        }
+        Packet::SubAck {
+            return_code: SUBACK_FAILURE,
+            ..
+        } => warn!("MQTT broker refused the setpoint subscription"),
+        Packet::SubAck { .. } | Packet::PingResp | Packet::Publish { .. } => {}
+        other => warn!("Unexpected MQTT packet: {}", other),
+    }
+}
+
+async fn publish_readings(
+    socket: &mut TcpSocket<'_>,
+    prefix: &TopicPrefix,
+    readings: &Readings,
+) -> Result<(), Error> {
+    let peltier_output = (state::config().thermal_actuator == ThermalActuator::Peltier)
+        .then_some(readings.peltier_output_pct);
+    let values = [
+        ("temperature", readings.temp_c, 2),
+        ("humidity", readings.humidity_rh, 1),
+        ("co2", readings.co2_ppm, 0),
+        ("heater/duty", Some(readings.heater_duty_pct), 1),
+        ("co2_valve/duty", Some(readings.co2_valve_duty_pct), 1),
+        ("humidifier/duty", Some(readings.humidifier_duty_pct), 1),
+        ("peltier/output", peltier_output, 1),
+    ];
+    for (name, value, precision) in values {
+        // Unavailable readings are not published rather than sent as placeholders
+        let Some(value) = value else { continue };
+        let mut payload: String<16> = String::new();
+        let _ = write!(payload, "{:.*}", precision, value);
+        publish(socket, &topic(prefix, name), payload.as_bytes(), false).await?;
+    }
+    for (probe, reading) in Probe::ALL.into_iter().zip(readings.probes) {
+        let Ok(reading) = reading else { continue };
+        let mut name: String<16> = String::new();
+        let _ = write!(name, "probe/{}", probe.name());
+        let mut payload: String<16> = String::new();
+        let _ = write!(payload, "{:.2}", reading.temp_c);
+        publish(socket, &topic(prefix, &name), payload.as_bytes(), false).await?;
+    }
+    Ok(())
+}
+
+async fn publish_alarms(
+    socket: &mut TcpSocket<'_>,
+    prefix: &TopicPrefix,
+    alarms: u32,
+) -> Result<(), Error> {
+    let mut payload: String<PAYLOAD_BUFFER_SIZE> = String::new();
+    for flag in alarm::ALL.iter().filter(|&&flag| alarms & flag != 0) {
+        if !payload.is_empty() {
+            let _ = payload.push(',');
+        }
+        let _ = payload.push_str(alarm::name(*flag));
+    }
+    if payload.is_empty() {
+        let _ = payload.push_str("none");
+    }
+    publish(socket, &topic(prefix, "alarms"), payload.as_bytes(), true).await
+}
+
+async fn publish(
+    socket: &mut TcpSocket<'_>,
+    topic: &str,
+    payload: &[u8],
+    retain: bool,
+) -> Result<(), Error> {
+    send(
+        socket,
+        &Packet::Publish {
+            topic,
+            payload,
+            qos: 0,
+            retain,
+            packet_id: None,
+        },
+    )
+    .await
+}
+
+async fn send(socket: &mut TcpSocket<'_>, packet: &Packet<'_>) -> Result<(), Error> {
+    let mut buf = [0u8; PACKET_BUFFER_SIZE];
+    let len = packet.encode(&mut buf)?;
+    socket.write_all(&buf[..len]).await?;
+    Ok(())
+}
+
+fn topic(prefix: &TopicPrefix, name: &str) -> String<TOPIC_BUFFER_SIZE> {
+    let mut topic = String::new();
+    // The prefix is at most 32 characters, so every topic fits
+    let _ = write!(topic, "{}/{}", prefix.as_str(), name);
+    topic
+}
@@ -0,0 +1,242 @@
+use core::cell::Cell;
+
+use defmt::{info, warn, Format};
+use embassy_net::udp::{self, PacketMetadata, UdpSocket};
+use embassy_net::Ipv4Address;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use icbm_protocol::sntp::{self, offset_delay, Packet, Timestamp, PACKET_SIZE};
+
+use super::NetStack;
+use crate::rtc::{self, Source};
+use crate::state;
+use crate::storage::config::Config;
+
+// Room for a reply with extension fields, which are ignored
+const RX_BUFFER_SIZE: usize = 128;
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+// A failed exchange is retried sooner than the sync interval
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+// How often a changed or enabled server setting is picked up
+const CONFIG_POLL: Duration = Duration::from_secs(10);
+// Offsets up to this are left to accumulate: the RTC only stamps whole seconds, and
+// stepping rarely leaves a long span to measure the drift over
+const STEP_THRESHOLD_US: i64 = 250_000;
+// The clock phase is measured to ~1 ms, so drift is reported once that is below 1 ppm
+const MIN_DRIFT_INTERVAL: Duration = Duration::from_secs(1800);
+// Replies that took longer than this are too uncertain to set the clock from
+const MAX_DELAY_US: i64 = 500_000;
+
+#[derive(Clone, Copy, Debug, Format)]
+pub struct SyncStatus {
+    pub server: [u8; 4], // 0.0.0.0 while disabled
+    pub last_sync: Option<Instant>,
+    // Server minus RTC before correcting it; `None` if the RTC was not set
+    pub offset_ms: Option<i32>,
+    pub delay_ms: u32,
+    pub stratum: u8,
+    // Rate of the RTC against the server since SNTP last set it, positive if fast
+    pub drift_ppm: Option<f32>,
+    pub syncs: u32,
+    pub failures: u32,
+}
+
+impl SyncStatus {
+    const INITIAL: SyncStatus = SyncStatus {
+        server: [0; 4],
+        last_sync: None,
+        offset_ms: None,
+        delay_ms: 0,
+        stratum: 0,
+        drift_ppm: None,
+        syncs: 0,
+        failures: 0,
+    };
+}
+
+static STATUS: Mutex<CriticalSectionRawMutex, Cell<SyncStatus>> =
+    Mutex::new(Cell::new(SyncStatus::INITIAL));
+
+pub fn status() -> SyncStatus {
+    STATUS.lock(Cell::get)
+}
+
+fn update(f: impl FnOnce(&mut SyncStatus)) {
+    STATUS.lock(|cell| {
+        let mut status = cell.get();
+        f(&mut status);
+        cell.set(status);
+    })
+}
+
+#[derive(Debug, Format)]
+enum Error {
+    Bind(udp::BindError),
+    Send(udp::SendError),
+    Recv(udp::RecvError),
+    Timeout,
+    Reply(sntp::Error),
+    Delay { ms: u32 },
+    Clock(&'static str),
+}
+
+// SNTP client keeping the RTC on the configured server's time (UTC).
+//
+// Every `ntpinterval` minutes one request is sent; the RTC is stepped if it is unset or
+// off by more than STEP_THRESHOLD_US, at the start of a server second so its phase is
+// right too. In between, the offset found at each sync over the time since SNTP last
+// set the clock gives the drift of the 32.768 kHz crystal. Sync is disabled while the
+// server address is 0.0.0.0.
+#[embassy_executor::task]
+pub async fn sntp_task(stack: &'static NetStack) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0u8; RX_BUFFER_SIZE];
+    let mut tx_buffer = [0u8; PACKET_SIZE];
+
+    loop {
+        let config = state::config();
+        if status().server != config.ntp_server {
+            // Results from another server say nothing about this one
+            update(|status| {
+                *status = SyncStatus {
+                    server: config.ntp_server,
+                    ..SyncStatus::INITIAL
+                }
+            });
+        }
+        if config.ntp_server == [0; 4] {
+            Timer::after(CONFIG_POLL).await;
+            continue;
+        }
+        stack.wait_config_up().await;
+
+        let [a, b, c, d] = config.ntp_server;
+        let server = Ipv4Address::new(a, b, c, d);
+        let interval = Duration::from_secs(config.ntp_interval_min as u64 * 60);
+        let mut socket = UdpSocket::new(
+            stack,
+            &mut rx_meta,
+            &mut rx_buffer,
+            &mut tx_meta,
+            &mut tx_buffer,
+        );
+        let result = match socket.bind(0) {
+            Ok(()) => sync(&socket, server).await,
+            Err(e) => Err(Error::Bind(e)),
+        };
+        drop(socket);
+
+        let wait = match result {
+            Ok(()) => interval,
+            Err(e) => {
+                warn!("SNTP sync with {} failed: {}", server, e);
+                update(|status| status.failures += 1);
+                match e {
+                    // The server asked us to back off
+                    Error::Reply(sntp::Error::Kiss(_)) => interval,
+                    _ => RETRY_DELAY.min(interval),
+                }
+            }
+        };
+        wait_unless_changed(wait, &config).await;
+    }
+}
+
+async fn sync(socket: &UdpSocket<'_>, server: Ipv4Address) -> Result<(), Error> {
+    // Local time for the exchange: the RTC, interpolated between its seconds by the
+    // uptime, or the uptime alone while the RTC is not set
+    let phase = rtc::next_second().await;
+    let local_us = |at: Instant| match phase {
+        Some((second, edge)) => second as u64 * 1_000_000 + (at - edge).as_micros(),
+        None => at.as_micros(),
+    };
+
+    let sent = Instant::now();
+    let transmit = Timestamp::from_unix_us(local_us(sent));
+    socket
+        .send_to(&Packet::request(transmit).encode(), (server, sntp::PORT))
+        .await
+        .map_err(Error::Send)?;
+
+    let mut buf = [0u8; RX_BUFFER_SIZE];
+    let receive = async {
+        loop {
+            let (n, _) = socket.recv_from(&mut buf).await.map_err(Error::Recv)?;
+            let received = Instant::now();
+            let reply = Packet::decode(&buf[..n]).map_err(Error::Reply)?;
+            match reply.check_reply(transmit) {
+                Ok(()) => return Ok((reply, received)),
+                // A late reply to an earlier request
+                Err(sntp::Error::Mismatch) => continue,
+                Err(e) => return Err(Error::Reply(e)),
+            }
+        }
+    };
+    let (reply, received) = with_timeout(REPLY_TIMEOUT, receive)
+        .await
+        .map_err(|_| Error::Timeout)??;
+
+    let (offset_us, delay_us) = offset_delay(
+        local_us(sent),
+        reply.receive.to_unix_us(),
+        reply.transmit.to_unix_us(),
+        local_us(received),
+    );
+    let delay_us = delay_us.max(0);
+    if delay_us > MAX_DELAY_US {
+        return Err(Error::Delay {
+            ms: (delay_us / 1000) as u32,
+        });
+    }
+
+    let mut drift_ppm = None;
+    if let (Some(_), Some((set_at, Source::Sntp))) = (phase, rtc::last_set()) {
+        let elapsed = received - set_at;
+        if elapsed >= MIN_DRIFT_INTERVAL {
+            // µs per s is ppm; a negative offset means the RTC is ahead
+            drift_ppm = Some(-(offset_us as f32) / elapsed.as_micros() as f32 * 1e6);
+        }
+    }
+
+    if phase.is_none() || offset_us.abs() > STEP_THRESHOLD_US {
+        let now_us =
+            (local_us(received) as i64 + offset_us) as u64 + received.elapsed().as_micros();
+        let next_s = now_us / 1_000_000 + 1;
+        Timer::after_micros(next_s * 1_000_000 - now_us).await;
+        rtc::set_unix(next_s as u32, Source::Sntp).map_err(Error::Clock)?;
+    }
+
+    let offset_ms =
+        phase.map(|_| (offset_us / 1000).clamp(i32::MIN as i64, i32::MAX as i64) as i32);
+    info!(
+        "SNTP sync with {}: offset {} ms, delay {} ms, stratum {}",
+        server,
+        offset_ms,
+        delay_us / 1000,
+        reply.stratum
+    );
+    update(|status| {
+        status.last_sync = Some(Instant::now());
+        status.offset_ms = offset_ms;
+        status.delay_ms = (delay_us / 1000) as u32;
+        status.stratum = reply.stratum;
+        status.drift_ppm = drift_ppm.or(status.drift_ppm);
+        status.syncs += 1;
+    });
+    Ok(())
+}
+
+// Waits out `wait`, returning early once the SNTP settings change
+async fn wait_unless_changed(wait: Duration, config: &Config) {
+    let until = Instant::now() + wait;
+    while Instant::now() < until {
+        Timer::after(CONFIG_POLL.min(until.saturating_duration_since(Instant::now()))).await;
+        let current = state::config();
+        if current.ntp_server != config.ntp_server
+            || current.ntp_interval_min != config.ntp_interval_min
+        {
+            return;
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use core::fmt::{self, Write as _};
+
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use defmt::{info, warn};
+use embassy_futures::select::{select, Either};
+use embassy_net::tcp::{self, TcpSocket};
+use embassy_time::{Duration, Timer};
+use embedded_io_async::Write;
+use heapless::String;
+
+use super::NetStack;
+use crate::rtc;
+use crate::state::{self, Readings};
+use crate::storage::config::Config;
+
+pub const PORT: u16 = 7070;
+pub const MAX_CLIENTS: usize = 2;
+
+const SOCKET_BUFFER_SIZE: usize = 1024;
+pub const STATUS_BUFFER_SIZE: usize = 512;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+// Dead peers are dropped after this long without an acknowledgement
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// Read-only telemetry: every connected client gets one JSON object per line with the
+// readings, setpoints and alarm flags, sent on connect, after every control cycle and
+// whenever the client sends anything (e.g. an empty line).
+// `nc <address> 7070` is enough to watch an incubator.
+#[embassy_executor::task(pool_size = MAX_CLIENTS)]
+pub async fn telemetry_task(stack: &'static NetStack) -> ! {
+    let mut rx_buffer = [0u8; SOCKET_BUFFER_SIZE];
+    let mut tx_buffer = [0u8; SOCKET_BUFFER_SIZE];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_keep_alive(Some(KEEP_ALIVE));
+        socket.set_timeout(Some(TIMEOUT));
+
+        if let Err(e) = socket.accept(PORT).await {
+            warn!("Telemetry accept error: {}", e);
+            Timer::after(POLL_INTERVAL).await;
+            continue;
+        }
+        info!("Telemetry client connected: {}", socket.remote_endpoint());
+
+        if let Err(e) = serve(&mut socket).await {
+            warn!("Telemetry client error: {}", e);
+        }
+        socket.close();
+        let _ = socket.flush().await;
+        socket.abort();
+        info!("Telemetry client disconnected");
+    }
+}
+
+async fn serve(socket: &mut TcpSocket<'_>) -> Result<(), tcp::Error> {
+    let (mut reader, mut writer) = socket.split();
+    let mut rx = [0u8; 64];
+    let mut last_update = None;
+    let mut requested = true;
+
+    loop {
+        let readings = state::readings();
+        if requested || last_update != Some(readings.updated_at_s) {
+            let mut out: String<STATUS_BUFFER_SIZE> = String::new();
+            // The buffer is sized for the largest status, so this cannot fail
+            let _ = write_status(&readings, &state::config(), &mut out);
+            let _ = out.push('\n');
+            writer.write_all(out.as_bytes()).await?;
+            last_update = Some(readings.updated_at_s);
+            requested = false;
+        }
+
+        match select(reader.read(&mut rx), Timer::after(POLL_INTERVAL)).await {
+            Either::First(Ok(0)) => return Ok(()),
+            Either::First(Ok(_)) => requested = true,
+            Either::First(Err(e)) => return Err(e),
+            Either::Second(()) => {}
+        }
+    }
+}
+
+// Current status as a single JSON object; unavailable readings are null
+pub fn write_status(
+    readings: &Readings,
+    config: &Config,
+    out: &mut impl fmt::Write,
+) -> fmt::Result {
+    out.write_str("{\"temp_c\":")?;
+    write_optional(out, readings.temp_c, 2)?;
+    match readings.temp_source {
+        Some(source) => write!(out, ",\"temp_source\":\"{}\"", source.name())?,
+        None => out.write_str(",\"temp_source\":null")?,
+    }
+    out.write_str(",\"co2_ppm\":")?;
+    write_optional(out, readings.co2_ppm, 0)?;
+    out.write_str(",\"humidity_rh\":")?;
+    write_optional(out, readings.humidity_rh, 1)?;
+    out.write_str(",\"flow_ml_min\":")?;
+    write_optional(out, readings.flow_ml_min, 2)?;
+    write!(out, ",\"door\":\"{}\"", readings.door.name())?;
+    out.write_str(",\"co2_supply_bar\":")?;
+    write_optional(out, readings.co2_supply_bar, 1)?;
+    write!(
+        out,
+        ",\"heater_on\":{},\"humidifier_on\":{},\"co2_bursts\":{},\"alarms\":{},\
+         \"updated_at_s\":{}",
+        readings.heater_on,
+        readings.humidifier_on,
+        readings.co2_bursts,
+        readings.alarms,
+        readings.updated_at_s
+    )?;
+    out.write_str(",\"time\":")?;
+    write_datetime(out, rtc::now())?;
+    out.write_str(",\"alarm_raised_at\":")?;
+    write_datetime(out, readings.alarm_raised_at)?;
+    out.write_str(",\"setpoints\":")?;
+    write_setpoints(config, out)?;
+    out.write_str("}")
+}
+
+pub fn write_setpoints(config: &Config, out: &mut impl fmt::Write) -> fmt::Result {
+    write!(
+        out,
+        "{{\"co2_ppm\":{:.0},\"temp_c\":{:.2},\"co2_tolerance_ppm\":{:.0},\
+         \"temp_tolerance_c\":{:.2},\"co2_burst_ms\":{},\"humidity_rh\":{:.1},\
+         \"humidity_tolerance_rh\":{:.1}}}",
+        config.target_co2_ppm,
+        config.target_temp_c,
+        config.co2_tolerance_ppm,
+        config.temp_tolerance_c,
+        config.co2_burst_ms,
+        config.target_humidity_rh,
+        config.humidity_tolerance_rh
+    )
+}
+
+// ISO 8601 in UTC, or null while the clock is not set
+fn write_datetime(out: &mut impl fmt::Write, datetime: Option<NaiveDateTime>) -> fmt::Result {
+    match datetime {
+        Some(datetime) => write!(
+            out,
+            "\"{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z\"",
+            datetime.year(),
+            datetime.month(),
+            datetime.day(),
+            datetime.hour(),
+            datetime.minute(),
+            datetime.second()
+        ),
+        None => out.write_str("null"),
+    }
+}
+
+fn write_optional(out: &mut impl fmt::Write, value: Option<f32>, precision: usize) -> fmt::Result {
+    match value {
+        Some(value) if value.is_finite() => write!(out, "{:.*}", precision, value),
+        _ => out.write_str("null"),
+    }
+}
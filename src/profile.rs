@@ -0,0 +1,304 @@
+use core::cell::RefCell;
+
+use defmt::{info, warn};
+use embassy_stm32::flash::Flash;
+use embassy_stm32::mode::Blocking;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use icbm_protocol::message::ProfileStatus;
+use icbm_protocol::profile::{Command, Profile, RunState, Segment, Variable, VARIABLES};
+
+use crate::rtc;
+use crate::state;
+use crate::storage::config::Config;
+use crate::storage::profile_store::{ProfileStore, StoredProfile};
+
+// Setpoint profiles (see icbm_protocol::profile): ramps, steps and holds of the
+// temperature and CO2 setpoints that replace the configured ones while active.
+//
+// A profile's clock is its elapsed running time, which stands still while paused. Every
+// state change is persisted, and progress is saved every SAVE_INTERVAL while running. A
+// profile running at a reset picks up where it was, adding the time the incubator was
+// off if the RTC is set so that it stays on the wall-clock schedule; without the RTC it
+// resumes from the last save instead. A scheduled start needs the RTC.
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Clone, Copy)]
+struct Progress {
+    stored: StoredProfile,
+    // Uptime `stored.elapsed_s` was taken at
+    since: Instant,
+}
+
+impl Progress {
+    const IDLE: Progress = Progress {
+        stored: StoredProfile {
+            profile: Profile::EMPTY,
+            state: RunState::Idle,
+            elapsed_s: 0,
+            saved_at: None,
+            starts_at: None,
+        },
+        since: Instant::from_ticks(0),
+    };
+
+    fn elapsed_s(&self) -> u32 {
+        match self.stored.state {
+            RunState::Running => {
+                let running_s = self.since.elapsed().as_secs().min(u32::MAX as u64) as u32;
+                self.stored.elapsed_s.saturating_add(running_s)
+            }
+            _ => self.stored.elapsed_s,
+        }
+    }
+
+    // Setpoints in effect and the segment each track is in, indexed by `Variable`
+    fn setpoints(&self, config: &Config) -> ([f32; VARIABLES], [u8; VARIABLES]) {
+        let mut values = [config.target_temp_c, config.target_co2_ppm];
+        let mut segments = [0; VARIABLES];
+        if self.stored.state.is_active() {
+            let elapsed_s = self.elapsed_s() as u64;
+            for variable in Variable::ALL {
+                let i = variable as usize;
+                let (value, segment) = self.stored.profile.tracks[i].setpoint(values[i], elapsed_s);
+                values[i] = value;
+                segments[i] = segment as u8;
+            }
+        }
+        (values, segments)
+    }
+
+    // Starts a waiting profile once its time has come. The time since then counts as
+    // already run, so a start missed while powered off is caught up with.
+    fn start_if_due(&mut self) -> bool {
+        let (RunState::Waiting, Some(at), Some(now)) =
+            (self.stored.state, self.stored.starts_at, rtc::unix_time())
+        else {
+            return false;
+        };
+        if now < at {
+            return false;
+        }
+        self.stored.state = RunState::Running;
+        self.stored.elapsed_s = now - at;
+        self.stored.starts_at = None;
+        self.since = Instant::now();
+        info!("Profile started");
+        true
+    }
+
+    fn finish_if_done(&mut self) -> bool {
+        let duration_s = self.stored.profile.duration_s();
+        if self.stored.state != RunState::Running || (self.elapsed_s() as u64) < duration_s {
+            return false;
+        }
+        self.stored.state = RunState::Finished;
+        self.stored.elapsed_s = duration_s.min(u32::MAX as u64) as u32;
+        info!("Profile finished, keeping its last setpoints");
+        true
+    }
+}
+
+static PROGRESS: Mutex<CriticalSectionRawMutex, RefCell<Progress>> =
+    Mutex::new(RefCell::new(Progress::IDLE));
+
+fn progress() -> Progress {
+    PROGRESS.lock(|p| *p.borrow())
+}
+
+// The configuration with the setpoints in effect, i.e. the profile's while one is active
+pub fn apply(mut config: Config) -> Config {
+    let ([temp_c, co2_ppm], _) = progress().setpoints(&config);
+    config.target_temp_c = temp_c;
+    config.target_co2_ppm = co2_ppm;
+    config
+}
+
+pub fn status() -> ProfileStatus {
+    let progress = progress();
+    let stored = &progress.stored;
+    let ([temp_c, co2_ppm], segment) = progress.setpoints(&state::config());
+    ProfileStatus {
+        state: stored.state,
+        elapsed_s: progress.elapsed_s(),
+        duration_s: stored.profile.duration_s().min(u32::MAX as u64) as u32,
+        starts_at: stored.starts_at,
+        segments: stored
+            .profile
+            .tracks
+            .map(|track| track.segments().len() as u8),
+        segment,
+        temp_c,
+        co2_ppm,
+    }
+}
+
+// Segment `index` of the variable's track and the number of segments in it
+pub fn segment(variable: Variable, index: u8) -> Option<(Segment, u8)> {
+    let profile = progress().stored.profile;
+    let segments = profile.track(variable).segments();
+    let segment = *segments.get(index as usize)?;
+    Some((segment, segments.len() as u8))
+}
+
+// Owned by the control loop, which executes the profile requests since they write flash
+pub struct ProfileRunner {
+    store: ProfileStore,
+    saved: Instant,
+}
+
+impl ProfileRunner {
+    pub fn load(flash: &mut Flash<'_, Blocking>) -> Self {
+        let (store, stored) = ProfileStore::load(flash);
+        let mut progress = Progress {
+            stored,
+            since: Instant::now(),
+        };
+        if stored.state == RunState::Running {
+            match (stored.saved_at, rtc::unix_time()) {
+                (Some(saved_at), Some(now)) if now >= saved_at => {
+                    progress.stored.elapsed_s = stored.elapsed_s.saturating_add(now - saved_at);
+                    info!(
+                        "Profile resumed at {} s, {} s after it was saved",
+                        progress.stored.elapsed_s,
+                        now - saved_at
+                    );
+                }
+                _ => warn!(
+                    "Clock not set, profile resumed from its last save at {} s",
+                    stored.elapsed_s
+                ),
+            }
+        }
+        PROGRESS.lock(|p| p.replace(progress));
+        ProfileRunner {
+            store,
+            saved: Instant::now(),
+        }
+    }
+
+    // Moves the profile on with time; called every control cycle
+    pub fn update(&mut self, flash: &mut Flash<'_, Blocking>) {
+        let mut progress = progress();
+        let changed = progress.start_if_due() | progress.finish_if_done();
+        let due =
+            progress.stored.state == RunState::Running && self.saved.elapsed() >= SAVE_INTERVAL;
+        if changed || due {
+            // Time moves on either way; a failed save only costs progress after a reset
+            if let Err(e) = self.commit(flash, progress) {
+                warn!("Profile: {}", e);
+                PROGRESS.lock(|p| p.replace(progress));
+            }
+        }
+    }
+
+    pub fn clear(&mut self, flash: &mut Flash<'_, Blocking>) -> Result<(), &'static str> {
+        let progress = Self::editable()?;
+        self.commit(
+            flash,
+            Progress {
+                stored: StoredProfile::default(),
+                ..progress
+            },
+        )
+    }
+
+    pub fn add(
+        &mut self,
+        flash: &mut Flash<'_, Blocking>,
+        variable: Variable,
+        segment: Segment,
+    ) -> Result<(), &'static str> {
+        let mut progress = Self::editable()?;
+        if let Some(target) = segment.target() {
+            // Targets must be setpoints the configuration would accept
+            let mut config = state::config();
+            match variable {
+                Variable::Temp => config.target_temp_c = target,
+                Variable::Co2 => config.target_co2_ppm = target,
+            }
+            config.validate()?;
+        }
+        progress.stored.profile.track_mut(variable).push(segment)?;
+        // Changing a finished profile makes it a new one
+        progress.stored.state = RunState::Idle;
+        progress.stored.elapsed_s = 0;
+        self.commit(flash, progress)
+    }
+
+    pub fn control(
+        &mut self,
+        flash: &mut Flash<'_, Blocking>,
+        command: Command,
+    ) -> Result<(), &'static str> {
+        let mut progress = progress();
+        let elapsed_s = progress.elapsed_s();
+        let stored = &mut progress.stored;
+        match (command, stored.state) {
+            (Command::Start { .. }, RunState::Waiting | RunState::Running | RunState::Paused) => {
+                return Err("Profile already started, stop it first");
+            }
+            (Command::Start { .. }, _) if stored.profile.is_empty() => {
+                return Err("Profile has no segments");
+            }
+            (Command::Start { at: None }, _) => {
+                stored.state = RunState::Running;
+                stored.elapsed_s = 0;
+                info!("Profile started");
+            }
+            (Command::Start { at: Some(at) }, _) => {
+                rtc::unix_time().ok_or("Clock not set")?;
+                stored.state = RunState::Waiting;
+                stored.elapsed_s = 0;
+                stored.starts_at = Some(at);
+                info!("Profile start scheduled at {}", at);
+                progress.start_if_due();
+            }
+            (Command::Pause, RunState::Running) => {
+                stored.state = RunState::Paused;
+                stored.elapsed_s = elapsed_s;
+                info!("Profile paused at {} s", elapsed_s);
+            }
+            (Command::Pause, _) => return Err("Profile is not running"),
+            (Command::Resume, RunState::Paused) => {
+                stored.state = RunState::Running;
+                info!("Profile resumed at {} s", stored.elapsed_s);
+            }
+            (Command::Resume, _) => return Err("Profile is not paused"),
+            (Command::Stop, _) => {
+                stored.state = RunState::Idle;
+                stored.elapsed_s = 0;
+                stored.starts_at = None;
+                info!("Profile stopped");
+            }
+        }
+        progress.since = Instant::now();
+        progress.finish_if_done();
+        self.commit(flash, progress)
+    }
+
+    // Segments can only be changed while the profile is not in progress
+    fn editable() -> Result<Progress, &'static str> {
+        let progress = progress();
+        match progress.stored.state {
+            RunState::Idle | RunState::Finished => Ok(progress),
+            _ => Err("Profile in progress, stop it first"),
+        }
+    }
+
+    // Persists the progress so far and makes it current
+    fn commit(
+        &mut self,
+        flash: &mut Flash<'_, Blocking>,
+        mut progress: Progress,
+    ) -> Result<(), &'static str> {
+        progress.stored.elapsed_s = progress.elapsed_s();
+        progress.since = Instant::now();
+        progress.stored.saved_at = rtc::unix_time();
+        self.store.save(flash, &progress.stored)?;
+        self.saved = Instant::now();
+        PROGRESS.lock(|p| p.replace(progress));
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+use core::cell::{Cell, RefCell};
+
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime};
+use defmt::{info, warn, Display2Format, Format};
+use embassy_stm32::rtc::Rtc;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
+
+// Wall-clock time from the STM32 RTC. The RTC lives in the backup domain and keeps
+// counting across resets; until it has been set over the host interface or by SNTP,
+// `now` returns `None` and records only carry uptime.
+
+// A reset backup domain restarts the calendar at 2000-01-01, and the RTC only counts
+// years within one century
+const MIN_YEAR: i32 = 2024;
+const MAX_YEAR: i32 = 2099;
+// Resolution of `next_second`
+const EDGE_POLL: Duration = Duration::from_millis(1);
+const EDGE_TIMEOUT: Duration = Duration::from_millis(1500);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum Source {
+    Host,
+    Sntp,
+}
+
+static RTC: Mutex<CriticalSectionRawMutex, RefCell<Option<Rtc>>> = Mutex::new(RefCell::new(None));
+// Uptime and source of the last `set` since boot
+static LAST_SET: Mutex<CriticalSectionRawMutex, Cell<Option<(Instant, Source)>>> =
+    Mutex::new(Cell::new(None));
+
+pub fn init(rtc: Rtc) {
+    match read(&rtc) {
+        Some(now) => info!("RTC running: {}", Display2Format(&now)),
+        None => warn!("RTC not set, timestamps use uptime until it is"),
+    }
+    RTC.lock(|cell| cell.replace(Some(rtc)));
+}
+
+pub fn now() -> Option<NaiveDateTime> {
+    RTC.lock(|cell| cell.borrow().as_ref().and_then(read))
+}
+
+// Seconds since the Unix epoch, as carried by the host protocol and the data log
+pub fn unix_time() -> Option<u32> {
+    now().map(|now| now.and_utc().timestamp() as u32)
+}
+
+pub fn set(datetime: NaiveDateTime, source: Source) -> Result<(), &'static str> {
+    if !(MIN_YEAR..=MAX_YEAR).contains(&datetime.year()) {
+        return Err("Date out of range");
+    }
+    RTC.lock(|cell| {
+        let mut rtc = cell.borrow_mut();
+        let rtc = rtc.as_mut().ok_or("RTC not initialised")?;
+        rtc.set_datetime(datetime.into())
+            .map_err(|_| "Failed to set RTC")
+    })?;
+    LAST_SET.lock(|cell| cell.set(Some((Instant::now(), source))));
+    info!("RTC set to {} ({})", Display2Format(&datetime), source);
+    Ok(())
+}
+
+pub fn last_set() -> Option<(Instant, Source)> {
+    LAST_SET.lock(Cell::get)
+}
+
+// Waits for the next RTC second to begin and returns it with the uptime it began at,
+// to within EDGE_POLL. The calendar only counts whole seconds, so this is how the
+// phase of the clock is measured; `None` if it is not set or not running.
+pub async fn next_second() -> Option<(u32, Instant)> {
+    let start = unix_time()?;
+    let deadline = Instant::now() + EDGE_TIMEOUT;
+    while Instant::now() < deadline {
+        Timer::after(EDGE_POLL).await;
+        let now = unix_time()?;
+        if now != start {
+            return Some((now, Instant::now()));
+        }
+    }
+    None
+}
+
+pub fn set_unix(unix_s: u32, source: Source) -> Result<(), &'static str> {
+    let datetime = DateTime::from_timestamp(unix_s as i64, 0).ok_or("Invalid time")?;
+    set(datetime.naive_utc(), source)
+}
+
+// Backup registers live in the backup domain with the calendar, so they survive resets
+// (and power loss with a VBAT cell) and read 0 after the domain was reset
+pub fn backup_register(register: usize) -> u32 {
+    RTC.lock(|cell| {
+        cell.borrow()
+            .as_ref()
+            .and_then(|rtc| rtc.read_backup_register(register))
+            .unwrap_or(0)
+    })
+}
+
+pub fn set_backup_register(register: usize, value: u32) {
+    RTC.lock(|cell| {
+        if let Some(rtc) = cell.borrow().as_ref() {
+            rtc.write_backup_register(register, value);
+        }
+    });
+}
+
+// Parses `YYYY-MM-DDTHH:MM:SS` (UTC), as entered on the console
+pub fn parse(text: &str) -> Option<NaiveDateTime> {
+    let (date, time) = text.split_once('T')?;
+    let (year, rest) = date.split_once('-')?;
+    let (month, day) = rest.split_once('-')?;
+    let (hour, rest) = time.split_once(':')?;
+    let (minute, second) = rest.split_once(':')?;
+    NaiveDate::from_ymd_opt(year.parse().ok()?, month.parse().ok()?, day.parse().ok()?)?
+        .and_hms_opt(
+            hour.parse().ok()?,
+            minute.parse().ok()?,
+            second.parse().ok()?,
+        )
+}
+
+fn read(rtc: &Rtc) -> Option<NaiveDateTime> {
+    let now: NaiveDateTime = rtc.now().ok()?.into();
+    (now.year() >= MIN_YEAR).then_some(now)
+}
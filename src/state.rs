@@ -0,0 +1,240 @@
+use core::cell::{Cell, RefCell};
+
+use chrono::NaiveDateTime;
+use defmt::{info, Display2Format, Format};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::channel::Channel;
+use embassy_sync::mutex::Mutex as AsyncMutex;
+use embassy_sync::signal::Signal;
+use icbm_protocol::message::LogChunk;
+use icbm_protocol::profile::{Command as ProfileCommand, Segment, Variable};
+
+use crate::door::DoorState;
+use crate::drivers::co2_solenoid::Wear;
+use crate::drivers::drv8873::BridgeFault;
+use crate::drivers::thermistor::{BankReading, PROBE_COUNT};
+use crate::fusion::TempSource;
+use crate::rtc;
+use crate::storage::calibration_log::CalibrationRecord;
+use crate::storage::config::Config;
+use crate::storage::sd_log::SdStatus;
+
+// State shared between the control loop (the only writer) and the host interfaces
+
+#[derive(Clone, Copy, Debug)]
+pub struct Readings {
+    // Fused chamber temperature (see fusion.rs), its uncertainty and the sensors behind it
+    pub temp_c: Option<f32>,
+    pub temp_uncertainty_c: Option<f32>,
+    pub temp_source: Option<TempSource>,
+    pub co2_ppm: Option<f32>,
+    // Cross-check only (see co2_check.rs); the ExplorIR's is the controlled value
+    pub scd41_co2_ppm: Option<f32>,
+    pub humidity_rh: Option<f32>,
+    pub flow_ml_min: Option<f32>,
+    pub heater_on: bool,
+    pub humidifier_on: bool,
+    // Share of the last control period each actuator was on, in percent
+    pub heater_duty_pct: f32,
+    pub co2_valve_duty_pct: f32,
+    pub humidifier_duty_pct: f32,
+    // Signed Peltier output while it is the thermal actuator, heating positive
+    pub peltier_output_pct: f32,
+    pub peltier_current_a: Option<f32>,
+    // Latched until cleared from the console
+    pub bridge_fault: Option<BridgeFault>,
+    // Thermistor bank, indexed like `Probe::ALL`
+    pub probes: BankReading,
+    pub door: DoorState,
+    // CO2 supply pressure, with a transducer fitted, and the learned CO2 rise per
+    // second of burst (see co2_supply.rs)
+    pub co2_supply_bar: Option<f32>,
+    pub co2_response_ppm_s: Option<f32>,
+    // Kept across resets in RTC backup registers
+    pub valve_wear: Wear,
+    pub co2_bursts: u32,
+    pub alarms: u32,       // icbm_protocol::message::alarm flags
+    pub updated_at_s: u64, // seconds since boot of the last control cycle
+    // Wall-clock time the most recent of the active alarms was raised, if the RTC is set
+    pub alarm_raised_at: Option<NaiveDateTime>,
+}
+
+impl Readings {
+    const fn new() -> Self {
+        Readings {
+            temp_c: None,
+            temp_uncertainty_c: None,
+            temp_source: None,
+            co2_ppm: None,
+            scd41_co2_ppm: None,
+            humidity_rh: None,
+            flow_ml_min: None,
+            heater_on: false,
+            humidifier_on: false,
+            heater_duty_pct: 0.0,
+            co2_valve_duty_pct: 0.0,
+            humidifier_duty_pct: 0.0,
+            peltier_output_pct: 0.0,
+            peltier_current_a: None,
+            bridge_fault: None,
+            probes: [Err("No reading"); PROBE_COUNT],
+            door: DoorState::Closed,
+            co2_supply_bar: None,
+            co2_response_ppm_s: None,
+            valve_wear: Wear {
+                on_time_ms: 0,
+                actuations: 0,
+            },
+            co2_bursts: 0,
+            alarms: 0,
+            updated_at_s: 0,
+            alarm_raised_at: None,
+        }
+    }
+}
+
+static READINGS: Mutex<CriticalSectionRawMutex, RefCell<Readings>> =
+    Mutex::new(RefCell::new(Readings::new()));
+
+static CONFIG: Mutex<CriticalSectionRawMutex, RefCell<Config>> =
+    Mutex::new(RefCell::new(Config::DEFAULT));
+
+pub fn readings() -> Readings {
+    READINGS.lock(|r| *r.borrow())
+}
+
+// Newly raised alarms are stamped with the wall-clock time here
+pub fn update_readings(f: impl FnOnce(&mut Readings)) {
+    let (raised, at) = READINGS.lock(|r| {
+        let mut r = r.borrow_mut();
+        let before = r.alarms;
+        f(&mut r);
+        let raised = r.alarms & !before;
+        if raised != 0 {
+            r.alarm_raised_at = rtc::now();
+        } else if r.alarms == 0 {
+            r.alarm_raised_at = None;
+        }
+        (raised, r.alarm_raised_at)
+    });
+    match (raised, at) {
+        (0, _) => {}
+        (raised, Some(at)) => info!("Alarm {:#x} raised at {}", raised, Display2Format(&at)),
+        (raised, None) => info!("Alarm {:#x} raised", raised),
+    }
+}
+
+// Active configuration; changes go through `Request::SetConfig` so they are persisted
+pub fn config() -> Config {
+    CONFIG.lock(|c| *c.borrow())
+}
+
+pub fn set_config(config: Config) {
+    CONFIG.lock(|c| *c.borrow_mut() = config);
+}
+
+// What the display shows between control cycles
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Format)]
+pub enum Screen {
+    Readings,
+    // Device health (see diagnostics.rs), refreshed every few seconds
+    Diagnostics,
+}
+
+static SCREEN: Mutex<CriticalSectionRawMutex, Cell<Screen>> =
+    Mutex::new(Cell::new(Screen::Readings));
+
+pub fn screen() -> Screen {
+    SCREEN.lock(Cell::get)
+}
+
+pub fn set_screen(screen: Screen) {
+    SCREEN.lock(|s| s.set(screen));
+}
+
+// Requests that need the sensors or the flash are executed by the control loop
+// between control cycles
+pub enum Request {
+    SetConfig(Config),
+    ResetConfig,
+    Calibrate,
+    SelfTest,
+    ReadCalibration(u32),
+    LogInfo,
+    ReadLog {
+        seq: u32,
+        offset: u16,
+    },
+    SdStatus,
+    NewRun,
+    ClearProfile,
+    AddProfileSegment {
+        variable: Variable,
+        segment: Segment,
+    },
+    ProfileControl(ProfileCommand),
+    ClearBridgeFault,
+    ResetValveWear,
+}
+
+pub enum Reply {
+    Done,
+    Error(&'static str),
+    SelfTest {
+        scd41: bool,
+        explorir: bool,
+    },
+    Calibrated {
+        seq: u32,
+    },
+    CalibrationEntry {
+        seq: u32,
+        total: u32,
+        record: CalibrationRecord,
+    },
+    LogInfo {
+        blocks: u32,
+        first_seq: u32,
+        last_seq: u32,
+    },
+    LogChunk(LogChunk),
+    SdStatus(SdStatus),
+}
+
+pub type ReplySignal = Signal<CriticalSectionRawMutex, Reply>;
+
+pub struct Command {
+    pub request: Request,
+    pub reply: &'static ReplySignal,
+}
+
+pub static COMMANDS: Channel<CriticalSectionRawMutex, Command, 4> = Channel::new();
+
+// Each host interface owns its own reply signal so replies cannot be picked up by
+// another interface
+pub async fn request(request: Request, reply: &'static ReplySignal) -> Reply {
+    reply.reset();
+    COMMANDS.send(Command { request, reply }).await;
+    reply.wait().await
+}
+
+// Reply signal shared by the tasks of one interface (e.g. a pool of connection
+// handlers); requests are serialised so each task waits for its own reply
+pub struct SharedReply {
+    lock: AsyncMutex<CriticalSectionRawMutex, ()>,
+    signal: ReplySignal,
+}
+
+impl SharedReply {
+    pub const fn new() -> Self {
+        SharedReply {
+            lock: AsyncMutex::new(()),
+            signal: ReplySignal::new(),
+        }
+    }
+
+    pub async fn request(&'static self, req: Request) -> Reply {
+        let _guard = self.lock.lock().await;
+        request(req, &self.signal).await
+    }
+}
@@ -0,0 +1,252 @@
+use chrono::{DateTime, NaiveDateTime};
+use defmt::{info, warn};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use heapless::String;
+
+use super::{
+    is_erased, open_record, seal_record, Reader, Writer, CALIBRATION_LOG_SECTOR,
+    RECORD_HEADER_SIZE, SECTOR_SIZE,
+};
+
+const CALIBRATION_MAGIC: u32 = 0x4C41_4349; // "ICAL"
+const CALIBRATION_VERSION: u16 = 2;
+const SLOT_SIZE: usize = 128;
+const SLOTS_PER_SECTOR: u32 = SECTOR_SIZE / SLOT_SIZE as u32;
+
+pub const EXPLORIR_SERIAL_LENGTH: usize = 32;
+
+// Presence flags for the optional readings
+const FLAG_TEMP_BEFORE: u8 = 1 << 0;
+const FLAG_TEMP_AFTER: u8 = 1 << 1;
+const FLAG_TEMP_OFFSET: u8 = 1 << 2;
+const FLAG_CO2_BEFORE: u8 = 1 << 3;
+const FLAG_CO2_AFTER: u8 = 1 << 4;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CalibrationRecord {
+    pub timestamp_s: u64, // seconds since boot at the time of calibration
+    pub scd41_serial: u64,
+    pub explorir_serial: String<EXPLORIR_SERIAL_LENGTH>,
+
+    // Reference values the sensors were calibrated against
+    pub reference_temp_c: f32,
+    pub pressure_pa: u32,
+    pub altitude_m: u16,
+    pub co2_pressure_mbar: f32,
+    pub reference_co2_ppm: u32,
+
+    // Readings around the calibration; `None` if the sensor could not be read
+    pub temp_before_c: Option<f32>,
+    pub temp_after_c: Option<f32>,
+    pub temp_offset_c: Option<f32>,
+    pub co2_before_ppm: Option<i32>,
+    pub co2_after_ppm: Option<i32>,
+
+    // Wall-clock time of the calibration if the RTC was set; not stored by older firmware
+    pub datetime: Option<NaiveDateTime>,
+}
+
+impl CalibrationRecord {
+    // Fields are only ever appended; bump CALIBRATION_VERSION when doing so
+    fn encode(&self, buf: &mut [u8]) -> usize {
+        let mut flags = 0;
+        for (present, flag) in [
+            (self.temp_before_c.is_some(), FLAG_TEMP_BEFORE),
+            (self.temp_after_c.is_some(), FLAG_TEMP_AFTER),
+            (self.temp_offset_c.is_some(), FLAG_TEMP_OFFSET),
+            (self.co2_before_ppm.is_some(), FLAG_CO2_BEFORE),
+            (self.co2_after_ppm.is_some(), FLAG_CO2_AFTER),
+        ] {
+            if present {
+                flags |= flag;
+            }
+        }
+
+        let mut serial = [0u8; EXPLORIR_SERIAL_LENGTH];
+        serial[..self.explorir_serial.len()].copy_from_slice(self.explorir_serial.as_bytes());
+
+        let mut w = Writer::new(buf);
+        w.u32(self.timestamp_s as u32);
+        w.u32((self.timestamp_s >> 32) as u32);
+        w.u32(self.scd41_serial as u32);
+        w.u32((self.scd41_serial >> 32) as u32);
+        w.u8(self.explorir_serial.len() as u8);
+        w.bytes(&serial);
+        w.f32(self.reference_temp_c);
+        w.u32(self.pressure_pa);
+        w.u16(self.altitude_m);
+        w.f32(self.co2_pressure_mbar);
+        w.u32(self.reference_co2_ppm);
+        w.u8(flags);
+        w.f32(self.temp_before_c.unwrap_or(0.0));
+        w.f32(self.temp_after_c.unwrap_or(0.0));
+        w.f32(self.temp_offset_c.unwrap_or(0.0));
+        w.i32(self.co2_before_ppm.unwrap_or(0));
+        w.i32(self.co2_after_ppm.unwrap_or(0));
+        // Unix seconds, 0 if unknown
+        w.u32(
+            self.datetime
+                .map_or(0, |datetime| datetime.and_utc().timestamp() as u32),
+        );
+        w.position()
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, &'static str> {
+        let mut r = Reader::new(buf);
+        let mut decode = || -> Option<Self> {
+            let timestamp_s = r.u32()? as u64 | (r.u32()? as u64) << 32;
+            let scd41_serial = r.u32()? as u64 | (r.u32()? as u64) << 32;
+            let serial_len = (r.u8()? as usize).min(EXPLORIR_SERIAL_LENGTH);
+            let serial = r.bytes::<EXPLORIR_SERIAL_LENGTH>()?;
+            let mut explorir_serial = String::new();
+            explorir_serial
+                .push_str(core::str::from_utf8(&serial[..serial_len]).ok()?)
+                .ok()?;
+
+            let reference_temp_c = r.f32()?;
+            let pressure_pa = r.u32()?;
+            let altitude_m = r.u16()?;
+            let co2_pressure_mbar = r.f32()?;
+            let reference_co2_ppm = r.u32()?;
+            let flags = r.u8()?;
+            let present = |flag: u8| flags & flag != 0;
+            let temp_before_c = r.f32()?;
+            let temp_after_c = r.f32()?;
+            let temp_offset_c = r.f32()?;
+            let co2_before_ppm = r.i32()?;
+            let co2_after_ppm = r.i32()?;
+            let datetime = r
+                .u32()
+                .filter(|&unix_s| unix_s != 0)
+                .and_then(|unix_s| DateTime::from_timestamp(unix_s as i64, 0))
+                .map(|datetime| datetime.naive_utc());
+
+            Some(CalibrationRecord {
+                timestamp_s,
+                scd41_serial,
+                explorir_serial,
+                reference_temp_c,
+                pressure_pa,
+                altitude_m,
+                co2_pressure_mbar,
+                reference_co2_ppm,
+                temp_before_c: present(FLAG_TEMP_BEFORE).then_some(temp_before_c),
+                temp_after_c: present(FLAG_TEMP_AFTER).then_some(temp_after_c),
+                temp_offset_c: present(FLAG_TEMP_OFFSET).then_some(temp_offset_c),
+                co2_before_ppm: present(FLAG_CO2_BEFORE).then_some(co2_before_ppm),
+                co2_after_ppm: present(FLAG_CO2_AFTER).then_some(co2_after_ppm),
+                datetime,
+            })
+        };
+        decode().ok_or("Truncated calibration record")
+    }
+}
+
+// The SCD41 returns its 48-bit serial as three CRC-protected words,
+// see `SCD41::get_serial_number`
+pub fn scd41_serial_from_raw(raw: &[u8; 9]) -> u64 {
+    (u64::from(u16::from_be_bytes([raw[0], raw[1]])) << 32)
+        | (u64::from(u16::from_be_bytes([raw[3], raw[4]])) << 16)
+        | u64::from(u16::from_be_bytes([raw[6], raw[7]]))
+}
+
+// Append-only calibration history in a dedicated flash sector.
+//
+// Entries are numbered with a monotonically increasing sequence number. Calibrations are
+// rare, so a single sector holds years of history; once it is full the sector is erased
+// and the log restarts, keeping the sequence numbering so gaps stay visible.
+pub struct CalibrationLog {
+    seq: u32,
+    next_slot: u32,
+}
+
+impl CalibrationLog {
+    pub fn open<F: NorFlash>(flash: &mut F) -> Self {
+        let mut seq = 0;
+        let mut next_slot = SLOTS_PER_SECTOR;
+
+        for slot in 0..SLOTS_PER_SECTOR {
+            let mut record = [0u8; SLOT_SIZE];
+            if flash.read(Self::offset(slot), &mut record).is_err() || is_erased(&record) {
+                next_slot = slot;
+                break;
+            }
+            if let Ok((_, record_seq, _)) =
+                open_record(&record, CALIBRATION_MAGIC, CALIBRATION_VERSION)
+            {
+                seq = seq.max(record_seq);
+            }
+        }
+
+        info!(
+            "Calibration log: {} entries, last sequence #{}",
+            next_slot, seq
+        );
+        CalibrationLog { seq, next_slot }
+    }
+
+    pub fn append<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        entry: &CalibrationRecord,
+    ) -> Result<u32, &'static str> {
+        if self.next_slot >= SLOTS_PER_SECTOR {
+            warn!("Calibration log full, erasing and restarting");
+            flash
+                .erase(CALIBRATION_LOG_SECTOR, CALIBRATION_LOG_SECTOR + SECTOR_SIZE)
+                .map_err(|_| "Failed to erase calibration log")?;
+            self.next_slot = 0;
+        }
+
+        let mut record = [0xFFu8; SLOT_SIZE];
+        let payload_len = entry.encode(&mut record[RECORD_HEADER_SIZE..]);
+        let seq = self.seq.wrapping_add(1);
+        seal_record(
+            &mut record,
+            CALIBRATION_MAGIC,
+            CALIBRATION_VERSION,
+            seq,
+            payload_len,
+        );
+
+        flash
+            .write(Self::offset(self.next_slot), &record)
+            .map_err(|_| "Failed to write calibration record")?;
+
+        self.seq = seq;
+        self.next_slot += 1;
+        info!("Calibration record #{} stored", seq);
+        Ok(seq)
+    }
+
+    // Number of entries currently held in the log
+    pub fn len(&self) -> u32 {
+        self.next_slot.min(SLOTS_PER_SECTOR)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Reads entry `index` (0 = oldest) and returns it with its sequence number
+    pub fn read<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        index: u32,
+    ) -> Result<(u32, CalibrationRecord), &'static str> {
+        if index >= self.len() {
+            return Err("Calibration log index out of range");
+        }
+
+        let mut record = [0u8; SLOT_SIZE];
+        flash
+            .read(Self::offset(index), &mut record)
+            .map_err(|_| "Failed to read calibration record")?;
+        let (_, seq, payload) = open_record(&record, CALIBRATION_MAGIC, CALIBRATION_VERSION)?;
+        Ok((seq, CalibrationRecord::decode(payload)?))
+    }
+
+    fn offset(slot: u32) -> u32 {
+        CALIBRATION_LOG_SECTOR + slot * SLOT_SIZE as u32
+    }
+}
@@ -0,0 +1,982 @@
+use defmt::{error, info, warn, Format};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+use crate::drivers::co2_solenoid::Wear;
+use crate::drivers::thermistor::{Divider, Model, PROBE_COUNT};
+
+use super::{
+    is_erased, open_record, seal_record, Reader, Writer, CONFIG_SECTOR_A, CONFIG_SECTOR_B,
+    RECORD_HEADER_SIZE, SECTOR_SIZE,
+};
+
+// Record layout
+const CONFIG_MAGIC: u32 = 0x4746_4349; // "ICFG"
+const CONFIG_VERSION: u16 = 14;
+const SLOT_SIZE: usize = 256;
+const SLOTS_PER_SECTOR: u32 = SECTOR_SIZE / SLOT_SIZE as u32;
+
+// Per-probe tag byte: the model kind, with the divider leg in the top bit. Records
+// before version 14 only hold stock (0) and Steinhart-Hart (1) low-side probes.
+const PROBE_STOCK: u8 = 0;
+const PROBE_STEINHART_HART: u8 = 1;
+const PROBE_BETA: u8 = 2;
+const PROBE_HIGH_SIDE: u8 = 0x80;
+
+// Largest payload Config::encode writes, group by group; keep in step with encode
+const MAX_PAYLOAD_SIZE: usize = 20 // control setpoints
+    + 18 // calibration references
+    + 6 + 1 + MQTT_TOPIC_LENGTH // MQTT broker, port and topic
+    + 1 + HTTP_TOKEN_LENGTH // HTTP token
+    + 1 // Modbus unit
+    + 6 // NTP server and interval
+    + 13 // humidity
+    + 11 // thermal actuator and Peltier drive
+    + PROBE_COUNT * 13 + 4 // probe models and dividers, and heatsink limit
+    + 4 // CO2 cross-check
+    + 9 // door handling
+    + 9 // CO2 supply pressure
+    + 6 // valve drive
+    + 12; // valve wear
+
+// Writer panics past the end of the slot, so a field that no longer fits must fail
+// the build rather than every save on the device
+const _: () = assert!(RECORD_HEADER_SIZE + MAX_PAYLOAD_SIZE <= SLOT_SIZE);
+
+const SECTORS: [u32; 2] = [CONFIG_SECTOR_A, CONFIG_SECTOR_B];
+// Names of the two sectors' slots in the flash layout (see storage/mod.rs)
+const SLOT_NAMES: [&str; 2] = ["A", "B"];
+
+// Defaults used when no valid record is stored
+pub const DEFAULT_TARGET_CO2_PPM: f32 = 50_000.0; // 5%
+pub const DEFAULT_TARGET_TEMP_C: f32 = 37.0; // Body temperature
+pub const DEFAULT_CO2_TOLERANCE_PPM: f32 = 2000.0; // ±0.2%
+pub const DEFAULT_TEMP_TOLERANCE_C: f32 = 1.0; // ±1°C
+pub const DEFAULT_CO2_BURST_MS: u32 = 500;
+pub const DEFAULT_CAL_REFERENCE_TEMP_C: f32 = 37.1;
+pub const DEFAULT_CAL_PRESSURE_PA: u32 = 102_133;
+pub const DEFAULT_CAL_ALTITUDE_M: u16 = 142;
+pub const DEFAULT_CAL_CO2_PRESSURE_MBAR: f32 = 1016.9325;
+pub const DEFAULT_CAL_CO2_REFERENCE_PPM: u32 = 51_000;
+pub const DEFAULT_MQTT_BROKER: [u8; 4] = [0, 0, 0, 0]; // publishing disabled
+pub const DEFAULT_MQTT_PORT: u16 = 1883;
+pub const DEFAULT_MQTT_TOPIC: &str = "icbm";
+pub const DEFAULT_MODBUS_UNIT: u8 = icbm_protocol::modbus::DEFAULT_UNIT_ID;
+pub const DEFAULT_NTP_SERVER: [u8; 4] = [0, 0, 0, 0]; // sync disabled
+pub const DEFAULT_NTP_INTERVAL_MIN: u16 = 60;
+pub const DEFAULT_TARGET_HUMIDITY_RH: f32 = 90.0;
+pub const DEFAULT_HUMIDITY_TOLERANCE_RH: f32 = 3.0;
+pub const DEFAULT_HUMIDITY_ALARM_RH: f32 = 80.0;
+pub const DEFAULT_PELTIER_PWM_HZ: u32 = 50_000;
+pub const DEFAULT_PELTIER_DEAD_TIME_MS: u16 = 100;
+pub const DEFAULT_PELTIER_RAMP_PCT_S: f32 = 20.0;
+pub const DEFAULT_HEATSINK_MAX_C: f32 = 60.0;
+pub const DEFAULT_CO2_CROSSCHECK_PCT: f32 = 15.0;
+pub const DEFAULT_DOOR_DROP_PCT: f32 = 10.0;
+pub const DEFAULT_DOOR_BOOST: f32 = 2.0;
+pub const DEFAULT_CO2_PRESSURE_FULL_BAR: f32 = 100.0;
+pub const DEFAULT_CO2_PRESSURE_MIN_BAR: f32 = 20.0;
+pub const DEFAULT_VALVE_PULL_IN_MS: u16 = 100;
+pub const DEFAULT_VALVE_HOLD_PCT: f32 = 100.0; // hold reduction off
+
+pub const MQTT_TOPIC_LENGTH: usize = 32;
+pub const HTTP_TOKEN_LENGTH: usize = 32;
+
+pub type TopicPrefix = FixedText<MQTT_TOPIC_LENGTH>;
+pub type HttpToken = FixedText<HTTP_TOKEN_LENGTH>;
+
+// Fixed-capacity printable ASCII text so the configuration stays `Copy`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FixedText<const N: usize> {
+    bytes: [u8; N],
+    len: u8,
+}
+
+impl<const N: usize> FixedText<N> {
+    pub const EMPTY: Self = FixedText {
+        bytes: [0; N],
+        len: 0,
+    };
+
+    const fn from_ascii(value: &str) -> Self {
+        let mut bytes = [0u8; N];
+        let mut i = 0;
+        while i < value.len() {
+            bytes[i] = value.as_bytes()[i];
+            i += 1;
+        }
+        FixedText {
+            bytes,
+            len: value.len() as u8,
+        }
+    }
+
+    pub fn new(value: &str) -> Result<Self, &'static str> {
+        if value.len() > N {
+            return Err("text is too long");
+        }
+        if !value.bytes().all(|b| b.is_ascii_graphic()) {
+            return Err("text must be printable ASCII without spaces");
+        }
+        Ok(FixedText::from_ascii(value))
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or("")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn encode(&self, w: &mut Writer) {
+        w.u8(self.len);
+        w.bytes(&self.bytes);
+    }
+
+    fn decode(r: &mut Reader) -> Option<Self> {
+        let len = r.u8()?;
+        let bytes = r.bytes()?;
+        (len as usize <= N).then_some(FixedText { bytes, len })
+    }
+}
+
+impl<const N: usize> Format for FixedText<N> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "{=str}", self.as_str())
+    }
+}
+
+// What drives the chamber temperature
+#[derive(Clone, Copy, Debug, PartialEq, Format)]
+pub enum ThermalActuator {
+    // Resistive heater pulsed on below the setpoint (drivers/bsz070.rs)
+    Heater,
+    // Peltier module on the DRV8873 bridge, heating and cooling (see thermal.rs)
+    Peltier,
+}
+
+impl ThermalActuator {
+    pub fn name(self) -> &'static str {
+        match self {
+            ThermalActuator::Heater => "heater",
+            ThermalActuator::Peltier => "peltier",
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(ThermalActuator::Heater),
+            1 => Some(ThermalActuator::Peltier),
+            _ => None,
+        }
+    }
+}
+
+// Wildcards and leading/trailing separators would produce unusable topics
+fn topic_prefix(value: &str) -> Result<TopicPrefix, &'static str> {
+    if value.is_empty()
+        || value.contains(['+', '#'])
+        || value.starts_with('/')
+        || value.ends_with('/')
+    {
+        return Err("MQTT topic must be non-empty, without wildcards or outer '/'");
+    }
+    TopicPrefix::new(value)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Format)]
+pub struct Config {
+    // Control setpoints
+    pub target_co2_ppm: f32,
+    pub target_temp_c: f32,
+    pub co2_tolerance_ppm: f32,
+    pub temp_tolerance_c: f32,
+    pub co2_burst_ms: u32,
+
+    // Calibration references (see bin/calibrate.rs)
+    pub cal_reference_temp_c: f32,
+    pub cal_pressure_pa: u32,
+    pub cal_altitude_m: u16,
+    pub cal_co2_pressure_mbar: f32,
+    pub cal_co2_reference_ppm: u32,
+
+    // MQTT telemetry publisher (see net/mqtt.rs)
+    pub mqtt_broker: [u8; 4],
+    pub mqtt_port: u16,
+    pub mqtt_topic: TopicPrefix,
+
+    // Bearer token required to change setpoints over HTTP; empty disables changes
+    pub http_token: HttpToken,
+
+    // Modbus RTU slave address (see modbus/rtu.rs)
+    pub modbus_unit: u8,
+
+    // SNTP clock sync (see net/sntp.rs)
+    pub ntp_server: [u8; 4],
+    pub ntp_interval_min: u16,
+
+    // Humidity (see humidity.rs); the alarm threshold applies with or without a
+    // humidifier, 0 disables it
+    pub target_humidity_rh: f32,
+    pub humidity_tolerance_rh: f32,
+    pub humidity_alarm_rh: f32,
+    pub humidifier_enabled: bool,
+
+    // Thermal actuator and the Peltier drive (see drivers/drv8873.rs)
+    pub thermal_actuator: ThermalActuator,
+    pub peltier_pwm_hz: u32,
+    pub peltier_dead_time_ms: u16,
+    pub peltier_ramp_pct_s: f32,
+
+    // Thermistor bank (see drivers/thermistor.rs), indexed like `Probe::ALL`: each
+    // probe's model, None for the stock probe, and divider leg; and the Peltier
+    // heatsink temperature that disables the bridge
+    pub probe_calibration: [Option<Model>; PROBE_COUNT],
+    pub probe_divider: [Divider; PROBE_COUNT],
+    pub heatsink_max_c: f32,
+
+    // Largest CO2 difference between the ExplorIR and the SCD41, in percent of the
+    // reading, before they are flagged as disagreeing (see co2_check.rs); 0 disables
+    pub co2_crosscheck_pct: f32,
+
+    // Door handling (see door.rs): whether a door switch is fitted, the CO2 drop per
+    // control cycle, in percent, taken as the door opening (0 disables) and the burst
+    // length factor while refilling afterwards
+    pub door_switch: bool,
+    pub door_drop_pct: f32,
+    pub door_boost: f32,
+
+    // CO2 supply pressure transducer (see drivers/pressure_transducer.rs): whether one
+    // is fitted, its full scale and the pressure below which the supply counts as
+    // exhausted, in bar
+    pub co2_pressure_sensor: bool,
+    pub co2_pressure_full_bar: f32,
+    pub co2_pressure_min_bar: f32,
+
+    // CO2 valve coil drive (see drivers/co2_solenoid.rs): full drive for the pull-in
+    // time after opening, then the hold duty
+    pub valve_pull_in_ms: u16,
+    pub valve_hold_pct: f32,
+
+    // Use of the CO2 valve so far. Not a setting: the control loop writes it through
+    // from the RTC backup registers every so often (see main.rs)
+    pub valve_wear: Wear,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::DEFAULT
+    }
+}
+
+impl Config {
+    pub const DEFAULT: Config = Config {
+        target_co2_ppm: DEFAULT_TARGET_CO2_PPM,
+        target_temp_c: DEFAULT_TARGET_TEMP_C,
+        co2_tolerance_ppm: DEFAULT_CO2_TOLERANCE_PPM,
+        temp_tolerance_c: DEFAULT_TEMP_TOLERANCE_C,
+        co2_burst_ms: DEFAULT_CO2_BURST_MS,
+        cal_reference_temp_c: DEFAULT_CAL_REFERENCE_TEMP_C,
+        cal_pressure_pa: DEFAULT_CAL_PRESSURE_PA,
+        cal_altitude_m: DEFAULT_CAL_ALTITUDE_M,
+        cal_co2_pressure_mbar: DEFAULT_CAL_CO2_PRESSURE_MBAR,
+        cal_co2_reference_ppm: DEFAULT_CAL_CO2_REFERENCE_PPM,
+        mqtt_broker: DEFAULT_MQTT_BROKER,
+        mqtt_port: DEFAULT_MQTT_PORT,
+        mqtt_topic: TopicPrefix::from_ascii(DEFAULT_MQTT_TOPIC),
+        http_token: HttpToken::EMPTY,
+        modbus_unit: DEFAULT_MODBUS_UNIT,
+        ntp_server: DEFAULT_NTP_SERVER,
+        ntp_interval_min: DEFAULT_NTP_INTERVAL_MIN,
+        target_humidity_rh: DEFAULT_TARGET_HUMIDITY_RH,
+        humidity_tolerance_rh: DEFAULT_HUMIDITY_TOLERANCE_RH,
+        humidity_alarm_rh: DEFAULT_HUMIDITY_ALARM_RH,
+        humidifier_enabled: false,
+        thermal_actuator: ThermalActuator::Heater,
+        peltier_pwm_hz: DEFAULT_PELTIER_PWM_HZ,
+        peltier_dead_time_ms: DEFAULT_PELTIER_DEAD_TIME_MS,
+        peltier_ramp_pct_s: DEFAULT_PELTIER_RAMP_PCT_S,
+        probe_calibration: [None; PROBE_COUNT],
+        probe_divider: [Divider::LowSide; PROBE_COUNT],
+        heatsink_max_c: DEFAULT_HEATSINK_MAX_C,
+        co2_crosscheck_pct: DEFAULT_CO2_CROSSCHECK_PCT,
+        door_switch: false,
+        door_drop_pct: DEFAULT_DOOR_DROP_PCT,
+        door_boost: DEFAULT_DOOR_BOOST,
+        co2_pressure_sensor: false,
+        co2_pressure_full_bar: DEFAULT_CO2_PRESSURE_FULL_BAR,
+        co2_pressure_min_bar: DEFAULT_CO2_PRESSURE_MIN_BAR,
+        valve_pull_in_ms: DEFAULT_VALVE_PULL_IN_MS,
+        valve_hold_pct: DEFAULT_VALVE_HOLD_PCT,
+        valve_wear: Wear {
+            on_time_ms: 0,
+            actuations: 0,
+        },
+    };
+
+    // Fields are only ever appended; bump CONFIG_VERSION and MAX_PAYLOAD_SIZE when doing so
+    fn encode(&self, buf: &mut [u8]) -> usize {
+        let mut w = Writer::new(buf);
+        w.f32(self.target_co2_ppm);
+        w.f32(self.target_temp_c);
+        w.f32(self.co2_tolerance_ppm);
+        w.f32(self.temp_tolerance_c);
+        w.u32(self.co2_burst_ms);
+        w.f32(self.cal_reference_temp_c);
+        w.u32(self.cal_pressure_pa);
+        w.u16(self.cal_altitude_m);
+        w.f32(self.cal_co2_pressure_mbar);
+        w.u32(self.cal_co2_reference_ppm);
+        w.bytes(&self.mqtt_broker);
+        w.u16(self.mqtt_port);
+        self.mqtt_topic.encode(&mut w);
+        self.http_token.encode(&mut w);
+        w.u8(self.modbus_unit);
+        w.bytes(&self.ntp_server);
+        w.u16(self.ntp_interval_min);
+        w.f32(self.target_humidity_rh);
+        w.f32(self.humidity_tolerance_rh);
+        w.f32(self.humidity_alarm_rh);
+        w.u8(self.humidifier_enabled as u8);
+        w.u8(self.thermal_actuator as u8);
+        w.u32(self.peltier_pwm_hz);
+        w.u16(self.peltier_dead_time_ms);
+        w.f32(self.peltier_ramp_pct_s);
+        // Model kind with the divider leg in the top bit, then the model's parameters
+        for (calibration, divider) in self.probe_calibration.iter().zip(self.probe_divider) {
+            let divider = match divider {
+                Divider::LowSide => 0,
+                Divider::HighSide => PROBE_HIGH_SIDE,
+            };
+            match *calibration {
+                Some(Model::SteinhartHart { a, b, c }) => {
+                    w.u8(PROBE_STEINHART_HART | divider);
+                    w.f32(a);
+                    w.f32(b);
+                    w.f32(c);
+                }
+                Some(Model::Beta {
+                    beta,
+                    r0_ohms,
+                    t0_c,
+                }) => {
+                    w.u8(PROBE_BETA | divider);
+                    w.f32(beta);
+                    w.f32(r0_ohms);
+                    w.f32(t0_c);
+                }
+                None => w.u8(PROBE_STOCK | divider),
+            }
+        }
+        w.f32(self.heatsink_max_c);
+        w.f32(self.co2_crosscheck_pct);
+        w.u8(self.door_switch as u8);
+        w.f32(self.door_drop_pct);
+        w.f32(self.door_boost);
+        w.u8(self.co2_pressure_sensor as u8);
+        w.f32(self.co2_pressure_full_bar);
+        w.f32(self.co2_pressure_min_bar);
+        w.u16(self.valve_pull_in_ms);
+        w.f32(self.valve_hold_pct);
+        w.u32(self.valve_wear.on_time_ms as u32);
+        w.u32((self.valve_wear.on_time_ms >> 32) as u32);
+        w.u32(self.valve_wear.actuations);
+        debug_assert!(w.position() <= MAX_PAYLOAD_SIZE);
+        w.position()
+    }
+
+    // Missing trailing fields (records from older versions) keep their defaults
+    fn decode(buf: &[u8]) -> Self {
+        let mut c = Config::default();
+        let mut r = Reader::new(buf);
+        c.target_co2_ppm = r.f32().unwrap_or(c.target_co2_ppm);
+        c.target_temp_c = r.f32().unwrap_or(c.target_temp_c);
+        c.co2_tolerance_ppm = r.f32().unwrap_or(c.co2_tolerance_ppm);
+        c.temp_tolerance_c = r.f32().unwrap_or(c.temp_tolerance_c);
+        c.co2_burst_ms = r.u32().unwrap_or(c.co2_burst_ms);
+        c.cal_reference_temp_c = r.f32().unwrap_or(c.cal_reference_temp_c);
+        c.cal_pressure_pa = r.u32().unwrap_or(c.cal_pressure_pa);
+        c.cal_altitude_m = r.u16().unwrap_or(c.cal_altitude_m);
+        c.cal_co2_pressure_mbar = r.f32().unwrap_or(c.cal_co2_pressure_mbar);
+        c.cal_co2_reference_ppm = r.u32().unwrap_or(c.cal_co2_reference_ppm);
+        c.mqtt_broker = r.bytes().unwrap_or(c.mqtt_broker);
+        c.mqtt_port = r.u16().unwrap_or(c.mqtt_port);
+        c.mqtt_topic = FixedText::decode(&mut r).unwrap_or(c.mqtt_topic);
+        c.http_token = FixedText::decode(&mut r).unwrap_or(c.http_token);
+        c.modbus_unit = r.u8().unwrap_or(c.modbus_unit);
+        c.ntp_server = r.bytes().unwrap_or(c.ntp_server);
+        c.ntp_interval_min = r.u16().unwrap_or(c.ntp_interval_min);
+        c.target_humidity_rh = r.f32().unwrap_or(c.target_humidity_rh);
+        c.humidity_tolerance_rh = r.f32().unwrap_or(c.humidity_tolerance_rh);
+        c.humidity_alarm_rh = r.f32().unwrap_or(c.humidity_alarm_rh);
+        c.humidifier_enabled = r.u8().map_or(c.humidifier_enabled, |b| b != 0);
+        c.thermal_actuator = r
+            .u8()
+            .and_then(ThermalActuator::from_u8)
+            .unwrap_or(c.thermal_actuator);
+        c.peltier_pwm_hz = r.u32().unwrap_or(c.peltier_pwm_hz);
+        c.peltier_dead_time_ms = r.u16().unwrap_or(c.peltier_dead_time_ms);
+        c.peltier_ramp_pct_s = r.f32().unwrap_or(c.peltier_ramp_pct_s);
+        for (calibration, divider) in c
+            .probe_calibration
+            .iter_mut()
+            .zip(c.probe_divider.iter_mut())
+        {
+            let Some(tag) = r.u8() else { break };
+            if tag & PROBE_HIGH_SIDE != 0 {
+                *divider = Divider::HighSide;
+            }
+            let kind = tag & !PROBE_HIGH_SIDE;
+            if kind == PROBE_STOCK {
+                continue;
+            }
+            if let (Some(x), Some(y), Some(z)) = (r.f32(), r.f32(), r.f32()) {
+                *calibration = match kind {
+                    PROBE_STEINHART_HART => Some(Model::SteinhartHart { a: x, b: y, c: z }),
+                    PROBE_BETA => Some(Model::Beta {
+                        beta: x,
+                        r0_ohms: y,
+                        t0_c: z,
+                    }),
+                    _ => None,
+                };
+            }
+        }
+        c.heatsink_max_c = r.f32().unwrap_or(c.heatsink_max_c);
+        c.co2_crosscheck_pct = r.f32().unwrap_or(c.co2_crosscheck_pct);
+        c.door_switch = r.u8().map_or(c.door_switch, |b| b != 0);
+        c.door_drop_pct = r.f32().unwrap_or(c.door_drop_pct);
+        c.door_boost = r.f32().unwrap_or(c.door_boost);
+        c.co2_pressure_sensor = r.u8().map_or(c.co2_pressure_sensor, |b| b != 0);
+        c.co2_pressure_full_bar = r.f32().unwrap_or(c.co2_pressure_full_bar);
+        c.co2_pressure_min_bar = r.f32().unwrap_or(c.co2_pressure_min_bar);
+        c.valve_pull_in_ms = r.u16().unwrap_or(c.valve_pull_in_ms);
+        c.valve_hold_pct = r.f32().unwrap_or(c.valve_hold_pct);
+        if let (Some(low), Some(high), Some(actuations)) = (r.u32(), r.u32(), r.u32()) {
+            c.valve_wear = Wear {
+                on_time_ms: (high as u64) << 32 | low as u64,
+                actuations,
+            };
+        }
+        c
+    }
+
+    // Sets a field from its console / MQTT key and a textual value, then validates
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), &'static str> {
+        fn float(value: &str) -> Result<f32, &'static str> {
+            value.parse().map_err(|_| "value is not a number")
+        }
+        fn int<T: core::str::FromStr>(value: &str) -> Result<T, &'static str> {
+            value.parse().map_err(|_| "value is not a valid integer")
+        }
+        fn address(value: &str, off: [u8; 4]) -> Result<[u8; 4], &'static str> {
+            match value {
+                "off" => Ok(off),
+                _ => value
+                    .parse::<core::net::Ipv4Addr>()
+                    .map(|addr| addr.octets())
+                    .map_err(|_| "value is not an IPv4 address or 'off'"),
+            }
+        }
+
+        match key {
+            "co2" => self.target_co2_ppm = float(value)?,
+            "temp" => self.target_temp_c = float(value)?,
+            "co2tol" => self.co2_tolerance_ppm = float(value)?,
+            "temptol" => self.temp_tolerance_c = float(value)?,
+            "burst" => self.co2_burst_ms = int(value)?,
+            "calref" => self.cal_reference_temp_c = float(value)?,
+            "calpressure" => self.cal_pressure_pa = int(value)?,
+            "calaltitude" => self.cal_altitude_m = int(value)?,
+            "calco2pressure" => self.cal_co2_pressure_mbar = float(value)?,
+            "calco2ref" => self.cal_co2_reference_ppm = int(value)?,
+            "mqttbroker" => self.mqtt_broker = address(value, DEFAULT_MQTT_BROKER)?,
+            "mqttport" => self.mqtt_port = int(value)?,
+            "mqtttopic" => self.mqtt_topic = topic_prefix(value)?,
+            "httptoken" => {
+                self.http_token = match value {
+                    "off" => HttpToken::EMPTY,
+                    _ => HttpToken::new(value)?,
+                }
+            }
+            "modbusunit" => self.modbus_unit = int(value)?,
+            "ntpserver" => self.ntp_server = address(value, DEFAULT_NTP_SERVER)?,
+            "ntpinterval" => self.ntp_interval_min = int(value)?,
+            "rh" => self.target_humidity_rh = float(value)?,
+            "rhtol" => self.humidity_tolerance_rh = float(value)?,
+            "rhalarm" => self.humidity_alarm_rh = float(value)?,
+            "humidifier" => {
+                self.humidifier_enabled = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err("value must be on or off"),
+                }
+            }
+            "actuator" => {
+                self.thermal_actuator = match value {
+                    "heater" => ThermalActuator::Heater,
+                    "peltier" => ThermalActuator::Peltier,
+                    _ => return Err("value must be heater or peltier"),
+                }
+            }
+            "peltierfreq" => self.peltier_pwm_hz = int(value)?,
+            "peltierdead" => self.peltier_dead_time_ms = int(value)?,
+            "peltierramp" => self.peltier_ramp_pct_s = float(value)?,
+            "sinkmax" => self.heatsink_max_c = float(value)?,
+            "co2check" => self.co2_crosscheck_pct = float(value)?,
+            "doorswitch" => {
+                self.door_switch = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err("value must be on or off"),
+                }
+            }
+            "doordrop" => self.door_drop_pct = float(value)?,
+            "doorboost" => self.door_boost = float(value)?,
+            "co2psensor" => {
+                self.co2_pressure_sensor = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err("value must be on or off"),
+                }
+            }
+            "co2pfull" => self.co2_pressure_full_bar = float(value)?,
+            "co2pmin" => self.co2_pressure_min_bar = float(value)?,
+            "valvepullin" => self.valve_pull_in_ms = int(value)?,
+            "valvehold" => self.valve_hold_pct = float(value)?,
+            _ => return Err("unknown key"),
+        }
+        self.validate()
+    }
+
+    // Rejects values the control loop cannot act on sensibly
+    pub fn validate(&self) -> Result<(), &'static str> {
+        match RULES.iter().find(|rule| !(rule.valid)(self)) {
+            Some(rule) => Err(rule.error),
+            None => Ok(()),
+        }
+    }
+
+    // Puts the fields of every rule a stored record breaks back to their defaults, so
+    // that limits tightened by newer firmware do not discard the rest of the record
+    fn repair(&mut self) {
+        for rule in RULES {
+            if !(rule.valid)(self) {
+                warn!("Stored config reset to default: {}", rule.error);
+                (rule.reset)(self);
+            }
+        }
+    }
+}
+
+// A limit on one or a few related fields, and how a stored record breaking it is
+// repaired
+struct Rule {
+    valid: fn(&Config) -> bool,
+    error: &'static str,
+    reset: fn(&mut Config),
+}
+
+// Range checks are written so that NaN fails them
+const RULES: &[Rule] = &[
+    Rule {
+        valid: |c| (0.0..=200_000.0).contains(&c.target_co2_ppm),
+        error: "CO2 setpoint must be between 0 and 200000 ppm",
+        reset: |c| c.target_co2_ppm = Config::DEFAULT.target_co2_ppm,
+    },
+    Rule {
+        valid: |c| (20.0..=45.0).contains(&c.target_temp_c),
+        error: "Temperature setpoint must be between 20 and 45 °C",
+        reset: |c| c.target_temp_c = Config::DEFAULT.target_temp_c,
+    },
+    // Wider tolerances leave control effectively switched off
+    Rule {
+        valid: |c| c.co2_tolerance_ppm > 0.0 && c.co2_tolerance_ppm <= 20_000.0,
+        error: "CO2 tolerance must be above 0 and at most 20000 ppm",
+        reset: |c| c.co2_tolerance_ppm = Config::DEFAULT.co2_tolerance_ppm,
+    },
+    Rule {
+        valid: |c| c.temp_tolerance_c > 0.0 && c.temp_tolerance_c <= 5.0,
+        error: "Temperature tolerance must be above 0 and at most 5 °C",
+        reset: |c| c.temp_tolerance_c = Config::DEFAULT.temp_tolerance_c,
+    },
+    Rule {
+        valid: |c| c.humidity_tolerance_rh > 0.0 && c.humidity_tolerance_rh <= 20.0,
+        error: "Humidity tolerance must be above 0 and at most 20 %RH",
+        reset: |c| c.humidity_tolerance_rh = Config::DEFAULT.humidity_tolerance_rh,
+    },
+    Rule {
+        valid: |c| (1..=10_000).contains(&c.co2_burst_ms),
+        error: "CO2 burst length must be between 1 and 10000 ms",
+        reset: |c| c.co2_burst_ms = Config::DEFAULT.co2_burst_ms,
+    },
+    Rule {
+        valid: |c| (70_000..=120_000).contains(&c.cal_pressure_pa),
+        error: "Calibration pressure must be between 70,000 and 120,000 Pa",
+        reset: |c| c.cal_pressure_pa = Config::DEFAULT.cal_pressure_pa,
+    },
+    Rule {
+        valid: |c| c.cal_altitude_m <= 3000,
+        error: "Calibration altitude must be between 0 and 3000 meters",
+        reset: |c| c.cal_altitude_m = Config::DEFAULT.cal_altitude_m,
+    },
+    Rule {
+        valid: |c| (0.0..=60.0).contains(&c.cal_reference_temp_c),
+        error: "Calibration reference temperature must be between 0 and 60 °C",
+        reset: |c| c.cal_reference_temp_c = Config::DEFAULT.cal_reference_temp_c,
+    },
+    // Written into the ExplorIR's calibration, so held to the CO2 setpoint range
+    Rule {
+        valid: |c| (1..=200_000).contains(&c.cal_co2_reference_ppm),
+        error: "Calibration CO2 reference must be between 1 and 200000 ppm",
+        reset: |c| c.cal_co2_reference_ppm = Config::DEFAULT.cal_co2_reference_ppm,
+    },
+    // The range the ExplorIR compensates for
+    Rule {
+        valid: |c| (300.0..=1100.0).contains(&c.cal_co2_pressure_mbar),
+        error: "Calibration CO2 sensor pressure must be between 300 and 1100 mbar",
+        reset: |c| c.cal_co2_pressure_mbar = Config::DEFAULT.cal_co2_pressure_mbar,
+    },
+    Rule {
+        valid: |c| c.mqtt_port != 0,
+        error: "MQTT port must be between 1 and 65535",
+        reset: |c| c.mqtt_port = Config::DEFAULT.mqtt_port,
+    },
+    Rule {
+        valid: |c| !c.mqtt_topic.is_empty(),
+        error: "MQTT topic must not be empty",
+        reset: |c| c.mqtt_topic = Config::DEFAULT.mqtt_topic,
+    },
+    // 0 is the broadcast address and 248-255 are reserved
+    Rule {
+        valid: |c| (1..=247).contains(&c.modbus_unit),
+        error: "Modbus unit must be between 1 and 247",
+        reset: |c| c.modbus_unit = Config::DEFAULT.modbus_unit,
+    },
+    Rule {
+        valid: |c| (1..=1440).contains(&c.ntp_interval_min),
+        error: "NTP interval must be between 1 and 1440 minutes",
+        reset: |c| c.ntp_interval_min = Config::DEFAULT.ntp_interval_min,
+    },
+    Rule {
+        valid: |c| {
+            (0.0..=100.0).contains(&c.target_humidity_rh)
+                && (0.0..=100.0).contains(&c.humidity_alarm_rh)
+        },
+        error: "Humidity setpoint and alarm must be between 0 and 100 %RH",
+        reset: |c| {
+            c.target_humidity_rh = Config::DEFAULT.target_humidity_rh;
+            c.humidity_alarm_rh = Config::DEFAULT.humidity_alarm_rh;
+        },
+    },
+    // The DRV8873 switches at up to 100 kHz; below 1 kHz the module sees the ripple
+    Rule {
+        valid: |c| (1_000..=100_000).contains(&c.peltier_pwm_hz),
+        error: "Peltier PWM frequency must be between 1000 and 100000 Hz",
+        reset: |c| c.peltier_pwm_hz = Config::DEFAULT.peltier_pwm_hz,
+    },
+    // A full reversal (dead time plus 200 % of ramp) has to fit well inside the
+    // watchdog timeout, as the control loop waits for it
+    Rule {
+        valid: |c| c.peltier_dead_time_ms <= 1000,
+        error: "Peltier dead time must be at most 1000 ms",
+        reset: |c| c.peltier_dead_time_ms = Config::DEFAULT.peltier_dead_time_ms,
+    },
+    Rule {
+        valid: |c| (10.0..=1000.0).contains(&c.peltier_ramp_pct_s),
+        error: "Peltier ramp rate must be between 10 and 1000 %/s",
+        reset: |c| c.peltier_ramp_pct_s = Config::DEFAULT.peltier_ramp_pct_s,
+    },
+    Rule {
+        valid: |c| (30.0..=100.0).contains(&c.heatsink_max_c),
+        error: "Heatsink limit must be between 30 and 100 °C",
+        reset: |c| c.heatsink_max_c = Config::DEFAULT.heatsink_max_c,
+    },
+    Rule {
+        valid: |c| (0.0..=100.0).contains(&c.co2_crosscheck_pct),
+        error: "CO2 cross-check threshold must be between 0 and 100 %",
+        reset: |c| c.co2_crosscheck_pct = Config::DEFAULT.co2_crosscheck_pct,
+    },
+    Rule {
+        valid: |c| (0.0..=50.0).contains(&c.door_drop_pct),
+        error: "Door CO2 drop must be between 0 and 50 %",
+        reset: |c| c.door_drop_pct = Config::DEFAULT.door_drop_pct,
+    },
+    Rule {
+        valid: |c| (1.0..=4.0).contains(&c.door_boost),
+        error: "Door recovery boost must be between 1 and 4",
+        reset: |c| c.door_boost = Config::DEFAULT.door_boost,
+    },
+    Rule {
+        valid: |c| (1.0..=400.0).contains(&c.co2_pressure_full_bar),
+        error: "Pressure transducer full scale must be between 1 and 400 bar",
+        reset: |c| c.co2_pressure_full_bar = Config::DEFAULT.co2_pressure_full_bar,
+    },
+    // After the full scale, which it is checked against
+    Rule {
+        valid: |c| (0.0..c.co2_pressure_full_bar).contains(&c.co2_pressure_min_bar),
+        error: "Minimum CO2 supply pressure must be below the full scale",
+        reset: |c| {
+            c.co2_pressure_full_bar = Config::DEFAULT.co2_pressure_full_bar;
+            c.co2_pressure_min_bar = Config::DEFAULT.co2_pressure_min_bar;
+        },
+    },
+    Rule {
+        valid: |c| (10..=1000).contains(&c.valve_pull_in_ms),
+        error: "Valve pull-in time must be between 10 and 1000 ms",
+        reset: |c| c.valve_pull_in_ms = Config::DEFAULT.valve_pull_in_ms,
+    },
+    // Much below this most valves drop out again
+    Rule {
+        valid: |c| (20.0..=100.0).contains(&c.valve_hold_pct),
+        error: "Valve hold duty must be between 20 and 100 %",
+        reset: |c| c.valve_hold_pct = Config::DEFAULT.valve_hold_pct,
+    },
+    Rule {
+        valid: |c| c.probe_calibration.iter().all(valid_probe_calibration),
+        error: "Probe calibration must be finite Steinhart-Hart coefficients or a positive \
+                beta and R0 with T0 between -50 and 150 °C",
+        reset: |c| {
+            for calibration in c.probe_calibration.iter_mut() {
+                if !valid_probe_calibration(calibration) {
+                    *calibration = None;
+                }
+            }
+        },
+    },
+];
+
+fn valid_probe_calibration(calibration: &Option<Model>) -> bool {
+    match calibration {
+        Some(Model::SteinhartHart { a, b, c }) => a.is_finite() && b.is_finite() && c.is_finite(),
+        Some(Model::Beta {
+            beta,
+            r0_ohms,
+            t0_c,
+        }) => {
+            beta.is_finite()
+                && *beta > 0.0
+                && r0_ohms.is_finite()
+                && *r0_ohms > 0.0
+                && (-50.0..=150.0).contains(t0_c)
+        }
+        None => true,
+    }
+}
+
+// Dual-slot, append-only configuration store.
+//
+// Each of the two sectors is filled with fixed size records; saving appends to the active
+// sector and only moves to (and erases) the other sector once the active one is full, so a
+// sector is erased once every SLOTS_PER_SECTOR saves. The previous sector is left intact
+// until then, which keeps a valid fallback if power is lost mid-write.
+pub struct ConfigStore {
+    seq: u32,
+    sector: usize,
+    next_slot: u32,
+}
+
+impl ConfigStore {
+    // Scans both sectors and returns the newest valid record, or defaults if none is found
+    pub fn load<F: NorFlash>(flash: &mut F) -> (Self, Config) {
+        let mut best: Option<(u32, usize, Config)> = None;
+        let mut next_free = [SLOTS_PER_SECTOR; 2];
+
+        for (sector, &base) in SECTORS.iter().enumerate() {
+            for slot in 0..SLOTS_PER_SECTOR {
+                let offset = base + slot * SLOT_SIZE as u32;
+                let mut record = [0u8; SLOT_SIZE];
+                if let Err(e) = flash.read(offset, &mut record) {
+                    error!(
+                        "Config flash read failed at {:#x}: {}",
+                        offset,
+                        defmt::Debug2Format(&e)
+                    );
+                    break;
+                }
+
+                if is_erased(&record) {
+                    next_free[sector] = slot;
+                    break;
+                }
+
+                match Self::parse(&record) {
+                    Ok((seq, config)) => {
+                        if best
+                            .as_ref()
+                            .map_or(true, |(best_seq, _, _)| seq > *best_seq)
+                        {
+                            best = Some((seq, sector, config));
+                        }
+                    }
+                    Err(e) => warn!("Skipping config record at {:#x}: {}", offset, e),
+                }
+            }
+        }
+
+        match best {
+            Some((seq, sector, config)) => {
+                info!(
+                    "Loaded config record #{} from slot {}",
+                    seq, SLOT_NAMES[sector]
+                );
+                (
+                    ConfigStore {
+                        seq,
+                        sector,
+                        next_slot: next_free[sector],
+                    },
+                    config,
+                )
+            }
+            None => {
+                warn!("No valid config record found, using defaults");
+                (
+                    ConfigStore {
+                        seq: 0,
+                        sector: 1, // first save erases and starts on sector A
+                        next_slot: SLOTS_PER_SECTOR,
+                    },
+                    Config::default(),
+                )
+            }
+        }
+    }
+
+    pub fn save<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        config: &Config,
+    ) -> Result<(), &'static str> {
+        config.validate()?;
+
+        let mut record = [0xFFu8; SLOT_SIZE];
+        let payload_len = config.encode(&mut record[RECORD_HEADER_SIZE..]);
+        let seq = self.seq.wrapping_add(1);
+        seal_record(&mut record, CONFIG_MAGIC, CONFIG_VERSION, seq, payload_len);
+
+        let (sector, slot) = if self.next_slot < SLOTS_PER_SECTOR {
+            (self.sector, self.next_slot)
+        } else {
+            let other = 1 - self.sector;
+            let base = SECTORS[other];
+            flash
+                .erase(base, base + SECTOR_SIZE)
+                .map_err(|_| "Failed to erase config sector")?;
+            (other, 0)
+        };
+
+        let offset = SECTORS[sector] + slot * SLOT_SIZE as u32;
+        flash
+            .write(offset, &record)
+            .map_err(|_| "Failed to write config record")?;
+
+        self.seq = seq;
+        self.sector = sector;
+        self.next_slot = slot + 1;
+        info!("Saved config record #{} at {:#x}", seq, offset);
+        Ok(())
+    }
+
+    // Erases both slots; the next load returns defaults
+    pub fn reset<F: NorFlash>(&mut self, flash: &mut F) -> Result<(), &'static str> {
+        for &base in SECTORS.iter() {
+            flash
+                .erase(base, base + SECTOR_SIZE)
+                .map_err(|_| "Failed to erase config sector")?;
+        }
+        self.seq = 0;
+        self.sector = 1;
+        self.next_slot = SLOTS_PER_SECTOR;
+        Ok(())
+    }
+
+    fn parse(record: &[u8; SLOT_SIZE]) -> Result<(u32, Config), &'static str> {
+        let (_, seq, payload) = open_record(record, CONFIG_MAGIC, CONFIG_VERSION)?;
+        let mut config = Config::decode(payload);
+        config.repair();
+        Ok((seq, config))
+    }
+
+    pub fn sequence(&self) -> u32 {
+        self.seq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A record saved under the looser limits of older firmware keeps everything but the
+    // fields the current limits reject
+    #[test]
+    fn repairs_old_limit_record() {
+        let fitted = Model::SteinhartHart {
+            a: 1.1e-3,
+            b: 2.4e-4,
+            c: 7.5e-8,
+        };
+        let mut old = Config::DEFAULT;
+        old.co2_tolerance_ppm = 30_000.0;
+        old.humidity_tolerance_rh = 25.0;
+        old.probe_calibration[0] = Some(Model::SteinhartHart {
+            a: f32::NAN,
+            b: 2.4e-4,
+            c: 7.5e-8,
+        });
+        old.probe_calibration[1] = Some(fitted);
+        old.mqtt_port = 8883;
+        old.ntp_server = [192, 168, 1, 1];
+        old.http_token = HttpToken::new("secret").unwrap();
+        old.valve_wear = Wear {
+            on_time_ms: 123_456_789_000,
+            actuations: 4321,
+        };
+        assert!(old.validate().is_err());
+
+        let mut record = [0xFFu8; SLOT_SIZE];
+        let len = old.encode(&mut record[RECORD_HEADER_SIZE..]);
+        seal_record(&mut record, CONFIG_MAGIC, CONFIG_VERSION, 7, len);
+        let (seq, config) = ConfigStore::parse(&record).unwrap();
+
+        assert_eq!(seq, 7);
+        assert_eq!(config.co2_tolerance_ppm, DEFAULT_CO2_TOLERANCE_PPM);
+        assert_eq!(config.humidity_tolerance_rh, DEFAULT_HUMIDITY_TOLERANCE_RH);
+        assert_eq!(config.probe_calibration[0], None);
+        assert_eq!(config.probe_calibration[1], Some(fitted));
+        assert_eq!(config.mqtt_port, 8883);
+        assert_eq!(config.ntp_server, [192, 168, 1, 1]);
+        assert_eq!(config.http_token, old.http_token);
+        assert_eq!(config.valve_wear, old.valve_wear);
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn keeps_probe_models_and_dividers() {
+        let mut saved = Config::DEFAULT;
+        saved.probe_calibration[0] = Some(Model::SteinhartHart {
+            a: 1.1e-3,
+            b: 2.4e-4,
+            c: 7.5e-8,
+        });
+        saved.probe_calibration[1] = Some(Model::Beta {
+            beta: 3435.0,
+            r0_ohms: 10_000.0,
+            t0_c: 25.0,
+        });
+        saved.probe_divider[1] = Divider::HighSide;
+        saved.probe_divider[2] = Divider::HighSide;
+        assert_eq!(saved.validate(), Ok(()));
+
+        let mut payload = [0u8; MAX_PAYLOAD_SIZE];
+        let len = saved.encode(&mut payload);
+        assert_eq!(Config::decode(&payload[..len]), saved);
+
+        saved.probe_calibration[3] = Some(Model::Beta {
+            beta: -3435.0,
+            r0_ohms: 10_000.0,
+            t0_c: 25.0,
+        });
+        assert!(saved.validate().is_err());
+    }
+}
@@ -0,0 +1,213 @@
+use defmt::{info, warn};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use icbm_protocol::datalog::{
+    entry_size, BlockHeader, Encoder, Sample, BLOCK_HEADER_SIZE, BLOCK_SIZE, MAX_ENTRY_SIZE,
+};
+
+use super::{DATALOG_SECTORS, DATALOG_START, SECTOR_SIZE};
+
+const BLOCKS_PER_SECTOR: u32 = SECTOR_SIZE / BLOCK_SIZE as u32;
+const BLOCKS: u32 = DATALOG_SECTORS * BLOCKS_PER_SECTOR;
+const READ_CHUNK: usize = 256;
+
+// Time-series log of the control loop in a ring of flash blocks, see
+// `icbm_protocol::datalog` for the format.
+//
+// Samples are programmed as they are taken, so a reset only loses the unused rest of the
+// current block; every boot starts a new block. Blocks carry a sequence number that the
+// host downloads by. Once the ring is full, the sector holding the oldest blocks is
+// erased, dropping BLOCKS_PER_SECTOR blocks at a time.
+pub struct DataLog {
+    boot: u16,
+    // Physical index and sequence number of the newest block
+    newest: Option<(u32, u32)>,
+    // Write position in the newest block; `None` until this boot's first sample
+    offset: Option<usize>,
+    encoder: Encoder,
+}
+
+impl DataLog {
+    pub fn open<F: NorFlash>(flash: &mut F) -> Self {
+        let mut log = DataLog {
+            boot: 0,
+            newest: None,
+            offset: None,
+            encoder: Encoder::new(),
+        };
+        let newest = log.scan(flash);
+        log.boot = newest.map_or(0, |header| header.boot.wrapping_add(1));
+
+        let (blocks, first_seq, last_seq) = log.info(flash);
+        info!(
+            "Data log: {} blocks (#{} - #{}), boot {}",
+            blocks, first_seq, last_seq, log.boot
+        );
+        log
+    }
+
+    pub fn append<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        sample: &Sample,
+    ) -> Result<(), &'static str> {
+        let mut entry = [0u8; MAX_ENTRY_SIZE];
+
+        if let (Some(offset), Some((block, _))) = (self.offset, self.newest) {
+            let mut encoder = self.encoder.clone();
+            let len = encoder.encode(sample, &mut entry);
+            if offset + len <= BLOCK_SIZE {
+                // A failed write may leave a partial entry behind, so continue in a new block
+                self.offset = None;
+                flash
+                    .write(address(block) + offset as u32, &entry[..len])
+                    .map_err(|_| "Failed to write data log entry")?;
+                self.offset = Some(offset + len);
+                self.encoder = encoder;
+                return Ok(());
+            }
+        }
+
+        let block = self.start_block(flash)?;
+        let mut encoder = Encoder::new();
+        let len = encoder.encode(sample, &mut entry);
+        flash
+            .write(address(block) + BLOCK_HEADER_SIZE as u32, &entry[..len])
+            .map_err(|_| "Failed to write data log entry")?;
+        self.offset = Some(BLOCK_HEADER_SIZE + len);
+        self.encoder = encoder;
+        Ok(())
+    }
+
+    // Number of blocks held and the sequence numbers of the oldest and newest one
+    pub fn info<F: NorFlash>(&self, flash: &mut F) -> (u32, u32, u32) {
+        let mut blocks = 0;
+        let mut first_seq = u32::MAX;
+        let mut last_seq = 0;
+        for block in 0..BLOCKS {
+            if let Some(header) = read_header(flash, block) {
+                blocks += 1;
+                first_seq = first_seq.min(header.seq);
+                last_seq = last_seq.max(header.seq);
+            }
+        }
+        if blocks == 0 {
+            first_seq = 0;
+        }
+        (blocks, first_seq, last_seq)
+    }
+
+    // Copies the block with sequence number `seq` from `offset` on into `buf`. Returns
+    // the used length of the block (header and entries) and the number of bytes copied.
+    pub fn read<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        seq: u32,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<(usize, usize), &'static str> {
+        let block = (0..BLOCKS)
+            .find(|&block| read_header(flash, block).is_some_and(|header| header.seq == seq))
+            .ok_or("No data log block with this sequence number")?;
+
+        let block_len = used_len(flash, block)?;
+        if offset > block_len {
+            return Err("Data log offset out of range");
+        }
+        let len = buf.len().min(block_len - offset);
+        flash
+            .read(address(block) + offset as u32, &mut buf[..len])
+            .map_err(|_| "Failed to read data log")?;
+        Ok((block_len, len))
+    }
+
+    // Finds the newest block and returns its header
+    fn scan<F: NorFlash>(&mut self, flash: &mut F) -> Option<BlockHeader> {
+        let mut newest: Option<(u32, BlockHeader)> = None;
+        for block in 0..BLOCKS {
+            if let Some(header) = read_header(flash, block) {
+                if newest.is_none_or(|(_, newest)| header.seq > newest.seq) {
+                    newest = Some((block, header));
+                }
+            }
+        }
+        self.newest = newest.map(|(block, header)| (block, header.seq));
+        newest.map(|(_, header)| header)
+    }
+
+    // Writes the header of the block after the newest one and returns its index.
+    // Reaching a sector that still holds old blocks erases it; a block left unusable
+    // by a torn write elsewhere in a sector is skipped.
+    fn start_block<F: NorFlash>(&mut self, flash: &mut F) -> Result<u32, &'static str> {
+        self.offset = None;
+        let (mut block, seq) = match self.newest {
+            Some((block, seq)) => ((block + 1) % BLOCKS, seq.wrapping_add(1)),
+            None => (0, 1),
+        };
+
+        loop {
+            if is_erased(flash, block)? {
+                break;
+            }
+            if block % BLOCKS_PER_SECTOR == 0 {
+                let start = address(block);
+                info!("Data log: erasing sector at {:#x}", start);
+                flash
+                    .erase(start, start + SECTOR_SIZE)
+                    .map_err(|_| "Failed to erase data log sector")?;
+                break;
+            }
+            warn!("Data log: skipping damaged block {}", block);
+            block = (block + 1) % BLOCKS;
+        }
+
+        let header = BlockHeader {
+            boot: self.boot,
+            seq,
+        };
+        flash
+            .write(address(block), &header.encode())
+            .map_err(|_| "Failed to write data log block header")?;
+        self.newest = Some((block, seq));
+        Ok(block)
+    }
+}
+
+fn address(block: u32) -> u32 {
+    DATALOG_START + block * BLOCK_SIZE as u32
+}
+
+fn read_header<F: ReadNorFlash>(flash: &mut F, block: u32) -> Option<BlockHeader> {
+    let mut header = [0u8; BLOCK_HEADER_SIZE];
+    flash.read(address(block), &mut header).ok()?;
+    BlockHeader::decode(&header)
+}
+
+fn is_erased<F: ReadNorFlash>(flash: &mut F, block: u32) -> Result<bool, &'static str> {
+    let mut chunk = [0u8; READ_CHUNK];
+    for offset in (0..BLOCK_SIZE).step_by(READ_CHUNK) {
+        flash
+            .read(address(block) + offset as u32, &mut chunk)
+            .map_err(|_| "Failed to read data log")?;
+        if chunk.iter().any(|&b| b != 0xFF) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// Length of the header and the entries written to a block. Entries are only checked
+// for a plausible length here; the host's decoder stops at a torn one.
+fn used_len<F: ReadNorFlash>(flash: &mut F, block: u32) -> Result<usize, &'static str> {
+    let mut pos = BLOCK_HEADER_SIZE;
+    while pos < BLOCK_SIZE {
+        let mut len = [0u8; 1];
+        flash
+            .read(address(block) + pos as u32, &mut len)
+            .map_err(|_| "Failed to read data log")?;
+        match entry_size(len[0]) {
+            Some(size) if pos + size <= BLOCK_SIZE => pos += size,
+            _ => break,
+        }
+    }
+    Ok(pos)
+}
@@ -0,0 +1,190 @@
+pub mod calibration_log;
+pub mod config;
+pub mod datalog;
+pub mod profile_store;
+pub mod sd_log;
+
+// Flash layout (offsets relative to 0x0800_0000, STM32F407VG with 1 MiB flash)
+//
+//   sector 0-5   0x00000 - 0x3FFFF   firmware image (see memory.x)
+//   sector 6     0x40000 - 0x5FFFF   setpoint profile and its progress
+//   sector 7     0x60000 - 0x7FFFF   calibration log
+//   sector 8     0x80000 - 0x9FFFF   configuration slot A
+//   sector 9     0xA0000 - 0xBFFFF   configuration slot B
+//   sector 10-11 0xC0000 - 0xFFFFF   data log ring
+//
+// Sectors 5-11 are 128 KiB each, which is also the erase granularity exposed by
+// embassy-stm32 through `NorFlash::ERASE_SIZE`.
+pub const SECTOR_SIZE: u32 = 0x2_0000;
+pub const PROFILE_SECTOR: u32 = 0x4_0000;
+pub const CALIBRATION_LOG_SECTOR: u32 = 0x6_0000;
+pub const CONFIG_SECTOR_A: u32 = 0x8_0000;
+pub const CONFIG_SECTOR_B: u32 = 0xA_0000;
+pub const DATALOG_START: u32 = 0xC_0000;
+pub const DATALOG_SECTORS: u32 = 2;
+
+const CRC32_INIT: u32 = 0xFFFF_FFFF;
+const CRC32_POLYNOMIAL: u32 = 0xEDB8_8320; // reflected IEEE 802.3
+
+// Every record stored in flash starts with the same header:
+// magic(4) + version(2) + payload len(2) + seq(4) + crc(4)
+pub const RECORD_HEADER_SIZE: usize = 16;
+pub const ERASED_WORD: u32 = 0xFFFF_FFFF;
+
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(CRC32_INIT, data)
+}
+
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ CRC32_POLYNOMIAL;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+// CRC covers the header (without the CRC field) followed by the payload
+fn record_crc(header: &[u8], payload: &[u8]) -> u32 {
+    !crc32_update(crc32_update(CRC32_INIT, &header[..12]), payload)
+}
+
+// Fills in the header of a record whose payload has already been written after
+// RECORD_HEADER_SIZE bytes of `record`
+pub fn seal_record(record: &mut [u8], magic: u32, version: u16, seq: u32, payload_len: usize) {
+    let mut w = Writer::new(&mut record[..12]);
+    w.u32(magic);
+    w.u16(version);
+    w.u16(payload_len as u16);
+    w.u32(seq);
+    let crc = record_crc(
+        &record[..12],
+        &record[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + payload_len],
+    );
+    record[12..RECORD_HEADER_SIZE].copy_from_slice(&crc.to_le_bytes());
+}
+
+// Validates a record and returns (version, seq, payload)
+pub fn open_record(
+    record: &[u8],
+    magic: u32,
+    max_version: u16,
+) -> Result<(u16, u32, &[u8]), &'static str> {
+    let mut r = Reader::new(record);
+    let stored_magic = r.u32().ok_or("Record too short")?;
+    let version = r.u16().ok_or("Record too short")?;
+    let payload_len = r.u16().ok_or("Record too short")? as usize;
+    let seq = r.u32().ok_or("Record too short")?;
+    let crc = r.u32().ok_or("Record too short")?;
+
+    if stored_magic != magic {
+        return Err("Bad magic");
+    }
+    if version == 0 || version > max_version {
+        return Err("Unsupported version");
+    }
+    if RECORD_HEADER_SIZE + payload_len > record.len() {
+        return Err("Invalid payload length");
+    }
+
+    let payload = &record[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + payload_len];
+    if record_crc(record, payload) != crc {
+        return Err("CRC mismatch");
+    }
+    Ok((version, seq, payload))
+}
+
+pub fn is_erased(record: &[u8]) -> bool {
+    record.len() >= 4
+        && u32::from_le_bytes([record[0], record[1], record[2], record[3]]) == ERASED_WORD
+}
+
+// Little-endian field writer used to serialise flash records
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Writer { buf, pos: 0 }
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.buf[self.pos..self.pos + data.len()].copy_from_slice(data);
+        self.pos += data.len();
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes(&[value]);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, value: f32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+}
+
+// Little-endian field reader; reads past the end return `None` so that records written
+// by older firmware (with fewer fields) can be decoded with defaults for the new fields
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+
+    pub fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let end = self.pos + N;
+        if end > self.buf.len() {
+            return None;
+        }
+        let mut out = [0u8; N];
+        out.copy_from_slice(&self.buf[self.pos..end]);
+        self.pos = end;
+        Some(out)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        self.bytes::<1>().map(|b| b[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        self.bytes().map(u16::from_le_bytes)
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    pub fn i32(&mut self) -> Option<i32> {
+        self.bytes().map(i32::from_le_bytes)
+    }
+
+    pub fn f32(&mut self) -> Option<f32> {
+        self.bytes().map(f32::from_le_bytes)
+    }
+}
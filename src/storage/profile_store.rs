@@ -0,0 +1,160 @@
+use defmt::{info, warn, Format};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use icbm_protocol::profile::{Profile, RunState, Segment, Variable, MAX_SEGMENTS};
+
+use super::{
+    is_erased, open_record, seal_record, Reader, Writer, PROFILE_SECTOR, RECORD_HEADER_SIZE,
+    SECTOR_SIZE,
+};
+
+const PROFILE_MAGIC: u32 = 0x4652_5049; // "IPRF"
+const PROFILE_VERSION: u16 = 1;
+const SLOT_SIZE: usize = 512;
+const SLOTS_PER_SECTOR: u32 = SECTOR_SIZE / SLOT_SIZE as u32;
+
+// Room for both tracks filled up
+const _: () = assert!(RECORD_HEADER_SIZE + 13 + 2 * (1 + MAX_SEGMENTS * 9) <= SLOT_SIZE);
+
+// The profile together with how far it has got
+#[derive(Clone, Copy, Debug, Default, PartialEq, Format)]
+pub struct StoredProfile {
+    pub profile: Profile,
+    pub state: RunState,
+    pub elapsed_s: u32,
+    // Wall-clock time the record was written if the RTC was set, so the time spent
+    // powered off can be accounted for
+    pub saved_at: Option<u32>,
+    // Scheduled start in Unix seconds while waiting
+    pub starts_at: Option<u32>,
+}
+
+impl StoredProfile {
+    fn encode(&self, buf: &mut [u8]) -> usize {
+        let mut w = Writer::new(buf);
+        w.u8(self.state as u8);
+        w.u32(self.elapsed_s);
+        // Unix seconds, 0 if unknown
+        w.u32(self.saved_at.unwrap_or(0));
+        w.u32(self.starts_at.unwrap_or(0));
+        for variable in Variable::ALL {
+            let segments = self.profile.track(variable).segments();
+            w.u8(segments.len() as u8);
+            for segment in segments {
+                let (kind, target, duration_s) = segment.to_parts();
+                w.u8(kind);
+                w.f32(target);
+                w.u32(duration_s);
+            }
+        }
+        w.position()
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, &'static str> {
+        let mut r = Reader::new(buf);
+        let mut decode = || -> Option<Self> {
+            let state = RunState::from_u8(r.u8()?)?;
+            let elapsed_s = r.u32()?;
+            let saved_at = r.u32().filter(|&unix_s| unix_s != 0);
+            let starts_at = r.u32().filter(|&unix_s| unix_s != 0);
+            let mut profile = Profile::EMPTY;
+            for variable in Variable::ALL {
+                let len = r.u8()?;
+                for _ in 0..len {
+                    let segment = Segment::from_parts(r.u8()?, r.f32()?, r.u32()?)?;
+                    profile.track_mut(variable).push(segment).ok()?;
+                }
+            }
+            Some(StoredProfile {
+                profile,
+                state,
+                elapsed_s,
+                saved_at,
+                starts_at,
+            })
+        };
+        decode().ok_or("Invalid profile record")
+    }
+}
+
+// Append-only profile records in a dedicated flash sector; the newest valid one is in
+// effect.
+//
+// Besides edits and state changes, progress is saved periodically while a profile runs,
+// so a sector lasts a couple of days of running before it is erased and the current
+// record rewritten at its start. A power loss during that erase loses the profile, which
+// is the price of having only one sector to spare.
+pub struct ProfileStore {
+    seq: u32,
+    next_slot: u32,
+}
+
+impl ProfileStore {
+    pub fn load<F: NorFlash>(flash: &mut F) -> (Self, StoredProfile) {
+        let mut best: Option<(u32, StoredProfile)> = None;
+        let mut next_slot = SLOTS_PER_SECTOR;
+
+        for slot in 0..SLOTS_PER_SECTOR {
+            let mut record = [0u8; SLOT_SIZE];
+            if flash.read(Self::offset(slot), &mut record).is_err() || is_erased(&record) {
+                next_slot = slot;
+                break;
+            }
+            match Self::parse(&record) {
+                Ok((seq, stored)) => {
+                    if best.as_ref().map_or(true, |(best_seq, _)| seq > *best_seq) {
+                        best = Some((seq, stored));
+                    }
+                }
+                Err(e) => warn!("Skipping profile record in slot {}: {}", slot, e),
+            }
+        }
+
+        match best {
+            Some((seq, stored)) => {
+                info!("Loaded profile record #{} ({})", seq, stored.state);
+                (ProfileStore { seq, next_slot }, stored)
+            }
+            None => (ProfileStore { seq: 0, next_slot }, StoredProfile::default()),
+        }
+    }
+
+    pub fn save<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        stored: &StoredProfile,
+    ) -> Result<(), &'static str> {
+        let mut record = [0xFFu8; SLOT_SIZE];
+        let payload_len = stored.encode(&mut record[RECORD_HEADER_SIZE..]);
+        let seq = self.seq.wrapping_add(1);
+        seal_record(
+            &mut record,
+            PROFILE_MAGIC,
+            PROFILE_VERSION,
+            seq,
+            payload_len,
+        );
+
+        if self.next_slot >= SLOTS_PER_SECTOR {
+            flash
+                .erase(PROFILE_SECTOR, PROFILE_SECTOR + SECTOR_SIZE)
+                .map_err(|_| "Failed to erase profile sector")?;
+            self.next_slot = 0;
+        }
+        flash
+            .write(Self::offset(self.next_slot), &record)
+            .map_err(|_| "Failed to write profile record")?;
+
+        self.seq = seq;
+        self.next_slot += 1;
+        Ok(())
+    }
+
+    fn parse(record: &[u8; SLOT_SIZE]) -> Result<(u32, StoredProfile), &'static str> {
+        let (_, seq, payload) = open_record(record, PROFILE_MAGIC, PROFILE_VERSION)?;
+        Ok((seq, StoredProfile::decode(payload)?))
+    }
+
+    fn offset(slot: u32) -> u32 {
+        PROFILE_SECTOR + slot * SLOT_SIZE as u32
+    }
+}
@@ -0,0 +1,335 @@
+use core::fmt::Write;
+
+use chrono::{DateTime, Datelike, NaiveDateTime, Timelike};
+use defmt::{info, warn};
+use embassy_embedded_hal::shared_bus::blocking::spi::SpiDeviceWithConfig;
+use embassy_stm32::gpio::Output;
+use embassy_stm32::mode::Blocking;
+use embassy_stm32::spi::{self, Spi};
+use embassy_stm32::time::hz;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_time::{Delay, Duration, Instant};
+use embedded_sdmmc::{
+    Mode, RawDirectory, RawVolume, SdCard, SdCardError, TimeSource, Timestamp, VolumeIdx,
+    VolumeManager,
+};
+use heapless::String;
+use icbm_protocol::datalog::{state as actuator, Sample};
+
+use crate::rtc;
+
+// The card is shared with the LCD on SPI3, each with its own chip select and clock
+pub type SdSpi<'a> = SpiDeviceWithConfig<'a, NoopRawMutex, Spi<'static, Blocking>, Output<'static>>;
+type Card<'a> = SdCard<SdSpi<'a>, Delay>;
+type Manager<'a> = VolumeManager<Card<'a>, RtcClock>;
+type Error = embedded_sdmmc::Error<SdCardError>;
+
+// Cards must be initialised at 400 kHz or less; afterwards SPI3 runs at its maximum
+// (42 MHz APB1 / 2)
+const INIT_FREQUENCY: u32 = 400_000;
+const FREQUENCY: u32 = 21_000_000;
+// Mount attempts while no card is inserted
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const LINE_SIZE: usize = 128;
+const MAX_RUN: u16 = 9999;
+
+const CSV_HEADER: &str =
+    "time_s,datetime,temp_c,humidity_rh,co2_ppm,flow_ml_min,heater_on,co2_dosed,humidifier_on,alarms\r\n";
+
+pub fn init_config() -> spi::Config {
+    let mut config = spi::Config::default();
+    config.frequency = hz(INIT_FREQUENCY);
+    config
+}
+
+// FAT timestamps of the written files, from the RTC; the FAT epoch while it is not set
+pub struct RtcClock;
+
+impl TimeSource for RtcClock {
+    fn get_timestamp(&self) -> Timestamp {
+        rtc::now()
+            .and_then(|now| {
+                Timestamp::from_calendar(
+                    now.year() as u16,
+                    now.month() as u8,
+                    now.day() as u8,
+                    now.hour() as u8,
+                    now.minute() as u8,
+                    now.second() as u8,
+                )
+                .ok()
+            })
+            .unwrap_or(Timestamp {
+                year_since_1970: 10,
+                zero_indexed_month: 0,
+                zero_indexed_day: 0,
+                hours: 0,
+                minutes: 0,
+                seconds: 0,
+            })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SdStatus {
+    pub mounted: bool,
+    pub run: u16,
+    pub file: String<12>, // last file written
+    pub lines: u32,       // written since boot
+    pub errors: u32,
+}
+
+// CSV run logs on a FAT16/FAT32 SD card, for pulling data without a computer attached.
+//
+// Every experiment (a boot, or `new_run`) gets its own RUNnnnn directory holding one
+// file per day: YYYYMMDD.CSV by the UTC date, or DAYnnnn.CSV counting days since the
+// start of the run while the RTC is not set. Each line is written with the file opened and closed
+// around it, so pulling the card loses at most the line being written. A failed access
+// is taken as a removed card: the volume is dropped and mounting is retried every
+// RETRY_INTERVAL.
+pub struct SdLog<'a> {
+    manager: Option<Manager<'a>>,
+    volume: Option<RawVolume>,
+    next_mount: Instant,
+    // Run directory; chosen on the first mount after boot or `new_run`
+    run: Option<u16>,
+    started: Instant,
+    status: SdStatus,
+}
+
+impl<'a> SdLog<'a> {
+    pub fn new(spi: SdSpi<'a>) -> Self {
+        SdLog {
+            manager: Some(VolumeManager::new(SdCard::new(spi, Delay), RtcClock)),
+            volume: None,
+            next_mount: Instant::now(),
+            run: None,
+            started: Instant::now(),
+            status: SdStatus::default(),
+        }
+    }
+
+    pub fn status(&self) -> SdStatus {
+        self.status.clone()
+    }
+
+    pub fn append(&mut self, sample: &Sample) {
+        if self.volume.is_none() && !self.mount() {
+            return;
+        }
+
+        let mut line: String<LINE_SIZE> = String::new();
+        let field = |line: &mut String<LINE_SIZE>, value: Option<f32>, decimals: usize| {
+            if let Some(value) = value {
+                let _ = write!(line, "{:.*}", decimals, value);
+            }
+            let _ = line.push(',');
+        };
+        let datetime = sample
+            .unix_s
+            .and_then(|unix_s| DateTime::from_timestamp(unix_s as i64, 0))
+            .map(|datetime| datetime.naive_utc());
+        let _ = write!(line, "{},", sample.time_s);
+        if let Some(datetime) = datetime {
+            let _ = write!(line, "{}", datetime);
+        }
+        let _ = line.push(',');
+        field(&mut line, sample.temp_c, 2);
+        field(&mut line, sample.humidity_rh, 1);
+        field(&mut line, sample.co2_ppm, 0);
+        field(&mut line, sample.flow_ml_min, 2);
+        let _ = write!(
+            line,
+            "{},{},{},{:#x}\r\n",
+            (sample.state & actuator::HEATER_ON != 0) as u8,
+            (sample.state & actuator::CO2_DOSED != 0) as u8,
+            (sample.state & actuator::HUMIDIFIER_ON != 0) as u8,
+            sample.alarms
+        );
+
+        let file_name = self.file_name(datetime);
+        match self.write_line(&file_name, &line) {
+            Ok(()) => {
+                self.status.file = file_name;
+                self.status.lines += 1;
+            }
+            Err(e) => {
+                warn!("SD card write failed ({}), assuming it was removed", e);
+                self.unmount();
+            }
+        }
+    }
+
+    // Starts a new experiment directory with the next file
+    pub fn new_run(&mut self) -> Result<u16, &'static str> {
+        self.run = None;
+        self.started = Instant::now();
+        if self.volume.is_none() {
+            // Retry right away rather than waiting out RETRY_INTERVAL
+            self.next_mount = Instant::now();
+            if !self.mount() {
+                return Err("No SD card");
+            }
+        } else {
+            match self.next_run() {
+                Ok(run) => self.run = Some(run),
+                Err(e) => {
+                    warn!("SD card directory scan failed: {}", e);
+                    self.unmount();
+                    return Err("SD card access failed");
+                }
+            }
+        }
+        let run = self.run.ok_or("No SD card")?;
+        self.status.run = run;
+        info!("Started SD card run RUN{:04}", run);
+        Ok(run)
+    }
+
+    fn mount(&mut self) -> bool {
+        if Instant::now() < self.next_mount {
+            return false;
+        }
+        self.next_mount = Instant::now() + RETRY_INTERVAL;
+        let Some(manager) = self.manager.as_mut() else {
+            return false;
+        };
+
+        let card = manager.device();
+        card.spi(|spi| spi.set_config(init_config()));
+        let size = match card.num_bytes() {
+            Ok(size) => size,
+            Err(_) => {
+                // No card is the normal case for most runs, so this is not a warning
+                card.mark_card_uninit();
+                return false;
+            }
+        };
+        card.spi(|spi| {
+            let mut config = init_config();
+            config.frequency = hz(FREQUENCY);
+            spi.set_config(config)
+        });
+
+        let volume = match manager.open_raw_volume(VolumeIdx(0)) {
+            Ok(volume) => volume,
+            Err(e) => {
+                warn!("SD card has no usable FAT volume: {}", e);
+                self.unmount();
+                return false;
+            }
+        };
+        self.volume = Some(volume);
+        if self.run.is_none() {
+            match self.next_run() {
+                Ok(run) => self.run = Some(run),
+                Err(e) => {
+                    warn!("SD card directory scan failed: {}", e);
+                    self.unmount();
+                    return false;
+                }
+            }
+        }
+
+        self.status.mounted = true;
+        self.status.run = self.run.unwrap_or(0);
+        info!(
+            "SD card mounted ({} MB), logging to RUN{:04}",
+            size / 1_000_000,
+            self.status.run
+        );
+        true
+    }
+
+    // Resets the card and the volume manager, dropping any open handles
+    fn unmount(&mut self) {
+        if let Some(manager) = self.manager.take() {
+            let (card, clock) = manager.free();
+            card.mark_card_uninit();
+            self.manager = Some(VolumeManager::new(card, clock));
+        }
+        if self.volume.take().is_some() {
+            self.status.errors += 1;
+        }
+        self.status.mounted = false;
+    }
+
+    // One more than the highest RUNnnnn directory on the card
+    fn next_run(&mut self) -> Result<u16, Error> {
+        let (manager, volume) = self.mounted()?;
+        let root = manager.open_root_dir(volume)?;
+        let mut last = 0;
+        manager.iterate_dir(root, |entry| {
+            let name = entry.name.base_name();
+            if entry.attributes.is_directory() && name.len() == 7 && name.starts_with(b"RUN") {
+                let run = core::str::from_utf8(&name[3..])
+                    .ok()
+                    .and_then(|digits| digits.parse::<u16>().ok());
+                last = last.max(run.unwrap_or(0));
+            }
+        })?;
+        manager.close_dir(root)?;
+        Ok((last + 1).min(MAX_RUN))
+    }
+
+    fn file_name(&self, datetime: Option<NaiveDateTime>) -> String<12> {
+        let mut name = String::new();
+        let _ = match datetime {
+            Some(datetime) => write!(
+                name,
+                "{:04}{:02}{:02}.CSV",
+                datetime.year(),
+                datetime.month(),
+                datetime.day()
+            ),
+            None => {
+                let day = (Instant::now() - self.started).as_secs() / 86_400;
+                write!(name, "DAY{:04}.CSV", day.min(9999))
+            }
+        };
+        name
+    }
+
+    fn write_line(&mut self, file_name: &str, line: &str) -> Result<(), Error> {
+        let run = self.run.unwrap_or(0);
+        let (manager, volume) = self.mounted()?;
+
+        let mut dir_name: String<8> = String::new();
+        let _ = write!(dir_name, "RUN{:04}", run);
+
+        let root = manager.open_root_dir(volume)?;
+        let dir = open_or_create_dir(manager, root, &dir_name)?;
+        let file = manager.open_file_in_dir(dir, file_name, Mode::ReadWriteCreateOrAppend)?;
+        if manager.file_length(file)? == 0 {
+            manager.write(file, CSV_HEADER.as_bytes())?;
+        }
+        manager.write(file, line.as_bytes())?;
+        manager.close_file(file)?;
+        manager.close_dir(dir)?;
+        manager.close_dir(root)?;
+        Ok(())
+    }
+
+    fn mounted(&mut self) -> Result<(&mut Manager<'a>, RawVolume), Error> {
+        match (self.manager.as_mut(), self.volume) {
+            (Some(manager), Some(volume)) => Ok((manager, volume)),
+            _ => Err(embedded_sdmmc::Error::DeviceError(
+                SdCardError::CardNotFound,
+            )),
+        }
+    }
+}
+
+fn open_or_create_dir(
+    manager: &mut Manager<'_>,
+    parent: RawDirectory,
+    name: &str,
+) -> Result<RawDirectory, Error> {
+    match manager.open_dir(parent, name) {
+        Err(embedded_sdmmc::Error::NotFound) => {
+            manager.make_dir_in_dir(parent, name)?;
+            manager.open_dir(parent, name)
+        }
+        result => result,
+    }
+}
@@ -0,0 +1,58 @@
+use embassy_stm32::time::Hertz;
+use embassy_time::Duration;
+
+use crate::drivers::drv8873::PeltierConfig;
+use crate::drivers::thermistor::{Probe, ThermistorConfig};
+use crate::storage::config::Config;
+
+// Temperature control with the Peltier module, the alternative to the heater's
+// on/off pulses (selected with the `actuator` configuration key).
+//
+// A PI controller sets the signed output: heating below the setpoint, cooling above.
+// The output holds for the whole control cycle, so the integral term settles at the
+// output that balances the losses to the room.
+
+// Full heating 2.5 °C below the setpoint
+const PROPORTIONAL_PCT_PER_C: f32 = 40.0;
+const INTEGRAL_PCT_PER_C: f32 = 2.0;
+
+pub struct PeltierPid {
+    integral_pct: f32,
+}
+
+impl PeltierPid {
+    pub const fn new() -> Self {
+        PeltierPid { integral_pct: 0.0 }
+    }
+
+    // Output for this cycle, -100 % (cooling) to +100 % (heating)
+    pub fn update(&mut self, config: &Config, temp_c: f32) -> f32 {
+        let error = config.target_temp_c - temp_c;
+        // Clamped so a long warm-up does not wind it up
+        self.integral_pct = (self.integral_pct + INTEGRAL_PCT_PER_C * error).clamp(-100.0, 100.0);
+        (PROPORTIONAL_PCT_PER_C * error + self.integral_pct).clamp(-100.0, 100.0)
+    }
+
+    pub fn reset(&mut self) {
+        self.integral_pct = 0.0;
+    }
+}
+
+pub fn peltier_config(config: &Config) -> PeltierConfig {
+    PeltierConfig {
+        pwm_frequency: Hertz(config.peltier_pwm_hz),
+        dead_time: Duration::from_millis(config.peltier_dead_time_ms as u64),
+        ramp_pct_per_s: config.peltier_ramp_pct_s,
+    }
+}
+
+// Bank probes sit on the stock series resistor; the model (the stock curve unless one
+// is configured) and the divider leg are per probe
+pub fn probe_config(config: &Config, probe: Probe) -> ThermistorConfig {
+    let mut thermistor = ThermistorConfig::default();
+    if let Some(model) = config.probe_calibration[probe as usize] {
+        thermistor.model = model;
+    }
+    thermistor.divider = config.probe_divider[probe as usize];
+    thermistor
+}